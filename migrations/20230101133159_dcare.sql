@@ -1,3 +1,13 @@
+-- DROP TABLE IF EXISTS user_roles;
+-- DROP TABLE IF EXISTS role_permissions;
+-- DROP TABLE IF EXISTS permissions;
+-- DROP TABLE IF EXISTS roles;
+-- DROP TABLE IF EXISTS password_histories;
+-- DROP TABLE IF EXISTS login_throttles;
+-- DROP TABLE IF EXISTS mfa_recovery_codes;
+-- DROP TABLE IF EXISTS user_mfa;
+-- DROP TABLE IF EXISTS password_resets;
+-- DROP TABLE IF EXISTS api_keys;
 -- DROP TABLE IF EXISTS sessions;
 -- DROP TABLE IF EXISTS order_gsheets;
 -- DROP TABLE IF EXISTS order_histories;
@@ -93,6 +103,25 @@ CREATE TABLE IF NOT EXISTS faults (
     cost integer NOT NULL
 );
 
+-- 外觀檢查項目, orders.appearance 的第 bit 位; 只新增/改名/停用, 不重新編號
+CREATE TABLE IF NOT EXISTS appearance_flags (
+    bit integer PRIMARY KEY CHECK (bit >= 0),
+    name text NOT NULL UNIQUE,                  -- API 代號
+    label text NOT NULL,                        -- 中文名稱, 匯出與收件單用
+    version integer NOT NULL DEFAULT 1,         -- 最後異動的詞彙版本
+    retired boolean NOT NULL DEFAULT false      -- 停用, 已有的工單照常顯示
+);
+INSERT INTO appearance_flags (bit, name, label) VALUES
+    (0, 'screen_cracked', '螢幕破裂'),
+    (1, 'screen_scratched', '螢幕刮傷'),
+    (2, 'housing_dented', '機殼凹陷'),
+    (3, 'housing_scratched', '機殼刮傷'),
+    (4, 'back_cracked', '背蓋破裂'),
+    (5, 'camera_lens_cracked', '鏡頭破裂'),
+    (6, 'buttons_damaged', '按鍵損壞'),
+    (7, 'water_indicator_tripped', '進水標籤變色')
+ON CONFLICT (bit) DO NOTHING;
+
 -- 工單狀態分類
 CREATE TABLE IF NOT EXISTS status (
     id integer PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
@@ -102,6 +131,52 @@ CREATE TABLE IF NOT EXISTS status (
 --INSERT INTO status(flow) values ('收件');
 --INSERT INTO status(flow) values ('報價');
 
+-- upgrade, statuses of the workflow have a code, rows without one are leftovers
+ALTER TABLE status ADD COLUMN IF NOT EXISTS code text UNIQUE;            -- 代碼
+ALTER TABLE status ADD COLUMN IF NOT EXISTS sort_order integer;          -- 顯示順序
+ALTER TABLE status ADD COLUMN IF NOT EXISTS initial boolean NOT NULL DEFAULT false; -- 開單可用
+
+-- existing rows of the same name take the code, the oldest one if several
+UPDATE status s SET code = w.code
+FROM (VALUES
+    ('received', '收件'), ('quoted', '報價'), ('updated', '更新'),
+    ('locked', '鎖定'), ('returned', '退件'), ('completed', '完成')
+) AS w (code, flow)
+WHERE s.code IS NULL AND s.id = (SELECT min(id) FROM status WHERE flow = w.flow)
+    AND NOT EXISTS (SELECT 1 FROM status WHERE code = w.code);
+
+INSERT INTO status (code, flow, sort_order, initial) VALUES
+    ('received', '收件', 1, true),
+    ('quoted', '報價', 2, false),
+    ('updated', '更新', 3, false),
+    ('locked', '鎖定', 4, false),
+    ('returned', '退件', 5, false),
+    ('completed', '完成', 6, false)
+ON CONFLICT (code) DO UPDATE SET sort_order = EXCLUDED.sort_order, initial = EXCLUDED.initial;
+
+-- 工單狀態流程, 不在表內的轉換一律拒絕
+CREATE TABLE IF NOT EXISTS status_transitions (
+    from_id integer NOT NULL REFERENCES status (id) ON DELETE CASCADE,
+    to_id integer NOT NULL REFERENCES status (id) ON DELETE CASCADE,
+    permission text,          -- 額外需要的權限, NULL 則可修改工單即可
+    PRIMARY KEY (from_id, to_id)
+);
+
+-- default workflow, first run only
+INSERT INTO status_transitions (from_id, to_id, permission)
+SELECT f.id, t.id, w.permission
+FROM (VALUES
+    ('received', 'quoted', NULL), ('received', 'returned', NULL),
+    ('quoted', 'updated', NULL), ('quoted', 'locked', 'order.lock'), ('quoted', 'returned', NULL),
+    ('updated', 'quoted', NULL), ('updated', 'locked', 'order.lock'), ('updated', 'returned', NULL),
+    ('locked', 'completed', NULL), ('locked', 'returned', NULL),
+    ('completed', 'updated', 'order.reopen'),
+    ('returned', 'received', 'order.reopen')
+) AS w (from_code, to_code, permission)
+JOIN status f ON f.code = w.from_code
+JOIN status t ON t.code = w.to_code
+WHERE NOT EXISTS (SELECT 1 FROM status_transitions);
+
 -- 工單
 CREATE TABLE IF NOT EXISTS orders (
     id integer PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
@@ -160,8 +235,590 @@ CREATE TABLE IF NOT EXISTS order_histories (
     cost integer
 );
 
+-- upgrade, every update bumps version, the ETag of optimistic concurrency
+ALTER TABLE orders ADD COLUMN IF NOT EXISTS version integer NOT NULL DEFAULT 1;     -- 版本
+ALTER TABLE orders ADD COLUMN IF NOT EXISTS update_at timestamptz;                  -- 修改時間
+ALTER TABLE users ADD COLUMN IF NOT EXISTS version integer NOT NULL DEFAULT 1;
+ALTER TABLE departments ADD COLUMN IF NOT EXISTS version integer NOT NULL DEFAULT 1;
+
+-- upgrade, changed fields of every update, {"field": {"old": .., "new": ..}}
+ALTER TABLE order_histories ADD COLUMN IF NOT EXISTS changes jsonb NOT NULL DEFAULT '{}';   -- 異動欄位
+CREATE INDEX IF NOT EXISTS order_histories_order_id_change_at ON order_histories (order_id, change_at);
+
+-- upgrade, soft delete, queries skip rows with deleted_at, admins restore or purge them
+ALTER TABLE orders ADD COLUMN IF NOT EXISTS deleted_at timestamptz;              -- 刪除時間
+ALTER TABLE orders ADD COLUMN IF NOT EXISTS deleted_by integer;                  -- 刪除人員
+ALTER TABLE users ADD COLUMN IF NOT EXISTS deleted_at timestamptz;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS deleted_by integer;
+ALTER TABLE departments ADD COLUMN IF NOT EXISTS deleted_at timestamptz;
+ALTER TABLE departments ADD COLUMN IF NOT EXISTS deleted_by integer;
+
+-- upgrade, the appearance flags outgrow bit(8), bit n is appearance_flags.bit n
+DO $$
+BEGIN
+    IF (SELECT data_type FROM information_schema.columns
+        WHERE table_name = 'orders' AND column_name = 'appearance') = 'bit' THEN
+        ALTER TABLE orders ALTER COLUMN appearance TYPE bit varying;
+    END IF;
+END $$;
+
+-- upgrade, purging a user or a catalog row must not take orders with it:
+-- people referenced are set NULL, departments/catalog/status refuse the purge
+DO $$
+DECLARE
+    fk record;
+BEGIN
+    FOR fk IN
+        SELECT * FROM (VALUES
+            ('orders', 'issuer_id', 'users', 'SET NULL'),
+            ('orders', 'contact_id', 'users', 'SET NULL'),
+            ('orders', 'servicer_id', 'users', 'SET NULL'),
+            ('orders', 'maintainer_id', 'users', 'SET NULL'),
+            ('orders', 'department_id', 'departments', 'RESTRICT'),
+            ('orders', 'model_id', 'models', 'RESTRICT'),
+            ('orders', 'accessory_id1', 'accessories', 'RESTRICT'),
+            ('orders', 'accessory_id2', 'accessories', 'RESTRICT'),
+            ('orders', 'fault_id1', 'faults', 'RESTRICT'),
+            ('orders', 'fault_id2', 'faults', 'RESTRICT'),
+            ('orders', 'status_id', 'status', 'RESTRICT'),
+            ('order_histories', 'issuer_id', 'users', 'SET NULL'),
+            ('order_histories', 'status_id', 'status', 'RESTRICT'),
+            ('users', 'title_id', 'titles', 'SET NULL'),
+            ('users', 'department_id', 'departments', 'RESTRICT')
+        ) AS t (tbl, col, ref, action)
+    LOOP
+        IF EXISTS (
+            SELECT 1 FROM pg_constraint
+            WHERE conname = fk.tbl || '_' || fk.col || '_fkey' AND confdeltype = 'c'
+        ) THEN
+            EXECUTE format(
+                'ALTER TABLE %I DROP CONSTRAINT %I, ADD CONSTRAINT %I FOREIGN KEY (%I) REFERENCES %I (id) ON DELETE %s',
+                fk.tbl, fk.tbl || '_' || fk.col || '_fkey', fk.tbl || '_' || fk.col || '_fkey',
+                fk.col, fk.ref, fk.action
+            );
+        END IF;
+    END LOOP;
+END $$;
+
+-- 工單附件, 照片與文件, 內容在 BlobStore
+CREATE TABLE IF NOT EXISTS order_attachments (
+    id integer PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    create_at timestamptz NOT NULL DEFAULT NOW(),                     -- 上傳時間
+    order_id integer NOT NULL REFERENCES orders (id) ON DELETE CASCADE, -- 工單
+    uploader_id integer REFERENCES users (id) ON DELETE SET NULL,       -- 上傳人員
+    filename text NOT NULL,         -- 原始檔名
+    content_type text NOT NULL,     -- 依內容判定的 MIME
+    size integer NOT NULL,          -- 去除 EXIF 後的大小
+    sha256 text NOT NULL,
+    blob_key text NOT NULL UNIQUE,  -- BlobStore key
+    thumbnail_key text,             -- 縮圖, 文件沒有
+    width integer,
+    height integer
+);
+CREATE INDEX IF NOT EXISTS order_attachments_order_id_idx ON order_attachments (order_id);
+
+-- 工單收付款, 只增不改; 作廢是一筆反向沖銷 (voids_id), orders.confirmed_paid 是其淨額
+CREATE TABLE IF NOT EXISTS order_payments (
+    id integer PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    create_at timestamptz NOT NULL DEFAULT NOW(),                       -- 收/退款時間
+    order_id integer NOT NULL REFERENCES orders (id) ON DELETE CASCADE, -- 工單
+    direction text NOT NULL CHECK (direction IN ('charge', 'refund')),  -- 收款/退款
+    method text NOT NULL CHECK (method IN ('cash', 'card', 'transfer', 'line_pay')), -- 現金/刷卡/轉帳/LINE Pay
+    amount integer NOT NULL CHECK (amount > 0),
+    receiver_id integer REFERENCES users (id) ON DELETE SET NULL,           -- 經手人員
+    department_id integer REFERENCES departments (id) ON DELETE RESTRICT,   -- 經手門市
+    remark text,                    -- 備註
+    voids_id integer UNIQUE REFERENCES order_payments (id)                 -- 沖銷的款項
+);
+CREATE INDEX IF NOT EXISTS order_payments_order_id_idx ON order_payments (order_id);
+
+CREATE OR REPLACE FUNCTION order_payments_append_only() RETURNS trigger
+LANGUAGE plpgsql AS $$
+BEGIN
+    -- purging the order takes its payments along
+    IF TG_OP = 'DELETE' AND NOT EXISTS (SELECT 1 FROM orders WHERE id = OLD.order_id) THEN
+        RETURN OLD;
+    END IF;
+    -- purging the receiver unlinks it, nothing else changes
+    IF TG_OP = 'UPDATE' AND NEW.receiver_id IS NULL
+        AND (NEW.id, NEW.create_at, NEW.order_id, NEW.direction, NEW.method, NEW.amount,
+             NEW.department_id, NEW.remark, NEW.voids_id)
+        IS NOT DISTINCT FROM
+            (OLD.id, OLD.create_at, OLD.order_id, OLD.direction, OLD.method, OLD.amount,
+             OLD.department_id, OLD.remark, OLD.voids_id) THEN
+        RETURN NEW;
+    END IF;
+    RAISE EXCEPTION 'order_payments is append-only, void the payment instead';
+END;
+$$;
+
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_trigger WHERE tgname = 'order_payments_append_only') THEN
+        CREATE TRIGGER order_payments_append_only BEFORE UPDATE OR DELETE ON order_payments
+        FOR EACH ROW EXECUTE FUNCTION order_payments_append_only();
+    END IF;
+END $$;
+
+-- upgrade, the amounts of orders from before the ledger become its first entries,
+-- confirmed_paid then counts the prepaid amount too
+WITH legacy AS (
+    INSERT INTO order_payments (create_at, order_id, direction, method, amount, department_id, remark)
+    SELECT o.issue_at, o.id, 'charge', 'cash', m.amount, o.department_id, m.remark
+    FROM orders o
+    CROSS JOIN LATERAL (VALUES (o.prepaid_free, '預收款'), (o.confirmed_paid, '已付款')) AS m (amount, remark)
+    WHERE m.amount > 0
+        AND NOT EXISTS (SELECT 1 FROM order_payments p WHERE p.order_id = o.id)
+    RETURNING order_id, amount
+)
+UPDATE orders o SET confirmed_paid = l.paid
+FROM (SELECT order_id, SUM(amount) AS paid FROM legacy GROUP BY order_id) AS l
+WHERE o.id = l.order_id;
+
+-- 工單報價, 修改即發新版; 客戶以報價連結 (token_hash) 免登入同意或拒絕
+CREATE TABLE IF NOT EXISTS quotes (
+    id integer PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    create_at timestamptz NOT NULL DEFAULT NOW(),                       -- 報價時間
+    order_id integer NOT NULL REFERENCES orders (id) ON DELETE CASCADE, -- 工單
+    version integer NOT NULL,                                           -- 第幾版
+    issuer_id integer REFERENCES users (id) ON DELETE SET NULL,         -- 報價人員
+    total integer NOT NULL CHECK (total >= 0),                          -- 報價總額
+    remark text,                    -- 備註
+    token_hash text NOT NULL UNIQUE,    -- 報價連結 token 的 sha256
+    expires_at timestamptz NOT NULL,    -- 連結到期時間
+    state text NOT NULL DEFAULT 'pending'
+        CHECK (state IN ('pending', 'accepted', 'rejected', 'superseded')), -- 待回覆/同意/拒絕/已改版
+    decided_at timestamptz,         -- 客戶回覆時間
+    decided_ip text,                -- 客戶回覆 IP
+    decided_user_agent text,
+    UNIQUE (order_id, version)
+);
+
+-- 報價明細, 故障/配件取自目錄, 工資自填
+CREATE TABLE IF NOT EXISTS quote_items (
+    id integer PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    quote_id integer NOT NULL REFERENCES quotes (id) ON DELETE CASCADE, -- 報價
+    kind text NOT NULL CHECK (kind IN ('fault', 'accessory', 'labour')), -- 故障/配件/工資
+    fault_id integer REFERENCES faults (id) ON DELETE RESTRICT,          -- 故障
+    accessory_id integer REFERENCES accessories (id) ON DELETE RESTRICT, -- 配件
+    description text NOT NULL,      -- 品項說明
+    quantity integer NOT NULL CHECK (quantity > 0),      -- 數量
+    unit_price integer NOT NULL CHECK (unit_price >= 0)  -- 單價
+);
+CREATE INDEX IF NOT EXISTS quote_items_quote_id_idx ON quote_items (quote_id);
+
+-- 門市自訂的收件單/請款單樣板, 沒有的用內建樣板
+CREATE TABLE IF NOT EXISTS department_templates (
+    department_id integer NOT NULL REFERENCES departments (id) ON DELETE CASCADE,
+    name text NOT NULL,             -- receipt.html, invoice.txt (PDF 的文字), ...
+    body text NOT NULL,             -- Tera 樣板
+    update_at timestamptz NOT NULL DEFAULT NOW(),
+    updated_by integer REFERENCES users (id) ON DELETE SET NULL,
+    PRIMARY KEY (department_id, name)
+);
+
+-- upgrade, catalog rows are unique so get-or-insert can use ON CONFLICT,
+-- duplicates from concurrent inserts are merged into the oldest row first
+DO $$
+BEGIN
+    IF to_regclass('models_brand_model_key') IS NULL THEN
+        UPDATE orders o SET model_id = k.keep
+        FROM (SELECT id, min(id) OVER (PARTITION BY brand, model) AS keep FROM models) k
+        WHERE o.model_id = k.id AND k.id <> k.keep;
+        DELETE FROM models m USING models k
+        WHERE m.brand = k.brand AND m.model = k.model AND m.id > k.id;
+        CREATE UNIQUE INDEX models_brand_model_key ON models (brand, model);
+    END IF;
+
+    IF to_regclass('accessories_item_key') IS NULL THEN
+        UPDATE orders o SET accessory_id1 = k.keep
+        FROM (SELECT id, min(id) OVER (PARTITION BY item) AS keep FROM accessories) k
+        WHERE o.accessory_id1 = k.id AND k.id <> k.keep;
+        UPDATE orders o SET accessory_id2 = k.keep
+        FROM (SELECT id, min(id) OVER (PARTITION BY item) AS keep FROM accessories) k
+        WHERE o.accessory_id2 = k.id AND k.id <> k.keep;
+        DELETE FROM accessories a USING accessories k WHERE a.item = k.item AND a.id > k.id;
+        CREATE UNIQUE INDEX accessories_item_key ON accessories (item);
+    END IF;
+
+    IF to_regclass('faults_item_key') IS NULL THEN
+        UPDATE orders o SET fault_id1 = k.keep
+        FROM (SELECT id, min(id) OVER (PARTITION BY item) AS keep FROM faults) k
+        WHERE o.fault_id1 = k.id AND k.id <> k.keep;
+        UPDATE orders o SET fault_id2 = k.keep
+        FROM (SELECT id, min(id) OVER (PARTITION BY item) AS keep FROM faults) k
+        WHERE o.fault_id2 = k.id AND k.id <> k.keep;
+        DELETE FROM faults f USING faults k WHERE f.item = k.item AND f.id > k.id;
+        CREATE UNIQUE INDEX faults_item_key ON faults (item);
+    END IF;
+END $$;
+
+-- 工單號流水號, 每部門每日從 1 開始
+CREATE TABLE IF NOT EXISTS order_sn_counters (
+    department_id integer NOT NULL REFERENCES departments (id) ON DELETE CASCADE,
+    day date NOT NULL,
+    last integer NOT NULL,        -- 最後發出的序號
+    PRIMARY KEY (department_id, day)
+);
+
+-- 搜尋用: 工單號/客戶/手機(含純數字)/地址/故障/備註, brand/model are matched through models
+ALTER TABLE orders ADD COLUMN IF NOT EXISTS search_text text GENERATED ALWAYS AS (
+    sn || ' ' || coalesce(customer_name, '') || ' ' || customer_phone || ' '
+    || regexp_replace(customer_phone, '\D', '', 'g') || ' ' || coalesce(customer_address, '')
+    || ' ' || coalesce(fault_other, '') || ' ' || coalesce(remark, '')
+) STORED;
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+CREATE INDEX IF NOT EXISTS orders_search_trgm_idx ON orders USING gin (search_text gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS orders_search_fts_idx ON orders USING gin (to_tsvector('simple', search_text));
+
 -- internal table for user login
 CREATE TABLE IF NOT EXISTS sessions (
     session_token BYTEA PRIMARY KEY,
-    user_id integer REFERENCES users (id) ON DELETE CASCADE
+    user_id integer REFERENCES users (id) ON DELETE CASCADE,
+    created_at timestamptz NOT NULL DEFAULT NOW(),     -- 登入時間
+    last_seen_at timestamptz NOT NULL DEFAULT NOW(),   -- 最後使用
+    expires_at timestamptz NOT NULL DEFAULT NOW(),     -- 閒置/絕對期限(取早者)
+    id integer GENERATED ALWAYS AS IDENTITY UNIQUE,    -- 對外識別(不暴露token)
+    user_agent text,                                   -- 裝置
+    ip text,
+    mfa_pending boolean NOT NULL DEFAULT false,        -- 密碼通過, 等待第二因素
+    mfa_failures integer NOT NULL DEFAULT 0
+);
+-- upgrade, sessions created before expiry existed are expired right away
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS created_at timestamptz NOT NULL DEFAULT NOW();
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS last_seen_at timestamptz NOT NULL DEFAULT NOW();
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS expires_at timestamptz NOT NULL DEFAULT NOW();
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS id integer GENERATED ALWAYS AS IDENTITY UNIQUE;
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS user_agent text;
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS ip text;
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS mfa_pending boolean NOT NULL DEFAULT false;
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS mfa_failures integer NOT NULL DEFAULT 0;
+CREATE INDEX IF NOT EXISTS sessions_expires_at_idx ON sessions (expires_at);
+CREATE INDEX IF NOT EXISTS sessions_user_id_idx ON sessions (user_id);
+
+-- machine clients (APP, scripts), Authorization: Bearer dck_<prefix>_<secret>
+CREATE TABLE IF NOT EXISTS api_keys (
+    id integer PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE, -- 擁有者
+    name text NOT NULL,                                -- 用途/名稱
+    key_prefix text NOT NULL,                          -- 顯示用前綴
+    key_hash text NOT NULL UNIQUE,                     -- sha256, 不存原始金鑰
+    scopes text[] NOT NULL DEFAULT '{}',               -- e.g. order:read, order:write, *
+    create_at timestamptz NOT NULL DEFAULT NOW(),
+    expires_at timestamptz,                            -- NULL 不過期
+    last_used_at timestamptz
+);
+CREATE INDEX IF NOT EXISTS api_keys_user_id_idx ON api_keys (user_id);
+
+-- forgot password, single-use and time-limited, only the hash of the mailed token is kept
+CREATE TABLE IF NOT EXISTS password_resets (
+    id integer PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    token_hash text NOT NULL UNIQUE,                   -- sha256
+    create_at timestamptz NOT NULL DEFAULT NOW(),
+    expires_at timestamptz NOT NULL,
+    used_at timestamptz,                               -- 已使用
+    ip text                                            -- 申請來源
+);
+CREATE INDEX IF NOT EXISTS password_resets_user_id_idx ON password_resets (user_id);
+
+-- TOTP 2FA, one authenticator per user
+CREATE TABLE IF NOT EXISTS user_mfa (
+    user_id integer PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    secret text NOT NULL,                              -- base32
+    confirmed_at timestamptz,                          -- NULL 尚未完成綁定
+    last_step bigint NOT NULL DEFAULT 0,               -- 最後使用的時間步, 防重放
+    create_at timestamptz NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS mfa_recovery_codes (
+    id integer PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    user_id integer NOT NULL REFERENCES user_mfa (user_id) ON DELETE CASCADE,
+    code_hash text NOT NULL,                           -- sha256
+    used_at timestamptz
+);
+CREATE INDEX IF NOT EXISTS mfa_recovery_codes_user_id_idx ON mfa_recovery_codes (user_id);
+
+-- failed logins, scope 'account' (key = account) or 'ip' (key = client IP)
+CREATE TABLE IF NOT EXISTS login_throttles (
+    scope text NOT NULL,
+    key text NOT NULL,
+    failures integer NOT NULL DEFAULT 0,               -- 連續失敗次數
+    last_failure_at timestamptz NOT NULL DEFAULT NOW(),
+    locked_until timestamptz,                          -- 退避/鎖定至
+    PRIMARY KEY (scope, key)
+);
+
+-- 稽核紀錄, append-only, no foreign keys so rows outlive what they are about
+CREATE TABLE IF NOT EXISTS audit_events (
+    id bigint PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    at timestamptz NOT NULL DEFAULT NOW(),             -- 時間
+    actor_id integer,                                  -- 操作者, NULL 未登入
+    actor text,                                        -- 操作者帳號
+    action text NOT NULL,                              -- e.g. user.update, session.login
+    entity_type text NOT NULL,                         -- user, department, order, ...
+    entity_id text,                                    -- account, shorten, sn, ...
+    before jsonb,                                      -- 異動前
+    after jsonb,                                       -- 異動後
+    ip text,                                           -- 來源
+    request_id text                                    -- X-Request-Id
 );
+CREATE INDEX IF NOT EXISTS audit_events_at_idx ON audit_events (at);
+CREATE INDEX IF NOT EXISTS audit_events_entity_idx ON audit_events (entity_type, entity_id);
+CREATE INDEX IF NOT EXISTS audit_events_actor_id_idx ON audit_events (actor_id);
+
+CREATE OR REPLACE FUNCTION audit_events_append_only() RETURNS trigger
+LANGUAGE plpgsql AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$;
+
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_trigger WHERE tgname = 'audit_events_append_only') THEN
+        CREATE TRIGGER audit_events_append_only BEFORE UPDATE OR DELETE ON audit_events
+        FOR EACH ROW EXECUTE FUNCTION audit_events_append_only();
+    END IF;
+END $$;
+
+-- previous password hashes of a user, new passwords can't repeat the last PASSWORD_HISTORY
+CREATE TABLE IF NOT EXISTS password_histories (
+    id integer PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    password text NOT NULL,                            -- PHC hash
+    create_at timestamptz NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS password_histories_user_id_idx ON password_histories (user_id, id);
+
+-- 角色, replaces the users.permission bits (kept in sync for older clients)
+CREATE TABLE IF NOT EXISTS roles (
+    id integer PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    name text NOT NULL UNIQUE,                         -- admin, gm, maintainer, ...
+    description text,
+    create_at timestamptz NOT NULL DEFAULT NOW()
+);
+
+-- 權限, <resource>.<action>
+CREATE TABLE IF NOT EXISTS permissions (
+    id integer PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    name text NOT NULL UNIQUE,                         -- e.g. order.update
+    description text
+);
+
+CREATE TABLE IF NOT EXISTS role_permissions (
+    role_id integer NOT NULL REFERENCES roles (id) ON DELETE CASCADE,
+    permission_id integer NOT NULL REFERENCES permissions (id) ON DELETE CASCADE,
+    PRIMARY KEY (role_id, permission_id)
+);
+
+CREATE TABLE IF NOT EXISTS user_roles (
+    user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    role_id integer NOT NULL REFERENCES roles (id) ON DELETE CASCADE,
+    PRIMARY KEY (user_id, role_id)
+);
+CREATE INDEX IF NOT EXISTS user_roles_role_id_idx ON user_roles (role_id);
+
+INSERT INTO permissions (name, description) VALUES
+    ('user.read', '查看使用者'),
+    ('user.create', '新增使用者'),
+    ('user.update', '修改其他使用者'),
+    ('user.delete', '刪除使用者'),
+    ('user.security', '解鎖/重設 2FA/登出其他使用者'),
+    ('role.manage', '管理角色與指派'),
+    ('order.read', '查看工單'),
+    ('order.create', '新增工單'),
+    ('order.update', '修改工單'),
+    ('order.delete', '刪除工單'),
+    ('department.read', '查看部門'),
+    ('department.create', '新增部門'),
+    ('department.update', '修改部門'),
+    ('department.delete', '刪除部門')
+ON CONFLICT (name) DO NOTHING;
+
+INSERT INTO roles (name, description) VALUES
+    ('admin', '系統管理員'),
+    ('gm', '總經理'),
+    ('maintainer', '維修'),
+    ('comissioner', '委員'),
+    ('jshall', '門市'),
+    ('other', '其他')
+ON CONFLICT (name) DO NOTHING;
+
+-- admin always holds every permission, new ones included
+INSERT INTO role_permissions (role_id, permission_id)
+SELECT r.id, p.id FROM roles r CROSS JOIN permissions p WHERE r.name = 'admin'
+ON CONFLICT DO NOTHING;
+
+DO $$
+BEGIN
+    -- default grants of the other built-in roles, first run only
+    IF NOT EXISTS (
+        SELECT 1 FROM role_permissions JOIN roles ON roles.id = role_id WHERE roles.name <> 'admin'
+    ) THEN
+        INSERT INTO role_permissions (role_id, permission_id)
+        SELECT r.id, p.id
+        FROM (VALUES
+            ('gm', 'user.read'), ('gm', 'user.create'), ('gm', 'user.update'),
+            ('gm', 'order.read'), ('gm', 'order.create'), ('gm', 'order.update'), ('gm', 'order.delete'),
+            ('gm', 'department.read'), ('gm', 'department.create'), ('gm', 'department.update'),
+            ('maintainer', 'user.read'), ('maintainer', 'order.read'), ('maintainer', 'order.update'),
+            ('maintainer', 'department.read'),
+            ('comissioner', 'user.read'), ('comissioner', 'order.read'), ('comissioner', 'order.create'),
+            ('comissioner', 'order.update'), ('comissioner', 'department.read'),
+            ('jshall', 'order.read'), ('jshall', 'order.create'), ('jshall', 'department.read'),
+            ('other', 'order.read'), ('other', 'department.read')
+        ) AS g (role, permission)
+        JOIN roles r ON r.name = g.role
+        JOIN permissions p ON p.name = g.permission;
+    END IF;
+
+    -- users.permission bits to user_roles, every set bit counts, first run only
+    IF NOT EXISTS (SELECT 1 FROM user_roles) THEN
+        INSERT INTO user_roles (user_id, role_id)
+        SELECT u.id, r.id
+        FROM users u
+        JOIN (VALUES (0, 'admin'), (1, 'gm'), (2, 'maintainer'), (3, 'comissioner'), (4, 'jshall'))
+            AS b (bit, role) ON u.permission IS NOT NULL AND get_bit(u.permission, b.bit) = 1
+        JOIN roles r ON r.name = b.role;
+
+        INSERT INTO user_roles (user_id, role_id)
+        SELECT u.id, r.id FROM users u JOIN roles r ON r.name = 'other'
+        WHERE NOT EXISTS (SELECT 1 FROM user_roles WHERE user_id = u.id);
+    END IF;
+END $$;
+
+DO $$
+BEGIN
+    -- reach over orders, out of the own department or assigned, granted when first added
+    IF NOT EXISTS (SELECT 1 FROM permissions WHERE name = 'order.all') THEN
+        INSERT INTO permissions (name, description) VALUES
+            ('order.all', '處理所有部門的工單'),
+            ('order.update_assigned', '修改指派給自己的工單');
+
+        INSERT INTO role_permissions (role_id, permission_id)
+        SELECT r.id, p.id
+        FROM (VALUES
+            ('admin', 'order.all'), ('admin', 'order.update_assigned'),
+            ('gm', 'order.all'), ('gm', 'department.delete'),
+            ('maintainer', 'order.update_assigned')
+        ) AS g (role, permission)
+        JOIN roles r ON r.name = g.role
+        JOIN permissions p ON p.name = g.permission
+        ON CONFLICT DO NOTHING;
+
+        -- maintainers update the orders assigned to them only
+        DELETE FROM role_permissions
+        WHERE role_id = (SELECT id FROM roles WHERE name = 'maintainer')
+            AND permission_id = (SELECT id FROM permissions WHERE name = 'order.update');
+    END IF;
+
+    -- transitions of the order status workflow, see status_transitions
+    IF NOT EXISTS (SELECT 1 FROM permissions WHERE name = 'order.lock') THEN
+        INSERT INTO permissions (name, description) VALUES
+            ('order.lock', '鎖定工單'),
+            ('order.reopen', '重新開啟已完成/退件的工單');
+
+        INSERT INTO role_permissions (role_id, permission_id)
+        SELECT r.id, p.id
+        FROM (VALUES
+            ('admin', 'order.lock'), ('admin', 'order.reopen'),
+            ('maintainer', 'order.lock'),
+            ('gm', 'order.reopen')
+        ) AS g (role, permission)
+        JOIN roles r ON r.name = g.role
+        JOIN permissions p ON p.name = g.permission
+        ON CONFLICT DO NOTHING;
+    END IF;
+
+    -- audit log, admins only
+    IF NOT EXISTS (SELECT 1 FROM permissions WHERE name = 'audit.read') THEN
+        INSERT INTO permissions (name, description) VALUES
+            ('audit.read', '查看/匯出稽核紀錄');
+
+        INSERT INTO role_permissions (role_id, permission_id)
+        SELECT r.id, p.id FROM roles r JOIN permissions p ON p.name = 'audit.read'
+        WHERE r.name = 'admin'
+        ON CONFLICT DO NOTHING;
+    END IF;
+
+    -- deleted orders/users/departments, admins only
+    IF NOT EXISTS (SELECT 1 FROM permissions WHERE name = 'trash.manage') THEN
+        INSERT INTO permissions (name, description) VALUES
+            ('trash.manage', '還原/永久刪除已刪除的工單、人員、部門');
+
+        INSERT INTO role_permissions (role_id, permission_id)
+        SELECT r.id, p.id FROM roles r JOIN permissions p ON p.name = 'trash.manage'
+        WHERE r.name = 'admin'
+        ON CONFLICT DO NOTHING;
+    END IF;
+
+    -- appearance flags, admins and GMs
+    IF NOT EXISTS (SELECT 1 FROM permissions WHERE name = 'catalog.manage') THEN
+        INSERT INTO permissions (name, description) VALUES
+            ('catalog.manage', '管理外觀檢查項目');
+
+        INSERT INTO role_permissions (role_id, permission_id)
+        SELECT r.id, p.id FROM roles r JOIN permissions p ON p.name = 'catalog.manage'
+        WHERE r.name IN ('admin', 'gm')
+        ON CONFLICT DO NOTHING;
+    END IF;
+
+    -- closing orders with a balance due, GMs (and admins)
+    IF NOT EXISTS (SELECT 1 FROM permissions WHERE name = 'order.close_unpaid') THEN
+        INSERT INTO permissions (name, description) VALUES
+            ('order.close_unpaid', '未結清的工單仍可完成');
+
+        INSERT INTO role_permissions (role_id, permission_id)
+        SELECT r.id, p.id FROM roles r JOIN permissions p ON p.name = 'order.close_unpaid'
+        WHERE r.name IN ('admin', 'gm')
+        ON CONFLICT DO NOTHING;
+    END IF;
+
+    -- department receipt/invoice templates, admins only
+    IF NOT EXISTS (SELECT 1 FROM permissions WHERE name = 'template.manage') THEN
+        INSERT INTO permissions (name, description) VALUES
+            ('template.manage', '修改部門的收件單/請款單樣板');
+
+        INSERT INTO role_permissions (role_id, permission_id)
+        SELECT r.id, p.id FROM roles r JOIN permissions p ON p.name = 'template.manage'
+        WHERE r.name = 'admin'
+        ON CONFLICT DO NOTHING;
+    END IF;
+END $$;
+
+CREATE OR REPLACE FUNCTION user_role_names(uid integer) RETURNS text[]
+LANGUAGE sql STABLE AS $$
+    SELECT COALESCE(array_agg(r.name ORDER BY r.name), '{}')
+    FROM user_roles ur JOIN roles r ON r.id = ur.role_id
+    WHERE ur.user_id = uid;
+$$;
+
+-- effective permissions, union over the roles of the user
+CREATE OR REPLACE FUNCTION user_permission_names(uid integer) RETURNS text[]
+LANGUAGE sql STABLE AS $$
+    SELECT COALESCE(array_agg(DISTINCT p.name ORDER BY p.name), '{}')
+    FROM user_roles ur
+    JOIN role_permissions rp ON rp.role_id = ur.role_id
+    JOIN permissions p ON p.id = rp.permission_id
+    WHERE ur.user_id = uid;
+$$;
+
+-- departments a user sees, own one and every descendant over department_orgs,
+-- NULL for everything (ADM department or admin role)
+CREATE OR REPLACE FUNCTION user_department_reach(uid integer) RETURNS integer[]
+LANGUAGE sql STABLE AS $$
+    WITH RECURSIVE reach (id) AS (
+        SELECT department_id FROM users WHERE id = uid AND department_id IS NOT NULL
+        UNION
+        SELECT o.child_id FROM department_orgs o JOIN reach ON o.parent_id = reach.id
+        WHERE o.child_id IS NOT NULL
+    )
+    SELECT CASE
+        WHEN 'admin' = ANY (user_role_names(uid)) OR EXISTS (
+            SELECT 1 FROM users u JOIN departments d ON d.id = u.department_id
+            WHERE u.id = uid AND d.shorten = 'ADM'
+        ) THEN NULL
+        ELSE (SELECT COALESCE(array_agg(id ORDER BY id), '{}') FROM reach)
+    END;
+$$;
@@ -0,0 +1,253 @@
+//! Vocabulary of the appearance checklist. Bit `n` of `orders.appearance` is the flag with
+//! `bit` n in `appearance_flags`; the API speaks its `name`, exports and receipts print its
+//! Chinese `label`. Flags are added, relabelled or retired but never renumbered, and every
+//! change bumps the vocabulary `version` so clients know to reload it.
+
+use std::{error::Error, fmt::Display};
+
+use anyhow::{anyhow, Result};
+use bit_vec::BitVec;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::Database;
+
+/// bits of an encoded checklist at least, the width of the old `bit(8)`
+const MIN_BITS: usize = 8;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, sqlx::FromRow)]
+pub struct AppearanceFlag {
+    /// position in `orders.appearance`
+    #[schema(example = 0)]
+    bit: i32,
+    #[schema(example = "screen_cracked")]
+    name: String,
+    #[schema(example = "螢幕破裂")]
+    label: String,
+    /// vocabulary version of the last change
+    version: i32,
+    /// no longer offered, still shown on the orders that have it
+    retired: bool,
+}
+
+impl AppearanceFlag {
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// a line of the checklist on a receipt
+#[derive(Debug, Serialize)]
+pub(crate) struct Check {
+    pub name: String,
+    pub label: String,
+    pub checked: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct UnknownFlag(pub String, pub Vec<String>);
+
+impl Display for UnknownFlag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "unknown appearance flag {}, one of {}",
+            self.0,
+            self.1.join(", ")
+        )
+    }
+}
+
+impl Error for UnknownFlag {}
+
+/// the flags, loaded per request as they are few
+pub(crate) struct Catalog {
+    /// by `bit`
+    flags: Vec<AppearanceFlag>,
+}
+
+impl Catalog {
+    pub async fn load(database: &Database) -> Result<Self> {
+        const QUERY: &str =
+            "SELECT bit, name, label, version, retired FROM appearance_flags ORDER BY bit;";
+
+        sqlx::query_as(QUERY)
+            .fetch_all(database)
+            .await
+            .map(Self::new)
+            .map_err(|e| anyhow!("DB error - {e}"))
+    }
+
+    pub fn new(mut flags: Vec<AppearanceFlag>) -> Self {
+        flags.sort_by_key(|f| f.bit);
+        Self { flags }
+    }
+
+    pub fn flags(&self) -> &[AppearanceFlag] {
+        &self.flags
+    }
+
+    pub fn version(&self) -> i32 {
+        self.flags
+            .iter()
+            .map(|f| f.version)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// flag by name or label
+    pub fn find(&self, name: &str) -> Result<&AppearanceFlag, UnknownFlag> {
+        self.flags
+            .iter()
+            .find(|f| f.name == name || f.label == name)
+            .ok_or_else(|| {
+                let names = self
+                    .flags
+                    .iter()
+                    .filter(|f| !f.retired)
+                    .map(|f| f.name.clone())
+                    .collect();
+                UnknownFlag(name.to_string(), names)
+            })
+    }
+
+    /// bits of the flags `names`, given by name or label
+    pub fn encode(&self, names: &[String]) -> Result<BitVec, UnknownFlag> {
+        let width = self
+            .flags
+            .iter()
+            .map(|f| f.bit as usize + 1)
+            .max()
+            .unwrap_or_default()
+            .max(MIN_BITS);
+        let mut bits = BitVec::from_elem(width, false);
+        for name in names {
+            bits.set(self.find(name)?.bit as usize, true);
+        }
+        Ok(bits)
+    }
+
+    /// names of the flags set in `bits`, in bit order; bits no flag has are left out
+    pub fn names(&self, bits: &BitVec) -> Vec<String> {
+        self.flags
+            .iter()
+            .filter(|f| bits.get(f.bit as usize).unwrap_or_default())
+            .map(|f| f.name.clone())
+            .collect()
+    }
+
+    /// labels of the flags `names`, unknown names as they are
+    pub fn labels(&self, names: &[String]) -> Vec<String> {
+        names
+            .iter()
+            .map(|name| match self.find(name) {
+                Ok(flag) => flag.label.clone(),
+                Err(_) => name.clone(),
+            })
+            .collect()
+    }
+
+    /// every flag on offer, and the retired ones the order has, checked as in `names`
+    pub fn checklist(&self, names: &[String]) -> Vec<Check> {
+        self.flags
+            .iter()
+            .map(|f| (f, names.contains(&f.name)))
+            .filter(|(f, checked)| *checked || !f.retired)
+            .map(|(f, checked)| Check {
+                name: f.name.clone(),
+                label: f.label.clone(),
+                checked,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flag(bit: i32, name: &str, label: &str, version: i32, retired: bool) -> AppearanceFlag {
+        AppearanceFlag {
+            bit,
+            name: name.to_string(),
+            label: label.to_string(),
+            version,
+            retired,
+        }
+    }
+
+    fn catalog() -> Catalog {
+        Catalog::new(vec![
+            flag(2, "housing_dented", "機殼凹陷", 1, false),
+            flag(0, "screen_cracked", "螢幕破裂", 1, false),
+            flag(1, "sim_tray_missing", "SIM 卡托遺失", 2, true),
+            flag(9, "water_indicator_tripped", "進水標籤變色", 3, false),
+        ])
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn encode_by_name_or_label() {
+        let catalog = catalog();
+        let bits = catalog
+            .encode(&names(&["screen_cracked", "進水標籤變色"]))
+            .unwrap();
+        assert_eq!(bits.len(), 10);
+        assert!(bits[0] && bits[9] && !bits[2]);
+
+        /* never narrower than the old bit(8) */
+        let catalog = Catalog::new(vec![flag(0, "screen_cracked", "螢幕破裂", 1, false)]);
+        assert_eq!(catalog.encode(&[]).unwrap(), BitVec::from_elem(8, false));
+    }
+
+    #[test]
+    fn names_decode_what_encode_gave() {
+        let catalog = catalog();
+        let given = names(&["water_indicator_tripped", "sim_tray_missing"]);
+        let mut bits = catalog.encode(&given).unwrap();
+        assert_eq!(
+            catalog.names(&bits),
+            ["sim_tray_missing", "water_indicator_tripped"]
+        );
+
+        /* a bit no flag has, and a flag past the bits */
+        bits.set(5, true);
+        bits.truncate(8);
+        assert_eq!(catalog.names(&bits), ["sim_tray_missing"]);
+    }
+
+    #[test]
+    fn unknown_flag_lists_the_offered_ones() {
+        let err = catalog().encode(&names(&["scratched"])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown appearance flag scratched, one of screen_cracked, housing_dented, water_indicator_tripped"
+        );
+    }
+
+    #[test]
+    fn checklist_keeps_retired_flags_only_when_checked() {
+        let catalog = catalog();
+        assert_eq!(catalog.version(), 3);
+
+        let labels: Vec<String> = catalog
+            .checklist(&names(&["housing_dented"]))
+            .into_iter()
+            .map(|c| format!("{}{}", if c.checked { "+" } else { "-" }, c.label))
+            .collect();
+        assert_eq!(labels, ["-螢幕破裂", "+機殼凹陷", "-進水標籤變色"]);
+
+        let checklist = catalog.checklist(&names(&["sim_tray_missing"]));
+        assert!(checklist
+            .iter()
+            .any(|c| c.checked && c.label == "SIM 卡托遺失"));
+
+        assert_eq!(
+            catalog.labels(&names(&["screen_cracked", "gone"])),
+            ["螢幕破裂", "gone"]
+        );
+    }
+}
@@ -0,0 +1,439 @@
+//! Uploads attached to orders. The type is sniffed from the content, never taken from the
+//! client: JPEG and PNG photos, PDF documents. Photos lose their EXIF/XMP/IPTC metadata
+//! (GPS, camera serials) before they are stored, a JPEG keeps only its orientation and
+//! its first image (the MPF/secondary images after it carry EXIF of their own), and get
+//! an upright JPEG thumbnail. PDFs are stored as they are, their metadata included.
+
+use std::io::Cursor;
+
+use image::{
+    imageops::FilterType,
+    io::{Limits, Reader},
+    DynamicImage, ImageFormat, ImageOutputFormat,
+};
+
+/// largest file
+pub(crate) const MAX_BYTES: usize = 10 * 1024 * 1024;
+/// body of one upload request, of several files
+pub(crate) const MAX_REQUEST_BYTES: usize = 4 * MAX_BYTES;
+/// longest edge of a thumbnail
+pub(crate) const THUMBNAIL_EDGE: u32 = 320;
+pub(crate) const THUMBNAIL_TYPE: &str = "image/jpeg";
+/// longest edge of a photo that is decoded, a 48 MP phone photo is 8064 wide
+const MAX_EDGE: u32 = 8192;
+/// most the decoder may allocate, such a photo is 146 MB as RGB; a small PNG of one
+/// color would otherwise decode to whatever its header claims
+const MAX_DECODE_BYTES: u64 = 160 * 1024 * 1024;
+
+const JPEG: &str = "image/jpeg";
+const PNG: &str = "image/png";
+const PDF: &str = "application/pdf";
+
+#[derive(Debug)]
+pub(crate) struct Processed {
+    pub content_type: &'static str,
+    /// content to store, metadata stripped
+    pub bytes: Vec<u8>,
+    pub thumbnail: Option<Vec<u8>>,
+    /// upright size of a photo
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Rejected {
+    Empty,
+    TooLarge(usize),
+    /// sniffed type, if any
+    Unsupported(Option<String>),
+    /// claims to be a photo but doesn't decode
+    Broken(String),
+}
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => f.write_str("empty file"),
+            Self::TooLarge(size) => write!(f, "{size} bytes, {MAX_BYTES} at most"),
+            Self::Unsupported(Some(mime)) => {
+                write!(f, "{mime} not supported, only JPEG, PNG and PDF")
+            }
+            Self::Unsupported(None) => f.write_str("unknown type, only JPEG, PNG and PDF"),
+            Self::Broken(e) => write!(f, "broken image - {e}"),
+        }
+    }
+}
+
+/// sniff, strip and thumbnail an upload
+pub(crate) fn process(bytes: Vec<u8>) -> Result<Processed, Rejected> {
+    if bytes.is_empty() {
+        return Err(Rejected::Empty);
+    }
+    if bytes.len() > MAX_BYTES {
+        return Err(Rejected::TooLarge(bytes.len()));
+    }
+
+    let mime = infer::get(&bytes).map(|kind| kind.mime_type());
+    let (content_type, format, bytes, orientation) = match mime {
+        Some(JPEG) => {
+            let (stripped, orientation) = strip_jpeg(&bytes);
+            (JPEG, ImageFormat::Jpeg, stripped, orientation)
+        }
+        Some(PNG) => (PNG, ImageFormat::Png, strip_png(&bytes), 1),
+        Some(PDF) => {
+            return Ok(Processed {
+                content_type: PDF,
+                bytes,
+                thumbnail: None,
+                width: None,
+                height: None,
+            })
+        }
+        other => return Err(Rejected::Unsupported(other.map(String::from))),
+    };
+
+    let image = upright(decode(&bytes, format)?, orientation);
+
+    let mut thumbnail = Vec::new();
+    DynamicImage::ImageRgb8(
+        image
+            .resize(THUMBNAIL_EDGE, THUMBNAIL_EDGE, FilterType::Triangle)
+            .to_rgb8(),
+    )
+    .write_to(
+        &mut Cursor::new(&mut thumbnail),
+        ImageOutputFormat::Jpeg(80),
+    )
+    .map_err(|e| Rejected::Broken(e.to_string()))?;
+
+    Ok(Processed {
+        content_type,
+        bytes,
+        thumbnail: Some(thumbnail),
+        width: i32::try_from(image.width()).ok(),
+        height: i32::try_from(image.height()).ok(),
+    })
+}
+
+/// decode within `MAX_EDGE` and `MAX_DECODE_BYTES`, a larger photo is rejected as broken
+fn decode(bytes: &[u8], format: ImageFormat) -> Result<DynamicImage, Rejected> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_EDGE);
+    limits.max_image_height = Some(MAX_EDGE);
+    limits.max_alloc = Some(MAX_DECODE_BYTES);
+
+    let mut reader = Reader::with_format(Cursor::new(bytes), format);
+    reader.limits(limits);
+    reader.decode().map_err(|e| Rejected::Broken(e.to_string()))
+}
+
+/// file extension of a sniffed type, for the blob key
+pub(crate) fn extension(content_type: &str) -> &'static str {
+    match content_type {
+        JPEG => "jpg",
+        PNG => "png",
+        PDF => "pdf",
+        _ => "bin",
+    }
+}
+
+/// turn by the EXIF orientation, 1 is upright already
+fn upright(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+const SOI: u8 = 0xd8;
+const EOI: u8 = 0xd9;
+const SOS: u8 = 0xda;
+const APP1: u8 = 0xe1;
+const APP2: u8 = 0xe2;
+const APP13: u8 = 0xed;
+const COM: u8 = 0xfe;
+
+/// drop the APP1 (EXIF, XMP), APP13 (IPTC) and comment segments of a JPEG, an EXIF
+/// orientation other than 1 is kept in a minimal APP1; `(stripped, orientation)`
+fn strip_jpeg(bytes: &[u8]) -> (Vec<u8>, u16) {
+    if bytes.len() < 4 || bytes[0] != 0xff || bytes[1] != SOI {
+        return (bytes.to_vec(), 1);
+    }
+
+    let mut out = Vec::with_capacity(bytes.len());
+    out.extend_from_slice(&bytes[..2]);
+    let mut orientation = None;
+    let mut at = 2;
+
+    while at + 4 <= bytes.len() && bytes[at] == 0xff {
+        let marker = bytes[at + 1];
+        /* fill bytes and the markers without length */
+        if marker == 0xff {
+            at += 1;
+            continue;
+        }
+        if marker == 0x01 || (0xd0..=0xd7).contains(&marker) {
+            out.extend_from_slice(&bytes[at..at + 2]);
+            at += 2;
+            continue;
+        }
+        if marker == SOS {
+            break;
+        }
+
+        let length = u16::from_be_bytes([bytes[at + 2], bytes[at + 3]]) as usize;
+        let end = (at + 2 + length).min(bytes.len());
+        let payload = &bytes[(at + 4).min(end)..end];
+
+        match marker {
+            APP1 => {
+                if orientation.is_none() {
+                    orientation = exif_orientation(payload);
+                    if let Some(o) = orientation.filter(|o| *o != 1) {
+                        out.extend_from_slice(&orientation_app1(o));
+                    }
+                }
+            }
+            /* index of the secondary images, dropped below */
+            APP2 if payload.starts_with(b"MPF\0") => {}
+            APP13 | COM => {}
+            _ => out.extend_from_slice(&bytes[at..end]),
+        }
+        at = end;
+    }
+    /* scan data as it is, up to the end of the first image */
+    let at = at.min(bytes.len());
+    out.extend_from_slice(&bytes[at..image_end(bytes, at)]);
+
+    (out, orientation.unwrap_or(1))
+}
+
+/// just past the EOI of the image whose first SOS is at `at`, the end of `bytes` if it's
+/// cut short; scan data escapes `0xff` as `ff 00`, so any other marker is a real one
+fn image_end(bytes: &[u8], mut at: usize) -> usize {
+    while at + 1 < bytes.len() {
+        if bytes[at] != 0xff {
+            at += 1;
+            continue;
+        }
+        match bytes[at + 1] {
+            EOI => return at + 2,
+            0x00 | 0xd0..=0xd7 => at += 2,
+            0xff => at += 1,
+            /* SOS, DHT, ... between the scans of a progressive JPEG */
+            _ if at + 4 <= bytes.len() => {
+                at += 2 + u16::from_be_bytes([bytes[at + 2], bytes[at + 3]]) as usize;
+            }
+            _ => break,
+        }
+    }
+    bytes.len()
+}
+
+/// orientation (tag 0x0112) of IFD0 of an `Exif\0\0` APP1 payload
+fn exif_orientation(payload: &[u8]) -> Option<u16> {
+    let tiff = payload.strip_prefix(b"Exif\0\0")?;
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |at: usize| -> Option<u16> {
+        let b = [*tiff.get(at)?, *tiff.get(at + 1)?];
+        Some(if big_endian {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        })
+    };
+    let u32_at = |at: usize| -> Option<u32> {
+        let b = [
+            *tiff.get(at)?,
+            *tiff.get(at + 1)?,
+            *tiff.get(at + 2)?,
+            *tiff.get(at + 3)?,
+        ];
+        Some(if big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        })
+    };
+
+    let ifd0 = u32_at(4)? as usize;
+    let entries = u16_at(ifd0)? as usize;
+    (0..entries)
+        .map(|i| ifd0 + 2 + i * 12)
+        .find(|entry| u16_at(*entry) == Some(0x0112))
+        .and_then(|entry| u16_at(entry + 8))
+}
+
+/// APP1 segment of a big-endian EXIF holding nothing but `orientation`
+fn orientation_app1(orientation: u16) -> Vec<u8> {
+    let mut tiff = Vec::with_capacity(26);
+    tiff.extend_from_slice(b"MM\0\x2a\0\0\0\x08");
+    tiff.extend_from_slice(&1u16.to_be_bytes());
+    /* tag, type SHORT, count 1, value left aligned */
+    tiff.extend_from_slice(&0x0112u16.to_be_bytes());
+    tiff.extend_from_slice(&3u16.to_be_bytes());
+    tiff.extend_from_slice(&1u32.to_be_bytes());
+    tiff.extend_from_slice(&orientation.to_be_bytes());
+    tiff.extend_from_slice(&[0, 0]);
+    /* no next IFD */
+    tiff.extend_from_slice(&0u32.to_be_bytes());
+
+    let mut segment = vec![0xff, APP1];
+    segment.extend_from_slice(&((2 + 6 + tiff.len()) as u16).to_be_bytes());
+    segment.extend_from_slice(b"Exif\0\0");
+    segment.extend_from_slice(&tiff);
+    segment
+}
+
+/// drop the eXIf and text chunks of a PNG
+fn strip_png(bytes: &[u8]) -> Vec<u8> {
+    const SIGNATURE: usize = 8;
+    if bytes.len() < SIGNATURE {
+        return bytes.to_vec();
+    }
+
+    let mut out = Vec::with_capacity(bytes.len());
+    out.extend_from_slice(&bytes[..SIGNATURE]);
+    let mut at = SIGNATURE;
+
+    while at + 12 <= bytes.len() {
+        let length = u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+        let end = (at + 12 + length as usize).min(bytes.len());
+        match &bytes[at + 4..at + 8] {
+            b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" => {}
+            _ => out.extend_from_slice(&bytes[at..end]),
+        }
+        at = end;
+    }
+    out.extend_from_slice(&bytes[at..]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(image: DynamicImage, format: ImageOutputFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+        bytes
+    }
+
+    /// little-endian EXIF of `orientation` and a GPS-ish string
+    fn exif_app1(orientation: u16) -> Vec<u8> {
+        let mut tiff = b"II\x2a\0\x08\0\0\0".to_vec();
+        tiff.extend_from_slice(&1u16.to_le_bytes());
+        tiff.extend_from_slice(&0x0112u16.to_le_bytes());
+        tiff.extend_from_slice(&3u16.to_le_bytes());
+        tiff.extend_from_slice(&1u32.to_le_bytes());
+        tiff.extend_from_slice(&orientation.to_le_bytes());
+        tiff.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        tiff.extend_from_slice(b"GPS 25.0330N 121.5654E");
+
+        let mut segment = vec![0xff, APP1];
+        segment.extend_from_slice(&((2 + 6 + tiff.len()) as u16).to_be_bytes());
+        segment.extend_from_slice(b"Exif\0\0");
+        segment.extend_from_slice(&tiff);
+        segment
+    }
+
+    #[test]
+    fn oversized_photo_is_not_decoded() {
+        let png = encode(
+            DynamicImage::new_luma8(MAX_EDGE + 1, 1),
+            ImageOutputFormat::Png,
+        );
+        assert!(matches!(process(png), Err(Rejected::Broken(_))));
+
+        let png = encode(DynamicImage::new_luma8(MAX_EDGE, 1), ImageOutputFormat::Png);
+        assert!(process(png).is_ok());
+    }
+
+    #[test]
+    fn jpeg_keeps_only_the_orientation() {
+        let plain = encode(DynamicImage::new_rgb8(64, 32), ImageOutputFormat::Jpeg(90));
+        let mut photo = plain[..2].to_vec();
+        photo.extend_from_slice(&exif_app1(6));
+        photo.extend_from_slice(&plain[2..]);
+
+        let processed = process(photo).unwrap();
+        assert_eq!(processed.content_type, "image/jpeg");
+        assert!(!processed.bytes.windows(3).any(|w| w == b"GPS"));
+        assert_eq!(strip_jpeg(&processed.bytes).1, 6);
+        /* turned upright */
+        assert_eq!((processed.width, processed.height), (Some(32), Some(64)));
+
+        let thumbnail = image::load_from_memory(&processed.thumbnail.unwrap()).unwrap();
+        assert!(thumbnail.width() <= THUMBNAIL_EDGE && thumbnail.height() <= THUMBNAIL_EDGE);
+        assert!(thumbnail.height() > thumbnail.width());
+    }
+
+    #[test]
+    fn jpeg_loses_the_images_after_the_first() {
+        let plain = encode(DynamicImage::new_rgb8(16, 16), ImageOutputFormat::Jpeg(90));
+        let mut secondary = plain[..2].to_vec();
+        secondary.extend_from_slice(&exif_app1(1));
+        secondary.extend_from_slice(&plain[2..]);
+
+        let mut photo = plain.clone();
+        photo.extend_from_slice(&secondary);
+
+        let (stripped, _) = strip_jpeg(&photo);
+        assert_eq!(stripped, plain);
+        assert!(!stripped.windows(3).any(|w| w == b"GPS"));
+    }
+
+    #[test]
+    fn png_loses_its_text() {
+        let plain = encode(DynamicImage::new_rgba8(8, 8), ImageOutputFormat::Png);
+        let text = b"Author\0somebody";
+        let mut chunk = (text.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(b"tEXt");
+        chunk.extend_from_slice(text);
+        chunk.extend_from_slice(&[0, 0, 0, 0]);
+        /* after the IHDR chunk */
+        let mut photo = plain[..33].to_vec();
+        photo.extend_from_slice(&chunk);
+        photo.extend_from_slice(&plain[33..]);
+
+        let processed = process(photo).unwrap();
+        assert_eq!(processed.content_type, "image/png");
+        assert_eq!(processed.bytes, plain);
+        assert!(processed.thumbnail.is_some());
+    }
+
+    #[test]
+    fn only_photos_and_pdf_are_taken() {
+        let pdf = b"%PDF-1.7\n1 0 obj\n<<>>\nendobj\n".to_vec();
+        let processed = process(pdf.clone()).unwrap();
+        assert_eq!(processed.content_type, "application/pdf");
+        assert_eq!(processed.bytes, pdf);
+        assert!(processed.thumbnail.is_none());
+
+        assert_eq!(process(Vec::new()).unwrap_err(), Rejected::Empty);
+        assert_eq!(
+            process(b"just text, named photo.jpg".to_vec()).unwrap_err(),
+            Rejected::Unsupported(None)
+        );
+        assert_eq!(
+            process(vec![0; MAX_BYTES + 1]).unwrap_err(),
+            Rejected::TooLarge(MAX_BYTES + 1)
+        );
+        assert!(matches!(
+            process(b"\xff\xd8\xff\xe0 not really a jpeg".to_vec()).unwrap_err(),
+            Rejected::Broken(_)
+        ));
+    }
+}
@@ -0,0 +1,244 @@
+//! Append-only `audit_events`: who did what to which user, department, order, ..., with its
+//! state before and after, the client IP and the request id. Written by the mutating
+//! handlers and by login/logout, admins read them with `audit.read`.
+
+use std::convert::Infallible;
+
+use anyhow::{anyhow, Result};
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use http::request::Parts;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{Executor, Postgres, Transaction};
+use tracing::error;
+use uuid::Uuid;
+
+use crate::authentication::{ClientInfo, CurrentUser};
+
+pub(crate) const REQUEST_ID: &str = "x-request-id";
+
+/// fields never written to the log, whatever entity they are of
+const REDACTED: [&str; 6] = [
+    "password",
+    "secret",
+    "key_hash",
+    "token",
+    "token_hash",
+    "session_token",
+];
+
+/// **REQUEST-ID MIDDLEWARE**, keeps the `X-Request-Id` of the client if it looks like one,
+/// makes one otherwise, and answers with it
+pub(crate) async fn request_id<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    let id = req
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| valid_request_id(v))
+        .map(String::from)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let value = HeaderValue::from_str(&id).unwrap_or_else(|_| HeaderValue::from_static("-"));
+    req.headers_mut().insert(REQUEST_ID, value.clone());
+
+    let mut response = next.run(req).await;
+    response.headers_mut().insert(REQUEST_ID, value);
+    response
+}
+
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Where the request comes from, what `record` adds to every event.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Audit {
+    ip: Option<String>,
+    request_id: Option<String>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Audit {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self {
+            ip: ClientInfo::from_parts(parts).ip,
+            request_id: parts
+                .headers
+                .get(REQUEST_ID)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from),
+        })
+    }
+}
+
+impl Audit {
+    /// append `event`, a failure is logged and doesn't fail the request, unless
+    /// `executor` is the transaction of the change
+    pub async fn record<'e, E>(&self, executor: E, event: AuditEvent) -> Result<()>
+    where
+        E: Executor<'e, Database = Postgres>,
+    {
+        const QUERY: &str = r#"
+            INSERT INTO audit_events (
+                actor_id, actor, action, entity_type, entity_id, before, after, ip, request_id
+            ) VALUES (
+                $1, $2, $3, $4, $5, $6, $7, $8, $9
+            );
+        "#;
+
+        sqlx::query(QUERY)
+            .bind(event.actor_id)
+            .bind(&event.actor)
+            .bind(event.action)
+            .bind(event.entity_type)
+            .bind(&event.entity_id)
+            .bind(&event.before)
+            .bind(&event.after)
+            .bind(&self.ip)
+            .bind(&self.request_id)
+            .execute(executor)
+            .await
+            .map(|_| ())
+            .map_err(|e| {
+                error!(
+                    "audit {} of {}/{} lost - {e}",
+                    event.action, event.entity_type, event.entity_id
+                );
+                anyhow!("DB error - {e}")
+            })
+    }
+
+    /// append `event` to `tx` of the change and commit, both land or neither does
+    pub async fn commit(&self, mut tx: Transaction<'_, Postgres>, event: AuditEvent) -> Result<()> {
+        self.record(&mut tx, event).await?;
+        tx.commit().await.map_err(|e| anyhow!("DB error - {e}"))
+    }
+}
+
+/// What is recorded, `action` is `<entity>.<verb>`, e.g. `user.update`.
+#[derive(Debug)]
+pub(crate) struct AuditEvent {
+    actor_id: Option<i32>,
+    actor: Option<String>,
+    action: &'static str,
+    entity_type: &'static str,
+    entity_id: String,
+    before: Option<Value>,
+    after: Option<Value>,
+}
+
+impl AuditEvent {
+    pub fn new(action: &'static str, entity_type: &'static str, entity_id: impl ToString) -> Self {
+        Self {
+            actor_id: None,
+            actor: None,
+            action,
+            entity_type,
+            entity_id: entity_id.to_string(),
+            before: None,
+            after: None,
+        }
+    }
+
+    /// `None` leaves the actor unknown
+    pub fn by<'a>(self, user: impl Into<Option<&'a CurrentUser>>) -> Self {
+        match user.into() {
+            Some(user) => self.by_account(Some(user.id), &user.account),
+            None => self,
+        }
+    }
+
+    /// actor not logged in (yet), e.g. of a login or password reset
+    pub fn by_account(mut self, id: Option<i32>, account: &str) -> Self {
+        self.actor_id = id;
+        self.actor = Some(account.to_string());
+        self
+    }
+
+    pub fn before(mut self, state: &impl Serialize) -> Self {
+        self.before = snapshot(state);
+        self
+    }
+
+    pub fn after(mut self, state: &impl Serialize) -> Self {
+        self.after = snapshot(state);
+        self
+    }
+}
+
+fn snapshot(state: &impl Serialize) -> Option<Value> {
+    let mut value = serde_json::to_value(state).ok()?;
+    redact(&mut value);
+    Some(value)
+}
+
+/// secrets of `value` replaced, nested ones too
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (field, value) in fields.iter_mut() {
+                if REDACTED.contains(&field.as_str()) {
+                    if !value.is_null() {
+                        *value = Value::String(String::from("[redacted]"));
+                    }
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn secrets_are_redacted() {
+        let user = json!({
+            "account": "jshall01",
+            "password": "$argon2id$v=19$...",
+            "email": null,
+            "keys": [{"id": 3, "key_hash": "9f86d0..."}],
+            "token": null,
+        });
+        let event = AuditEvent::new("user.update", "user", "jshall01").after(&user);
+
+        assert_eq!(
+            event.after,
+            Some(json!({
+                "account": "jshall01",
+                "password": "[redacted]",
+                "email": null,
+                "keys": [{"id": 3, "key_hash": "[redacted]"}],
+                "token": null,
+            }))
+        );
+    }
+
+    #[test]
+    fn request_ids_of_clients() {
+        assert!(valid_request_id("4bf92f35-77b3-4da6-a3ce-929d0e0e4736"));
+        assert!(valid_request_id("req_01.2"));
+        assert!(!valid_request_id(""));
+        assert!(!valid_request_id("a b"));
+        assert!(!valid_request_id("x\r\nSet-Cookie: a=b"));
+        assert!(!valid_request_id(&"a".repeat(65)));
+    }
+}
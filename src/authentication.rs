@@ -1,19 +1,163 @@
-use std::str::FromStr;
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
 
 use anyhow::{anyhow, Result};
-use bit_vec::BitVec;
-use pbkdf2::{
-    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Pbkdf2,
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
 };
+use bit_vec::BitVec;
+use chrono::Duration;
+use http::{header::AUTHORIZATION, request::Parts, HeaderMap, Method};
 use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use shuttle_secrets::SecretStore;
+use sqlx::{Postgres, Transaction};
+use tracing::error;
 //use tracing::{debug, info};
 
 use crate::{
+    audit::{Audit, AuditEvent},
+    dcare_user::query_raw_user,
     errors::{LoginError, SignupError},
+    login_throttle::{self, ThrottlePolicy},
+    password::HashPolicy,
+    password_policy::{self, PasswordPolicy},
+    rbac, secret,
+    trash::{self, Trash},
     Database, Random, USER_COOKIE_NAME,
 };
 
+/// Authentication settings, read from `SecretStore` (or environment) at startup.
+#[derive(Clone, Debug)]
+pub struct AuthConfig {
+    /// a session never outlives this, counted from login
+    pub(crate) session_absolute: Duration,
+    /// a session expires when unused for this long
+    pub(crate) session_idle: Duration,
+    /// password reset token lifetime
+    pub(crate) reset_lifetime: Duration,
+    /// reset page, the mailed link is `<reset_url>?token=...`
+    pub(crate) reset_url: Option<String>,
+    /// time to enter the second factor after the password
+    pub(crate) mfa_pending: Duration,
+    /// role names (admin, gm, maintainer, comissioner, jshall, other, ...) that must use 2FA
+    pub(crate) mfa_required_roles: Vec<String>,
+    /// issuer shown in the authenticator app
+    pub(crate) mfa_issuer: String,
+    /// failed logins per account
+    pub(crate) throttle_account: ThrottlePolicy,
+    /// failed logins per client IP, any account
+    pub(crate) throttle_ip: ThrottlePolicy,
+    /// failures further apart than this start over
+    pub(crate) throttle_window: Duration,
+    /// Argon2id params of new hashes, older hashes are upgraded on login
+    pub(crate) password_hash: HashPolicy,
+    /// proxies in front of the service, each appending to X-Forwarded-For, see `ClientInfo`
+    pub(crate) trusted_proxy_hops: usize,
+    /// strength and reuse rules of new passwords
+    pub(crate) password_policy: PasswordPolicy,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            session_absolute: Duration::hours(12),
+            session_idle: Duration::hours(1),
+            reset_lifetime: Duration::minutes(30),
+            reset_url: None,
+            mfa_pending: Duration::minutes(5),
+            mfa_required_roles: vec![String::from("admin"), String::from("gm")],
+            mfa_issuer: String::from("dcare"),
+            throttle_account: ThrottlePolicy {
+                free_failures: 3,
+                lock_failures: 10,
+                lock: Duration::minutes(30),
+            },
+            throttle_ip: ThrottlePolicy {
+                free_failures: 10,
+                lock_failures: 50,
+                lock: Duration::minutes(30),
+            },
+            throttle_window: Duration::hours(1),
+            password_hash: HashPolicy::default(),
+            password_policy: PasswordPolicy::default(),
+            /* shuttle's proxy */
+            trusted_proxy_hops: 1,
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn from_secrets(secret_store: &SecretStore) -> Self {
+        let default = Self::default();
+        let seconds = |key: &str, fallback: Duration| {
+            secret(secret_store, key)
+                .and_then(|s| s.parse::<i64>().ok())
+                .filter(|s| *s > 0)
+                .map_or(fallback, Duration::seconds)
+        };
+        let count = |key: &str, fallback: i32| {
+            secret(secret_store, key)
+                .and_then(|s| s.parse::<i32>().ok())
+                .filter(|s| *s > 0)
+                .unwrap_or(fallback)
+        };
+
+        Self {
+            session_absolute: seconds("SESSION_ABSOLUTE_SECS", default.session_absolute),
+            session_idle: seconds("SESSION_IDLE_SECS", default.session_idle),
+            reset_lifetime: seconds("PASSWORD_RESET_SECS", default.reset_lifetime),
+            reset_url: secret(secret_store, "RESET_URL_BASE"),
+            mfa_pending: seconds("MFA_PENDING_SECS", default.mfa_pending),
+            mfa_required_roles: secret(secret_store, "MFA_REQUIRED_ROLES").map_or(
+                default.mfa_required_roles,
+                |roles| {
+                    roles
+                        .split(',')
+                        .map(|r| r.trim().to_lowercase())
+                        .filter(|r| !r.is_empty())
+                        .collect()
+                },
+            ),
+            mfa_issuer: secret(secret_store, "MFA_ISSUER").unwrap_or(default.mfa_issuer),
+            throttle_account: ThrottlePolicy {
+                lock_failures: count(
+                    "LOGIN_LOCK_FAILURES",
+                    default.throttle_account.lock_failures,
+                ),
+                lock: seconds("LOGIN_LOCK_SECS", default.throttle_account.lock),
+                ..default.throttle_account
+            },
+            throttle_ip: ThrottlePolicy {
+                lock_failures: count("LOGIN_IP_LOCK_FAILURES", default.throttle_ip.lock_failures),
+                lock: seconds("LOGIN_LOCK_SECS", default.throttle_ip.lock),
+                ..default.throttle_ip
+            },
+            throttle_window: seconds("LOGIN_FAILURE_WINDOW_SECS", default.throttle_window),
+            password_hash: HashPolicy::from_secrets(secret_store),
+            password_policy: PasswordPolicy::from_secrets(secret_store),
+            /* 0 is meaningful, no proxy at all */
+            trusted_proxy_hops: secret(secret_store, "TRUSTED_PROXY_HOPS")
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(default.trusted_proxy_hops),
+        }
+    }
+
+    /// role policy, users holding any of these roles can't login without a second factor
+    pub fn mfa_required(&self, roles: &[String]) -> bool {
+        roles.iter().any(|r| self.mfa_required_roles.contains(r))
+    }
+
+    /// cookie Max-Age, the browser may drop it once the session can't be alive anymore
+    pub fn cookie_max_age(&self) -> i64 {
+        self.session_absolute.num_seconds()
+    }
+}
+
 #[derive(Clone, Copy)]
 pub(crate) struct SessionToken(u128);
 
@@ -42,59 +186,385 @@ impl SessionToken {
     }
 }
 
+/// Where a request comes from, recorded on the session it creates.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl ClientInfo {
+    /// Each of the `hops` trusted proxies appends the address it got the request from to
+    /// X-Forwarded-For, so the client is the `hops`-th entry from the right; whatever is left
+    /// of it came from the client and proves nothing. Without proxies, or with fewer entries
+    /// than proxies, the client is the `peer`.
+    pub fn from_headers(headers: &HeaderMap, hops: usize, peer: Option<IpAddr>) -> Self {
+        let forwarded: Vec<&str> = headers
+            .get_all("X-Forwarded-For")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .collect();
+        let ip = forwarded
+            .len()
+            .checked_sub(hops)
+            .filter(|_| hops > 0)
+            .and_then(|i| forwarded[i].parse::<IpAddr>().ok())
+            .or(peer)
+            .map(|ip| ip.to_string());
+
+        Self {
+            user_agent: headers
+                .get("User-Agent")
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty()),
+            ip,
+        }
+    }
+
+    /// the client of the request, by the proxy hops of `AuthConfig`
+    pub fn from_parts(parts: &Parts) -> Self {
+        let hops = parts
+            .extensions
+            .get::<AuthConfig>()
+            .map_or(AuthConfig::default().trusted_proxy_hops, |c| {
+                c.trusted_proxy_hops
+            });
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        Self::from_headers(&parts.headers, hops, peer)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientInfo
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_parts(parts))
+    }
+}
+
 #[derive(Clone)]
 pub(crate) struct CurrentUser {
     pub id: i32,
     pub account: String,
+    /// own department and its descendants, `None` sees every department
+    pub department_reach: Option<Vec<i32>>,
+    /// old bits, mirror of the built-in roles
     pub permission: BitVec,
+    pub roles: Vec<String>,
+    /// effective, over all `roles`
+    pub permissions: Vec<String>,
+}
+
+impl CurrentUser {
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.permissions.iter().any(|p| p == permission)
+    }
+}
+
+/// `users` columns of `CurrentUser`
+type CurrentUserRow = (
+    i32,
+    String,
+    Option<Vec<i32>>,
+    BitVec,
+    Vec<String>,
+    Vec<String>,
+);
+
+impl From<CurrentUserRow> for CurrentUser {
+    fn from(
+        (id, account, department_reach, permission, roles, permissions): CurrentUserRow,
+    ) -> Self {
+        Self {
+            id,
+            account,
+            department_reach,
+            permission,
+            roles,
+            permissions,
+        }
+    }
 }
 
+/// API keys are told apart from session tokens by this prefix, `dck_<prefix>_<secret>`
+pub(crate) const API_KEY_PREFIX: &str = "dck_";
+
+/// What the request authenticates with, a session (cookie or bearer) or an API key.
 #[derive(Clone)]
-pub(crate) struct AuthState(Option<(SessionToken, Option<CurrentUser>, Database)>);
+pub(crate) enum Credential {
+    Session(SessionToken),
+    ApiKey {
+        /// sha256 of the presented key, only the hash is stored
+        hash: String,
+        /// scope this request needs, see `required_scope`
+        scope: String,
+    },
+}
+
+#[derive(Clone)]
+pub(crate) struct AuthState(Option<(Credential, Option<CurrentUser>, Database, AuthConfig)>);
 
 impl AuthState {
     pub fn logged_in(&self) -> bool {
         self.0.is_some()
     }
 
+    pub fn session_token(&self) -> Option<SessionToken> {
+        match self.0.as_ref() {
+            Some((Credential::Session(session_token), ..)) => Some(*session_token),
+            _ => None,
+        }
+    }
+
+    /// request made by a machine client, with an API key
+    pub fn by_api_key(&self) -> bool {
+        matches!(self.0.as_ref(), Some((Credential::ApiKey { .. }, ..)))
+    }
+
+    /// user of a session still waiting for its second factor, `get_user` ignores those
+    pub async fn pending_user(&self) -> Option<CurrentUser> {
+        const QUERY: &str = r#"
+            SELECT users.id, account, user_department_reach(users.id), permission,
+                user_role_names(users.id), user_permission_names(users.id)
+            FROM users JOIN sessions ON user_id = users.id
+            WHERE session_token = $1 AND mfa_pending AND expires_at > NOW()
+                AND users.deleted_at IS NULL;"#;
+
+        let session_token = self.session_token()?;
+        let (_, _, database, _) = self.0.as_ref()?;
+        let user: Option<CurrentUserRow> = sqlx::query_as(QUERY)
+            .bind(&session_token.into_database_value())
+            .fetch_optional(database)
+            .await
+            .unwrap_or_else(|e| {
+                error!("pending session lookup fail - {e}");
+                None
+            });
+
+        user.map(CurrentUser::from)
+    }
+
     pub async fn get_user(&mut self) -> Option<&CurrentUser> {
-        let (session_token, store, database) = self.0.as_mut()?;
+        let (credential, store, database, config) = self.0.as_mut()?;
         if store.is_none() {
-            const QUERY: &str =
-                "SELECT id, account, permission FROM users JOIN sessions ON user_id = id WHERE session_token = $1;";
-
-            let user: Option<(i32, String, BitVec)> = sqlx::query_as(QUERY)
-                .bind(&session_token.into_database_value())
-                .fetch_optional(&*database)
-                .await
-                .unwrap();
-
-            if let Some((id, account, permission)) = user {
-                *store = Some(CurrentUser {
-                    id,
-                    account,
-                    permission,
-                });
-            }
+            *store = match credential {
+                Credential::Session(session_token) => {
+                    session_user(database, config, *session_token).await
+                }
+                Credential::ApiKey { hash, scope } => api_key_user(database, hash, scope).await,
+            };
         }
         store.as_ref()
     }
 }
 
-/// TODO date
-pub(crate) async fn new_session(database: &Database, random: Random, user_id: i32) -> SessionToken {
-    const QUERY: &str = "INSERT INTO sessions (session_token, user_id) VALUES ($1, $2);";
+async fn session_user(
+    database: &Database,
+    config: &AuthConfig,
+    session_token: SessionToken,
+) -> Option<CurrentUser> {
+    /* slide the idle window on use, but never past the absolute lifetime */
+    const QUERY: &str = r#"
+        WITH touched AS (
+            UPDATE sessions SET
+                last_seen_at = NOW(),
+                expires_at = LEAST(
+                    created_at + make_interval(secs => $2),
+                    NOW() + make_interval(secs => $3)
+                )
+            WHERE session_token = $1 AND expires_at > NOW() AND NOT mfa_pending
+            RETURNING user_id
+        )
+        SELECT id, account, user_department_reach(id), permission,
+            user_role_names(id), user_permission_names(id)
+        FROM users JOIN touched ON user_id = id
+        WHERE deleted_at IS NULL;"#;
+
+    let user: Option<CurrentUserRow> = sqlx::query_as(QUERY)
+        .bind(&session_token.into_database_value())
+        .bind(config.session_absolute.num_seconds() as f64)
+        .bind(config.session_idle.num_seconds() as f64)
+        .fetch_optional(database)
+        .await
+        .unwrap_or_else(|e| {
+            error!("session lookup fail - {e}");
+            None
+        });
+
+    if user.is_none() {
+        let _ = purge_expired_sessions(database).await;
+    }
+
+    user.map(CurrentUser::from)
+}
+
+async fn api_key_user(database: &Database, hash: &str, scope: &str) -> Option<CurrentUser> {
+    const QUERY: &str = r#"
+        WITH used AS (
+            UPDATE api_keys SET last_used_at = NOW()
+            WHERE key_hash = $1 AND (expires_at IS NULL OR expires_at > NOW())
+            RETURNING id AS key_id, user_id, scopes
+        )
+        SELECT id, account, user_department_reach(id), permission,
+            user_role_names(id), user_permission_names(id), key_id, scopes
+        FROM users JOIN used ON user_id = id
+        WHERE deleted_at IS NULL;"#;
+
+    #[allow(clippy::type_complexity)]
+    let key: Option<(
+        i32,
+        String,
+        Option<Vec<i32>>,
+        BitVec,
+        Vec<String>,
+        Vec<String>,
+        i32,
+        Vec<String>,
+    )> = sqlx::query_as(QUERY)
+        .bind(hash)
+        .fetch_optional(database)
+        .await
+        .unwrap_or_else(|e| {
+            error!("api-key lookup fail - {e}");
+            None
+        });
+
+    let (id, account, department_reach, permission, roles, permissions, key_id, scopes) = key?;
+    if !scope_allowed(&scopes, scope) {
+        error!("api-key/{key_id} of {account} lacks scope {scope}");
+        return None;
+    }
+
+    Some(CurrentUser::from((
+        id,
+        account,
+        department_reach,
+        permission,
+        roles,
+        permissions,
+    )))
+}
+
+/// Scope an API key needs for the request, `<resource>:<read|write>`,
+/// resource is the first path segment after `/api/v1/`, e.g. `order:read`.
+pub(crate) fn required_scope(method: &Method, path: &str) -> String {
+    let resource = path
+        .trim_start_matches("/api/v1/")
+        .split('/')
+        .next()
+        .filter(|r| !r.is_empty())
+        .unwrap_or("*");
+    let action = match *method {
+        Method::GET | Method::HEAD | Method::OPTIONS => "read",
+        _ => "write",
+    };
+
+    format!("{resource}:{action}")
+}
+
+/// `*` grants all, `order:*` every action on order, `order:write` implies `order:read`.
+pub(crate) fn scope_allowed(scopes: &[String], required: &str) -> bool {
+    let (resource, action) = required.split_once(':').unwrap_or((required, ""));
+
+    scopes.iter().any(|scope| match scope.split_once(':') {
+        _ if scope == "*" => true,
+        Some((r, a)) if r == resource => {
+            a == "*" || a == action || (a == "write" && action == "read")
+        }
+        _ => false,
+    })
+}
+
+/// sha256 hex of an opaque token (API key, reset token), only the hash is stored
+pub(crate) fn token_hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// `len` bytes from the OS as hex, for tokens that are credentials on their own (API keys,
+/// reset tokens, quote links); never from the shared generator, seeded once with 64 bits
+pub(crate) fn token_hex(len: usize) -> String {
+    let mut pool = vec![0u8; len];
+    OsRng.fill_bytes(&mut pool);
+    pool.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// New session starts with one idle window, `AuthState::get_user` slides it on use.
+pub(crate) async fn new_session(
+    database: &Database,
+    random: Random,
+    config: &AuthConfig,
+    client: &ClientInfo,
+    user_id: i32,
+) -> Result<SessionToken> {
+    let lifetime = std::cmp::min(config.session_absolute, config.session_idle);
+    insert_session(database, random, client, user_id, lifetime, false).await
+}
+
+/// Password checked, second factor not yet, only `/api/v1/login/mfa` and MFA enrollment accept it.
+pub(crate) async fn new_pending_session(
+    database: &Database,
+    random: Random,
+    config: &AuthConfig,
+    client: &ClientInfo,
+    user_id: i32,
+) -> Result<SessionToken> {
+    insert_session(database, random, client, user_id, config.mfa_pending, true).await
+}
+
+async fn insert_session(
+    database: &Database,
+    random: Random,
+    client: &ClientInfo,
+    user_id: i32,
+    lifetime: Duration,
+    mfa_pending: bool,
+) -> Result<SessionToken> {
+    const QUERY: &str = r#"
+        INSERT INTO sessions (
+            session_token, user_id, expires_at, user_agent, ip, mfa_pending
+        ) VALUES (
+            $1, $2, NOW() + make_interval(secs => $3), $4, $5, $6
+        );"#;
+
+    let _ = purge_expired_sessions(database).await;
 
     let session_token = SessionToken::generate_new(random);
 
-    let _result = sqlx::query(QUERY)
+    sqlx::query(QUERY)
         .bind(&session_token.into_database_value())
         .bind(user_id)
+        .bind(lifetime.num_seconds() as f64)
+        .bind(&client.user_agent)
+        .bind(&client.ip)
+        .bind(mfa_pending)
         .execute(database)
         .await
-        .unwrap();
+        .map_err(|e| anyhow!("DB error - {e}"))?;
 
-    session_token
+    Ok(session_token)
+}
+
+pub(crate) async fn purge_expired_sessions(database: &Database) -> Result<u64> {
+    const QUERY: &str = "DELETE FROM sessions WHERE expires_at <= NOW();";
+
+    sqlx::query(QUERY)
+        .execute(database)
+        .await
+        .map(|r| r.rows_affected())
+        .map_err(|e| anyhow!("DB error - {e}"))
 }
 
 /// **AUTH MIDDLEWARE**
@@ -102,24 +572,40 @@ pub(crate) async fn auth<B>(
     mut req: http::Request<B>,
     next: axum::middleware::Next<B>,
     database: Database,
+    config: AuthConfig,
 ) -> axum::response::Response {
-    let session_token = req
+    let bearer = req
         .headers()
-        .get_all("Cookie")
-        .iter()
-        .filter_map(|cookie| {
-            cookie
-                .to_str()
-                .ok()
-                .and_then(|cookie| cookie.parse::<cookie::Cookie>().ok())
-        })
-        .find_map(|cookie| {
-            (cookie.name() == USER_COOKIE_NAME).then(move || cookie.value().to_owned())
-        })
-        .and_then(|cookie_value| cookie_value.parse::<SessionToken>().ok());
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.trim().to_owned());
+
+    let credential = match bearer {
+        Some(key) if key.starts_with(API_KEY_PREFIX) => Some(Credential::ApiKey {
+            hash: token_hash(&key),
+            scope: required_scope(req.method(), req.uri().path()),
+        }),
+        Some(token) => token.parse::<SessionToken>().ok().map(Credential::Session),
+        None => req
+            .headers()
+            .get_all("Cookie")
+            .iter()
+            .filter_map(|cookie| {
+                cookie
+                    .to_str()
+                    .ok()
+                    .and_then(|cookie| cookie.parse::<cookie::Cookie>().ok())
+            })
+            .find_map(|cookie| {
+                (cookie.name() == USER_COOKIE_NAME).then(move || cookie.value().to_owned())
+            })
+            .and_then(|cookie_value| cookie_value.parse::<SessionToken>().ok())
+            .map(Credential::Session),
+    };
 
     req.extensions_mut()
-        .insert(AuthState(session_token.map(|v| (v, None, database))));
+        .insert(AuthState(credential.map(|v| (v, None, database, config))));
 
     next.run(req).await
 }
@@ -128,6 +614,8 @@ pub(crate) async fn auth<B>(
 pub(crate) async fn signup(
     database: &Database,
     random: Random,
+    config: &AuthConfig,
+    client: &ClientInfo,
     account: &str,
     password: &str,
 ) -> Result<SessionToken, SignupError> {
@@ -141,17 +629,13 @@ pub(crate) async fn signup(
     if !valid_username(account) {
         return Err(SignupError::InvalidUsername);
     }
+    config.password_policy.check(password, &[account])?;
 
     const INSERT_QUERY: &str =
         "INSERT INTO users (account, password) VALUES ($1, $2) RETURNING id;";
 
-    let salt = SaltString::generate(&mut OsRng);
-
-    // Hash password to PHC string ($pbkdf2-sha256$...)
-    let password_hash = Pbkdf2.hash_password(password.as_bytes(), &salt);
-
-    let hashed_password = if let Ok(password) = password_hash {
-        password.to_string()
+    let hashed_password = if let Ok(pwd) = password_hashed(config, password) {
+        pwd
     } else {
         return Err(SignupError::InvalidPassword);
     };
@@ -165,7 +649,7 @@ pub(crate) async fn signup(
     let user_id: i32 = match fetch_one {
         Ok((user_id,)) => user_id,
         Err(sqlx::Error::Database(database))
-            if database.constraint() == Some("users_username_key") =>
+            if database.constraint() == Some("users_account_key") =>
         {
             return Err(SignupError::UsernameExists);
         }
@@ -174,25 +658,30 @@ pub(crate) async fn signup(
         }
     };
 
-    Ok(new_session(database, random, user_id).await)
+    new_session(database, random, config, client, user_id)
+        .await
+        .map_err(|e| {
+            error!("session of {account} - {e}");
+            SignupError::InternalError
+        })
 }
 
-pub(crate) fn password_hashed(password: &str) -> Result<String> {
-    let salt = SaltString::generate(&mut OsRng);
-
-    // Hash password to PHC string ($pbkdf2-sha256$...)
-    let password_hash = Pbkdf2.hash_password(password.as_bytes(), &salt);
-
-    if let Ok(password) = password_hash {
-        Ok(password.to_string())
-    } else {
-        Err(anyhow!("invalid password"))
-    }
+/// PHC string of the configured policy ($argon2id$...)
+pub(crate) fn password_hashed(config: &AuthConfig, password: &str) -> Result<String> {
+    config
+        .password_hash
+        .hash(password)
+        .map_err(|e| anyhow!("{e}"))
 }
 
+/// `event` is recorded in the transaction of the user and its roles, `after` as created
 pub(crate) async fn signup2(
     database: &Database,
+    audit: &Audit,
+    event: AuditEvent,
     random: Random,
+    config: &AuthConfig,
+    client: &ClientInfo,
     account: &str,
     password: &str,
     permission: &BitVec,
@@ -210,6 +699,7 @@ pub(crate) async fn signup2(
     if !valid_username(account) {
         return Err(SignupError::InvalidUsername);
     }
+    password_policy::check_new(database, config, None, password, &[account, phone, email]).await?;
 
     const INSERT_QUERY: &str = r#"
         INSERT INTO users (
@@ -228,15 +718,27 @@ pub(crate) async fn signup2(
             $9
         ) RETURNING id;"#;
 
-    let hashed_password = if let Ok(pwd) = password_hashed(password) {
+    let hashed_password = if let Ok(pwd) = password_hashed(config, password) {
         pwd
     } else {
         return Err(SignupError::InvalidPassword);
     };
 
+    /* a self-service signup (no bits) gets no role, not even `other` */
+    let roles = if permission.any() {
+        rbac::roles_from_bits(permission)
+    } else {
+        Vec::new()
+    };
+
+    let mut tx = database.begin().await.map_err(|e| {
+        error!("signup {account} - {e}");
+        SignupError::InternalError
+    })?;
+
     let fetch_one = sqlx::query_as(INSERT_QUERY)
         .bind(account)
-        .bind(hashed_password)
+        .bind(&hashed_password)
         .bind(permission)
         .bind(username)
         .bind(worker_id)
@@ -244,13 +746,13 @@ pub(crate) async fn signup2(
         .bind(department_id)
         .bind(phone)
         .bind(email)
-        .fetch_one(database)
+        .fetch_one(&mut tx)
         .await;
 
     let user_id: i32 = match fetch_one {
         Ok((user_id,)) => user_id,
         Err(sqlx::Error::Database(database))
-            if database.constraint() == Some("users_username_key") =>
+            if database.constraint() == Some("users_account_key") =>
         {
             return Err(SignupError::UsernameExists);
         }
@@ -259,37 +761,163 @@ pub(crate) async fn signup2(
         }
     };
 
-    Ok(new_session(database, random, user_id).await)
+    if let Err(e) = password_policy::history_push(&mut tx, config, user_id, &hashed_password).await
+    {
+        error!("password history of user/{user_id} - {e}");
+    }
+    match rbac::user_roles_replace(&mut tx, user_id, &roles).await {
+        Ok(true) => {}
+        Ok(false) => {
+            error!("roles of user/{user_id} - unknown role in {roles:?}");
+            return Err(SignupError::InternalError);
+        }
+        Err(e) => {
+            error!("roles of user/{user_id} - {e}");
+            return Err(SignupError::InternalError);
+        }
+    }
+    let after = query_raw_user(&mut tx, account).await;
+    audit
+        .record(&mut tx, event.after(&after))
+        .await
+        .map_err(|_| SignupError::InternalError)?;
+    tx.commit().await.map_err(|e| {
+        error!("signup {account} - {e}");
+        SignupError::InternalError
+    })?;
+
+    new_session(database, random, config, client, user_id)
+        .await
+        .map_err(|e| {
+            error!("session of {account} - {e}");
+            SignupError::InternalError
+        })
+}
+
+/// store the password hashed again under the current hash policy
+async fn password_rehash(database: &Database, user_id: i32, hashed_password: &str) -> Result<()> {
+    const QUERY: &str = "UPDATE users SET password = $2 WHERE id = $1;";
+
+    sqlx::query(QUERY)
+        .bind(user_id)
+        .bind(hashed_password)
+        .execute(database)
+        .await
+        .map(|_| ())
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+/// Second factor still owed after the password.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MfaChallenge {
+    /// enrolled, send the TOTP (or a recovery code) to `/api/v1/login/mfa`
+    Totp,
+    /// role requires 2FA but none enrolled yet, enroll/confirm with the pending session
+    Enroll,
+}
+
+pub(crate) struct LoginSession {
+    pub session_token: SessionToken,
+    pub permission: BitVec,
+    /// `Some`, `session_token` is a pending session, not logged in yet
+    pub mfa: Option<MfaChallenge>,
 }
 
 pub(crate) async fn login(
     database: &Database,
     random: Random,
+    config: &AuthConfig,
+    client: &ClientInfo,
     account: &str,
     password: &str,
-) -> Result<(SessionToken, BitVec), LoginError> {
-    const LOGIN_QUERY: &str =
-        "SELECT id, password, permission FROM users WHERE users.account = $1;";
+) -> Result<LoginSession, LoginError> {
+    const LOGIN_QUERY: &str = r#"
+        SELECT id, password, permission, user_role_names(users.id), user_mfa.confirmed_at IS NOT NULL
+        FROM users LEFT JOIN user_mfa ON user_mfa.user_id = users.id
+        WHERE users.account = $1 AND users.deleted_at IS NULL;"#;
+
+    let ip = client.ip.as_deref();
+    match login_throttle::retry_after(database, account, ip).await {
+        Ok(Some(secs)) => return Err(LoginError::TooManyAttempts(secs)),
+        Ok(None) => {}
+        Err(e) => {
+            error!("login throttle - {e}");
+            return Err(LoginError::InternalError);
+        }
+    }
 
-    let row: Option<(i32, String, BitVec)> = sqlx::query_as(LOGIN_QUERY)
+    let row: Option<(i32, String, BitVec, Vec<String>, bool)> = sqlx::query_as(LOGIN_QUERY)
         .bind(account)
         .fetch_optional(database)
         .await
-        .unwrap();
+        .map_err(|e| {
+            error!("login {account} - {e}");
+            LoginError::InternalError
+        })?;
+
+    let (user_id, hashed_password, permission, roles, mfa_enrolled) = match row {
+        Some(row) => row,
+        None => {
+            /* unknown account costs the same hash round, no timing hint either */
+            let _ = password_hashed(config, password);
+            if let Err(e) = login_throttle::record_failure(database, config, account, ip).await {
+                error!("login throttle - {e}");
+            }
+            return Err(LoginError::InvalidCredentials);
+        }
+    };
+
+    match config.password_hash.verify(password, &hashed_password) {
+        Ok(true) => {}
+        Ok(false) => {
+            if let Err(e) = login_throttle::record_failure(database, config, account, ip).await {
+                error!("login throttle - {e}");
+            }
+            return Err(LoginError::InvalidCredentials);
+        }
+        Err(e) => {
+            error!("password hash of {account} unusable - {e}");
+            return Err(LoginError::InternalError);
+        }
+    }
+
+    match config.password_hash.upgrade(password, &hashed_password) {
+        Ok(Some(rehashed)) => {
+            if let Err(e) = password_rehash(database, user_id, &rehashed).await {
+                error!("password rehash of {account} - {e}");
+            }
+        }
+        Ok(None) => {}
+        Err(e) => error!("password rehash of {account} - {e}"),
+    }
 
-    let (user_id, hashed_password, permission) = if let Some(row) = row {
-        row
+    let mfa = if mfa_enrolled {
+        Some(MfaChallenge::Totp)
+    } else if config.mfa_required(&roles) {
+        Some(MfaChallenge::Enroll)
     } else {
-        return Err(LoginError::UserDoesNotExist);
+        None
     };
-
-    // Verify password against PHC string
-    let parsed_hash = PasswordHash::new(&hashed_password).unwrap();
-    if let Err(_err) = Pbkdf2.verify_password(password.as_bytes(), &parsed_hash) {
-        return Err(LoginError::WrongPassword);
+    /* failures are cleared once the code is right too, see `login_mfa_api` */
+    if mfa != Some(MfaChallenge::Totp) {
+        let _ = login_throttle::record_success(database, account).await;
     }
 
-    Ok((new_session(database, random, user_id).await, permission))
+    let session_token = if mfa.is_some() {
+        new_pending_session(database, random, config, client, user_id).await
+    } else {
+        new_session(database, random, config, client, user_id).await
+    };
+    let session_token = session_token.map_err(|e| {
+        error!("session of {account} - {e}");
+        LoginError::InternalError
+    })?;
+
+    Ok(LoginSession {
+        session_token,
+        permission,
+        mfa,
+    })
 }
 
 #[allow(dead_code)]
@@ -299,21 +927,88 @@ pub(crate) async fn delete_user(auth_state: AuthState) {
             SELECT user_id FROM sessions WHERE sessions.session_token = $1
         );";
 
+    let session_token = auth_state.session_token().unwrap();
     let auth_state = auth_state.0.unwrap();
     let _res = sqlx::query(DELETE_QUERY)
-        .bind(&auth_state.0.into_database_value())
+        .bind(&session_token.into_database_value())
         .execute(&auth_state.2)
         .await
         .unwrap();
 }
 
-pub(crate) async fn delete_user2(database: &Database, user: &str) -> Result<()> {
-    const DELETE_QUERY: &str = "DELETE FROM users WHERE account = $1;";
+/// soft delete in `tx`, logged out everywhere and pending resets dropped,
+/// `Ok(false)` if the user isn't at `version` anymore
+pub(crate) async fn delete_user2(
+    tx: &mut Transaction<'_, Postgres>,
+    user: &str,
+    version: i32,
+    by: Option<i32>,
+) -> Result<bool> {
+    const SESSIONS_QUERY: &str =
+        "DELETE FROM sessions WHERE user_id = (SELECT id FROM users WHERE account = $1);";
+    const RESETS_QUERY: &str = r#"
+        DELETE FROM password_resets
+        WHERE user_id = (SELECT id FROM users WHERE account = $1) AND used_at IS NULL;
+    "#;
+
+    if !trash::delete(&mut *tx, Trash::User, user, version, by).await? {
+        return Ok(false);
+    }
+    for query in [SESSIONS_QUERY, RESETS_QUERY] {
+        sqlx::query(query)
+            .bind(user)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| anyhow!("DB error - {e}"))?;
+    }
 
-    sqlx::query(DELETE_QUERY)
-        .bind(user)
-        .execute(database)
-        .await
-        .map(|_| ())
-        .map_err(|e| anyhow!("DB error - {e}"))
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    fn forwarded(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append("X-Forwarded-For", HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn client_is_what_the_trusted_proxy_saw() {
+        let peer = Some(IpAddr::from([10, 0, 0, 1]));
+
+        /* the client made up the first entry, the proxy appended the real one */
+        let headers = forwarded(&["1.2.3.4, 203.0.113.7"]);
+        let client = ClientInfo::from_headers(&headers, 1, peer);
+        assert_eq!(client.ip.as_deref(), Some("203.0.113.7"));
+
+        let headers = forwarded(&["1.2.3.4", "203.0.113.7, 10.1.1.1"]);
+        let client = ClientInfo::from_headers(&headers, 2, peer);
+        assert_eq!(client.ip.as_deref(), Some("203.0.113.7"));
+    }
+
+    #[test]
+    fn peer_without_proxies_or_forwarded_hops() {
+        let peer = Some(IpAddr::from([10, 0, 0, 1]));
+
+        let headers = forwarded(&["1.2.3.4"]);
+        assert_eq!(
+            ClientInfo::from_headers(&headers, 0, peer).ip.as_deref(),
+            Some("10.0.0.1")
+        );
+        assert_eq!(
+            ClientInfo::from_headers(&headers, 2, peer).ip.as_deref(),
+            Some("10.0.0.1")
+        );
+        assert_eq!(
+            ClientInfo::from_headers(&forwarded(&["junk"]), 1, None).ip,
+            None
+        );
+    }
 }
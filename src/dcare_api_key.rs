@@ -0,0 +1,413 @@
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, Postgres};
+use tracing::error;
+use utoipa::ToSchema;
+
+use crate::audit::{Audit, AuditEvent};
+use crate::authentication::{token_hash, token_hex, AuthState, API_KEY_PREFIX};
+use crate::errors::NotLoggedIn;
+use crate::{ApiResponse, Database};
+
+/// resources a scope may name, the first path segment after `/api/v1/`
+const SCOPE_RESOURCES: &[&str] = &["me", "user", "department", "order", "roles", "permissions"];
+const SCOPE_ACTIONS: &[&str] = &["read", "write", "*"];
+
+#[derive(Debug, Serialize, Deserialize, ToSchema, sqlx::FromRow)]
+pub struct ApiKeyInfo {
+    id: i32,
+    name: String,
+    /// `dck_<prefix>_...`, to tell keys apart, the full key is shown only once
+    key_prefix: String,
+    scopes: Vec<String>,
+    create_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ApiKeysResponse {
+    code: u16,
+    api_keys: Option<Vec<ApiKeyInfo>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ApiKeyCreated {
+    code: u16,
+    /// send as `Authorization: Bearer <key>`, not retrievable later
+    key: String,
+    api_key: ApiKeyInfo,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ApiKeyNew {
+    #[schema(example = "nightly integration")]
+    name: String,
+    /// `<resource>:<read|write|*>` or `*`, resource is one of me/user/department/order/roles/permissions
+    #[schema(example = json!(["order:read", "order:write"]))]
+    scopes: Vec<String>,
+    /// never expire if not given
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ApiKeyUpdate {
+    name: Option<String>,
+    scopes: Option<Vec<String>>,
+    /// kept if not given
+    expires_at: Option<DateTime<Utc>>,
+    /// the key never expires from now on, not along with `expires_at`
+    clear_expiry: Option<bool>,
+}
+
+fn valid_scope(scope: &str) -> bool {
+    scope == "*"
+        || scope.split_once(':').map_or(false, |(resource, action)| {
+            SCOPE_RESOURCES.contains(&resource) && SCOPE_ACTIONS.contains(&action)
+        })
+}
+
+fn invalid_scopes(scopes: &[String]) -> Option<ApiResponse> {
+    scopes
+        .iter()
+        .find(|s| !valid_scope(s))
+        .map(|s| ApiResponse::new(400, Some(format!("invalid scope {s}"))))
+}
+
+async fn query_api_keys(database: &Database, user_id: i32) -> Result<Vec<ApiKeyInfo>> {
+    const QUERY: &str = r#"
+        SELECT
+            id, name, key_prefix, scopes, create_at, expires_at, last_used_at
+        FROM api_keys
+        WHERE user_id = $1
+        ORDER BY id;
+    "#;
+
+    sqlx::query_as::<_, ApiKeyInfo>(QUERY)
+        .bind(user_id)
+        .fetch_all(database)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+async fn api_key_insert<'e, E>(
+    executor: E,
+    user_id: i32,
+    new: &ApiKeyNew,
+    key_prefix: &str,
+    key_hash: &str,
+) -> Result<ApiKeyInfo>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    const QUERY: &str = r#"
+        INSERT INTO api_keys (
+            user_id, name, key_prefix, key_hash, scopes, expires_at
+        ) VALUES (
+            $1, $2, $3, $4, $5, $6
+        ) RETURNING id, name, key_prefix, scopes, create_at, expires_at, last_used_at;
+    "#;
+
+    sqlx::query_as::<_, ApiKeyInfo>(QUERY)
+        .bind(user_id)
+        .bind(&new.name)
+        .bind(key_prefix)
+        .bind(key_hash)
+        .bind(&new.scopes)
+        .bind(new.expires_at)
+        .fetch_one(executor)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+async fn api_key_update<'e, E>(
+    executor: E,
+    user_id: i32,
+    id: i32,
+    update: &ApiKeyUpdate,
+) -> Result<bool>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    const QUERY: &str = r#"
+        UPDATE api_keys SET
+            name = COALESCE($3, name),
+            scopes = COALESCE($4, scopes),
+            expires_at = CASE WHEN $6 THEN NULL ELSE COALESCE($5, expires_at) END
+        WHERE user_id = $1 AND id = $2;
+    "#;
+
+    sqlx::query(QUERY)
+        .bind(user_id)
+        .bind(id)
+        .bind(&update.name)
+        .bind(&update.scopes)
+        .bind(update.expires_at)
+        .bind(update.clear_expiry.unwrap_or(false))
+        .execute(executor)
+        .await
+        .map(|r| r.rows_affected() > 0)
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+async fn api_key_delete<'e, E>(executor: E, user_id: i32, id: i32) -> Result<bool>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    const QUERY: &str = "DELETE FROM api_keys WHERE user_id = $1 AND id = $2;";
+
+    sqlx::query(QUERY)
+        .bind(user_id)
+        .bind(id)
+        .execute(executor)
+        .await
+        .map(|r| r.rows_affected() > 0)
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+/// logged in user, key management itself is for people, not for API keys
+async fn key_owner(current_user: &mut AuthState) -> Result<i32, ApiResponse> {
+    if current_user.by_api_key() {
+        return Err(ApiResponse::new(
+            405,
+            Some(String::from("permission deny, login required")),
+        ));
+    }
+
+    current_user
+        .get_user()
+        .await
+        .map(|myself| myself.id)
+        .ok_or_else(|| ApiResponse::new(400, Some(format!("{}", &NotLoggedIn))))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/me/api-keys",
+    responses(
+        (status = 200, description = "list my API keys", body = ApiKeysResponse),
+        (status = 400, description = "not login, ", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn my_api_keys_api(
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+) -> impl IntoResponse {
+    let user_id = if let Some(myself) = current_user.get_user().await {
+        myself.id
+    } else {
+        let resp = ApiResponse::new(400, Some(format!("{}", &NotLoggedIn)));
+        return (StatusCode::OK, Json(resp)).into_response();
+    };
+
+    match query_api_keys(&database, user_id).await {
+        Ok(api_keys) => {
+            let resp = ApiKeysResponse {
+                code: 200,
+                api_keys: Some(api_keys),
+            };
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => {
+            let resp = ApiResponse::new(500, Some(format!("{e}")));
+            error!("{:?}", &resp);
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/me/api-keys",
+    request_body = ApiKeyNew,
+    responses(
+        (status = 200, description = "API key created, the key is shown only this time", body = ApiKeyCreated),
+        (status = 400, description = "not login, invalid scope, ", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+        (status = 405, description = "permission deny, API keys can't create keys", body = ApiResponse, example = json!(ApiResponse::new(405, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = [])
+    ),
+)]
+pub(crate) async fn my_api_key_create_api(
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+    Json(new): Json<ApiKeyNew>,
+) -> impl IntoResponse {
+    let user_id = match key_owner(&mut current_user).await {
+        Ok(id) => id,
+        Err(resp) => return (StatusCode::OK, Json(resp)).into_response(),
+    };
+
+    if new.name.trim().is_empty() {
+        let resp = ApiResponse::new(400, Some(String::from("name required")));
+        return (StatusCode::OK, Json(resp)).into_response();
+    }
+    if let Some(resp) = invalid_scopes(&new.scopes) {
+        return (StatusCode::OK, Json(resp)).into_response();
+    }
+
+    let key_prefix = token_hex(4);
+    let key = format!("{API_KEY_PREFIX}{key_prefix}_{}", token_hex(24));
+
+    let mut tx = match database.begin().await {
+        Ok(tx) => tx,
+        Err(e) => {
+            let resp = ApiResponse::new(500, Some(format!("DB error - {e}")));
+            error!("{:?}", &resp);
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+    };
+    let created = match api_key_insert(&mut tx, user_id, &new, &key_prefix, &token_hash(&key)).await
+    {
+        Ok(api_key) => {
+            let event = AuditEvent::new("api_key.create", "api_key", api_key.id)
+                .by(current_user.get_user().await)
+                .after(&api_key);
+            audit.commit(tx, event).await.map(|_| api_key)
+        }
+        Err(e) => Err(e),
+    };
+    match created {
+        Ok(api_key) => {
+            let resp = ApiKeyCreated {
+                code: 200,
+                key,
+                api_key,
+            };
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => {
+            let resp = ApiResponse::new(500, Some(format!("{e}")));
+            error!("{:?}", &resp);
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/me/api-keys/{id}",
+    params(
+        ("id" = i32, Path, description = "API key id")
+    ),
+    request_body = ApiKeyUpdate,
+    responses(
+        (status = 200, description = "update success", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 400, description = "not login, invalid scope, expires_at along with clear_expiry, ", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+        (status = 404, description = "API key not found, ", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+        (status = 405, description = "permission deny, API keys can't modify keys", body = ApiResponse, example = json!(ApiResponse::new(405, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = [])
+    ),
+)]
+pub(crate) async fn my_api_key_update_api(
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+    Path(id): Path<i32>,
+    Json(update): Json<ApiKeyUpdate>,
+) -> impl IntoResponse {
+    let user_id = match key_owner(&mut current_user).await {
+        Ok(id) => id,
+        Err(resp) => return (StatusCode::OK, Json(resp)).into_response(),
+    };
+
+    if let Some(resp) = update.scopes.as_deref().and_then(invalid_scopes) {
+        return (StatusCode::OK, Json(resp)).into_response();
+    }
+    if update.clear_expiry == Some(true) && update.expires_at.is_some() {
+        let resp = ApiResponse::new(
+            400,
+            Some(String::from("expires_at and clear_expiry together")),
+        );
+        return (StatusCode::OK, Json(resp)).into_response();
+    }
+
+    let mut tx = match database.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return key_response(Err(anyhow!("DB error - {e}")), id),
+    };
+    let mut result = api_key_update(&mut tx, user_id, id, &update).await;
+    if let Ok(true) = result {
+        let event = AuditEvent::new("api_key.update", "api_key", id)
+            .by(current_user.get_user().await)
+            .after(&update);
+        result = audit.commit(tx, event).await.map(|_| true);
+    }
+    key_response(result, id)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/me/api-keys/{id}",
+    params(
+        ("id" = i32, Path, description = "API key id")
+    ),
+    responses(
+        (status = 200, description = "revoke success", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 400, description = "not login, ", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+        (status = 404, description = "API key not found, ", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn my_api_key_delete_api(
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+    Path(id): Path<i32>,
+) -> impl IntoResponse {
+    /* a leaked key may still revoke itself */
+    let user_id = if let Some(myself) = current_user.get_user().await {
+        myself.id
+    } else {
+        let resp = ApiResponse::new(400, Some(format!("{}", &NotLoggedIn)));
+        return (StatusCode::OK, Json(resp)).into_response();
+    };
+
+    let mut tx = match database.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return key_response(Err(anyhow!("DB error - {e}")), id),
+    };
+    let mut result = api_key_delete(&mut tx, user_id, id).await;
+    if let Ok(true) = result {
+        let event =
+            AuditEvent::new("api_key.delete", "api_key", id).by(current_user.get_user().await);
+        result = audit.commit(tx, event).await.map(|_| true);
+    }
+    key_response(result, id)
+}
+
+fn key_response(result: Result<bool>, id: i32) -> axum::response::Response {
+    let resp = match result {
+        Ok(true) => ApiResponse::new(200, Some(String::from("success"))),
+        Ok(false) => ApiResponse::new(404, Some(format!("api-key/{id} not found"))),
+        Err(e) => {
+            let resp = ApiResponse::new(500, Some(format!("{e}")));
+            error!("{:?}", &resp);
+            resp
+        }
+    };
+    (StatusCode::OK, Json(resp)).into_response()
+}
@@ -0,0 +1,233 @@
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::appearance::{AppearanceFlag, Catalog};
+use crate::audit::{Audit, AuditEvent};
+use crate::rbac::{CatalogManage, OrderRead, Require};
+use crate::{ApiResponse, Database};
+
+/// longest label, in characters
+const LABEL_MAX: usize = 32;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct AppearanceResponse {
+    code: u16,
+    /// bumped by every change of the flags
+    version: Option<i32>,
+    /// by bit
+    flags: Option<Vec<AppearanceFlag>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct AppearanceFlagNew {
+    #[schema(example = "sim_tray_missing")]
+    name: String,
+    #[schema(example = "SIM 卡托遺失")]
+    label: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct AppearanceFlagUpdate {
+    label: Option<String>,
+    /// retired flags are no longer offered, orders keep them
+    retired: Option<bool>,
+}
+
+fn json_response(code: u16, message: String) -> Response {
+    let resp = ApiResponse::new(code, Some(message));
+    if code >= 500 {
+        error!("{:?}", &resp);
+    }
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
+fn valid_flag_name(name: &str) -> bool {
+    (1..=48).contains(&name.len())
+        && name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| matches!(c, 'a'..='z' | '0'..='9' | '_'))
+}
+
+fn valid_label(label: &str) -> bool {
+    !label.trim().is_empty() && label.chars().count() <= LABEL_MAX
+}
+
+/// orders give flags by name or label, neither may stand for two flags
+fn taken(catalog: &Catalog, text: &str, except: Option<&str>) -> bool {
+    matches!(catalog.find(text), Ok(flag) if Some(flag.name()) != except)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/appearance",
+    responses(
+        (status = 200, description = "the appearance flags of orders, retired ones included", body = AppearanceResponse),
+        (status = 403, description = "permission deny, order.read required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn appearance_api(
+    _current: Require<OrderRead>,
+    Extension(database): Extension<Database>,
+) -> impl IntoResponse {
+    match Catalog::load(&database).await {
+        Ok(catalog) => {
+            let resp = AppearanceResponse {
+                code: 200,
+                version: Some(catalog.version()),
+                flags: Some(catalog.flags().to_vec()),
+            };
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => json_response(500, format!("{e}")),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/appearance",
+    request_body = AppearanceFlagNew,
+    responses(
+        (status = 200, description = "flag added on the next bit, the vocabulary version bumped", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 400, description = "invalid name or label", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, catalog.manage required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 409, description = "name or label taken", body = ApiResponse, example = json!(ApiResponse::new(409, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn appearance_create_api(
+    current: Require<CatalogManage>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+    Json(flag): Json<AppearanceFlagNew>,
+) -> impl IntoResponse {
+    if !valid_flag_name(&flag.name) {
+        return json_response(400, format!("invalid flag name {}", flag.name));
+    }
+    if !valid_label(&flag.label) {
+        return json_response(400, format!("invalid label {}", flag.label));
+    }
+    let catalog = match Catalog::load(&database).await {
+        Ok(catalog) => catalog,
+        Err(e) => return json_response(500, format!("{e}")),
+    };
+    if taken(&catalog, &flag.name, None) || taken(&catalog, flag.label.trim(), None) {
+        return json_response(409, format!("appearance/{} exists", flag.name));
+    }
+
+    const QUERY: &str = r#"
+        INSERT INTO appearance_flags (bit, name, label, version)
+        SELECT COALESCE(MAX(bit) + 1, 0), $1, $2, COALESCE(MAX(version), 0) + 1
+        FROM appearance_flags
+        ON CONFLICT DO NOTHING
+        RETURNING bit, name, label, version, retired;"#;
+    match sqlx::query_as::<_, AppearanceFlag>(QUERY)
+        .bind(&flag.name)
+        .bind(flag.label.trim())
+        .fetch_optional(&database)
+        .await
+    {
+        Ok(Some(created)) => {
+            info!("{} created appearance/{}", current.account, flag.name);
+            let event = AuditEvent::new("appearance.create", "appearance", &flag.name)
+                .by(&*current)
+                .after(&created);
+            let _ = audit.record(&database, event).await;
+            json_response(200, String::from("success"))
+        }
+        /* added meanwhile */
+        Ok(None) => json_response(409, format!("appearance/{} exists", flag.name)),
+        Err(e) => json_response(500, format!("DB error - {e}")),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/appearance/{name}",
+    params(
+        ("name" = String, Path, description = "flag name")
+    ),
+    request_body = AppearanceFlagUpdate,
+    responses(
+        (status = 200, description = "flag relabelled or (un)retired, the vocabulary version bumped", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 400, description = "invalid label", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, catalog.manage required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 404, description = "flag not found", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+        (status = 409, description = "label taken by another flag", body = ApiResponse, example = json!(ApiResponse::new(409, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn appearance_update_api(
+    current: Require<CatalogManage>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+    Path(name): Path<String>,
+    Json(update): Json<AppearanceFlagUpdate>,
+) -> impl IntoResponse {
+    if let Some(ref label) = update.label {
+        if !valid_label(label) {
+            return json_response(400, format!("invalid label {label}"));
+        }
+    }
+    let catalog = match Catalog::load(&database).await {
+        Ok(catalog) => catalog,
+        Err(e) => return json_response(500, format!("{e}")),
+    };
+    let orig = match catalog.flags().iter().find(|f| f.name() == name) {
+        Some(orig) => orig,
+        None => return json_response(404, format!("appearance/{name} not found")),
+    };
+    if let Some(ref label) = update.label {
+        if taken(&catalog, label.trim(), Some(&name)) {
+            return json_response(409, format!("label {label} taken"));
+        }
+    }
+
+    const QUERY: &str = r#"
+        UPDATE appearance_flags SET
+            label = COALESCE($2, label),
+            retired = COALESCE($3, retired),
+            version = (SELECT MAX(version) + 1 FROM appearance_flags)
+        WHERE name = $1
+        RETURNING bit, name, label, version, retired;"#;
+    match sqlx::query_as::<_, AppearanceFlag>(QUERY)
+        .bind(&name)
+        .bind(update.label.as_deref().map(str::trim))
+        .bind(update.retired)
+        .fetch_optional(&database)
+        .await
+    {
+        Ok(Some(updated)) => {
+            info!("{} updated appearance/{name}", current.account);
+            let event = AuditEvent::new("appearance.update", "appearance", &name)
+                .by(&*current)
+                .before(orig)
+                .after(&updated);
+            let _ = audit.record(&database, event).await;
+            json_response(200, String::from("success"))
+        }
+        Ok(None) => json_response(404, format!("appearance/{name} not found")),
+        Err(e) => json_response(500, format!("DB error - {e}")),
+    }
+}
@@ -0,0 +1,590 @@
+use axum::{
+    extract::{Extension, Multipart, Path},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::error;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::attachment::{self, Processed};
+use crate::audit::{Audit, AuditEvent};
+use crate::authentication::{AuthState, CurrentUser};
+use crate::blob::SharedBlobStore;
+use crate::dcare_order::query_order_scope;
+use crate::errors::NotLoggedIn;
+use crate::policy::{self, OrderAction};
+use crate::{rbac, ApiResponse, Database};
+
+/// longest file name kept, in characters
+const FILENAME_MAX: usize = 200;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema, sqlx::FromRow)]
+pub struct AttachmentInfo {
+    id: i32,
+    create_at: DateTime<Utc>,
+    /// account of the uploader
+    uploader: Option<String>,
+    #[schema(example = "scratch.jpg")]
+    filename: String,
+    /// sniffed from the content
+    #[schema(example = "image/jpeg")]
+    content_type: String,
+    /// bytes, metadata stripped
+    size: i32,
+    sha256: String,
+    width: Option<i32>,
+    height: Option<i32>,
+    /// `GET .../{id}/thumbnail` has one
+    thumbnail: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct AttachmentsResponse {
+    code: u16,
+    attachments: Option<Vec<AttachmentInfo>>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct AttachmentBlob {
+    filename: String,
+    content_type: String,
+    blob_key: String,
+    thumbnail_key: Option<String>,
+}
+
+const SELECT: &str = r#"
+    SELECT
+        a.id,
+        a.create_at,
+        u.account AS uploader,
+        a.filename,
+        a.content_type,
+        a.size,
+        a.sha256,
+        a.width,
+        a.height,
+        a.thumbnail_key IS NOT NULL AS thumbnail
+    FROM order_attachments a
+        LEFT JOIN users u ON u.id = a.uploader_id
+"#;
+
+fn json_response(code: u16, message: String) -> Response {
+    let resp = ApiResponse::new(code, Some(message));
+    if code >= 500 {
+        error!("{:?}", &resp);
+    }
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
+/// id of order `sn` if `action` on it is allowed, the response otherwise
+async fn order_id(
+    database: &Database,
+    current: &CurrentUser,
+    sn: &str,
+    action: OrderAction,
+) -> Result<i32, Response> {
+    match query_order_scope(database, sn).await {
+        Some((id, scope)) if policy::order_allowed(current, action, scope) => Ok(id),
+        Some(_) => Err(rbac::forbidden(format!("permission deny, order/{sn}"))),
+        None => Err(json_response(404, format!("order/{sn} not found"))),
+    }
+}
+
+async fn query_attachments(database: &Database, order_id: i32) -> Result<Vec<AttachmentInfo>> {
+    let query = format!("{SELECT} WHERE a.order_id = $1 ORDER BY a.id;");
+    sqlx::query_as::<_, AttachmentInfo>(&query)
+        .bind(order_id)
+        .fetch_all(database)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+async fn query_attachment(
+    database: &Database,
+    order_id: i32,
+    id: i32,
+) -> Result<Option<AttachmentInfo>> {
+    let query = format!("{SELECT} WHERE a.order_id = $1 AND a.id = $2;");
+    sqlx::query_as::<_, AttachmentInfo>(&query)
+        .bind(order_id)
+        .bind(id)
+        .fetch_optional(database)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+async fn query_blob(database: &Database, order_id: i32, id: i32) -> Result<Option<AttachmentBlob>> {
+    const QUERY: &str = r#"
+        SELECT filename, content_type, blob_key, thumbnail_key
+        FROM order_attachments
+        WHERE order_id = $1 AND id = $2;"#;
+
+    sqlx::query_as::<_, AttachmentBlob>(QUERY)
+        .bind(order_id)
+        .bind(id)
+        .fetch_optional(database)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+/// blob keys of the attachments of deleted order `sn`, to drop after purging it
+pub(crate) async fn deleted_order_blob_keys(database: &Database, sn: &str) -> Vec<String> {
+    const QUERY: &str = r#"
+        SELECT a.blob_key, a.thumbnail_key
+        FROM order_attachments a
+            JOIN orders o ON o.id = a.order_id
+        WHERE o.sn = $1 AND o.deleted_at IS NOT NULL;"#;
+
+    sqlx::query_as::<_, (String, Option<String>)>(QUERY)
+        .bind(sn)
+        .fetch_all(database)
+        .await
+        .map(|rows| {
+            rows.into_iter()
+                .flat_map(|(blob, thumbnail)| std::iter::once(blob).chain(thumbnail))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// delete `keys`, failures are only logged, the rows are gone already
+pub(crate) async fn delete_blobs(blobs: &SharedBlobStore, keys: &[String]) {
+    for key in keys {
+        if let Err(e) = blobs.delete(key).await {
+            error!("{e}");
+        }
+    }
+}
+
+/// last path component, without control characters, `FILENAME_MAX` characters at most
+fn clean_filename(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(FILENAME_MAX)
+        .collect();
+    match name.trim() {
+        "" => "attachment".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// `inline`, ASCII fallback and the UTF-8 name
+fn content_disposition(filename: &str) -> String {
+    let ascii: String = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || " ._-()".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let encoded: String = filename
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'_' | b'-' => (b as char).to_string(),
+            _ => format!("%{b:02X}"),
+        })
+        .collect();
+    format!("inline; filename=\"{ascii}\"; filename*=UTF-8''{encoded}")
+}
+
+/// store the blobs of one upload and its row, the blobs are dropped again on failure
+async fn store(
+    database: &Database,
+    blobs: &SharedBlobStore,
+    order_id: i32,
+    uploader: i32,
+    filename: &str,
+    processed: Processed,
+) -> Result<i32> {
+    let base = format!("orders/{order_id}/{}", Uuid::new_v4());
+    let blob_key = format!("{base}.{}", attachment::extension(processed.content_type));
+    let thumbnail_key = processed
+        .thumbnail
+        .as_ref()
+        .map(|_| format!("{base}.thumb.jpg"));
+    let sha256 = HEXLOWER.encode(&Sha256::digest(&processed.bytes));
+    let size = processed.bytes.len() as i32;
+
+    let mut stored = Vec::new();
+    let result: Result<i32> = async {
+        blobs
+            .put(&blob_key, processed.content_type, processed.bytes)
+            .await?;
+        stored.push(blob_key.clone());
+        if let (Some(key), Some(thumbnail)) = (&thumbnail_key, processed.thumbnail) {
+            blobs
+                .put(key, attachment::THUMBNAIL_TYPE, thumbnail)
+                .await?;
+            stored.push(key.clone());
+        }
+
+        const QUERY: &str = r#"
+            INSERT INTO order_attachments (
+                order_id, uploader_id, filename, content_type, size, sha256,
+                blob_key, thumbnail_key, width, height
+            ) VALUES (
+                $1, $2, $3, $4, $5, $6, $7, $8, $9, $10
+            ) RETURNING id;"#;
+        sqlx::query_as::<_, (i32,)>(QUERY)
+            .bind(order_id)
+            .bind(uploader)
+            .bind(filename)
+            .bind(processed.content_type)
+            .bind(size)
+            .bind(&sha256)
+            .bind(&blob_key)
+            .bind(&thumbnail_key)
+            .bind(processed.width)
+            .bind(processed.height)
+            .fetch_one(database)
+            .await
+            .map(|(id,)| id)
+            .map_err(|e| anyhow!("DB error - {e}"))
+    }
+    .await;
+
+    if result.is_err() {
+        delete_blobs(blobs, &stored).await;
+    }
+    result
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/order/{sn}/attachments",
+    params(
+        ("sn" = String, Path, description = "order serial-number")
+    ),
+    request_body(content = String, description = "multipart/form-data, every part with a file name is an attachment: JPEG, PNG or PDF, 10 MiB each at most", content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "the attachments stored, photos without EXIF/XMP/IPTC metadata and with a thumbnail", body = AttachmentsResponse),
+        (status = 400, description = "no file, too large, type not supported or broken image; nothing stored", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, order.update within reach required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 404, description = "order not found", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn order_attachment_upload(
+    Extension(mut auth_state): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    Extension(blobs): Extension<SharedBlobStore>,
+    audit: Audit,
+    Path(sn): Path<String>,
+    mut multipart: Multipart,
+) -> impl IntoResponse {
+    let current = match auth_state.get_user().await {
+        Some(current) => current,
+        None => return json_response(400, format!("{}", &NotLoggedIn)),
+    };
+    let order_id = match order_id(&database, current, &sn, OrderAction::Update).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+
+    /* check every file before storing any */
+    let mut uploads = Vec::new();
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return json_response(400, format!("multipart - {e}")),
+        };
+        let filename = match field.file_name() {
+            Some(name) => clean_filename(name),
+            None => continue,
+        };
+        let bytes = match field.bytes().await {
+            Ok(bytes) => bytes.to_vec(),
+            Err(e) => return json_response(400, format!("{filename} - {e}")),
+        };
+        /* decoding and thumbnailing a photo takes a while, off the executor */
+        match tokio::task::spawn_blocking(move || attachment::process(bytes)).await {
+            Ok(Ok(processed)) => uploads.push((filename, processed)),
+            Ok(Err(rejected)) => return json_response(400, format!("{filename} - {rejected}")),
+            Err(e) => return json_response(500, format!("{filename} - {e}")),
+        }
+    }
+    if uploads.is_empty() {
+        return json_response(400, String::from("no file in the request"));
+    }
+
+    let mut attachments = Vec::new();
+    for (filename, processed) in uploads {
+        let id = match store(
+            &database, &blobs, order_id, current.id, &filename, processed,
+        )
+        .await
+        {
+            Ok(id) => id,
+            Err(e) => return json_response(500, format!("{filename} - {e}")),
+        };
+        if let Ok(Some(info)) = query_attachment(&database, order_id, id).await {
+            let event = AuditEvent::new("attachment.create", "order", &sn)
+                .by(current)
+                .after(&info);
+            let _ = audit.record(&database, event).await;
+            attachments.push(info);
+        }
+    }
+
+    let resp = AttachmentsResponse {
+        code: 200,
+        attachments: Some(attachments),
+    };
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/order/{sn}/attachments",
+    params(
+        ("sn" = String, Path, description = "order serial-number")
+    ),
+    responses(
+        (status = 200, description = "attachments of the order, oldest first", body = AttachmentsResponse),
+        (status = 403, description = "permission deny, order.read within reach required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 404, description = "order not found", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn order_attachments_request(
+    Extension(mut auth_state): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    Path(sn): Path<String>,
+) -> impl IntoResponse {
+    let current = match auth_state.get_user().await {
+        Some(current) => current,
+        None => return json_response(400, format!("{}", &NotLoggedIn)),
+    };
+    let order_id = match order_id(&database, current, &sn, OrderAction::Read).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+
+    match query_attachments(&database, order_id).await {
+        Ok(attachments) => {
+            let resp = AttachmentsResponse {
+                code: 200,
+                attachments: Some(attachments),
+            };
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => json_response(500, format!("{e}")),
+    }
+}
+
+/// content of attachment `id`, or its thumbnail
+async fn serve(
+    mut auth_state: AuthState,
+    database: Database,
+    blobs: SharedBlobStore,
+    sn: String,
+    id: i32,
+    thumbnail: bool,
+) -> Response {
+    let current = match auth_state.get_user().await {
+        Some(current) => current,
+        None => return json_response(400, format!("{}", &NotLoggedIn)),
+    };
+    let order_id = match order_id(&database, current, &sn, OrderAction::Read).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+
+    let blob = match query_blob(&database, order_id, id).await {
+        Ok(Some(blob)) => blob,
+        Ok(None) => return json_response(404, format!("order/{sn}/attachments/{id} not found")),
+        Err(e) => return json_response(500, format!("{e}")),
+    };
+    let (key, content_type) = if thumbnail {
+        match blob.thumbnail_key {
+            Some(key) => (key, attachment::THUMBNAIL_TYPE.to_string()),
+            None => return json_response(404, format!("order/{sn}/attachments/{id} no thumbnail")),
+        }
+    } else {
+        (blob.blob_key, blob.content_type)
+    };
+
+    let bytes = match blobs.get(&key).await {
+        Ok(Some(bytes)) => bytes,
+        Ok(None) => return json_response(500, format!("blob {key} missing")),
+        Err(e) => return json_response(500, format!("{e}")),
+    };
+
+    let mut response = (StatusCode::OK, bytes).into_response();
+    let headers = response.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&content_type) {
+        headers.insert(header::CONTENT_TYPE, value);
+    }
+    if let Ok(value) = HeaderValue::from_str(&content_disposition(&blob.filename)) {
+        headers.insert(header::CONTENT_DISPOSITION, value);
+    }
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("private, max-age=3600"),
+    );
+    response
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/order/{sn}/attachments/{id}",
+    params(
+        ("sn" = String, Path, description = "order serial-number"),
+        ("id" = i32, Path, description = "attachment id"),
+    ),
+    responses(
+        (status = 200, description = "the stored content, `Content-Type` as sniffed at upload"),
+        (status = 403, description = "permission deny, order.read within reach required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 404, description = "order or attachment not found", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn order_attachment_request(
+    Extension(auth_state): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    Extension(blobs): Extension<SharedBlobStore>,
+    Path((sn, id)): Path<(String, i32)>,
+) -> impl IntoResponse {
+    serve(auth_state, database, blobs, sn, id, false).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/order/{sn}/attachments/{id}/thumbnail",
+    params(
+        ("sn" = String, Path, description = "order serial-number"),
+        ("id" = i32, Path, description = "attachment id"),
+    ),
+    responses(
+        (status = 200, description = "upright JPEG thumbnail, 320 pixels on the longer edge at most"),
+        (status = 403, description = "permission deny, order.read within reach required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 404, description = "order or attachment not found, or a document without thumbnail", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn order_attachment_thumbnail(
+    Extension(auth_state): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    Extension(blobs): Extension<SharedBlobStore>,
+    Path((sn, id)): Path<(String, i32)>,
+) -> impl IntoResponse {
+    serve(auth_state, database, blobs, sn, id, true).await
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/order/{sn}/attachments/{id}",
+    params(
+        ("sn" = String, Path, description = "order serial-number"),
+        ("id" = i32, Path, description = "attachment id to delete"),
+    ),
+    responses(
+        (status = 200, description = "delete success, the content is removed from the store too", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("delete success"))))),
+        (status = 403, description = "permission deny, order.update within reach required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 404, description = "order or attachment not found", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn order_attachment_delete(
+    Extension(mut auth_state): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    Extension(blobs): Extension<SharedBlobStore>,
+    audit: Audit,
+    Path((sn, id)): Path<(String, i32)>,
+) -> impl IntoResponse {
+    let current = match auth_state.get_user().await {
+        Some(current) => current,
+        None => return json_response(400, format!("{}", &NotLoggedIn)),
+    };
+    let order_id = match order_id(&database, current, &sn, OrderAction::Update).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let info = match query_attachment(&database, order_id, id).await {
+        Ok(Some(info)) => info,
+        Ok(None) => return json_response(404, format!("order/{sn}/attachments/{id} not found")),
+        Err(e) => return json_response(500, format!("{e}")),
+    };
+
+    const QUERY: &str = r#"
+        DELETE FROM order_attachments WHERE order_id = $1 AND id = $2
+        RETURNING blob_key, thumbnail_key;"#;
+
+    match sqlx::query_as::<_, (String, Option<String>)>(QUERY)
+        .bind(order_id)
+        .bind(id)
+        .fetch_optional(&database)
+        .await
+    {
+        Ok(Some((blob_key, thumbnail_key))) => {
+            let keys: Vec<String> = std::iter::once(blob_key).chain(thumbnail_key).collect();
+            delete_blobs(&blobs, &keys).await;
+
+            let event = AuditEvent::new("attachment.delete", "order", &sn)
+                .by(current)
+                .before(&info);
+            let _ = audit.record(&database, event).await;
+
+            json_response(200, String::from("delete success"))
+        }
+        Ok(None) => json_response(404, format!("order/{sn}/attachments/{id} not found")),
+        Err(e) => json_response(500, format!("DB error - {e}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filenames_are_cleaned_for_storing_and_serving() {
+        assert_eq!(clean_filename("C:\\Users\\x\\刮傷.jpg"), "刮傷.jpg");
+        assert_eq!(clean_filename("../../etc/passwd"), "passwd");
+        assert_eq!(clean_filename("a\r\nb.png"), "ab.png");
+        assert_eq!(clean_filename("  "), "attachment");
+
+        assert_eq!(
+            content_disposition("刮傷 \"1\".jpg"),
+            "inline; filename=\"__ _1_.jpg\"; \
+             filename*=UTF-8''%E5%88%AE%E5%82%B7%20%221%22.jpg"
+        );
+    }
+}
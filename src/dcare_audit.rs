@@ -0,0 +1,290 @@
+use axum::{
+    extract::{Extension, Query},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::error;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::audit::{Audit, AuditEvent};
+use crate::query_filter::{list, ListFilter};
+use crate::rbac::{AuditRead, Require};
+use crate::{ApiResponse, Database};
+
+/// rows of one CSV export at most, page with `offset` for more
+const EXPORT_LIMIT: i32 = 10000;
+
+#[derive(Deserialize, IntoParams)]
+pub struct AuditListQuery {
+    offset: Option<i32>,
+    entries: Option<i32>,
+    /// account of the actor, comma separated
+    actor: Option<String>,
+    /// e.g. `user.update,session.login`
+    action: Option<String>,
+    /// `user`, `department`, `order`, `session`, `role`, `api_key`, `audit`
+    entity_type: Option<String>,
+    /// account, shorten, sn, ... of the entity
+    entity_id: Option<String>,
+    ip: Option<String>,
+    request_id: Option<String>,
+    start: Option<NaiveDate>,
+    end: Option<NaiveDate>,
+}
+
+impl AuditListQuery {
+    /// add the filters to `filter`, `(offset, entries)` of the page
+    pub fn parse(mine: Option<Query<Self>>, filter: &mut ListFilter) -> (i32, i32) {
+        if let Some(Query(q)) = mine {
+            filter
+                .any_of("a.actor", list(&q.actor))
+                .any_of("a.action", list(&q.action))
+                .any_of("a.entity_type", list(&q.entity_type))
+                .eq("a.entity_id", q.entity_id)
+                .eq("a.ip", q.ip)
+                .eq("a.request_id", q.request_id)
+                .range("a.at", q.start, q.end);
+
+            (q.offset.unwrap_or(0), q.entries.unwrap_or(100))
+        } else {
+            (0, 100)
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema, sqlx::FromRow)]
+pub struct AuditEventInfo {
+    id: i64,
+    at: DateTime<Utc>,
+    actor: Option<String>,
+    #[schema(example = "user.update")]
+    action: String,
+    entity_type: String,
+    entity_id: Option<String>,
+    /// state before, secrets redacted
+    #[schema(value_type = Option<Object>)]
+    before: Option<Value>,
+    /// state after, secrets redacted
+    #[schema(value_type = Option<Object>)]
+    after: Option<Value>,
+    ip: Option<String>,
+    request_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct AuditEventsResponse {
+    code: u16,
+    events: Option<Vec<AuditEventInfo>>,
+}
+
+const SELECT: &str = r#"
+    SELECT
+        a.id,
+        a.at,
+        a.actor,
+        a.action,
+        a.entity_type,
+        a.entity_id,
+        a.before,
+        a.after,
+        a.ip,
+        a.request_id
+    FROM audit_events a
+"#;
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/audit",
+    params(
+        AuditListQuery
+    ),
+    responses(
+        (status = 200, description = "audit events, newest first", body = AuditEventsResponse),
+        (status = 403, description = "permission deny, audit.read required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn audit_list_api(
+    _: Require<AuditRead>,
+    Extension(database): Extension<Database>,
+    query: Option<Query<AuditListQuery>>,
+) -> impl IntoResponse {
+    let mut filter = ListFilter::new(SELECT);
+    let (offset, entries) = AuditListQuery::parse(query, &mut filter);
+    let mut query = filter.finish("a.at DESC, a.id DESC", entries, offset);
+
+    match query
+        .build_query_as::<AuditEventInfo>()
+        .fetch_all(&database)
+        .await
+    {
+        Ok(events) => {
+            let resp = AuditEventsResponse {
+                code: 200,
+                events: Some(events),
+            };
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => {
+            let resp = ApiResponse::new(500, Some(format!("DB error - {e}")));
+            error!("{:?}", &resp);
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/audit/export",
+    params(
+        AuditListQuery
+    ),
+    responses(
+        (status = 200, description = "audit events as CSV (UTF-8 with BOM), newest first, 10000 from `offset` at most, `entries` is ignored", content_type = "text/csv", body = String),
+        (status = 403, description = "permission deny, audit.read required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn audit_export_api(
+    current: Require<AuditRead>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+    query: Option<Query<AuditListQuery>>,
+) -> impl IntoResponse {
+    let mut filter = ListFilter::new(SELECT);
+    let (offset, _) = AuditListQuery::parse(query, &mut filter);
+    let mut query = filter.finish("a.at DESC, a.id DESC", EXPORT_LIMIT, offset);
+
+    let events = match query
+        .build_query_as::<AuditEventInfo>()
+        .fetch_all(&database)
+        .await
+    {
+        Ok(events) => events,
+        Err(e) => {
+            let resp = ApiResponse::new(500, Some(format!("DB error - {e}")));
+            error!("{:?}", &resp);
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+    };
+
+    /* who took a copy of the log is part of the log */
+    let event = AuditEvent::new("audit.export", "audit", events.len()).by(&*current);
+    let _ = audit.record(&database, event).await;
+
+    let mut csv = String::from("\u{feff}");
+    csv.push_str(CSV_HEADER);
+    for event in &events {
+        csv.push_str(&event.csv_row());
+    }
+
+    let filename = format!("audit-{}.csv", Utc::now().format("%Y%m%d%H%M%S"));
+    (
+        StatusCode::OK,
+        [
+            (
+                header::CONTENT_TYPE,
+                String::from("text/csv; charset=utf-8"),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{filename}\""),
+            ),
+        ],
+        csv,
+    )
+        .into_response()
+}
+
+const CSV_HEADER: &str = "id,at,actor,action,entity_type,entity_id,ip,request_id,before,after\r\n";
+
+impl AuditEventInfo {
+    fn csv_row(&self) -> String {
+        let text = |v: &Option<String>| v.clone().unwrap_or_default();
+        let json = |v: &Option<Value>| v.as_ref().map(Value::to_string).unwrap_or_default();
+        let fields = [
+            self.id.to_string(),
+            self.at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            text(&self.actor),
+            self.action.clone(),
+            self.entity_type.clone(),
+            text(&self.entity_id),
+            text(&self.ip),
+            text(&self.request_id),
+            json(&self.before),
+            json(&self.after),
+        ];
+        let fields: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        format!("{}\r\n", fields.join(","))
+    }
+}
+
+/// RFC 4180 quoting, text a spreadsheet would run as a formula gets a leading `'`
+fn csv_field(field: &str) -> String {
+    let field = if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{field}")
+    } else {
+        field.to_string()
+    };
+
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn csv_is_quoted_and_defused() {
+        assert_eq!(csv_field("user.update"), "user.update");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("line\nbreak"), "\"line\nbreak\"");
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("-1"), "'-1");
+    }
+
+    #[test]
+    fn csv_row_in_header_order() {
+        let event = AuditEventInfo {
+            id: 7,
+            at: DateTime::parse_from_rfc3339("2031-02-03T04:05:06Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            actor: Some(String::from("admin")),
+            action: String::from("department.delete"),
+            entity_type: String::from("department"),
+            entity_id: Some(String::from("BM")),
+            before: Some(json!({"shorten": "BM", "store_name": "板橋, 門市"})),
+            after: None,
+            ip: None,
+            request_id: Some(String::from("r1")),
+        };
+
+        assert_eq!(CSV_HEADER.split(',').count(), 10);
+        assert_eq!(
+            event.csv_row(),
+            "7,2031-02-03T04:05:06.000Z,admin,department.delete,department,BM,,r1,\
+             \"{\"\"shorten\"\":\"\"BM\"\",\"\"store_name\"\":\"\"板橋, 門市\"\"}\",\r\n"
+        );
+    }
+}
@@ -0,0 +1,652 @@
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use http::Response;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::audit::{Audit, AuditEvent};
+use crate::authentication::{
+    new_session, token_hash, AuthConfig, AuthState, ClientInfo, CurrentUser, SessionToken,
+};
+use crate::dcare_user::{login_success_response, query_user_id, update_login_at};
+use crate::errors::{LoginError, NotLoggedIn};
+use crate::login_throttle;
+use crate::mfa;
+use crate::rbac::{Require, UserSecurity};
+use crate::utils::session_cookie;
+use crate::{ApiResponse, Database, Random, USER_COOKIE_NAME};
+
+/// wrong codes a pending session may send before it is dropped
+const MAX_PENDING_FAILURES: i32 = 5;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct MfaCode {
+    /// 6 digits TOTP, or a recovery code
+    #[schema(example = "123456")]
+    code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct MfaEnrollResponse {
+    code: u16,
+    /// base32, for manual entry
+    secret: String,
+    /// render as QR code for the authenticator app
+    otpauth_uri: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct MfaConfirmResponse {
+    code: u16,
+    /// one-time codes for a lost authenticator, shown only this time
+    recovery_codes: Vec<String>,
+    /// set when confirmed with a pending login session, the session is logged in now
+    session_key: Option<String>,
+    session_value: Option<String>,
+}
+
+struct MfaRaw {
+    secret: String,
+    confirmed: bool,
+    last_step: i64,
+}
+
+async fn query_mfa(database: &Database, user_id: i32) -> Result<Option<MfaRaw>> {
+    const QUERY: &str = r#"
+        SELECT secret, confirmed_at IS NOT NULL, last_step FROM user_mfa WHERE user_id = $1;
+    "#;
+
+    let mfa: Option<(String, bool, i64)> = sqlx::query_as(QUERY)
+        .bind(user_id)
+        .fetch_optional(database)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+
+    Ok(mfa.map(|(secret, confirmed, last_step)| MfaRaw {
+        secret,
+        confirmed,
+        last_step,
+    }))
+}
+
+/// new secret replaces an unconfirmed one, `false` if 2FA is already on
+async fn mfa_secret_upsert(database: &Database, user_id: i32, secret: &str) -> Result<bool> {
+    const QUERY: &str = r#"
+        INSERT INTO user_mfa (user_id, secret) VALUES ($1, $2)
+        ON CONFLICT (user_id) DO UPDATE SET
+            secret = EXCLUDED.secret,
+            last_step = 0,
+            create_at = NOW()
+        WHERE user_mfa.confirmed_at IS NULL;
+    "#;
+
+    sqlx::query(QUERY)
+        .bind(user_id)
+        .bind(secret)
+        .execute(database)
+        .await
+        .map(|r| r.rows_affected() > 0)
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+/// confirm enrollment with the first good code, replace the recovery codes
+async fn mfa_confirm(
+    database: &Database,
+    user_id: i32,
+    step: i64,
+    recovery_codes: &[String],
+) -> Result<bool> {
+    const CONFIRM_QUERY: &str = r#"
+        UPDATE user_mfa SET confirmed_at = NOW(), last_step = $2
+        WHERE user_id = $1 AND confirmed_at IS NULL AND last_step < $2;
+    "#;
+    const DELETE_QUERY: &str = "DELETE FROM mfa_recovery_codes WHERE user_id = $1;";
+    const INSERT_QUERY: &str =
+        "INSERT INTO mfa_recovery_codes (user_id, code_hash) VALUES ($1, $2);";
+
+    let mut tx = database
+        .begin()
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+
+    let confirmed = sqlx::query(CONFIRM_QUERY)
+        .bind(user_id)
+        .bind(step)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?
+        .rows_affected()
+        > 0;
+    if !confirmed {
+        return Ok(false);
+    }
+
+    sqlx::query(DELETE_QUERY)
+        .bind(user_id)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+    for code in recovery_codes {
+        sqlx::query(INSERT_QUERY)
+            .bind(user_id)
+            .bind(token_hash(&mfa::normalize_recovery_code(code)))
+            .execute(&mut tx)
+            .await
+            .map_err(|e| anyhow!("DB error - {e}"))?;
+    }
+
+    tx.commit().await.map_err(|e| anyhow!("DB error - {e}"))?;
+    Ok(true)
+}
+
+/// recovery codes go along (cascade)
+async fn mfa_delete(database: &Database, user_id: i32) -> Result<bool> {
+    const QUERY: &str = "DELETE FROM user_mfa WHERE user_id = $1;";
+
+    sqlx::query(QUERY)
+        .bind(user_id)
+        .execute(database)
+        .await
+        .map(|r| r.rows_affected() > 0)
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+/// TOTP of a confirmed enrollment, or an unused recovery code, each good once
+async fn mfa_check_code(
+    database: &Database,
+    user_id: i32,
+    mfa: &MfaRaw,
+    code: &str,
+) -> Result<bool> {
+    const TOTP_QUERY: &str =
+        "UPDATE user_mfa SET last_step = $2 WHERE user_id = $1 AND last_step < $2;";
+    const RECOVERY_QUERY: &str = r#"
+        UPDATE mfa_recovery_codes SET used_at = NOW()
+        WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL;
+    "#;
+
+    let now = Utc::now().timestamp() as u64;
+    let result = match mfa::verify(&mfa.secret, code, now, mfa.last_step) {
+        Some(step) => {
+            sqlx::query(TOTP_QUERY)
+                .bind(user_id)
+                .bind(step)
+                .execute(database)
+                .await
+        }
+        None => {
+            sqlx::query(RECOVERY_QUERY)
+                .bind(user_id)
+                .bind(token_hash(&mfa::normalize_recovery_code(code)))
+                .execute(database)
+                .await
+        }
+    };
+
+    result
+        .map(|r| r.rows_affected() > 0)
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+/// count a wrong code, drop the pending session once it runs out of tries
+async fn pending_failure(database: &Database, session_token: SessionToken) -> Result<()> {
+    const FAILURE_QUERY: &str = r#"
+        UPDATE sessions SET mfa_failures = mfa_failures + 1
+        WHERE session_token = $1 AND mfa_pending
+        RETURNING mfa_failures;
+    "#;
+    const DELETE_QUERY: &str = "DELETE FROM sessions WHERE session_token = $1;";
+
+    let failures: Option<(i32,)> = sqlx::query_as(FAILURE_QUERY)
+        .bind(&session_token.into_database_value())
+        .fetch_optional(database)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+
+    if matches!(failures, Some((n,)) if n >= MAX_PENDING_FAILURES) {
+        sqlx::query(DELETE_QUERY)
+            .bind(&session_token.into_database_value())
+            .execute(database)
+            .await
+            .map_err(|e| anyhow!("DB error - {e}"))?;
+    }
+    Ok(())
+}
+
+/// second factor passed, the pending session is swapped for a real one
+async fn promote_pending(
+    database: &Database,
+    random: Random,
+    config: &AuthConfig,
+    client: &ClientInfo,
+    pending: SessionToken,
+    user: &CurrentUser,
+) -> Result<SessionToken, LoginError> {
+    const QUERY: &str = "DELETE FROM sessions WHERE session_token = $1 AND mfa_pending;";
+
+    if let Err(e) = sqlx::query(QUERY)
+        .bind(&pending.into_database_value())
+        .execute(database)
+        .await
+    {
+        error!("pending session of {} not removed - {e}", user.account);
+    }
+    let _ = update_login_at(database, &user.account).await;
+
+    new_session(database, random, config, client, user.id)
+        .await
+        .map_err(|e| {
+            error!("session of {} - {e}", user.account);
+            LoginError::InternalError
+        })
+}
+
+/// logged in or pending (enrollment forced by policy) user, never an API key,
+/// `bool` is `true` for pending
+async fn mfa_user(current_user: &mut AuthState) -> Result<(CurrentUser, bool), ApiResponse> {
+    if current_user.by_api_key() {
+        return Err(ApiResponse::new(
+            405,
+            Some(String::from("permission deny, login required")),
+        ));
+    }
+
+    if let Some(myself) = current_user.get_user().await {
+        Ok((myself.clone(), false))
+    } else if let Some(pending) = current_user.pending_user().await {
+        Ok((pending, true))
+    } else {
+        Err(ApiResponse::new(400, Some(format!("{}", &NotLoggedIn))))
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/login/mfa",
+    request_body = MfaCode,
+    responses(
+        (status = 200, description = "login success, return cookie session key/value", body = crate::dcare_user::ResponseUserLogin),
+        (status = 400, description = "no pending login, wrong code, ", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+        (status = 429, description = "too many wrong codes or failed logins, `Retry-After` seconds", body = ApiResponse, example = json!(ApiResponse::new(429, Some(String::from("Too many failed logins, retry after 30 seconds"))))),
+    ),
+    security(
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn login_mfa_api(
+    Extension(current_user): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    Extension(random): Extension<Random>,
+    Extension(auth_config): Extension<AuthConfig>,
+    client: ClientInfo,
+    audit: Audit,
+    Json(mfa_code): Json<MfaCode>,
+) -> impl IntoResponse {
+    let (user, pending) = match (
+        current_user.pending_user().await,
+        current_user.session_token(),
+    ) {
+        (Some(user), Some(pending)) => (user, pending),
+        _ => {
+            let resp = ApiResponse::new(400, Some(String::from("no pending login")));
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+    };
+
+    /* wrong codes add up per account, a fresh pending session starts no fresh count */
+    let ip = client.ip.as_deref();
+    match login_throttle::retry_after(&database, &user.account, ip).await {
+        Ok(Some(secs)) => {
+            let event = AuditEvent::new("session.login_failed", "user", &user.account)
+                .by(&user)
+                .after(&json!({"reason": "too many attempts", "retry_after": secs}));
+            let _ = audit.record(&database, event).await;
+
+            let resp =
+                ApiResponse::new(429, Some(format!("{}", LoginError::TooManyAttempts(secs))));
+            return Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header("content-type", "application/json")
+                .header("Retry-After", secs.to_string())
+                .body(json!(resp).to_string())
+                .unwrap()
+                .into_response();
+        }
+        Ok(None) => {}
+        Err(e) => {
+            let resp = ApiResponse::new(500, Some(format!("login throttle - {e}")));
+            error!("{:?}", &resp);
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+    }
+
+    let passed = match query_mfa(&database, user.id).await {
+        Ok(Some(mfa)) if mfa.confirmed => {
+            mfa_check_code(&database, user.id, &mfa, &mfa_code.code).await
+        }
+        Ok(_) => Ok(false),
+        Err(e) => Err(e),
+    };
+
+    match passed {
+        Ok(true) => {
+            info!("{} passed second factor", user.account);
+            let _ = login_throttle::record_success(&database, &user.account).await;
+            let event = AuditEvent::new("session.login", "user", &user.account)
+                .by(&user)
+                .after(&json!({"mfa": "passed"}));
+            let _ = audit.record(&database, event).await;
+
+            let session_token =
+                match promote_pending(&database, random, &auth_config, &client, pending, &user)
+                    .await
+                {
+                    Ok(session_token) => session_token,
+                    Err(e) => {
+                        let resp = ApiResponse::new(500, Some(format!("{e}")));
+                        return (StatusCode::OK, Json(resp)).into_response();
+                    }
+                };
+            login_success_response(
+                session_token,
+                &user.permission,
+                auth_config.cookie_max_age(),
+            )
+            .into_response()
+        }
+        Ok(false) => {
+            let _ = pending_failure(&database, pending).await;
+            if let Err(e) =
+                login_throttle::record_mfa_failure(&database, &auth_config, &user.account, ip).await
+            {
+                error!("login throttle - {e}");
+            }
+            let event = AuditEvent::new("session.login_failed", "user", &user.account)
+                .by(&user)
+                .after(&json!({"reason": "invalid code"}));
+            let _ = audit.record(&database, event).await;
+
+            let resp = ApiResponse::new(400, Some(String::from("invalid code")));
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => {
+            let resp = ApiResponse::new(500, Some(format!("{e}")));
+            error!("{:?}", &resp);
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/me/mfa/enroll",
+    responses(
+        (status = 200, description = "new TOTP secret, confirm it with a code to turn 2FA on", body = MfaEnrollResponse),
+        (status = 400, description = "not login, ", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+        (status = 409, description = "2FA already on, ", body = ApiResponse, example = json!(ApiResponse::new(409, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = [])
+    ),
+)]
+pub(crate) async fn mfa_enroll_api(
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    Extension(auth_config): Extension<AuthConfig>,
+    audit: Audit,
+) -> impl IntoResponse {
+    let (user, _) = match mfa_user(&mut current_user).await {
+        Ok(user) => user,
+        Err(resp) => return (StatusCode::OK, Json(resp)).into_response(),
+    };
+
+    let secret = mfa::generate_secret();
+    match mfa_secret_upsert(&database, user.id, &secret).await {
+        Ok(true) => {
+            let event = AuditEvent::new("mfa.enroll", "user", &user.account).by(&user);
+            let _ = audit.record(&database, event).await;
+
+            let resp = MfaEnrollResponse {
+                code: 200,
+                otpauth_uri: mfa::provisioning_uri(&auth_config.mfa_issuer, &user.account, &secret),
+                secret,
+            };
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Ok(false) => {
+            let resp = ApiResponse::new(409, Some(String::from("2FA already on")));
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => {
+            let resp = ApiResponse::new(500, Some(format!("{e}")));
+            error!("{:?}", &resp);
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/me/mfa/confirm",
+    request_body = MfaCode,
+    responses(
+        (status = 200, description = "2FA on, recovery codes returned once; a pending login session is logged in", body = MfaConfirmResponse),
+        (status = 400, description = "not login, not enrolled, wrong code, ", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = [])
+    ),
+)]
+pub(crate) async fn mfa_confirm_api(
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    Extension(random): Extension<Random>,
+    Extension(auth_config): Extension<AuthConfig>,
+    client: ClientInfo,
+    audit: Audit,
+    Json(mfa_code): Json<MfaCode>,
+) -> impl IntoResponse {
+    let (user, pending) = match mfa_user(&mut current_user).await {
+        Ok(user) => user,
+        Err(resp) => return (StatusCode::OK, Json(resp)).into_response(),
+    };
+
+    let now = Utc::now().timestamp() as u64;
+    let recovery_codes = mfa::generate_recovery_codes();
+    let confirmed = match query_mfa(&database, user.id).await {
+        Ok(Some(mfa)) if !mfa.confirmed => {
+            match mfa::verify(&mfa.secret, &mfa_code.code, now, mfa.last_step) {
+                Some(step) => mfa_confirm(&database, user.id, step, &recovery_codes).await,
+                None => Ok(false),
+            }
+        }
+        Ok(_) => {
+            let resp = ApiResponse::new(400, Some(String::from("not enrolled, or 2FA already on")));
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+        Err(e) => Err(e),
+    };
+
+    match confirmed {
+        Ok(true) => {
+            info!("{} turned 2FA on", user.account);
+            let event = AuditEvent::new("mfa.enable", "user", &user.account).by(&user);
+            let _ = audit.record(&database, event).await;
+
+            let session_token = match (pending, current_user.session_token()) {
+                (true, Some(pending)) => {
+                    match promote_pending(&database, random, &auth_config, &client, pending, &user)
+                        .await
+                    {
+                        Ok(session_token) => Some(session_token),
+                        Err(e) => {
+                            let resp = ApiResponse::new(500, Some(format!("{e}")));
+                            return (StatusCode::OK, Json(resp)).into_response();
+                        }
+                    }
+                }
+                _ => None,
+            };
+            let token = session_token.map(|t| t.into_cookie_value());
+
+            let resp = MfaConfirmResponse {
+                code: 200,
+                recovery_codes,
+                session_key: token.as_ref().map(|_| USER_COOKIE_NAME.to_string()),
+                session_value: token.clone(),
+            };
+            let mut builder = Response::builder()
+                .status(http::StatusCode::OK)
+                .header("content-type", "application/json");
+            if let Some(token) = token {
+                builder = builder.header(
+                    "Set-Cookie",
+                    session_cookie(&token, auth_config.cookie_max_age()),
+                );
+            }
+            builder
+                .body(json!(resp).to_string())
+                .unwrap()
+                .into_response()
+        }
+        Ok(false) => {
+            if pending {
+                if let Some(pending) = current_user.session_token() {
+                    let _ = pending_failure(&database, pending).await;
+                }
+            }
+            let resp = ApiResponse::new(400, Some(String::from("invalid code")));
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => {
+            let resp = ApiResponse::new(500, Some(format!("{e}")));
+            error!("{:?}", &resp);
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/me/mfa",
+    request_body = MfaCode,
+    responses(
+        (status = 200, description = "2FA off", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 400, description = "not login, wrong code, ", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+        (status = 405, description = "permission deny, role requires 2FA", body = ApiResponse, example = json!(ApiResponse::new(405, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = [])
+    ),
+)]
+pub(crate) async fn mfa_disable_api(
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    Extension(auth_config): Extension<AuthConfig>,
+    audit: Audit,
+    Json(mfa_code): Json<MfaCode>,
+) -> impl IntoResponse {
+    let user = match mfa_user(&mut current_user).await {
+        Ok((user, false)) => user,
+        Ok((_, true)) => {
+            let resp = ApiResponse::new(400, Some(format!("{}", &NotLoggedIn)));
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+        Err(resp) => return (StatusCode::OK, Json(resp)).into_response(),
+    };
+
+    if auth_config.mfa_required(&user.roles) {
+        let resp = ApiResponse::new(405, Some(String::from("permission deny, 2FA required")));
+        return (StatusCode::OK, Json(resp)).into_response();
+    }
+
+    let passed = match query_mfa(&database, user.id).await {
+        Ok(Some(mfa)) if mfa.confirmed => {
+            mfa_check_code(&database, user.id, &mfa, &mfa_code.code).await
+        }
+        Ok(_) => Ok(false),
+        Err(e) => Err(e),
+    };
+
+    let resp = match passed {
+        Ok(true) => match mfa_delete(&database, user.id).await {
+            Ok(_) => {
+                info!("{} turned 2FA off", user.account);
+                let event = AuditEvent::new("mfa.disable", "user", &user.account).by(&user);
+                let _ = audit.record(&database, event).await;
+                Ok(ApiResponse::new(200, Some(String::from("success"))))
+            }
+            Err(e) => Err(e),
+        },
+        Ok(false) => Ok(ApiResponse::new(400, Some(String::from("invalid code")))),
+        Err(e) => Err(e),
+    };
+
+    let resp = resp.unwrap_or_else(|e| {
+        let resp = ApiResponse::new(500, Some(format!("{e}")));
+        error!("{:?}", &resp);
+        resp
+    });
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/user/{account}/mfa",
+    params(
+        ("account" = String, Path, description = "user account")
+    ),
+    responses(
+        (status = 200, description = "2FA of user reset, for a lost authenticator", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 404, description = "user not found / 2FA not on, ", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, user.security required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn user_mfa_reset_api(
+    current: Require<UserSecurity>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+    Path(account): Path<String>,
+) -> impl IntoResponse {
+    let user_id = match query_user_id(&database, &account).await {
+        Some(id) => id,
+        None => {
+            let resp = ApiResponse::new(404, Some(format!("user/{account} not found")));
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+    };
+
+    let resp = match mfa_delete(&database, user_id).await {
+        Ok(true) => {
+            info!("2FA of {account} reset");
+            let event = AuditEvent::new("mfa.reset", "user", &account).by(&*current);
+            let _ = audit.record(&database, event).await;
+            ApiResponse::new(200, Some(String::from("success")))
+        }
+        Ok(false) => ApiResponse::new(404, Some(format!("2FA of {account} not on"))),
+        Err(e) => {
+            let resp = ApiResponse::new(500, Some(format!("{e}")));
+            error!("{:?}", &resp);
+            resp
+        }
+    };
+    (StatusCode::OK, Json(resp)).into_response()
+}
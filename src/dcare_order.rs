@@ -2,28 +2,44 @@ use axum::{
     extract::State,
     extract::{Extension, Path, Query},
     http::StatusCode,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     //response::{Html, Redirect},
     Json,
 };
 
 use anyhow::{anyhow, Result};
 use bit_vec::BitVec;
-use chrono::{DateTime, Datelike, Local, NaiveDate, Timelike, Utc};
+use chrono::{DateTime, Local, NaiveDate, Utc};
 use serde::{/*serde_if_integer128, */ Deserialize, Serialize};
-//use serde_json::json;
+use serde_json::{json, Value};
+use sqlx::{Executor, Postgres};
 use tracing::{
-    debug, error,
+    debug,
+    error,
     //info,
 };
 use utoipa::{IntoParams, ToSchema};
 
 use crate::{authentication::AuthState, Pagination};
 
+use crate::appearance::Catalog;
+use crate::audit::{Audit, AuditEvent};
+use crate::blob::SharedBlobStore;
+use crate::dcare_attachment::{delete_blobs, deleted_order_blob_keys};
 use crate::dcare_user::{query_user_id, shared_store_users_get};
 use crate::department::{department_shorten_query, shared_store_departments_get};
 use crate::errors::NotLoggedIn;
+use crate::etag::{self, IfMatch};
 use crate::gsheets::{GooglesheetPosition, SharedDcareGoogleSheet};
+use crate::order_changes;
+use crate::order_sn::{self, next_sn, SnFormat};
+use crate::order_status::{TransitionError, Workflow};
+use crate::payment::{self, Direction, Entry, Method};
+use crate::policy::{self, OrderAction, OrderScope};
+use crate::query_filter::{like_escape, list, ListFilter};
+use crate::rbac::{self, OrderCloseUnpaid, OrderRead, Permission, Require, TrashManage};
+use crate::receipt::Amounts;
+use crate::trash::{self, Trash};
 use crate::{ApiResponse, Database, Random, SharedState};
 
 type Price = i32;
@@ -32,9 +48,14 @@ type Price = i32;
 pub struct OrderListQuery {
     offset: Option<i32>,
     entries: Option<i32>,
+    /// search sn, customer, phone (partial too), address, brand/model, fault and remark,
+    /// best match first
+    q: Option<String>,
     department: Option<String>,
     contact: Option<String>,
     phone: Option<String>,
+    /// start of the customer name
+    customer: Option<String>,
     servicer: Option<String>,
     maintainer: Option<String>,
     status: Option<String>,
@@ -44,105 +65,89 @@ pub struct OrderListQuery {
 }
 
 impl OrderListQuery {
-    pub fn parse(mine: Option<Query<Self>>) -> (i32, i32, String) {
-        if let Some(ref q) = mine {
-            let offset = q.offset.map_or(0, |o| o);
-            let entries = q.entries.map_or(100, |e| e);
-
-            let where_is = if let Some(ref p) = q.phone {
-                format!("WHERE o.customer_phone = '{p}'")
-            } else {
-                "".to_string()
-            };
-            let where_is = if let Some(ref d) = q.department {
-                let sql_d =
-                    format!("o.department_id = (SELECT id FROM departments WHERE shorten = '{d}')");
-                if where_is.is_empty() {
-                    format!("WHERE {sql_d}")
-                } else {
-                    format!("{where_is} AND {sql_d}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref u) = q.contact {
-                let sql_u = format!("o.contact_id = (SELECT id FROM users WHERE account = '{u}')");
-                if where_is.is_empty() {
-                    format!("WHERE {sql_u}")
-                } else {
-                    format!("{where_is} AND {sql_u}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref u) = q.servicer {
-                let sql_u = format!("o.contact_id = (SELECT id FROM users WHERE account = '{u}')");
-                if where_is.is_empty() {
-                    format!("WHERE {sql_u}")
-                } else {
-                    format!("{where_is} AND {sql_u}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref u) = q.maintainer {
-                let sql_u = format!("o.contact_id = (SELECT id FROM users WHERE account = '{u}')");
-                if where_is.is_empty() {
-                    format!("WHERE {sql_u}")
-                } else {
-                    format!("{where_is} AND {sql_u}")
-                }
-            } else {
-                where_is
-            };
-            let mut where_is = if let Some(ref s) = q.status {
-                let sql = format!("o.status_id = (SELECT id FROM status WHERE flow = '{s}')");
-                if where_is.is_empty() {
-                    format!("WHERE {sql}")
-                } else {
-                    format!("{where_is} AND {sql}")
-                }
-            } else {
-                where_is
-            };
-
-            if let Some(ref c) = q.life_cycle {
-                let sql = format!("o.life_cycle = '{c}'");
-                where_is = if where_is.is_empty() {
-                    format!("WHERE {sql}")
-                } else {
-                    format!("{where_is} AND {sql}")
-                };
+    /// add the filters to `filter`, `(offset, entries)` of the page
+    pub fn parse(mine: Option<Query<Self>>, filter: &mut ListFilter) -> (i32, i32) {
+        if let Some(Query(q)) = mine {
+            if let Some(ref search) = q.q {
+                order_search(filter, search);
             }
-
-            let where_is = if let Some(ref s) = q.issue_start {
-                let sql = format!("o.issue_at >= '{s}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql}")
-                } else {
-                    format!("{where_is} AND {sql}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref s) = q.issue_end {
-                let sql = format!("o.issue_at < '{s}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql}")
-                } else {
-                    format!("{where_is} AND {sql}")
-                }
-            } else {
-                where_is
-            };
-
-            (offset, entries, where_is)
+            filter
+                .eq("o.customer_phone", q.phone)
+                .prefix("o.customer_name", q.customer.as_deref())
+                .any_of_lookup(
+                    "o.department_id",
+                    "departments",
+                    "shorten",
+                    list(&q.department),
+                )
+                .any_of_lookup("o.contact_id", "users", "account", list(&q.contact))
+                .any_of_lookup("o.servicer_id", "users", "account", list(&q.servicer))
+                .any_of_lookup("o.maintainer_id", "users", "account", list(&q.maintainer))
+                .any_of_lookup("o.status_id", "status", "flow", list(&q.status))
+                .any_of("o.life_cycle", list(&q.life_cycle))
+                .range("o.issue_at", q.issue_start, q.issue_end);
+
+            (q.offset.unwrap_or(0), q.entries.unwrap_or(100))
         } else {
-            (0, 100, "".to_string())
+            (0, 100)
         }
     }
 }
 
+/// `q` of the order list, full-text and trigram match over `orders.search_text` plus
+/// brand and model, ranked by relevance
+fn order_search(filter: &mut ListFilter, search: &str) {
+    let search = search.trim().to_string();
+    if search.is_empty() {
+        return;
+    }
+    let contains = format!("%{}%", like_escape(&search));
+    /* 0912-345 finds 0912345678, search_text has the phone as digits too */
+    let digits = phone_digits(&search).map_or_else(|| contains.clone(), |d| format!("%{d}%"));
+
+    let q = search.clone();
+    filter.with(|b| {
+        b.push("(o.search_text ILIKE ")
+            .push_bind(contains.clone())
+            .push(" OR o.search_text ILIKE ")
+            .push_bind(digits)
+            .push(" OR m.brand ILIKE ")
+            .push_bind(contains.clone())
+            .push(" OR m.model ILIKE ")
+            .push_bind(contains)
+            .push(" OR to_tsvector('simple', o.search_text) @@ plainto_tsquery('simple', ")
+            .push_bind(q.clone())
+            .push(") OR ")
+            .push_bind(q)
+            .push(" <% o.search_text)");
+    });
+    filter.rank_by(move |b| {
+        b.push("(ts_rank(to_tsvector('simple', o.search_text), plainto_tsquery('simple', ")
+            .push_bind(search.clone())
+            .push(")) + word_similarity(")
+            .push_bind(search.clone())
+            .push(", o.search_text) + CASE WHEN o.sn = ")
+            .push_bind(search.clone())
+            .push(" OR o.customer_phone = ")
+            .push_bind(search)
+            .push(" THEN 1 ELSE 0 END) DESC");
+    });
+}
+
+/// digits of a phone number typed with separators, `0912-345 678`
+fn phone_digits(search: &str) -> Option<String> {
+    let phone_like = search
+        .chars()
+        .all(|c| c.is_ascii_digit() || matches!(c, '-' | ' ' | '+' | '(' | ')'));
+    let digits: String = search.chars().filter(char::is_ascii_digit).collect();
+
+    if phone_like && digits.len() >= 3 && digits.len() != search.len() {
+        Some(digits)
+    } else {
+        None
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, ToSchema, IntoParams, Default)]
 pub struct OrderApiResponse {
     code: u16,
@@ -224,6 +229,16 @@ pub struct OrderRawInfo {
     life_cycle: String,
     servicer_id: Option<i32>,
     maintainer_id: Option<i32>,
+    version: i32,
+}
+
+impl OrderRawInfo {
+    fn scope(&self) -> OrderScope {
+        OrderScope {
+            department_id: self.department_id,
+            maintainer_id: self.maintainer_id,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, ToSchema, sqlx::FromRow)]
@@ -244,8 +259,9 @@ pub struct OrderInfo {
     accessory1: Option<String>,
     accessory2: Option<String>,
     accessory_other: Option<String>,
-    #[schema(example = json!({"storage": [1], "nbits": 8}))]
-    appearance: BitVec,
+    /// names of the appearance flags, see `GET /api/v1/appearance`
+    #[schema(example = json!(["screen_cracked", "housing_dented"]))]
+    appearance: Vec<String>,
     appearance_other: Option<String>,
     service: Option<String>,
     fault1: Option<String>,
@@ -254,8 +270,12 @@ pub struct OrderInfo {
     photo_url: Option<String>,
     remark: Option<String>,
     cost: Option<i32>,
+    /// taken at intake, counted in confirmed_paid
     prepaid_free: Option<i32>,
+    /// net of the payment ledger, see `GET /api/v1/order/{sn}/payments`
     confirmed_paid: Option<i32>,
+    /// cost less confirmed_paid, negative when a refund is owed
+    balance_due: i32,
     warranty_expired: Option<bool>,
     status: String,
     life_cycle: String,
@@ -263,6 +283,21 @@ pub struct OrderInfo {
     maintainer: Option<String>,
 }
 
+impl OrderInfo {
+    pub(crate) fn issue_at(&self) -> DateTime<Utc> {
+        self.issue_at
+    }
+
+    /// names of the appearance flags
+    pub(crate) fn appearance(&self) -> &[String] {
+        &self.appearance
+    }
+
+    pub(crate) fn amounts(&self) -> Amounts {
+        Amounts::new(self.cost, self.prepaid_free, self.confirmed_paid)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, ToSchema, IntoParams, Clone)]
 pub struct OrderUpdate {
     #[schema(example = "department's shorten as ADM, BM, ...")]
@@ -277,8 +312,9 @@ pub struct OrderUpdate {
     accessory1: Option<String>,
     accessory2: Option<String>,
     accessory_other: Option<String>,
-    #[schema(example = json!({"storage": [2], "nbits": 8}))]
-    appearance: Option<BitVec>,
+    /// names (or labels) of the appearance flags, replacing the ones of the order
+    #[schema(example = json!(["screen_cracked"]))]
+    appearance: Option<Vec<String>>,
     appearance_other: Option<String>,
     service: Option<String>,
     fault1: Option<String>,
@@ -287,10 +323,14 @@ pub struct OrderUpdate {
     photo_url: Option<String>,
     remark: Option<String>,
     cost: Option<i32>,
+    /// kept by the payment ledger, only the current value is accepted
     prepaid_free: Option<i32>,
+    /// kept by the payment ledger, only the current value is accepted
     confirmed_paid: Option<i32>,
     warranty_expired: Option<bool>,
     status: Option<String>,
+    /// close (完成) with a balance due, order.close_unpaid required
+    close_unpaid: Option<bool>,
     life_cycle: Option<String>,
     #[schema(example = "user's account")]
     servicer: Option<String>,
@@ -316,8 +356,10 @@ pub struct OrderNew {
     accessory1: Option<String>,
     accessory2: Option<String>,
     accessory_other: Option<String>,
-    #[schema(example = json!({"storage": [1], "nbits": 8}))]
-    appearance: BitVec,
+    /// names (or labels) of the appearance flags, see `GET /api/v1/appearance`
+    #[schema(example = json!(["screen_cracked", "housing_dented"]))]
+    #[serde(default)]
+    appearance: Vec<String>,
     appearance_other: Option<String>,
     service: Option<String>,
     fault1: Option<String>,
@@ -326,8 +368,11 @@ pub struct OrderNew {
     photo_url: Option<String>,
     remark: Option<String>,
     cost: Option<i32>,
+    /// taken at intake, the first entries of the payment ledger with confirmed_paid
     prepaid_free: Option<i32>,
     confirmed_paid: Option<i32>,
+    /// how prepaid_free and confirmed_paid were paid, cash if not given
+    payment_method: Option<Method>,
     warranty_expired: Option<bool>,
     status: String,
     life_cycle: Option<String>,
@@ -378,6 +423,9 @@ pub struct OrderHistory {
     life_cycle: String,
     remark: Option<String>,
     cost: Option<i32>,
+    /// fields of the order changed, `{"field": {"old": .., "new": ..}}`
+    #[schema(value_type = Object, example = json!({"customer_phone": {"old": "0912345678", "new": "0987654321"}}))]
+    changes: Value,
 
     department: Option<String>,
 }
@@ -394,59 +442,39 @@ pub struct OrderHistoryListQuery {
 }
 
 impl OrderHistoryListQuery {
-    pub fn parse(mine: Option<Query<Self>>) -> (i32, i32, String) {
-        if let Some(ref q) = mine {
-            let offset = q.offset.map_or(0, |o| o);
-            let entries = q.entries.map_or(100, |e| e);
-
-            let where_is = if let Some(ref p) = q.issuer {
-                format!("WHERE h.issuer_id = (SELECT id FROM users WHERE account = '{p}')")
-            } else {
-                "".to_string()
-            };
-            let where_is = if let Some(ref d) = q.department {
-                let sql_d =
-                    format!("o.department_id = (SELECT id FROM departments WHERE shorten = '{d}')");
-                if where_is.is_empty() {
-                    format!("WHERE {sql_d}")
-                } else {
-                    format!("{where_is} AND {sql_d}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref s) = q.change_start {
-                let sql = format!("h.change_at >= '{s}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql}")
-                } else {
-                    format!("{where_is} AND {sql}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref s) = q.change_end {
-                let sql = format!("h.change_at < '{s}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql}")
-                } else {
-                    format!("{where_is} AND {sql}")
-                }
-            } else {
-                where_is
-            };
-
-            (offset, entries, where_is)
+    /// add the filters to `filter`, `(offset, entries)` of the page
+    pub fn parse(mine: Option<Query<Self>>, filter: &mut ListFilter) -> (i32, i32) {
+        if let Some(Query(q)) = mine {
+            filter
+                .any_of_lookup("h.issuer_id", "users", "account", list(&q.issuer))
+                .any_of_lookup(
+                    "o.department_id",
+                    "departments",
+                    "shorten",
+                    list(&q.department),
+                )
+                .range("h.change_at", q.change_start, q.change_end);
+
+            (q.offset.unwrap_or(0), q.entries.unwrap_or(100))
         } else {
-            (0, 100, "".to_string())
+            (0, 100)
         }
     }
 }
 
+#[derive(Deserialize, IntoParams)]
+pub struct OrderAsOfQuery {
+    /// also the order as it was then, from the changes after it
+    as_of: Option<DateTime<Utc>>,
+}
+
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct OrderHistoriesResponse {
     code: u16,
     histories: Option<Vec<OrderHistory>>,
+    /// the order `as_of` the time asked, none before it was issued
+    #[schema(value_type = Option<Object>)]
+    order: Option<Value>,
 }
 
 #[utoipa::path(
@@ -456,11 +484,17 @@ pub struct OrderHistoriesResponse {
         ("sn" = String, Path, description = "order serial-number")
     ),
     responses(
-        (status = 200, description = "get detail order information", body = OrderResponse)
-    )
+        (status = 200, description = "get detail order information, `ETag` is its version", body = OrderResponse),
+        (status = 403, description = "permission deny, order of another department", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
 )]
 pub(crate) async fn order_request(
-    Extension(_auth_state): Extension<AuthState>,
+    Extension(mut auth_state): Extension<AuthState>,
     Extension(database): Extension<Database>,
     Path(sn): Path<String>,
 ) -> impl IntoResponse {
@@ -469,14 +503,165 @@ pub(crate) async fn order_request(
         order: None,
     };
 
+    let current = match auth_state.get_user().await {
+        Some(current) => current,
+        None => return (StatusCode::OK, Json(resp)).into_response(),
+    };
+    let orig = match query_raw_order(&database, &sn).await {
+        Some(orig) => orig,
+        None => return (StatusCode::OK, Json(resp)).into_response(),
+    };
+    if !policy::order_allowed(current, OrderAction::Read, orig.scope()) {
+        return rbac::forbidden(format!("permission deny, order/{sn}"));
+    }
+
     if let Some(o) = query_order(&database, &sn).await {
         resp.code = 200;
         resp.order = Some(o);
     }
 
+    etag::with_etag(orig.version, (StatusCode::OK, Json(resp)))
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct OrderTransition {
+    #[schema(example = "quoted")]
+    code: String,
+    #[schema(example = "報價")]
+    status: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct OrderTransitionsResponse {
+    code: u16,
+    status: Option<String>,
+    transitions: Option<Vec<OrderTransition>>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/order/{sn}/transitions",
+    params(
+        ("sn" = String, Path, description = "order serial-number")
+    ),
+    responses(
+        (status = 200, description = "statuses the order may move to next, by the current user", body = OrderTransitionsResponse),
+        (status = 404, description = "order not found, ", body = OrderTransitionsResponse),
+        (status = 403, description = "permission deny, order of another department", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn order_transitions_request(
+    Extension(mut auth_state): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    Path(sn): Path<String>,
+) -> Response {
+    let mut resp = OrderTransitionsResponse {
+        code: 400,
+        status: None,
+        transitions: None,
+    };
+
+    let current = match auth_state.get_user().await {
+        Some(current) => current,
+        None => return (StatusCode::OK, Json(resp)).into_response(),
+    };
+    let orig = match query_raw_order(&database, &sn).await {
+        Some(orig) => orig,
+        None => {
+            resp.code = 404;
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+    };
+    if !policy::order_allowed(current, OrderAction::Read, orig.scope()) {
+        return rbac::forbidden(format!("permission deny, order/{sn}"));
+    }
+
+    let workflow = match Workflow::load(&database).await {
+        Ok(workflow) => workflow,
+        Err(e) => {
+            error!("{e}");
+            resp.code = 500;
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+    };
+
+    /* nothing is next unless the order may be updated at all */
+    let next = if policy::order_allowed(current, OrderAction::Update, orig.scope()) {
+        workflow.next(orig.status_id, current)
+    } else {
+        vec![]
+    };
+
+    resp.code = 200;
+    resp.status = orig
+        .status_id
+        .and_then(|id| workflow.status(id))
+        .map(|s| s.flow.clone());
+    resp.transitions = Some(
+        next.into_iter()
+            .map(|s| OrderTransition {
+                code: s.code.clone(),
+                status: s.flow.clone(),
+            })
+            .collect(),
+    );
+
     (StatusCode::OK, Json(resp)).into_response()
 }
 
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct OrderSnValidateResponse {
+    code: u16,
+    sn: String,
+    /// the check digit matches, numbers from before check digits mostly don't
+    valid: bool,
+    /// an order of this number exists and is in reach of the user
+    exists: bool,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/order/sn/validate/{sn}",
+    params(
+        ("sn" = String, Path, description = "order serial-number, as typed")
+    ),
+    responses(
+        (status = 200, description = "check digit and existence of the serial-number", body = OrderSnValidateResponse),
+        (status = 403, description = "permission deny, order.read required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn order_sn_validate(
+    current: Require<OrderRead>,
+    Extension(database): Extension<Database>,
+    Path(sn): Path<String>,
+) -> impl IntoResponse {
+    let sn = sn.trim().to_string();
+    let valid = order_sn::is_valid(&sn);
+    /* orders of other departments don't exist to the user */
+    let exists = matches!(
+        query_order_scope(&database, &sn).await,
+        Some((_, scope)) if policy::order_allowed(&current, OrderAction::Read, scope)
+    );
+
+    let resp = OrderSnValidateResponse {
+        code: 200,
+        sn,
+        valid,
+        exists,
+    };
+    (StatusCode::OK, Json(resp))
+}
+
 #[utoipa::path(
     put,
     path = "/api/v1/order/{sn}",
@@ -485,24 +670,31 @@ pub(crate) async fn order_request(
     ),
     request_body = OrderUpdate,
     responses(
-        (status = 200, description = "update success", body = OrderApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 200, description = "update success, `ETag` is the new version", body = OrderApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 409, description = "updated by somebody else meanwhile, `current` is the order now; or closing (完成) with a balance due", body = ApiResponse, example = json!(ApiResponse::new(409, Some(String::from("..."))))),
+        (status = 412, description = "`If-Match` isn't the current version, `current` is the order now", body = ApiResponse, example = json!(ApiResponse::new(412, Some(String::from("..."))))),
+        (status = 400, description = "unknown status or transition not allowed, or paid amounts changed (see payments)", body = OrderApiResponse, example = json!(ApiResponse::new(400, Some(String::from("status 收件 -> 完成 not allowed, next: 報價, 退件"))))),
         (status = 404, description = "order not found, ", body = OrderApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
-        (status = 405, description = "permission deny, ", body = OrderApiResponse, example = json!(ApiResponse::new(405, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, order out of reach or status transition (order.lock, order.reopen, order.close_unpaid)", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
         (status = 500, description = "server error, ", body = OrderApiResponse, example = json!(ApiResponse::new(500, Some(String::from("..."))))),
     ),
     security(
         //(), // <-- make optional authentication
-        ("logined cookie/session-id" = [])
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
     ),
 )]
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn order_update(
     Extension(mut current_user): Extension<AuthState>,
     Extension(gsheets): Extension<SharedDcareGoogleSheet>,
     Path(sn): Path<String>,
     Extension(database): Extension<Database>,
     State(state): State<SharedState>,
+    audit: Audit,
+    if_match: IfMatch,
     Json(order): Json<OrderUpdate>,
-) -> Json<OrderApiResponse> {
+) -> Response {
     let mut resp = OrderApiResponse::new(400, None);
     let order_dup = order.clone();
 
@@ -510,7 +702,7 @@ pub(crate) async fn order_update(
         user
     } else {
         resp.update(400, Some(format!("{}", &NotLoggedIn)), None, None);
-        return Json(resp);
+        return Json(resp).into_response();
     };
 
     let orig = match query_raw_order(&database, &sn).await {
@@ -518,101 +710,169 @@ pub(crate) async fn order_update(
         None => {
             resp.update(404, Some(format!("order/{sn} not found")), None, None);
             error!("{:?}", &resp);
-            return Json(resp);
+            return Json(resp).into_response();
         }
     };
 
+    if !policy::order_allowed(issuer, OrderAction::Update, orig.scope()) {
+        return rbac::forbidden(format!("permission deny, order/{sn}"));
+    }
+    if !if_match.matches(orig.version) {
+        return etag::precondition_failed(query_order(&database, &sn).await, orig.version);
+    }
+
+    /* money moves through the payment ledger, the amounts of the order follow it */
+    let rewritten = matches!(order.prepaid_free, Some(paid) if Some(paid) != orig.prepaid_free)
+        || matches!(order.confirmed_paid, Some(paid) if Some(paid) != orig.confirmed_paid);
+    if rewritten {
+        resp.update(
+            400,
+            Some(format!(
+                "amounts paid are recorded in /api/v1/order/{sn}/payments"
+            )),
+            None,
+            None,
+        );
+        return Json(resp).into_response();
+    }
+
     let department_id = match order.department {
         Some(department) => match department_shorten_query(&database, &department).await {
             Ok(id) => Some(id),
             Err(e) => {
                 resp.update(404, Some(format!("{e}")), None, None);
                 error!("{:?}", &resp);
-                return Json(resp);
+                return Json(resp).into_response();
             }
         },
         None => orig.department_id,
     };
 
-    let bm = model_map_by_id(&database, orig.model_id).await;
-    let (orig_brand, orig_model) = bm.as_ref().map_or(("unknown", "unknown"), |(b, m)| (b, m));
-    let brand = order.brand.as_ref().map_or(orig_brand, |b| b);
-    let model = order.model.as_ref().map_or(orig_model, |m| m);
-    let model_id = match model_id_or_insert(&database, brand, model, None).await {
-        Ok(id) => id,
+    /* catalog rows, the order and its history commit together, returning early rolls back */
+    let mut tx = match database.begin().await {
+        Ok(tx) => tx,
         Err(e) => {
-            resp.update(500, Some(format!("{e}")), None, None);
+            resp.update(500, Some(format!("DB error - {e}")), None, None);
             error!("{:?}", &resp);
-            return Json(resp);
+            return Json(resp).into_response();
+        }
+    };
+    /* the model is only looked up (or added) when the update names a brand or model */
+    let model_id = if order.brand.is_some() || order.model.is_some() {
+        let bm = model_map_by_id(&database, orig.model_id).await;
+        let (orig_brand, orig_model) = bm.as_ref().map_or(("unknown", "unknown"), |(b, m)| (b, m));
+        let brand = order.brand.as_ref().map_or(orig_brand, |b| b);
+        let model = order.model.as_ref().map_or(orig_model, |m| m);
+
+        match model_id_or_insert(&mut tx, brand, model, None).await {
+            Ok(id) => Some(id),
+            Err(e) => {
+                resp.update(500, Some(format!("{e}")), None, None);
+                error!("{:?}", &resp);
+                return Json(resp).into_response();
+            }
         }
+    } else {
+        orig.model_id
     };
 
     let accessory_id1 = match order.accessory1 {
-        Some(ref item) => match accessory_id_or_insert(&database, item, 0).await {
+        Some(ref item) => match accessory_id_or_insert(&mut tx, item, 0).await {
             Ok(id) => Some(id),
             Err(e) => {
                 resp.update(500, Some(format!("{e}")), None, None);
                 error!("{:?}", &resp);
-                return Json(resp);
+                return Json(resp).into_response();
             }
         },
         None => orig.accessory_id1,
     };
 
     let accessory_id2 = match order.accessory2 {
-        Some(ref item) => match accessory_id_or_insert(&database, item, 0).await {
+        Some(ref item) => match accessory_id_or_insert(&mut tx, item, 0).await {
             Ok(id) => Some(id),
             Err(e) => {
                 resp.update(500, Some(format!("{e}")), None, None);
                 error!("{:?}", &resp);
-                return Json(resp);
+                return Json(resp).into_response();
             }
         },
         None => orig.accessory_id2,
     };
 
-    let appearance = order.appearance.or(Some(orig.appearance));
+    let catalog = match Catalog::load(&database).await {
+        Ok(catalog) => catalog,
+        Err(e) => {
+            resp.update(500, Some(format!("{e}")), None, None);
+            error!("{:?}", &resp);
+            return Json(resp).into_response();
+        }
+    };
+    let appearance = match order.appearance {
+        Some(ref names) => match catalog.encode(names) {
+            Ok(bits) => bits,
+            Err(e) => {
+                resp.update(400, Some(format!("{e}")), None, None);
+                error!("{:?}", &resp);
+                return Json(resp).into_response();
+            }
+        },
+        None => orig.appearance.clone(),
+    };
 
     let appearance_other = if order.appearance_other.is_some() {
         order.appearance_other
     } else {
-        orig.appearance_other
+        orig.appearance_other.clone()
     };
 
     let fault_id1 = match order.fault1 {
-        Some(ref item) => match fault_id_or_insert(&database, item, 0).await {
+        Some(ref item) => match fault_id_or_insert(&mut tx, item, 0).await {
             Ok(id) => Some(id),
             Err(e) => {
                 resp.update(500, Some(format!("{e}")), None, None);
                 error!("{:?}", &resp);
-                return Json(resp);
+                return Json(resp).into_response();
             }
         },
         None => orig.fault_id1,
     };
 
     let fault_id2 = match order.fault2 {
-        Some(ref item) => match fault_id_or_insert(&database, item, 0).await {
+        Some(ref item) => match fault_id_or_insert(&mut tx, item, 0).await {
             Ok(id) => Some(id),
             Err(e) => {
                 resp.update(500, Some(format!("{e}")), None, None);
                 error!("{:?}", &resp);
-                return Json(resp);
+                return Json(resp).into_response();
             }
         },
         None => orig.fault_id2,
     };
 
-    let status_id = match order.status {
-        Some(ref status) => match status_id_or_insert(&database, status).await {
-            Ok(id) => Some(id),
-            Err(e) => {
-                resp.update(500, Some(format!("{e}")), None, None);
-                error!("{:?}", &resp);
-                return Json(resp);
+    let (status_id, closing) = match order.status {
+        Some(ref status) => {
+            let workflow = match Workflow::load(&database).await {
+                Ok(workflow) => workflow,
+                Err(e) => {
+                    resp.update(500, Some(format!("{e}")), None, None);
+                    error!("{:?}", &resp);
+                    return Json(resp).into_response();
+                }
+            };
+            match workflow.check(orig.status_id, status, issuer) {
+                Ok(id) => (Some(id), Some(id) != orig.status_id && workflow.closes(id)),
+                Err(e @ TransitionError::PermissionDeny { .. }) => {
+                    return rbac::forbidden(format!("{e}"));
+                }
+                Err(e) => {
+                    resp.update(400, Some(format!("{e}")), None, None);
+                    error!("{:?}", &resp);
+                    return Json(resp).into_response();
+                }
             }
-        },
-        None => orig.status_id,
+        }
+        None => (orig.status_id, false),
     };
 
     let life_cycle = order.life_cycle.as_ref().map_or(&orig.life_cycle, |lc| lc);
@@ -627,7 +887,7 @@ pub(crate) async fn order_update(
                     None,
                     None,
                 );
-                return Json(resp);
+                return Json(resp).into_response();
             }
         }
     } else {
@@ -644,35 +904,71 @@ pub(crate) async fn order_update(
                     None,
                     None,
                 );
-                return Json(resp);
+                return Json(resp).into_response();
             }
         }
     } else {
         orig.maintainer_id
     };
 
-    let customer_address = order.customer_address.or(orig.customer_address);
-    let customer_name = order.customer_name.or(orig.customer_name);
-    let customer_phone = order.customer_phone.map_or(orig.customer_phone, |p| p);
+    /* no handing the order over to where the issuer can't reach it */
+    let updated = OrderScope {
+        department_id,
+        maintainer_id,
+    };
+    if !policy::order_allowed(issuer, OrderAction::Update, updated) {
+        return rbac::forbidden(format!("permission deny, order/{sn} can't be moved there"));
+    }
+
+    let customer_address = order
+        .customer_address
+        .or_else(|| orig.customer_address.clone());
+    let customer_name = order.customer_name.or_else(|| orig.customer_name.clone());
+    let customer_phone = order
+        .customer_phone
+        .unwrap_or_else(|| orig.customer_phone.clone());
 
-    let accessory_other = order.accessory_other.or(orig.accessory_other);
-    let service = order.service.or(orig.service);
-    let fault_other = order.fault_other.or(orig.fault_other);
-    let photo_url = order.photo_url.or(orig.photo_url);
-    let remark = order.remark.or(orig.remark);
+    let accessory_other = order
+        .accessory_other
+        .or_else(|| orig.accessory_other.clone());
+    let service = order.service.or_else(|| orig.service.clone());
+    let fault_other = order.fault_other.or_else(|| orig.fault_other.clone());
+    let photo_url = order.photo_url.or_else(|| orig.photo_url.clone());
+    let remark = order.remark.or_else(|| orig.remark.clone());
     let cost = order.cost.or(orig.cost);
-    let prepaid_free = order.prepaid_free.or(orig.prepaid_free);
-    let confirmed_paid = order.confirmed_paid.or(orig.confirmed_paid);
+    let prepaid_free = orig.prepaid_free;
+    let confirmed_paid = orig.confirmed_paid;
     let warranty_expired = order.warranty_expired.or(orig.warranty_expired);
 
+    /* closed orders are settled, unless a GM closes one unpaid */
+    let balance_due = payment::balance_due(cost, confirmed_paid);
+    let unpaid = closing && balance_due != 0;
+    if unpaid && order.close_unpaid != Some(true) {
+        resp.update(
+            409,
+            Some(format!(
+                "order/{sn} has {balance_due} due, settle it or close it unpaid"
+            )),
+            None,
+            None,
+        );
+        return Json(resp).into_response();
+    }
+    if unpaid && !issuer.has_permission(OrderCloseUnpaid::NAME) {
+        return rbac::forbidden(format!(
+            "permission deny, {} required to close order/{sn} unpaid",
+            OrderCloseUnpaid::NAME
+        ));
+    }
+
     const UPDATE_QUERY: &str = r#"
         WITH order_updated AS (
             UPDATE orders SET 
                 department_id = $1,
                 customer_address = $2,
-                customer_name = $21,
-                customer_phone = $22,
-                model_id = $23,
+                customer_name = $20,
+                customer_phone = $21,
+                model_id = $22,
                 accessory_id1 = $3,
                 accessory_id2 = $4,
                 accessory_other = $5,
@@ -686,30 +982,17 @@ pub(crate) async fn order_update(
                 remark = $13,
                 cost = $14,
                 prepaid_free = $15,
-                confirmed_paid = $25,
-                warranty_expired = $26,
+                confirmed_paid = $24,
+                warranty_expired = $25,
                 status_id = $16,
-                life_cycle = $24,
+                life_cycle = $23,
                 servicer_id = $17,
-                maintainer_id = $18
-            WHERE sn = $19 RETURNING id
-        )
-        INSERT INTO order_histories (
-            order_id,
-            issuer_id,
-            status_id,
-            life_cycle,
-            remark,
-            cost
-        ) VALUES (
-            (SELECT id FROM order_updated),
-            $20,
-            $16,
-            $24,
-            $13,
-            $14
-        ) RETURNING id;"#;
-    let fetch_one: Result<(i32,), _> = sqlx::query_as(UPDATE_QUERY)
+                maintainer_id = $18,
+                version = version + 1,
+                update_at = NOW()
+            WHERE sn = $19 AND version = $26
+            RETURNING *;"#;
+    let fetch_one = sqlx::query_as::<_, OrderRawInfo>(UPDATE_QUERY)
         .bind(department_id)
         .bind(customer_address)
         .bind(accessory_id1)
@@ -729,35 +1012,106 @@ pub(crate) async fn order_update(
         .bind(servicer_id)
         .bind(maintainer_id)
         .bind(&sn)
-        .bind(issuer.id)
         .bind(&customer_name)
         .bind(&customer_phone)
         .bind(model_id)
         .bind(life_cycle)
         .bind(confirmed_paid)
         .bind(warranty_expired)
-        .fetch_one(&database)
+        .bind(orig.version)
+        .fetch_optional(&mut tx)
         .await;
 
-    match fetch_one {
-        Ok((id,)) => {
-            if let Ok(sheet_pos) = OrderGoogleSheetSql::from_query(&database, orig.id).await {
-                let _ = gsheets_order_update(gsheets.clone(), state, sheet_pos, &order_dup).await;
-            }
-
-            resp.update(
-                200,
-                Some(format!("order update success - history{id}")),
-                Some(sn),
-                Some(customer_phone),
-            );
+    let after = match fetch_one {
+        Ok(Some(after)) => after,
+        Ok(None) => {
+            /* the merge above is of a stale snapshot */
+            drop(tx);
+            let current = query_raw_order(&database, &sn).await.map(|o| o.version);
+            return etag::conflict(query_order(&database, &sn).await, current);
+        }
+        Err(e) => {
+            resp.update(500, Some(format!("{e}")), None, None);
+            error!("{:?}", &resp);
+            return Json(resp).into_response();
         }
+    };
+
+    const HISTORY_QUERY: &str = r#"
+        INSERT INTO order_histories (
+            order_id,
+            issuer_id,
+            status_id,
+            life_cycle,
+            remark,
+            cost,
+            changes
+        ) VALUES (
+            $1,
+            $2,
+            $3,
+            $4,
+            $5,
+            $6,
+            $7
+        ) RETURNING id;
+    "#;
+    let fetch_one: Result<(i32,), _> = sqlx::query_as(HISTORY_QUERY)
+        .bind(after.id)
+        .bind(issuer.id)
+        .bind(after.status_id)
+        .bind(&after.life_cycle)
+        .bind(&after.remark)
+        .bind(after.cost)
+        .bind(order_changes::diff(
+            &order_snapshot(&orig, &catalog),
+            &order_snapshot(&after, &catalog),
+        ))
+        .fetch_one(&mut tx)
+        .await;
+    let history_id = match fetch_one {
+        Ok((id,)) => id,
         Err(e) => {
             resp.update(500, Some(format!("{e}")), None, None);
             error!("{:?}", &resp);
+            return Json(resp).into_response();
         }
+    };
+    let event = AuditEvent::new("order.update", "order", &sn)
+        .by(issuer)
+        .before(&order_snapshot(&orig, &catalog))
+        .after(&order_snapshot(&after, &catalog));
+    if let Err(e) = audit.record(&mut tx, event).await {
+        resp.update(500, Some(format!("{e}")), None, None);
+        return Json(resp).into_response();
+    }
+    if unpaid {
+        let event = AuditEvent::new("order.close_unpaid", "order", &sn)
+            .by(issuer)
+            .after(&json!({ "balance_due": balance_due }));
+        if let Err(e) = audit.record(&mut tx, event).await {
+            resp.update(500, Some(format!("{e}")), None, None);
+            return Json(resp).into_response();
+        }
+    }
+    if let Err(e) = tx.commit().await {
+        resp.update(500, Some(format!("DB error - {e}")), None, None);
+        error!("{:?}", &resp);
+        return Json(resp).into_response();
     }
-    Json(resp)
+
+    /* the sheet is a copy outside of the transaction, it follows once the DB committed */
+    if let Ok(sheet_pos) = OrderGoogleSheetSql::from_query(&database, orig.id).await {
+        let _ = gsheets_order_update(gsheets.clone(), state, sheet_pos, &order_dup).await;
+    }
+
+    resp.update(
+        200,
+        Some(format!("order update success - history{history_id}")),
+        Some(sn),
+        Some(customer_phone),
+    );
+    etag::with_etag(after.version, Json(resp))
 }
 
 #[utoipa::path(
@@ -767,30 +1121,35 @@ pub(crate) async fn order_update(
         ("sn" = String, Path, description = "order(serial-number) to delete")
     ),
     responses(
-        (status = 200, description = "delete success", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 200, description = "delete success, the order and its histories go to the trash, trash.manage restores it", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
         (status = 404, description = "order not found, ", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
-        (status = 405, description = "permission deny", body = ApiResponse, example = json!(ApiResponse::new(405, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, order.delete within reach required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 409, description = "updated by somebody else meanwhile, `current` is the order now", body = ApiResponse, example = json!(ApiResponse::new(409, Some(String::from("..."))))),
+        (status = 412, description = "`If-Match` isn't the current version, `current` is the order now", body = ApiResponse, example = json!(ApiResponse::new(412, Some(String::from("..."))))),
     ),
     security(
         //(), // <-- make optional authentication
-        ("logined cookie/session-id" = [])
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
     ),
 )]
 pub(crate) async fn order_delete(
     Extension(mut current_user): Extension<AuthState>,
     Extension(database): Extension<Database>,
+    audit: Audit,
     Path(sn): Path<String>,
+    if_match: IfMatch,
 ) -> impl IntoResponse {
     let mut resp = ApiResponse::new(400, None);
 
-    let _issuer = if let Some(user) = current_user.get_user().await {
+    let issuer = if let Some(user) = current_user.get_user().await {
         user
     } else {
         resp.update(400, Some(format!("{}", &NotLoggedIn)));
         return (StatusCode::OK, Json(resp)).into_response();
     };
 
-    let _orig = match query_raw_order(&database, &sn).await {
+    let orig = match query_raw_order(&database, &sn).await {
         Some(orig) => orig,
         None => {
             resp.update(404, Some(format!("order/{sn} not found")));
@@ -799,24 +1158,116 @@ pub(crate) async fn order_delete(
         }
     };
 
-    const QUERY: &str = r#"
-        WITH order_hist_deleted AS (
-            DELETE FROM order_histories
-                WHERE order_id = ( SELECT id FROM orders WHERE sn = $1)
-            RETURNING id
-        )
-        DELETE from orders WHERE sn = $1
-        RETURNING id;"#;
+    if !policy::order_allowed(issuer, OrderAction::Delete, orig.scope()) {
+        return rbac::forbidden(format!("permission deny, order/{sn}"));
+    }
+    if !if_match.matches(orig.version) {
+        return etag::precondition_failed(query_order(&database, &sn).await, orig.version);
+    }
+    let catalog = match Catalog::load(&database).await {
+        Ok(catalog) => catalog,
+        Err(e) => {
+            resp.update(500, Some(format!("{e}")));
+            error!("{:?}", &resp);
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+    };
+
+    /* the delete and its audit event commit together */
+    let mut tx = match database.begin().await {
+        Ok(tx) => tx,
+        Err(e) => {
+            resp.update(500, Some(format!("DB error - {e}")));
+            error!("{:?}", &resp);
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+    };
+    match trash::delete(&mut tx, Trash::Order, &sn, orig.version, Some(issuer.id)).await {
+        Ok(true) => {
+            let event = AuditEvent::new("order.delete", "order", &sn)
+                .by(issuer)
+                .before(&order_snapshot(&orig, &catalog));
+            if let Err(e) = audit.commit(tx, event).await {
+                resp.update(500, Some(format!("{e}")));
+                return (StatusCode::OK, Json(resp)).into_response();
+            }
+            resp.update(200, Some("delete success".to_string()));
+        }
+        Ok(false) => {
+            let current = query_raw_order(&database, &sn).await.map(|o| o.version);
+            return etag::conflict(query_order(&database, &sn).await, current);
+        }
+        Err(e) => {
+            resp.update(500, Some(format!("{e}")));
+            error!("{:?}", &resp);
+        }
+    }
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/order/{sn}/restore",
+    params(
+        ("sn" = String, Path, description = "deleted order(serial-number) to restore")
+    ),
+    responses(
+        (status = 200, description = "restore success, `ETag` is the new version", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("restore success"))))),
+        (status = 404, description = "no deleted order of the serial-number", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, trash.manage required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn order_restore(
+    current: Require<TrashManage>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+    Path(sn): Path<String>,
+) -> impl IntoResponse {
+    trash::restore(&database, &audit, &current, Trash::Order, &sn).await
+}
 
-    if sqlx::query_as::<_, OrderI32Res>(QUERY)
-        .bind(sn)
-        .fetch_all(&database)
+#[utoipa::path(
+    delete,
+    path = "/api/v1/order/{sn}/purge",
+    params(
+        ("sn" = String, Path, description = "deleted order(serial-number) to remove for good")
+    ),
+    responses(
+        (status = 200, description = "purge success, histories and attachments of the order go with it", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("purge success"))))),
+        (status = 404, description = "no deleted order of the serial-number, delete it first", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, trash.manage required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn order_purge(
+    current: Require<TrashManage>,
+    Extension(database): Extension<Database>,
+    Extension(blobs): Extension<SharedBlobStore>,
+    audit: Audit,
+    Path(sn): Path<String>,
+) -> impl IntoResponse {
+    /* the rows of the attachments cascade, their blobs don't */
+    let keys = deleted_order_blob_keys(&database, &sn).await;
+    let response = trash::purge(&database, &audit, &current, Trash::Order, &sn).await;
+
+    const QUERY: &str = "SELECT id FROM orders WHERE sn = $1;";
+    if let Ok(None) = sqlx::query_as::<_, OrderI32Res>(QUERY)
+        .bind(&sn)
+        .fetch_optional(&database)
         .await
-        .is_ok()
     {
-        resp.update(200, Some("delete success".to_string()));
+        delete_blobs(&blobs, &keys).await;
     }
-    (StatusCode::OK, Json(resp)).into_response()
+    response
 }
 
 #[utoipa::path(
@@ -826,10 +1277,17 @@ pub(crate) async fn order_delete(
         OrderListQuery,
     ),
     responses(
-        (status = 200, description = "get order list", body = OrdersResponse)
-    )
+        (status = 200, description = "get order list, only the orders within reach of the user", body = OrdersResponse),
+        (status = 403, description = "permission deny, order.read required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
 )]
 pub(crate) async fn order_list_request(
+    Extension(mut auth_state): Extension<AuthState>,
     Extension(database): Extension<Database>,
     query: Option<Query<OrderListQuery>>,
 ) -> impl IntoResponse {
@@ -838,11 +1296,15 @@ pub(crate) async fn order_list_request(
         orders: None,
     };
 
-    let (offset, entries, where_dep) = OrderListQuery::parse(query);
-    //info!("[debug] where_dep = {where_dep}");
+    let reach = match auth_state.get_user().await {
+        Some(current) => match policy::order_read_filter(current) {
+            Some(reach) => reach,
+            None => return rbac::forbidden(String::from("permission deny, order.read required")),
+        },
+        None => return (StatusCode::OK, Json(resp)).into_response(),
+    };
 
-    let query = format!(
-        r#"
+    const SELECT: &str = r#"
         SELECT
             o.sn,
             o.issue_at,
@@ -862,13 +1324,17 @@ pub(crate) async fn order_list_request(
             LEFT JOIN users u1 ON u1.id = o.contact_id
             LEFT JOIN users u2 ON u2.id = o.servicer_id
             LEFT JOIN users u3 ON u3.id = o.maintainer_id
-        {where_dep}
-        ORDER BY issue_at
-        LIMIT {entries} OFFSET {offset};
-    "#
-    );
+            LEFT JOIN models m ON m.id = o.model_id
+    "#;
 
-    if let Ok(orders) = sqlx::query_as::<_, OrderSummary>(&query)
+    let mut filter = ListFilter::new(SELECT);
+    let (offset, entries) = OrderListQuery::parse(query, &mut filter);
+    filter.trusted(&reach);
+    filter.trusted("o.deleted_at IS NULL");
+    let mut query = filter.finish("issue_at", entries, offset);
+
+    if let Ok(orders) = query
+        .build_query_as::<OrderSummary>()
         .fetch_all(&database)
         .await
     {
@@ -907,6 +1373,7 @@ async fn gsheets_order_append(
     gsheets: SharedDcareGoogleSheet,
     state: SharedState,
     order: &OrderNew,
+    catalog: &Catalog,
     issue_at: DateTime<Utc>,
     sn: &str,
 ) -> GooglesheetPosition {
@@ -960,8 +1427,9 @@ async fn gsheets_order_append(
         .as_ref()
         .unwrap_or(&String::from(""))
         .to_string();
-    let appearance = format!("{:?}", order.appearance); /* TODO sync with APP */
-    /* TODO order.appearance_other */
+    let mut appearance = catalog.labels(&order.appearance);
+    appearance.extend(order.appearance_other.clone());
+    let appearance = appearance.join("、");
     let service = order
         .service
         .as_ref()
@@ -998,10 +1466,10 @@ async fn gsheets_order_append(
     } else {
         "".to_string()
     };
-    let confirmed_paid = if let Some(ref cost) = order.confirmed_paid {
-        format!("{cost}")
-    } else {
-        "".to_string()
+    /* net paid as in the ledger, the prepaid amount included */
+    let confirmed_paid = match (order.prepaid_free, order.confirmed_paid) {
+        (None, None) => "".to_string(),
+        (prepaid, paid) => format!("{}", prepaid.unwrap_or(0) + paid.unwrap_or(0)),
     };
     let life_cycle = order
         .life_cycle /* TODO sync with APP */
@@ -1085,6 +1553,48 @@ async fn gsheets_order_update(
     Ok(())
 }
 
+/// column W of the order, the net of its payment ledger
+pub(crate) async fn gsheets_paid_update(
+    gsheets: SharedDcareGoogleSheet,
+    database: &Database,
+    order_id: i32,
+    paid: i32,
+) {
+    let mut pos = match OrderGoogleSheetSql::from_query(database, order_id).await {
+        Ok(pos) => pos,
+        Err(_) => return,
+    };
+    pos.column = "W".to_string();
+    if let Err(e) = gsheets.modify(vec![format!("{paid}")], &pos).await {
+        error!("modify confirmed-paid in {:?} fail - {e}", pos);
+    } else {
+        debug!("modify confirmed-paid in {:?} success", pos);
+    }
+}
+
+/// columns U and Y of the order, the cost and status a quote moved it to
+pub(crate) async fn gsheets_status_update(
+    gsheets: SharedDcareGoogleSheet,
+    database: &Database,
+    order_id: i32,
+    cost: Option<i32>,
+    status: &str,
+) {
+    let mut pos = match OrderGoogleSheetSql::from_query(database, order_id).await {
+        Ok(pos) => pos,
+        Err(_) => return,
+    };
+    let cost = cost.map(|cost| format!("{cost}")).unwrap_or_default();
+    for (column, value) in [("U", cost), ("Y", status.to_string())] {
+        pos.column = column.to_string();
+        if let Err(e) = gsheets.modify(vec![value], &pos).await {
+            error!("modify cost/status in {:?} fail - {e}", pos);
+        } else {
+            debug!("modify cost/status in {:?} success", pos);
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 struct OrderNewRes {
     id: i32,
@@ -1097,25 +1607,35 @@ struct OrderNewRes {
     request_body = OrderNew,
     responses(
         (status = 200, description = "add order success", body = OrderApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
-        (status = 400, description = "order exist, ", body = OrderApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+        (status = 400, description = "order exist or status not initial, ", body = OrderApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, order.create for the department required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 409, description = "the serial-number is taken, by a deleted order maybe (restore or purge it first)", body = ApiResponse, example = json!(ApiResponse::new(409, Some(String::from("..."))))),
         (status = 500, description = "server DB error, ", body = OrderApiResponse, example = json!(ApiResponse::new(500, Some(String::from("..."))))),
     ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
 )]
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn order_create(
     Extension(database): Extension<Database>,
     State(state): State<SharedState>,
     Extension(_random): Extension<Random>,
     Extension(gsheets): Extension<SharedDcareGoogleSheet>,
     Extension(mut current_user): Extension<AuthState>,
+    Extension(sn_format): Extension<SnFormat>,
+    audit: Audit,
     Json(order): Json<OrderNew>,
-) -> Json<OrderApiResponse> {
+) -> Response {
     let mut resp = OrderApiResponse::new(400, None);
 
     let issuer = if let Some(user) = current_user.get_user().await {
         user
     } else {
         resp.update(400, Some(format!("{}", &NotLoggedIn)), None, None);
-        return Json(resp);
+        return Json(resp).into_response();
     };
 
     let contact_id = if let Some(ref contact) = order.contact {
@@ -1123,7 +1643,7 @@ pub(crate) async fn order_create(
             Some(id) => Some(id),
             None => {
                 resp.update(400, Some("contact staff not found".to_string()), None, None);
-                return Json(resp);
+                return Json(resp).into_response();
             }
         }
     } else {
@@ -1131,82 +1651,133 @@ pub(crate) async fn order_create(
     };
 
     let department_id = match department_shorten_query(&database, &order.department).await {
-        Ok(id) => Some(id),
+        Ok(id) => id,
         Err(e) => {
             resp.update(404, Some(format!("{e}")), None, None);
             error!("{:?}", &resp);
-            return Json(resp);
+            return Json(resp).into_response();
+        }
+    };
+
+    if !policy::order_create_allowed(issuer, Some(department_id)) {
+        return rbac::forbidden(format!(
+            "permission deny, orders of {} can't be created",
+            &order.department
+        ));
+    }
+
+    let catalog = match Catalog::load(&database).await {
+        Ok(catalog) => catalog,
+        Err(e) => {
+            resp.update(500, Some(format!("{e}")), None, None);
+            error!("{:?}", &resp);
+            return Json(resp).into_response();
+        }
+    };
+    let appearance = match catalog.encode(&order.appearance) {
+        Ok(bits) => bits,
+        Err(e) => {
+            resp.update(400, Some(format!("{e}")), None, None);
+            error!("{:?}", &resp);
+            return Json(resp).into_response();
         }
     };
 
+    if order.prepaid_free.unwrap_or(0) < 0 || order.confirmed_paid.unwrap_or(0) < 0 {
+        resp.update(
+            400,
+            Some("amounts paid can't be negative".to_string()),
+            None,
+            None,
+        );
+        return Json(resp).into_response();
+    }
+
     let brand = &order.brand;
     let model = match order.model {
         Some(ref m) => m,
         None => "unknown",
     };
-    let model_id = match model_id_or_insert(&database, brand, model, None).await {
+
+    /* catalog rows, the serial number, the order and its history commit together */
+    let mut tx = match database.begin().await {
+        Ok(tx) => tx,
+        Err(e) => {
+            resp.update(500, Some(format!("DB error - {e}")), None, None);
+            error!("{:?}", &resp);
+            return Json(resp).into_response();
+        }
+    };
+    let model_id = match model_id_or_insert(&mut tx, brand, model, None).await {
         Ok(id) => id,
         Err(e) => {
             resp.update(500, Some(format!("{e}")), None, None);
             error!("{:?}", &resp);
-            return Json(resp);
+            return Json(resp).into_response();
         }
     };
 
     let accessory_id1 = match order.accessory1 {
-        Some(ref item) => match accessory_id_or_insert(&database, item, 0).await {
+        Some(ref item) => match accessory_id_or_insert(&mut tx, item, 0).await {
             Ok(id) => Some(id),
             Err(e) => {
                 resp.update(500, Some(format!("{e}")), None, None);
                 error!("{:?}", &resp);
-                return Json(resp);
+                return Json(resp).into_response();
             }
         },
         None => None,
     };
 
     let accessory_id2 = match order.accessory2 {
-        Some(ref item) => match accessory_id_or_insert(&database, item, 0).await {
+        Some(ref item) => match accessory_id_or_insert(&mut tx, item, 0).await {
             Ok(id) => Some(id),
             Err(e) => {
                 resp.update(500, Some(format!("{e}")), None, None);
                 error!("{:?}", &resp);
-                return Json(resp);
+                return Json(resp).into_response();
             }
         },
         None => None,
     };
 
     let fault_id1 = match order.fault1 {
-        Some(ref item) => match fault_id_or_insert(&database, item, 0).await {
+        Some(ref item) => match fault_id_or_insert(&mut tx, item, 0).await {
             Ok(id) => Some(id),
             Err(e) => {
                 resp.update(500, Some(format!("{e}")), None, None);
                 error!("{:?}", &resp);
-                return Json(resp);
+                return Json(resp).into_response();
             }
         },
         None => None,
     };
 
     let fault_id2 = match order.fault2 {
-        Some(ref item) => match fault_id_or_insert(&database, item, 0).await {
+        Some(ref item) => match fault_id_or_insert(&mut tx, item, 0).await {
             Ok(id) => Some(id),
             Err(e) => {
                 resp.update(500, Some(format!("{e}")), None, None);
                 error!("{:?}", &resp);
-                return Json(resp);
+                return Json(resp).into_response();
             }
         },
         None => None,
     };
 
-    let status_id = match status_id_or_insert(&database, &order.status).await {
-        Ok(id) => id,
+    let status_id = match Workflow::load(&database).await {
+        Ok(workflow) => match workflow.initial(&order.status) {
+            Ok(status) => status.id,
+            Err(e) => {
+                resp.update(400, Some(format!("{e}")), None, None);
+                error!("{:?}", &resp);
+                return Json(resp).into_response();
+            }
+        },
         Err(e) => {
             resp.update(500, Some(format!("{e}")), None, None);
             error!("{:?}", &resp);
-            return Json(resp);
+            return Json(resp).into_response();
         }
     };
 
@@ -1220,7 +1791,7 @@ pub(crate) async fn order_create(
                     None,
                     None,
                 );
-                return Json(resp);
+                return Json(resp).into_response();
             }
         }
     } else {
@@ -1237,7 +1808,7 @@ pub(crate) async fn order_create(
                     None,
                     None,
                 );
-                return Json(resp);
+                return Json(resp).into_response();
             }
         }
     } else {
@@ -1245,13 +1816,27 @@ pub(crate) async fn order_create(
     };
 
     let life_cycle = order.life_cycle.as_ref().map_or("進行中", |l| l);
-    let warranty_expired = order.warranty_expired
-        .unwrap_or(false);
-    let confirmed_paid = order.confirmed_paid
-        .unwrap_or(0);
+    let warranty_expired = order.warranty_expired.unwrap_or(false);
+    /* the ledger opens with the intake amounts, the prepaid one is paid too */
+    let confirmed_paid = order.prepaid_free.unwrap_or(0) + order.confirmed_paid.unwrap_or(0);
 
-    let sn = OrderSN::generate(&database, &order.department).await;
     let issue_at = Utc::now();
+    let sn = match next_sn(
+        &mut tx,
+        &sn_format,
+        department_id,
+        &order.department,
+        issue_at,
+    )
+    .await
+    {
+        Ok(sn) => sn,
+        Err(e) => {
+            resp.update(500, Some(format!("{e}")), None, None);
+            error!("{:?}", &resp);
+            return Json(resp).into_response();
+        }
+    };
 
     const INSERT_QUERY: &str = r#"
         INSERT INTO orders (
@@ -1301,7 +1886,7 @@ pub(crate) async fn order_create(
         .bind(accessory_id1)
         .bind(accessory_id2)
         .bind(&order.accessory_other)
-        .bind(&order.appearance)
+        .bind(&appearance)
         .bind(&order.appearance_other)
         .bind(&order.service)
         .bind(fault_id1)
@@ -1315,84 +1900,139 @@ pub(crate) async fn order_create(
         .bind(life_cycle)
         .bind(servicer_id)
         .bind(maintainer_id)
-        .bind(&sn.0)
+        .bind(&sn)
         .bind(issue_at)
         .bind(confirmed_paid)
         .bind(warranty_expired)
-        .fetch_one(&database)
+        .fetch_one(&mut tx)
         .await;
 
     debug!("fetch_one as {:?}", fetch_one);
 
     let order_id = match fetch_one {
         Ok((id,)) => id,
+        Err(sqlx::Error::Database(e)) if e.constraint() == Some("orders_sn_key") => {
+            if let Some(taken) = trash::taken(&database, Trash::Order, &sn).await {
+                return taken;
+            }
+            resp.update(409, Some(format!("order/{sn} exists")), None, None);
+            error!("{:?}", &resp);
+            return Json(resp).into_response();
+        }
         Err(e) => {
             resp.update(500, Some(format!("{e}")), None, None);
             error!("{:?}", &resp);
-            return Json(resp);
+            return Json(resp).into_response();
         }
     };
+    let method = order.payment_method.unwrap_or(Method::Cash);
+    for (amount, remark) in [
+        (order.prepaid_free, "預收款"),
+        (order.confirmed_paid, "已付款"),
+    ] {
+        let amount = match amount {
+            Some(amount) if amount > 0 => amount,
+            _ => continue,
+        };
+        let entry = Entry {
+            order_id,
+            direction: Direction::Charge,
+            method: method.as_str(),
+            amount,
+            receiver_id: Some(issuer.id),
+            remark: Some(remark),
+            voids_id: None,
+        };
+        if let Err(e) = payment::insert(&mut tx, &entry).await {
+            resp.update(500, Some(format!("{e}")), None, None);
+            error!("{:?}", &resp);
+            return Json(resp).into_response();
+        }
+    }
+    const HISTORY_QUERY: &str = r#"
+        INSERT INTO order_histories (
+            order_id,
+            issuer_id,
+            status_id,
+            life_cycle,
+            remark,
+            cost
+        ) VALUES (
+            $1,
+            $2,
+            $3,
+            $4,
+            $5,
+            $6
+        );
+    "#;
+    let executed = sqlx::query(HISTORY_QUERY)
+        .bind(order_id)
+        .bind(issuer.id)
+        .bind(status_id)
+        .bind(life_cycle)
+        .bind(&order.remark)
+        .bind(order.cost)
+        .execute(&mut tx)
+        .await;
+    if let Err(e) = executed {
+        resp.update(500, Some(format!("{e}")), None, None);
+        error!("{:?}", &resp);
+        return Json(resp).into_response();
+    }
+    let event = AuditEvent::new("order.create", "order", &sn)
+        .by(issuer)
+        .after(&order);
+    if let Err(e) = audit.record(&mut tx, event).await {
+        resp.update(500, Some(format!("{e}")), None, None);
+        return Json(resp).into_response();
+    }
+    if let Err(e) = tx.commit().await {
+        resp.update(500, Some(format!("DB error - {e}")), None, None);
+        error!("{:?}", &resp);
+        return Json(resp).into_response();
+    }
 
-    let gsheet_pos =
-        gsheets_order_append(gsheets.clone(), state.clone(), &order, issue_at, &sn.0).await;
+    /* the sheet is a copy outside of the transaction, a lost position only unlinks it */
+    let gsheet_pos = gsheets_order_append(
+        gsheets.clone(),
+        state.clone(),
+        &order,
+        &catalog,
+        issue_at,
+        &sn,
+    )
+    .await;
     debug!("gsheet_pos = {:?}", gsheet_pos);
 
-    const INSERT_QUERY2: &str = r#"
-        WITH history_created AS (
-            INSERT INTO order_histories (
-                order_id,
-                issuer_id,
-                status_id,
-                life_cycle,
-                remark,
-                cost
-            ) VALUES (
-                $1,
-                $2,
-                $3,
-                $4,
-                $5,
-                $6
-            ) RETURNING id
-        )
+    const GSHEET_QUERY: &str = r#"
         INSERT INTO order_gsheets (
             order_id,
             sheet_column,
             sheet_row
         ) VALUES (
             $1,
-            $7,
-            $8
-        ) RETURNING id;
+            $2,
+            $3
+        );
     "#;
-    let fetch_one: Result<(i32,), _> = sqlx::query_as(INSERT_QUERY2)
+    if let Err(e) = sqlx::query(GSHEET_QUERY)
         .bind(order_id)
-        .bind(issuer.id)
-        .bind(status_id)
-        .bind(life_cycle)
-        .bind(&order.remark)
-        .bind(order.cost)
         .bind(&gsheet_pos.column)
         .bind(gsheet_pos.row)
-        .fetch_one(&database)
-        .await;
-
-    match fetch_one {
-        Ok((id,)) => {
-            resp.update(
-                200,
-                Some(format!("order{id} create success")),
-                Some(sn.0),
-                Some(order.customer_phone),
-            );
-        }
-        Err(e) => {
-            resp.update(500, Some(format!("{e}")), None, None);
-            error!("{:?}", &resp);
-        }
+        .execute(&database)
+        .await
+    {
+        error!("order{order_id} sheet position {:?} lost - {e}", gsheet_pos);
     }
 
-    Json(resp)
+    resp.update(
+        200,
+        Some(format!("order{order_id} create success")),
+        Some(sn),
+        Some(order.customer_phone),
+    );
+    Json(resp).into_response()
 }
 
 async fn model_map_by_id(database: &Database, id: Option<i32>) -> Option<(String, String)> {
@@ -1413,129 +2053,90 @@ async fn model_map_by_id(database: &Database, id: Option<i32>) -> Option<(String
     }
 }
 
-async fn model_id_or_insert(
-    database: &Database,
+/// id of the model, added if new, concurrent callers get the same row
+async fn model_id_or_insert<'e, E>(
+    executor: E,
     brand: &str,
     model: &str,
     _price: Option<u32>,
-) -> Result<i32> {
-    const QUERY: &str = "SELECT id FROM models WHERE brand = $1 AND model = $2;";
-    let m_id: Option<(i32,)> = sqlx::query_as(QUERY)
+) -> Result<i32>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    /* DO UPDATE, not DO NOTHING, so RETURNING has the existing row too */
+    const QUERY: &str = r#"
+        INSERT INTO models (
+            brand, model
+        ) VALUES (
+            $1, $2
+        )
+        ON CONFLICT (brand, model) DO UPDATE SET brand = EXCLUDED.brand
+        RETURNING id;"#;
+
+    sqlx::query_as(QUERY)
         .bind(brand)
         .bind(model)
-        .fetch_optional(database)
+        .fetch_one(executor)
         .await
-        .unwrap();
-
-    if let Some((id,)) = m_id {
-        Ok(id)
-    } else {
-        const INSERT_QUERY: &str = r#"
-            INSERT INTO models (
-                brand, model
-            ) VALUES (
-                $1, $2
-            ) RETURNING id;"#;
-
-        let fetch_one = sqlx::query_as(INSERT_QUERY)
-            .bind(brand)
-            .bind(model)
-            .fetch_one(database)
-            .await;
-
-        match fetch_one {
-            Ok((m_id,)) => Ok(m_id),
-            Err(err) => Err(anyhow!("insert model fail - {err}")),
-        }
-    }
+        .map(|(id,)| id)
+        .map_err(|e| anyhow!("insert model fail - {e}"))
 }
 
-async fn accessory_id_or_insert(database: &Database, item: &str, price: Price) -> Result<i32> {
-    const QUERY: &str = "SELECT id FROM accessories WHERE item = $1;";
-    let accessory: Option<(i32,)> = sqlx::query_as(QUERY)
+/// id of the accessory, added with `price` if new, the price of an existing one stays
+async fn accessory_id_or_insert<'e, E>(executor: E, item: &str, price: Price) -> Result<i32>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    const QUERY: &str = r#"
+        INSERT INTO accessories (
+            item, price
+        ) VALUES (
+            $1, $2
+        )
+        ON CONFLICT (item) DO UPDATE SET item = EXCLUDED.item
+        RETURNING id;"#;
+
+    sqlx::query_as(QUERY)
         .bind(item)
-        .fetch_optional(database)
+        .bind(price)
+        .fetch_one(executor)
         .await
-        .unwrap();
-
-    if let Some((id,)) = accessory {
-        Ok(id)
-    } else {
-        const INSERT_QUERY: &str = r#"
-            INSERT INTO accessories (
-                item, price
-            ) VALUES (
-                $1, $2
-            ) RETURNING id;"#;
-        let fetch_one = sqlx::query_as(INSERT_QUERY)
-            .bind(item)
-            .bind(price)
-            .fetch_one(database)
-            .await;
-
-        match fetch_one {
-            Ok((a_id,)) => Ok(a_id),
-            Err(err) => Err(anyhow!("insert accessory fail - {err}")),
-        }
-    }
+        .map(|(id,)| id)
+        .map_err(|e| anyhow!("insert accessory fail - {e}"))
 }
 
-async fn fault_id_or_insert(database: &Database, item: &str, cost: Price) -> Result<i32> {
-    const QUERY: &str = "SELECT id FROM faults WHERE item = $1;";
-    let f_id: Option<(i32,)> = sqlx::query_as(QUERY)
+/// id of the fault, added with `cost` if new, the cost of an existing one stays
+async fn fault_id_or_insert<'e, E>(executor: E, item: &str, cost: Price) -> Result<i32>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    const QUERY: &str = r#"
+        INSERT INTO faults (
+            item, cost
+        ) VALUES (
+            $1, $2
+        )
+        ON CONFLICT (item) DO UPDATE SET item = EXCLUDED.item
+        RETURNING id;"#;
+
+    sqlx::query_as(QUERY)
         .bind(item)
-        .fetch_optional(database)
+        .bind(cost)
+        .fetch_one(executor)
         .await
-        .unwrap();
-
-    if let Some((id,)) = f_id {
-        Ok(id)
-    } else {
-        const INSERT_QUERY: &str = r#"
-            INSERT INTO faults (
-                item, cost
-            ) VALUES (
-                $1, $2
-            ) RETURNING id;"#;
-        let fetch_one = sqlx::query_as(INSERT_QUERY)
-            .bind(item)
-            .bind(cost)
-            .fetch_one(database)
-            .await;
-
-        match fetch_one {
-            Ok((id,)) => Ok(id),
-            Err(err) => Err(anyhow!("insert fault fail - {err}")),
-        }
-    }
+        .map(|(id,)| id)
+        .map_err(|e| anyhow!("insert fault fail - {e}"))
 }
 
-async fn status_id_or_insert(database: &Database, flow: &str) -> Result<i32> {
-    const QUERY: &str = "SELECT id FROM status WHERE flow = $1;";
-    let f_id: Option<(i32,)> = sqlx::query_as(QUERY)
-        .bind(flow)
-        .fetch_optional(database)
-        .await
-        .unwrap();
-
-    if let Some((id,)) = f_id {
-        Ok(id)
-    } else {
-        const INSERT_QUERY: &str = "INSERT INTO status (flow) VALUES ($1) RETURNING id;";
-        let fetch_one = sqlx::query_as(INSERT_QUERY)
-            .bind(flow)
-            .fetch_one(database)
-            .await;
-
-        match fetch_one {
-            Ok((id,)) => Ok(id),
-            Err(err) => Err(anyhow!("insert status fail - {err}")),
-        }
-    }
+/// `order` as histories and the audit log keep it, the appearance by flag names
+fn order_snapshot(order: &OrderRawInfo, catalog: &Catalog) -> Value {
+    let mut snapshot = json!(order);
+    snapshot["appearance"] = json!(catalog.names(&order.appearance));
+    snapshot
 }
 
 #[allow(dead_code)]
-async fn query_order(database: &Database, sn: &str) -> Option<OrderInfo> {
+pub(crate) async fn query_order(database: &Database, sn: &str) -> Option<OrderInfo> {
     const QUERY: &str = r#"
         SELECT
             o.sn,
@@ -1551,7 +2152,11 @@ async fn query_order(database: &Database, sn: &str) -> Option<OrderInfo> {
             s1.item AS accessory1,
             s2.item AS accessory2,
             o.accessory_other,
-            o.appearance,
+            ARRAY(
+                SELECT f.name FROM appearance_flags f
+                WHERE substring(o.appearance FROM f.bit + 1 FOR 1) = B'1'
+                ORDER BY f.bit
+            ) AS appearance,
             o.appearance_other,
             o.service,
             f1.item AS fault1,
@@ -1562,6 +2167,7 @@ async fn query_order(database: &Database, sn: &str) -> Option<OrderInfo> {
             o.cost,
             o.prepaid_free,
             o.confirmed_paid,
+            COALESCE(o.cost, 0) - COALESCE(o.confirmed_paid, 0) AS balance_due,
             o.warranty_expired,
             s.flow status,
             o.life_cycle AS life_cycle,
@@ -1578,7 +2184,7 @@ async fn query_order(database: &Database, sn: &str) -> Option<OrderInfo> {
             LEFT JOIN faults f2 ON f2.id = o.fault_id2
             LEFT JOIN users u2 ON u2.id = o.servicer_id
             LEFT JOIN users u3 ON u3.id = o.maintainer_id
-        WHERE o.sn = $1;
+        WHERE o.sn = $1 AND o.deleted_at IS NULL;
     "#;
 
     match sqlx::query_as::<_, OrderInfo>(QUERY)
@@ -1593,7 +2199,7 @@ async fn query_order(database: &Database, sn: &str) -> Option<OrderInfo> {
 
 #[allow(dead_code)]
 async fn query_raw_order(database: &Database, sn: &str) -> Option<OrderRawInfo> {
-    const QUERY: &str = "SELECT * FROM orders WHERE sn = $1;";
+    const QUERY: &str = "SELECT * FROM orders WHERE sn = $1 AND deleted_at IS NULL;";
 
     match sqlx::query_as::<_, OrderRawInfo>(QUERY)
         .bind(sn)
@@ -1605,42 +2211,15 @@ async fn query_raw_order(database: &Database, sn: &str) -> Option<OrderRawInfo>
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
-pub struct OrderSN(String);
-
-impl OrderSN {
-    async fn generate(database: &Database, department_shorten: &str) -> Self {
-        /* (old) DB 22 12 21 20 17 07 0 => DD YY MM DD hh mm ss 0
-         * (new) DD DY MM DD hh XX XX 0
-         */
-        const QUERY: &str = r#"
-            SELECT
-                id
-            FROM orders
-            ORDER BY id DESC LIMIT 1;
-        "#;
-        let res: Result<Option<OrderI32Res>, _> =
-            sqlx::query_as(QUERY).fetch_optional(database).await;
-        let next = match res {
-            Ok(res) => res.map_or(1, |r| r.id + 1),
-            Err(_) => 1,
-        };
-
-        let now = Utc::now();
-
-        let res = format!(
-            "{department_shorten:0<3}{y}{mm:02}{dd:02}{hh:02}{next:04}0",
-            y = (now.year() % 10),
-            mm = now.month(),
-            dd = now.day(),
-            hh = now.hour()
-        );
-        Self(res)
-    }
+/// id and policy scope of order `sn`
+pub(crate) async fn query_order_scope(database: &Database, sn: &str) -> Option<(i32, OrderScope)> {
+    query_raw_order(database, sn)
+        .await
+        .map(|o| (o.id, o.scope()))
 }
 
 pub(crate) async fn query_order_by_department_id(database: &Database, did: i32) -> Option<String> {
-    const QUERY: &str = "SELECT * FROM orders WHERE department_id = $1;";
+    const QUERY: &str = "SELECT * FROM orders WHERE department_id = $1 AND deleted_at IS NULL;";
 
     if let Ok(order) = sqlx::query_as::<_, OrderRawInfo>(QUERY)
         .bind(did)
@@ -1653,48 +2232,58 @@ pub(crate) async fn query_order_by_department_id(database: &Database, did: i32)
     }
 }
 
-pub(crate) async fn query_order_by_user_id(database: &Database, uid: i32) -> Option<String> {
-    const QUERY: &str = r#"
-            SELECT * FROM orders
-            WHERE
-                issuer_id = $1 OR
-                contact_id = $1 OR
-                servicer_id = $1 OR
-                maintainer_id = $1;
-        "#;
-
-    if let Ok(order) = sqlx::query_as::<_, OrderRawInfo>(QUERY)
-        .bind(uid)
-        .fetch_optional(database)
-        .await
-    {
-        order.and_then(|o| o.sn)
-    } else {
-        None
-    }
-}
-
 #[utoipa::path(
     get,
     path = "/api/v1/order/history/{sn}",
     params(
         ("sn" = String, Path, description = "order serial-number"),
         Pagination,
+        OrderAsOfQuery,
     ),
     responses(
-        (status = 200, description = "get detail order history information", body = OrderResponse)
-    )
+        (status = 200, description = "get detail order history information with the changed fields, and the order as of `as_of` if asked", body = OrderHistoriesResponse),
+        (status = 403, description = "permission deny, order of another department", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
 )]
 pub(crate) async fn order_history_request(
-    Extension(_auth_state): Extension<AuthState>,
+    Extension(mut auth_state): Extension<AuthState>,
     Extension(database): Extension<Database>,
     Path(sn): Path<String>,
     page: Option<Query<Pagination>>,
-) -> Json<OrderHistoriesResponse> {
+    as_of: Option<Query<OrderAsOfQuery>>,
+) -> Response {
     let mut resp = OrderHistoriesResponse {
         code: 400,
         histories: None,
+        order: None,
+    };
+
+    let current = match auth_state.get_user().await {
+        Some(current) => current,
+        None => return Json(resp).into_response(),
     };
+    let orig = query_raw_order(&database, &sn).await;
+    if let Some(ref orig) = orig {
+        if !policy::order_allowed(current, OrderAction::Read, orig.scope()) {
+            return rbac::forbidden(format!("permission deny, order/{sn}"));
+        }
+    }
+
+    if let (Some(orig), Some(at)) = (orig, as_of.and_then(|q| q.0.as_of)) {
+        match order_as_of(&database, &orig, at).await {
+            Ok(order) => resp.order = order,
+            Err(e) => {
+                error!("order/{sn} as of {at} - {e}");
+                resp.code = 500;
+                return Json(resp).into_response();
+            }
+        }
+    }
 
     let (offset, entries) = Pagination::parse(page);
 
@@ -1708,13 +2297,14 @@ pub(crate) async fn order_history_request(
             h.life_cycle AS life_cycle,
             h.remark AS remark,
             h.cost AS cost,
+            h.changes AS changes,
             d.shorten AS department
         FROM order_histories h
             LEFT JOIN orders o ON o.id = h.order_id
             LEFT JOIN status s ON s.id = h.status_id
             LEFT JOIN users u ON u.id = h.issuer_id
             LEFT JOIN departments d ON d.id = u.department_id
-        WHERE h.order_id = (SELECT id FROM orders WHERE sn = $1)
+        WHERE h.order_id = (SELECT id FROM orders WHERE sn = $1 AND deleted_at IS NULL)
         ORDER BY change_at
         LIMIT {entries} OFFSET {offset};
     "#
@@ -1728,7 +2318,36 @@ pub(crate) async fn order_history_request(
         resp.histories = Some(histories);
         resp.code = 200;
     }
-    Json(resp)
+    Json(resp).into_response()
+}
+
+/// `orig` as it was at `at`, `None` before it was issued
+async fn order_as_of(
+    database: &Database,
+    orig: &OrderRawInfo,
+    at: DateTime<Utc>,
+) -> Result<Option<Value>> {
+    if at < orig.issue_at {
+        return Ok(None);
+    }
+
+    const QUERY: &str = r#"
+        SELECT changes FROM order_histories
+        WHERE order_id = $1 AND change_at > $2
+        ORDER BY change_at DESC, id DESC;
+    "#;
+    let later: Vec<(Value,)> = sqlx::query_as(QUERY)
+        .bind(orig.id)
+        .bind(at)
+        .fetch_all(database)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+
+    let catalog = Catalog::load(database).await?;
+    Ok(Some(order_changes::as_of(
+        &order_snapshot(orig, &catalog),
+        later.iter().map(|(changes,)| changes),
+    )))
 }
 
 #[utoipa::path(
@@ -1738,23 +2357,35 @@ pub(crate) async fn order_history_request(
         OrderHistoryListQuery
     ),
     responses(
-        (status = 200, description = "get order history list", body = OrdersResponse)
-    )
+        (status = 200, description = "get order history list, only of the orders within reach of the user", body = OrderHistoriesResponse),
+        (status = 403, description = "permission deny, order.read required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
 )]
 pub(crate) async fn order_history_list_request(
+    Extension(mut auth_state): Extension<AuthState>,
     Extension(database): Extension<Database>,
     query: Option<Query<OrderHistoryListQuery>>,
-) -> Json<OrderHistoriesResponse> {
+) -> Response {
     let mut resp = OrderHistoriesResponse {
         code: 400,
         histories: None,
+        order: None,
     };
 
-    let (offset, entries, where_dep) = OrderHistoryListQuery::parse(query);
-    //info!("[debug] where_dep = {where_dep}");
+    let reach = match auth_state.get_user().await {
+        Some(current) => match policy::order_read_filter(current) {
+            Some(reach) => reach,
+            None => return rbac::forbidden(String::from("permission deny, order.read required")),
+        },
+        None => return Json(resp).into_response(),
+    };
 
-    let query = format!(
-        r#"
+    const SELECT: &str = r#"
         SELECT
             o.sn AS sn,
             h.change_at AS change_at,
@@ -1763,26 +2394,30 @@ pub(crate) async fn order_history_list_request(
             h.life_cycle AS life_cycle,
             h.remark AS remark,
             h.cost AS cost,
+            h.changes AS changes,
             d.shorten AS department
         FROM order_histories h
             LEFT JOIN orders o ON o.id = h.order_id
             LEFT JOIN status s ON s.id = h.status_id
             LEFT JOIN users u ON u.id = h.issuer_id
             LEFT JOIN departments d ON d.id = u.department_id
-        {where_dep}
-        ORDER BY change_at
-        LIMIT {entries} OFFSET {offset};
-    "#
-    );
+    "#;
 
-    if let Ok(histories) = sqlx::query_as::<_, OrderHistory>(&query)
+    let mut filter = ListFilter::new(SELECT);
+    let (offset, entries) = OrderHistoryListQuery::parse(query, &mut filter);
+    filter.trusted(&reach);
+    filter.trusted("o.deleted_at IS NULL");
+    let mut query = filter.finish("change_at", entries, offset);
+
+    if let Ok(histories) = query
+        .build_query_as::<OrderHistory>()
         .fetch_all(&database)
         .await
     {
         resp.histories = Some(histories);
         resp.code = 200;
     }
-    Json(resp)
+    Json(resp).into_response()
 }
 
 /*#[test]
@@ -1798,3 +2433,17 @@ fn test_format() {
 
     assert_eq!(res, "NN0309080700990".to_string());
 }*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phone_typed_with_separators() {
+        assert_eq!(phone_digits("0912-345 678").as_deref(), Some("0912345678"));
+        assert_eq!(phone_digits("(02) 2345").as_deref(), Some("022345"));
+        assert_eq!(phone_digits("0912345"), None);
+        assert_eq!(phone_digits("9-1"), None);
+        assert_eq!(phone_digits("BM2301"), None);
+    }
+}
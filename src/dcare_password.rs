@@ -0,0 +1,296 @@
+use axum::{extract::Extension, http::StatusCode, response::IntoResponse, Json};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::audit::{Audit, AuditEvent};
+use crate::authentication::{password_hashed, token_hash, token_hex, AuthConfig, ClientInfo};
+use crate::errors::{SignupError, SignupErrorResponse};
+use crate::mailer::{Mail, SharedMailer};
+use crate::password_policy;
+use crate::{ApiResponse, Database};
+
+/// same answer whether the account exists or not
+const FORGOT_RESPONSE: &str = "if the account exists, a reset mail has been sent";
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PasswordForgot {
+    /// account or email
+    #[schema(example = "clerk01")]
+    account: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PasswordReset {
+    /// token from the reset mail
+    token: String,
+    password: String,
+}
+
+/// users to mail, skip those with no email or a token issued within the last minute
+async fn query_reset_targets(
+    database: &Database,
+    account: &str,
+) -> Result<Vec<(i32, String, String)>> {
+    const QUERY: &str = r#"
+        SELECT id, account, email FROM users
+        WHERE (account = $1 OR email = $1) AND COALESCE(email, '') <> ''
+            AND deleted_at IS NULL
+            AND NOT EXISTS (
+                SELECT 1 FROM password_resets
+                WHERE user_id = users.id AND used_at IS NULL
+                    AND create_at > NOW() - INTERVAL '1 minute'
+            );
+    "#;
+
+    sqlx::query_as(QUERY)
+        .bind(account)
+        .fetch_all(database)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+/// new token replaces any outstanding one of the user, recorded with it
+async fn reset_token_insert(
+    database: &Database,
+    audit: &Audit,
+    user_id: i32,
+    account: &str,
+    token_hash: &str,
+    config: &AuthConfig,
+    client: &ClientInfo,
+) -> Result<()> {
+    const DELETE_QUERY: &str =
+        "DELETE FROM password_resets WHERE user_id = $1 AND used_at IS NULL;";
+    const INSERT_QUERY: &str = r#"
+        INSERT INTO password_resets (
+            user_id, token_hash, expires_at, ip
+        ) VALUES (
+            $1, $2, NOW() + make_interval(secs => $3), $4
+        );
+    "#;
+
+    let mut tx = database
+        .begin()
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+
+    sqlx::query(DELETE_QUERY)
+        .bind(user_id)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+    sqlx::query(INSERT_QUERY)
+        .bind(user_id)
+        .bind(token_hash)
+        .bind(config.reset_lifetime.num_seconds() as f64)
+        .bind(&client.ip)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+    let event =
+        AuditEvent::new("password.forgot", "user", account).by_account(Some(user_id), account);
+    audit.record(&mut tx, event).await?;
+
+    tx.commit().await.map_err(|e| anyhow!("DB error - {e}"))
+}
+
+/// Result of a reset request with a well-formed body.
+enum ResetOutcome {
+    /// user id and account
+    Done(i32, String),
+    InvalidToken,
+    /// refused by the password policy, the token stays usable
+    Rejected(SignupError),
+}
+
+/// consume the token, set the password and drop every session, all or nothing
+async fn password_reset(
+    database: &Database,
+    audit: &Audit,
+    config: &AuthConfig,
+    token_hash: &str,
+    password: &str,
+) -> Result<ResetOutcome> {
+    const CONSUME_QUERY: &str = r#"
+        UPDATE password_resets SET used_at = NOW()
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+        RETURNING user_id;
+    "#;
+    const USER_QUERY: &str = "SELECT account, phone, email FROM users WHERE id = $1;";
+    const PASSWORD_QUERY: &str = "UPDATE users SET password = $2 WHERE id = $1;";
+    const SESSIONS_QUERY: &str = "DELETE FROM sessions WHERE user_id = $1;";
+
+    let mut tx = database
+        .begin()
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+
+    let user_id: Option<(i32,)> = sqlx::query_as(CONSUME_QUERY)
+        .bind(token_hash)
+        .fetch_optional(&mut tx)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+    let user_id = match user_id {
+        Some((user_id,)) => user_id,
+        None => return Ok(ResetOutcome::InvalidToken),
+    };
+
+    let (account, phone, email): (String, String, Option<String>) = sqlx::query_as(USER_QUERY)
+        .bind(user_id)
+        .fetch_one(&mut tx)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+    let personal = [
+        Some(account.as_str()),
+        Some(phone.as_str()),
+        email.as_deref(),
+    ];
+    let personal: Vec<&str> = personal.into_iter().flatten().collect();
+
+    /* dropping tx rolls the token back */
+    if let Err(error) =
+        password_policy::check_new(database, config, Some(user_id), password, &personal).await
+    {
+        return Ok(ResetOutcome::Rejected(error));
+    }
+
+    let hashed_password = password_hashed(config, password)?;
+    sqlx::query(PASSWORD_QUERY)
+        .bind(user_id)
+        .bind(&hashed_password)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+    password_policy::history_push(&mut tx, config, user_id, &hashed_password).await?;
+    sqlx::query(SESSIONS_QUERY)
+        .bind(user_id)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+    let event =
+        AuditEvent::new("password.reset", "user", &account).by_account(Some(user_id), &account);
+    audit.record(&mut tx, event).await?;
+
+    tx.commit().await.map_err(|e| anyhow!("DB error - {e}"))?;
+    Ok(ResetOutcome::Done(user_id, account))
+}
+
+fn reset_mail(config: &AuthConfig, account: &str, email: &str, token: &str) -> Mail {
+    let link = match &config.reset_url {
+        Some(url) => format!("{url}?token={token}"),
+        None => format!("reset token: {token}"),
+    };
+
+    Mail {
+        to: email.to_string(),
+        subject: String::from("dcare password reset"),
+        body: format!(
+            "A password reset was requested for account {account}.\n\n{link}\n\n\
+            It expires in {} minutes and works once. Ignore this mail if it wasn't you.",
+            config.reset_lifetime.num_minutes()
+        ),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/password/forgot",
+    request_body = PasswordForgot,
+    responses(
+        (status = 200, description = "reset mail sent if the account exists", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from(FORGOT_RESPONSE))))),
+    )
+)]
+pub(crate) async fn password_forgot_api(
+    Extension(database): Extension<Database>,
+    Extension(auth_config): Extension<AuthConfig>,
+    Extension(mailer): Extension<SharedMailer>,
+    client: ClientInfo,
+    audit: Audit,
+    Json(forgot): Json<PasswordForgot>,
+) -> impl IntoResponse {
+    let resp = ApiResponse::new(200, Some(String::from(FORGOT_RESPONSE)));
+
+    let targets = match query_reset_targets(&database, forgot.account.trim()).await {
+        Ok(targets) => targets,
+        Err(e) => {
+            error!("password forgot - {e}");
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+    };
+
+    for (user_id, account, email) in targets {
+        let token = token_hex(32);
+        if let Err(e) = reset_token_insert(
+            &database,
+            &audit,
+            user_id,
+            &account,
+            &token_hash(&token),
+            &auth_config,
+            &client,
+        )
+        .await
+        {
+            error!("password forgot {account} - {e}");
+            continue;
+        }
+
+        let mail = reset_mail(&auth_config, &account, &email, &token);
+        match mailer.send(mail).await {
+            Ok(_) => info!("password reset mail sent to {account}"),
+            Err(e) => error!("password reset mail to {account} - {e}"),
+        }
+    }
+
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/password/reset",
+    request_body = PasswordReset,
+    responses(
+        (status = 200, description = "password changed, all sessions of the user logged out", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 400, description = "invalid/expired/used token (`ApiResponse`), or password refused by the policy, see `SignupError`", body = SignupErrorResponse, example = json!(SignupErrorResponse::from(SignupError::PasswordTooCommon))),
+    )
+)]
+pub(crate) async fn password_reset_api(
+    Extension(database): Extension<Database>,
+    Extension(auth_config): Extension<AuthConfig>,
+    audit: Audit,
+    Json(reset): Json<PasswordReset>,
+) -> impl IntoResponse {
+    match password_reset(
+        &database,
+        &audit,
+        &auth_config,
+        &token_hash(reset.token.trim()),
+        &reset.password,
+    )
+    .await
+    {
+        Ok(ResetOutcome::Done(user_id, account)) => {
+            info!("password reset for user/{user_id} {account}");
+
+            let resp = ApiResponse::new(200, Some(String::from("success")));
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Ok(ResetOutcome::InvalidToken) => {
+            let resp = ApiResponse::new(400, Some(String::from("invalid or expired token")));
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Ok(ResetOutcome::Rejected(error)) => {
+            let resp = SignupErrorResponse::from(error);
+            error!("{:?}", &resp);
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => {
+            let resp = ApiResponse::new(500, Some(format!("{e}")));
+            error!("{:?}", &resp);
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+    }
+}
@@ -0,0 +1,417 @@
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, Postgres};
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::audit::{Audit, AuditEvent};
+use crate::authentication::{AuthState, CurrentUser};
+use crate::dcare_order::{gsheets_paid_update, query_order_scope};
+use crate::errors::NotLoggedIn;
+use crate::gsheets::SharedDcareGoogleSheet;
+use crate::payment::{self, Direction, Entry, LedgerError, Method};
+use crate::policy::{self, OrderAction};
+use crate::{rbac, ApiResponse, Database};
+
+/// longest remark, in characters
+const REMARK_MAX: usize = 200;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema, sqlx::FromRow)]
+pub struct PaymentInfo {
+    id: i32,
+    create_at: DateTime<Utc>,
+    #[schema(example = "charge")]
+    direction: String,
+    #[schema(example = "line_pay")]
+    method: String,
+    amount: i32,
+    /// account of the staff taking or giving the money
+    receiver: Option<String>,
+    /// shorten of the department
+    department: Option<String>,
+    remark: Option<String>,
+    /// the entry this one voids
+    voids_id: Option<i32>,
+    /// the entry voiding this one
+    voided_by: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PaymentsResponse {
+    code: u16,
+    cost: Option<i32>,
+    /// net of the ledger
+    confirmed_paid: Option<i32>,
+    /// cost less confirmed_paid, negative when a refund is owed
+    balance_due: Option<i32>,
+    /// oldest first
+    payments: Option<Vec<PaymentInfo>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PaymentNew {
+    direction: Direction,
+    method: Method,
+    #[schema(example = 1500)]
+    amount: i32,
+    remark: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PaymentVoid {
+    /// why the entry is voided
+    #[schema(example = "刷卡金額輸入錯誤")]
+    remark: String,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct Voidable {
+    direction: String,
+    method: String,
+    amount: i32,
+    voids_id: Option<i32>,
+    voided: bool,
+}
+
+const SELECT: &str = r#"
+    SELECT
+        p.id,
+        p.create_at,
+        p.direction,
+        p.method,
+        p.amount,
+        u.account AS receiver,
+        d.shorten AS department,
+        p.remark,
+        p.voids_id,
+        v.id AS voided_by
+    FROM order_payments p
+        LEFT JOIN users u ON u.id = p.receiver_id
+        LEFT JOIN departments d ON d.id = p.department_id
+        LEFT JOIN order_payments v ON v.voids_id = p.id
+"#;
+
+fn json_response(code: u16, message: String) -> Response {
+    let resp = ApiResponse::new(code, Some(message));
+    if code >= 500 {
+        error!("{:?}", &resp);
+    }
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
+fn ledger_response(e: LedgerError) -> Response {
+    match e {
+        e @ (LedgerError::Overdrawn { .. } | LedgerError::Voided(_)) => {
+            json_response(409, format!("{e}"))
+        }
+        e @ LedgerError::Database(_) => json_response(500, format!("{e}")),
+    }
+}
+
+/// id of order `sn` if `action` on it is allowed, the response otherwise
+async fn order_id(
+    database: &Database,
+    current: &CurrentUser,
+    sn: &str,
+    action: OrderAction,
+) -> Result<i32, Response> {
+    match query_order_scope(database, sn).await {
+        Some((id, scope)) if policy::order_allowed(current, action, scope) => Ok(id),
+        Some(_) => Err(rbac::forbidden(format!("permission deny, order/{sn}"))),
+        None => Err(json_response(404, format!("order/{sn} not found"))),
+    }
+}
+
+fn valid_remark(remark: &str) -> bool {
+    remark.chars().count() <= REMARK_MAX
+}
+
+async fn query_payment<'e, E>(executor: E, id: i32) -> Result<PaymentInfo>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    let query = format!("{SELECT} WHERE p.id = $1;");
+    sqlx::query_as::<_, PaymentInfo>(&query)
+        .bind(id)
+        .fetch_one(executor)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+/// the ledger of the order and what it leaves due
+async fn payments_response(database: &Database, order_id: i32) -> Response {
+    const ORDER_QUERY: &str = "SELECT cost, confirmed_paid FROM orders WHERE id = $1;";
+    let query = format!("{SELECT} WHERE p.order_id = $1 ORDER BY p.id;");
+
+    let amounts = sqlx::query_as::<_, (Option<i32>, Option<i32>)>(ORDER_QUERY)
+        .bind(order_id)
+        .fetch_one(database)
+        .await;
+    let payments = sqlx::query_as::<_, PaymentInfo>(&query)
+        .bind(order_id)
+        .fetch_all(database)
+        .await;
+    match (amounts, payments) {
+        (Ok((cost, paid)), Ok(payments)) => {
+            let resp = PaymentsResponse {
+                code: 200,
+                cost,
+                confirmed_paid: paid,
+                balance_due: Some(payment::balance_due(cost, paid)),
+                payments: Some(payments),
+            };
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        (Err(e), _) | (_, Err(e)) => json_response(500, format!("DB error - {e}")),
+    }
+}
+
+/// append `entry` with its audit event, then follow with the sheet
+async fn append(
+    database: &Database,
+    gsheets: Option<SharedDcareGoogleSheet>,
+    audit: &Audit,
+    current: &CurrentUser,
+    sn: &str,
+    action: &'static str,
+    entry: Entry<'_>,
+) -> Response {
+    let mut tx = match database.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return json_response(500, format!("DB error - {e}")),
+    };
+    let (id, paid) = match payment::append(&mut tx, &entry).await {
+        Ok(appended) => appended,
+        Err(e) => return ledger_response(e),
+    };
+    let created = match query_payment(&mut tx, id).await {
+        Ok(created) => created,
+        Err(e) => return json_response(500, format!("{e}")),
+    };
+    let event = AuditEvent::new(action, "order", sn)
+        .by(current)
+        .after(&created);
+    if let Err(e) = audit.record(&mut tx, event).await {
+        return json_response(500, format!("{e}"));
+    }
+    if let Err(e) = tx.commit().await {
+        return json_response(500, format!("DB error - {e}"));
+    }
+    info!(
+        "{} {action} {} on order/{sn}",
+        current.account, entry.amount
+    );
+
+    /* the sheet is a copy outside of the transaction, it follows once the DB committed */
+    if let Some(gsheets) = gsheets {
+        gsheets_paid_update(gsheets, database, entry.order_id, paid).await;
+    }
+    payments_response(database, entry.order_id).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/order/{sn}/payments",
+    params(
+        ("sn" = String, Path, description = "order serial-number")
+    ),
+    responses(
+        (status = 200, description = "payment ledger of the order, voided entries and their voids included, and the balance due", body = PaymentsResponse),
+        (status = 403, description = "permission deny, order.read within reach required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 404, description = "order not found", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn order_payments_request(
+    Extension(mut auth_state): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    Path(sn): Path<String>,
+) -> impl IntoResponse {
+    let current = match auth_state.get_user().await {
+        Some(current) => current,
+        None => return json_response(400, format!("{}", &NotLoggedIn)),
+    };
+    match order_id(&database, current, &sn, OrderAction::Read).await {
+        Ok(order_id) => payments_response(&database, order_id).await,
+        Err(response) => response,
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/order/{sn}/payments",
+    params(
+        ("sn" = String, Path, description = "order serial-number")
+    ),
+    request_body = PaymentNew,
+    responses(
+        (status = 200, description = "payment or refund recorded by the current user, the ledger after it", body = PaymentsResponse),
+        (status = 400, description = "amount not positive or remark too long", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, order.update within reach required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 404, description = "order not found", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+        (status = 409, description = "refund larger than the paid amount", body = ApiResponse, example = json!(ApiResponse::new(409, Some(String::from("2000 can't be given back, 1500 paid"))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn order_payment_create(
+    Extension(mut auth_state): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    gsheets: Option<Extension<SharedDcareGoogleSheet>>,
+    audit: Audit,
+    Path(sn): Path<String>,
+    Json(new): Json<PaymentNew>,
+) -> impl IntoResponse {
+    let current = match auth_state.get_user().await {
+        Some(current) => current,
+        None => return json_response(400, format!("{}", &NotLoggedIn)),
+    };
+    if new.amount <= 0 {
+        return json_response(400, format!("invalid amount {}", new.amount));
+    }
+    let remark = new
+        .remark
+        .as_deref()
+        .map(str::trim)
+        .filter(|r| !r.is_empty());
+    if matches!(remark, Some(remark) if !valid_remark(remark)) {
+        return json_response(400, format!("remark longer than {REMARK_MAX}"));
+    }
+    let order_id = match order_id(&database, current, &sn, OrderAction::Update).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+
+    let entry = Entry {
+        order_id,
+        direction: new.direction,
+        method: new.method.as_str(),
+        amount: new.amount,
+        receiver_id: Some(current.id),
+        remark,
+        voids_id: None,
+    };
+    let gsheets = gsheets.map(|Extension(gsheets)| gsheets);
+    append(
+        &database,
+        gsheets,
+        &audit,
+        current,
+        &sn,
+        "payment.create",
+        entry,
+    )
+    .await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/order/{sn}/payments/{id}/void",
+    params(
+        ("sn" = String, Path, description = "order serial-number"),
+        ("id" = i32, Path, description = "payment id")
+    ),
+    request_body = PaymentVoid,
+    responses(
+        (status = 200, description = "reversing entry recorded, the ledger after it", body = PaymentsResponse),
+        (status = 400, description = "no remark, or the payment is a void itself", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, order.update within reach required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 404, description = "order or payment not found", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+        (status = 409, description = "voided already, or voiding a charge takes back more than paid", body = ApiResponse, example = json!(ApiResponse::new(409, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn order_payment_void(
+    Extension(mut auth_state): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    gsheets: Option<Extension<SharedDcareGoogleSheet>>,
+    audit: Audit,
+    Path((sn, id)): Path<(String, i32)>,
+    Json(void): Json<PaymentVoid>,
+) -> impl IntoResponse {
+    let current = match auth_state.get_user().await {
+        Some(current) => current,
+        None => return json_response(400, format!("{}", &NotLoggedIn)),
+    };
+    let remark = void.remark.trim();
+    if remark.is_empty() || !valid_remark(remark) {
+        return json_response(400, format!("remark of 1 to {REMARK_MAX} required"));
+    }
+    let order_id = match order_id(&database, current, &sn, OrderAction::Update).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+
+    const QUERY: &str = r#"
+        SELECT
+            p.direction,
+            p.method,
+            p.amount,
+            p.voids_id,
+            EXISTS (SELECT 1 FROM order_payments v WHERE v.voids_id = p.id) AS voided
+        FROM order_payments p
+        WHERE p.order_id = $1 AND p.id = $2;"#;
+    let voidable = match sqlx::query_as::<_, Voidable>(QUERY)
+        .bind(order_id)
+        .bind(id)
+        .fetch_optional(&database)
+        .await
+    {
+        Ok(Some(voidable)) => voidable,
+        Ok(None) => return json_response(404, format!("order/{sn}/payments/{id} not found")),
+        Err(e) => return json_response(500, format!("DB error - {e}")),
+    };
+    if voidable.voids_id.is_some() {
+        return json_response(
+            400,
+            format!("payments/{id} is a void, record a new payment"),
+        );
+    }
+    if voidable.voided {
+        return ledger_response(LedgerError::Voided(id));
+    }
+    let direction = match Direction::parse(&voidable.direction) {
+        Some(direction) => direction.reverse(),
+        None => return json_response(500, format!("payments/{id} in {}", voidable.direction)),
+    };
+
+    let entry = Entry {
+        order_id,
+        direction,
+        method: &voidable.method,
+        amount: voidable.amount,
+        receiver_id: Some(current.id),
+        remark: Some(remark),
+        voids_id: Some(id),
+    };
+    let gsheets = gsheets.map(|Extension(gsheets)| gsheets);
+    append(
+        &database,
+        gsheets,
+        &audit,
+        current,
+        &sn,
+        "payment.void",
+        entry,
+    )
+    .await
+}
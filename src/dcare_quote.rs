@@ -0,0 +1,886 @@
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+    Form, Json,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json as JsonColumn, Executor, Postgres, Transaction};
+use tera::Context;
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::audit::{Audit, AuditEvent};
+use crate::authentication::{token_hash, token_hex, AuthState, ClientInfo, CurrentUser};
+use crate::dcare_order::{gsheets_status_update, query_order_scope};
+use crate::errors::NotLoggedIn;
+use crate::gsheets::SharedDcareGoogleSheet;
+use crate::order_changes;
+use crate::order_status::{TransitionError, Workflow, QUOTED};
+use crate::policy::{self, OrderAction};
+use crate::quote::{self, Decision, ItemKind, QuoteConfig, Refusal};
+use crate::receipt::local_time;
+use crate::{rbac, ApiResponse, Database, Templates};
+
+/// most items of a quote
+const ITEMS_MAX: usize = 50;
+/// longest description of an item, in characters
+const DESCRIPTION_MAX: usize = 100;
+/// longest remark, in characters
+const REMARK_MAX: usize = 200;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct QuoteItemInfo {
+    #[schema(example = "fault")]
+    kind: String,
+    /// the catalog item, none for labour
+    #[schema(example = "螢幕破裂")]
+    item: Option<String>,
+    description: String,
+    quantity: i32,
+    unit_price: i32,
+    /// quantity times unit price
+    amount: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema, sqlx::FromRow)]
+pub struct QuoteInfo {
+    id: i32,
+    version: i32,
+    create_at: DateTime<Utc>,
+    /// account of the staff issuing it
+    issuer: Option<String>,
+    total: i32,
+    remark: Option<String>,
+    /// pending, accepted, rejected or superseded
+    #[schema(example = "pending")]
+    state: String,
+    expires_at: DateTime<Utc>,
+    /// when the customer answered
+    decided_at: Option<DateTime<Utc>>,
+    /// IP the customer answered from
+    decided_ip: Option<String>,
+    #[schema(value_type = Vec<QuoteItemInfo>)]
+    items: JsonColumn<Vec<QuoteItemInfo>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct QuotesResponse {
+    code: u16,
+    /// newest version first
+    quotes: Option<Vec<QuoteInfo>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct QuoteItemNew {
+    kind: ItemKind,
+    /// item of the `faults` or `accessories` catalog, not for labour
+    #[schema(example = "螢幕破裂")]
+    item: Option<String>,
+    /// the catalog item if not given, required for labour
+    description: Option<String>,
+    /// 1 if not given
+    quantity: Option<i32>,
+    /// the catalog price if not given, required for labour
+    #[schema(example = 2500)]
+    unit_price: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct QuoteNew {
+    items: Vec<QuoteItemNew>,
+    remark: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct QuoteCreated {
+    code: u16,
+    quote: Option<QuoteInfo>,
+    /// shown this once, only its hash is kept
+    token: Option<String>,
+    /// the link to send to the customer
+    #[schema(example = "/quote/3f1c...")]
+    approval_url: Option<String>,
+}
+
+/// a quote as the customer sees it, nothing of the customer in it
+#[derive(Debug, Serialize, Deserialize, ToSchema, sqlx::FromRow)]
+pub struct QuoteView {
+    sn: String,
+    store_name: Option<String>,
+    store_telephone: Option<String>,
+    brand: Option<String>,
+    model: Option<String>,
+    version: i32,
+    create_at: DateTime<Utc>,
+    total: i32,
+    remark: Option<String>,
+    #[schema(example = "pending")]
+    state: String,
+    /// no longer answerable
+    expired: bool,
+    expires_at: DateTime<Utc>,
+    decided_at: Option<DateTime<Utc>>,
+    #[schema(value_type = Vec<QuoteItemInfo>)]
+    items: JsonColumn<Vec<QuoteItemInfo>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct QuoteViewResponse {
+    code: u16,
+    quote: Option<QuoteView>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct QuoteAnswer {
+    decision: Decision,
+}
+
+/// status and cost of the order, the fields a quote changes
+#[derive(Debug, PartialEq, Eq, Serialize, sqlx::FromRow)]
+struct OrderState {
+    status_id: Option<i32>,
+    cost: Option<i32>,
+}
+
+/// an item as stored, catalog ones resolved
+struct Line {
+    kind: ItemKind,
+    fault_id: Option<i32>,
+    accessory_id: Option<i32>,
+    description: String,
+    quantity: i32,
+    unit_price: i32,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct Pending {
+    id: i32,
+    order_id: i32,
+    total: i32,
+    state: String,
+    expires_at: DateTime<Utc>,
+    sn: String,
+    status_id: Option<i32>,
+    cost: Option<i32>,
+}
+
+/// Result of an answer to a well-formed token.
+enum Answer {
+    /// order sn
+    Done(String),
+    Unknown,
+    Refused(Refusal),
+}
+
+const ITEMS: &str = r#"
+    COALESCE((
+        SELECT jsonb_agg(jsonb_build_object(
+            'kind', i.kind,
+            'item', COALESCE(f.item, a.item),
+            'description', i.description,
+            'quantity', i.quantity,
+            'unit_price', i.unit_price,
+            'amount', i.quantity * i.unit_price
+        ) ORDER BY i.id)
+        FROM quote_items i
+            LEFT JOIN faults f ON f.id = i.fault_id
+            LEFT JOIN accessories a ON a.id = i.accessory_id
+        WHERE i.quote_id = q.id
+    ), '[]') AS items
+"#;
+
+fn json_response(code: u16, message: String) -> Response {
+    let resp = ApiResponse::new(code, Some(message));
+    if code >= 500 {
+        error!("{:?}", &resp);
+    }
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
+/// id of order `sn` if `action` on it is allowed, the response otherwise
+async fn order_id(
+    database: &Database,
+    current: &CurrentUser,
+    sn: &str,
+    action: OrderAction,
+) -> Result<i32, Response> {
+    match query_order_scope(database, sn).await {
+        Some((id, scope)) if policy::order_allowed(current, action, scope) => Ok(id),
+        Some(_) => Err(rbac::forbidden(format!("permission deny, order/{sn}"))),
+        None => Err(json_response(404, format!("order/{sn} not found"))),
+    }
+}
+
+fn trimmed(text: Option<&str>) -> Option<&str> {
+    text.map(str::trim).filter(|t| !t.is_empty())
+}
+
+async fn query_quote<'e, E>(executor: E, id: i32) -> Result<QuoteInfo>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    let query = format!(
+        r#"
+        SELECT
+            q.id,
+            q.version,
+            q.create_at,
+            u.account AS issuer,
+            q.total,
+            q.remark,
+            q.state,
+            q.expires_at,
+            q.decided_at,
+            q.decided_ip,
+            {ITEMS}
+        FROM quotes q
+            LEFT JOIN users u ON u.id = q.issuer_id
+        WHERE q.id = $1;"#
+    );
+    sqlx::query_as::<_, QuoteInfo>(&query)
+        .bind(id)
+        .fetch_one(executor)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+/// the quote of `token`, unless its order is deleted
+async fn query_quote_view(database: &Database, token: &str) -> Result<Option<QuoteView>> {
+    let query = format!(
+        r#"
+        SELECT
+            o.sn,
+            d.store_name,
+            d.telephone AS store_telephone,
+            m.brand,
+            m.model,
+            q.version,
+            q.create_at,
+            q.total,
+            q.remark,
+            q.state,
+            q.expires_at <= NOW() AS expired,
+            q.expires_at,
+            q.decided_at,
+            {ITEMS}
+        FROM quotes q
+            JOIN orders o ON o.id = q.order_id
+            LEFT JOIN departments d ON d.id = o.department_id
+            LEFT JOIN models m ON m.id = o.model_id
+        WHERE q.token_hash = $1 AND o.deleted_at IS NULL;"#
+    );
+    sqlx::query_as::<_, QuoteView>(&query)
+        .bind(token_hash(token.trim()))
+        .fetch_optional(database)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+/// `item` as stored, at the catalog price unless it has one; the response if invalid
+async fn resolve(
+    tx: &mut Transaction<'_, Postgres>,
+    item: &QuoteItemNew,
+) -> Result<Line, Response> {
+    const FAULT_QUERY: &str = "SELECT id, item, cost FROM faults WHERE item = $1;";
+    const ACCESSORY_QUERY: &str = "SELECT id, item, price FROM accessories WHERE item = $1;";
+
+    let quantity = item.quantity.unwrap_or(1);
+    if quantity <= 0 {
+        return Err(json_response(400, format!("invalid quantity {quantity}")));
+    }
+    if matches!(item.unit_price, Some(price) if price < 0) {
+        return Err(json_response(
+            400,
+            String::from("unit price can't be negative"),
+        ));
+    }
+    let description = trimmed(item.description.as_deref());
+    if matches!(description, Some(d) if d.chars().count() > DESCRIPTION_MAX) {
+        return Err(json_response(
+            400,
+            format!("description longer than {DESCRIPTION_MAX}"),
+        ));
+    }
+
+    let query = match item.kind {
+        ItemKind::Fault => FAULT_QUERY,
+        ItemKind::Accessory => ACCESSORY_QUERY,
+        ItemKind::Labour => {
+            return match (description, item.unit_price) {
+                (Some(description), Some(unit_price)) => Ok(Line {
+                    kind: ItemKind::Labour,
+                    fault_id: None,
+                    accessory_id: None,
+                    description: description.to_string(),
+                    quantity,
+                    unit_price,
+                }),
+                _ => Err(json_response(
+                    400,
+                    String::from("labour needs a description and a unit price"),
+                )),
+            };
+        }
+    };
+    let name = match trimmed(item.item.as_deref()) {
+        Some(name) => name,
+        None => {
+            return Err(json_response(
+                400,
+                format!("{} item required", item.kind.as_str()),
+            ))
+        }
+    };
+    let (id, catalog_item, price) = match sqlx::query_as::<_, (i32, String, i32)>(query)
+        .bind(name)
+        .fetch_optional(&mut *tx)
+        .await
+    {
+        Ok(Some(found)) => found,
+        Ok(None) => {
+            return Err(json_response(
+                400,
+                format!("unknown {} {name}", item.kind.as_str()),
+            ))
+        }
+        Err(e) => return Err(json_response(500, format!("DB error - {e}"))),
+    };
+
+    Ok(Line {
+        kind: item.kind,
+        fault_id: (item.kind == ItemKind::Fault).then_some(id),
+        accessory_id: (item.kind == ItemKind::Accessory).then_some(id),
+        description: description.map_or(catalog_item, String::from),
+        quantity,
+        unit_price: item.unit_price.unwrap_or(price),
+    })
+}
+
+/// the order moves from `from` to `to` with the history of what changed; no issuer when
+/// the customer answered
+async fn order_move(
+    tx: &mut Transaction<'_, Postgres>,
+    order_id: i32,
+    issuer_id: Option<i32>,
+    from: &OrderState,
+    to: &OrderState,
+) -> Result<()> {
+    const QUERY: &str = r#"
+        WITH order_updated AS (
+            UPDATE orders SET
+                status_id = $2,
+                cost = $3,
+                version = version + 1,
+                update_at = NOW()
+            WHERE id = $1
+            RETURNING *
+        )
+        INSERT INTO order_histories (
+            order_id, issuer_id, status_id, life_cycle, remark, cost, changes
+        )
+        SELECT id, $4, status_id, life_cycle, remark, cost, $5
+        FROM order_updated;"#;
+
+    if from == to {
+        return Ok(());
+    }
+    sqlx::query(QUERY)
+        .bind(order_id)
+        .bind(to.status_id)
+        .bind(to.cost)
+        .bind(issuer_id)
+        .bind(order_changes::diff(from, to))
+        .execute(&mut *tx)
+        .await
+        .map(|_| ())
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+/// status and cost of the order in the sheet, as `to` left them
+async fn sheet_follow(
+    gsheets: SharedDcareGoogleSheet,
+    database: &Database,
+    workflow: &Workflow,
+    order_id: i32,
+    to: &OrderState,
+) {
+    let status = to
+        .status_id
+        .and_then(|id| workflow.status(id))
+        .map(|s| s.flow.as_str())
+        .unwrap_or_default();
+    gsheets_status_update(gsheets, database, order_id, to.cost, status).await;
+}
+
+/// record the customer's `decision` on the quote of `token` and move the order on, as far
+/// as the workflow goes from where the order is now
+async fn answer(
+    database: &Database,
+    gsheets: Option<SharedDcareGoogleSheet>,
+    audit: &Audit,
+    token: &str,
+    decision: Decision,
+    client: &ClientInfo,
+) -> Result<Answer> {
+    const PENDING_QUERY: &str = r#"
+        SELECT q.id, q.order_id, q.total, q.state, q.expires_at, o.sn, o.status_id, o.cost
+        FROM quotes q
+            JOIN orders o ON o.id = q.order_id
+        WHERE q.token_hash = $1 AND o.deleted_at IS NULL
+        FOR UPDATE;"#;
+    const DECIDE_QUERY: &str = r#"
+        UPDATE quotes SET
+            state = $2,
+            decided_at = NOW(),
+            decided_ip = $3,
+            decided_user_agent = $4
+        WHERE id = $1;"#;
+
+    let workflow = Workflow::load(database).await?;
+    let mut tx = database
+        .begin()
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+
+    let pending = match sqlx::query_as::<_, Pending>(PENDING_QUERY)
+        .bind(token_hash(token.trim()))
+        .fetch_optional(&mut tx)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?
+    {
+        Some(pending) => pending,
+        None => return Ok(Answer::Unknown),
+    };
+    if let Err(refusal) = quote::answerable(&pending.state, pending.expires_at, Utc::now()) {
+        return Ok(Answer::Refused(refusal));
+    }
+
+    sqlx::query(DECIDE_QUERY)
+        .bind(pending.id)
+        .bind(decision.state())
+        .bind(&client.ip)
+        .bind(&client.user_agent)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+
+    let from = OrderState {
+        status_id: pending.status_id,
+        cost: pending.cost,
+    };
+    let to = OrderState {
+        status_id: workflow
+            .follow(from.status_id, decision.status())
+            .map(|s| s.id)
+            .or(from.status_id),
+        cost: match decision {
+            Decision::Accept => Some(pending.total),
+            Decision::Reject => from.cost,
+        },
+    };
+    order_move(&mut tx, pending.order_id, None, &from, &to).await?;
+
+    let answered = query_quote(&mut tx, pending.id).await?;
+    let action = match decision {
+        Decision::Accept => "quote.accept",
+        Decision::Reject => "quote.reject",
+    };
+    let event = AuditEvent::new(action, "order", &pending.sn)
+        .by_account(None, "customer")
+        .after(&answered);
+    audit.record(&mut tx, event).await?;
+
+    tx.commit().await.map_err(|e| anyhow!("DB error - {e}"))?;
+
+    /* the sheet is a copy outside of the transaction, it follows once the DB committed */
+    if let Some(gsheets) = gsheets {
+        sheet_follow(gsheets, database, &workflow, pending.order_id, &to).await;
+    }
+    Ok(Answer::Done(pending.sn))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/order/{sn}/quotes",
+    params(
+        ("sn" = String, Path, description = "order serial-number")
+    ),
+    responses(
+        (status = 200, description = "every version of the quote of the order, with the items and the answers", body = QuotesResponse),
+        (status = 403, description = "permission deny, order.read within reach required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 404, description = "order not found", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn order_quotes_request(
+    Extension(mut auth_state): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    Path(sn): Path<String>,
+) -> impl IntoResponse {
+    let current = match auth_state.get_user().await {
+        Some(current) => current,
+        None => return json_response(400, format!("{}", &NotLoggedIn)),
+    };
+    let order_id = match order_id(&database, current, &sn, OrderAction::Read).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+
+    const QUERY: &str = "SELECT id FROM quotes WHERE order_id = $1 ORDER BY version DESC;";
+    let ids = match sqlx::query_as::<_, (i32,)>(QUERY)
+        .bind(order_id)
+        .fetch_all(&database)
+        .await
+    {
+        Ok(ids) => ids,
+        Err(e) => return json_response(500, format!("DB error - {e}")),
+    };
+    let mut quotes = Vec::with_capacity(ids.len());
+    for (id,) in ids {
+        match query_quote(&database, id).await {
+            Ok(quote) => quotes.push(quote),
+            Err(e) => return json_response(500, format!("{e}")),
+        }
+    }
+
+    let resp = QuotesResponse {
+        code: 200,
+        quotes: Some(quotes),
+    };
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/order/{sn}/quotes",
+    params(
+        ("sn" = String, Path, description = "order serial-number")
+    ),
+    request_body = QuoteNew,
+    responses(
+        (status = 200, description = "next version of the quote issued, the pending one superseded and the order moved to 報價; the token of the approval link is shown this once", body = QuoteCreated),
+        (status = 400, description = "no or too many items, unknown catalog item, invalid amounts, or the order can't move to 報價", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, order.update within reach required, or the transition's permission", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 404, description = "order not found", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn order_quote_create(
+    Extension(mut auth_state): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    Extension(config): Extension<QuoteConfig>,
+    gsheets: Option<Extension<SharedDcareGoogleSheet>>,
+    audit: Audit,
+    Path(sn): Path<String>,
+    Json(new): Json<QuoteNew>,
+) -> impl IntoResponse {
+    let current = match auth_state.get_user().await {
+        Some(current) => current,
+        None => return json_response(400, format!("{}", &NotLoggedIn)),
+    };
+    if new.items.is_empty() || new.items.len() > ITEMS_MAX {
+        return json_response(400, format!("1 to {ITEMS_MAX} items required"));
+    }
+    let remark = trimmed(new.remark.as_deref());
+    if matches!(remark, Some(remark) if remark.chars().count() > REMARK_MAX) {
+        return json_response(400, format!("remark longer than {REMARK_MAX}"));
+    }
+    let order_id = match order_id(&database, current, &sn, OrderAction::Update).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let workflow = match Workflow::load(&database).await {
+        Ok(workflow) => workflow,
+        Err(e) => return json_response(500, format!("{e}")),
+    };
+
+    const LOCK_QUERY: &str = "SELECT status_id, cost FROM orders WHERE id = $1 FOR UPDATE;";
+    const SUPERSEDE_QUERY: &str =
+        "UPDATE quotes SET state = 'superseded' WHERE order_id = $1 AND state = 'pending';";
+    const QUOTE_QUERY: &str = r#"
+        INSERT INTO quotes (
+            order_id, version, issuer_id, total, remark, token_hash, expires_at
+        )
+        SELECT $1, COALESCE(MAX(version), 0) + 1, $2, $3, $4, $5, NOW() + make_interval(secs => $6)
+        FROM quotes
+        WHERE order_id = $1
+        RETURNING id;"#;
+    const ITEM_QUERY: &str = r#"
+        INSERT INTO quote_items (
+            quote_id, kind, fault_id, accessory_id, description, quantity, unit_price
+        ) VALUES (
+            $1, $2, $3, $4, $5, $6, $7
+        );"#;
+
+    let mut tx = match database.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return json_response(500, format!("DB error - {e}")),
+    };
+    let from = match sqlx::query_as::<_, OrderState>(LOCK_QUERY)
+        .bind(order_id)
+        .fetch_one(&mut tx)
+        .await
+    {
+        Ok(from) => from,
+        Err(e) => return json_response(500, format!("DB error - {e}")),
+    };
+    let status_id = match workflow.check(from.status_id, QUOTED, current) {
+        Ok(id) => id,
+        Err(e @ TransitionError::PermissionDeny { .. }) => {
+            return rbac::forbidden(format!("{e}"));
+        }
+        Err(e) => return json_response(400, format!("{e}")),
+    };
+
+    let mut lines = Vec::with_capacity(new.items.len());
+    for item in &new.items {
+        match resolve(&mut tx, item).await {
+            Ok(line) => lines.push(line),
+            Err(response) => return response,
+        }
+    }
+    let total = match quote::total(lines.iter().map(|l| (l.quantity, l.unit_price))) {
+        Some(total) => total,
+        None => return json_response(400, String::from("total out of range")),
+    };
+
+    if let Err(e) = sqlx::query(SUPERSEDE_QUERY)
+        .bind(order_id)
+        .execute(&mut tx)
+        .await
+    {
+        return json_response(500, format!("DB error - {e}"));
+    }
+    let token = token_hex(32);
+    let (quote_id,) = match sqlx::query_as::<_, (i32,)>(QUOTE_QUERY)
+        .bind(order_id)
+        .bind(current.id)
+        .bind(total)
+        .bind(remark)
+        .bind(token_hash(&token))
+        .bind(config.lifetime.num_seconds() as f64)
+        .fetch_one(&mut tx)
+        .await
+    {
+        Ok(id) => id,
+        Err(e) => return json_response(500, format!("DB error - {e}")),
+    };
+    for line in &lines {
+        if let Err(e) = sqlx::query(ITEM_QUERY)
+            .bind(quote_id)
+            .bind(line.kind.as_str())
+            .bind(line.fault_id)
+            .bind(line.accessory_id)
+            .bind(&line.description)
+            .bind(line.quantity)
+            .bind(line.unit_price)
+            .execute(&mut tx)
+            .await
+        {
+            return json_response(500, format!("DB error - {e}"));
+        }
+    }
+
+    let to = OrderState {
+        status_id: Some(status_id),
+        cost: from.cost,
+    };
+    if let Err(e) = order_move(&mut tx, order_id, Some(current.id), &from, &to).await {
+        return json_response(500, format!("{e}"));
+    }
+    let created = match query_quote(&mut tx, quote_id).await {
+        Ok(created) => created,
+        Err(e) => return json_response(500, format!("{e}")),
+    };
+    let event = AuditEvent::new("quote.create", "order", &sn)
+        .by(current)
+        .after(&created);
+    if let Err(e) = audit.record(&mut tx, event).await {
+        return json_response(500, format!("{e}"));
+    }
+    if let Err(e) = tx.commit().await {
+        return json_response(500, format!("DB error - {e}"));
+    }
+    info!(
+        "{} quoted {total} as version {} of order/{sn}",
+        current.account, created.version
+    );
+
+    /* the sheet is a copy outside of the transaction, it follows once the DB committed */
+    if let Some(Extension(gsheets)) = gsheets {
+        sheet_follow(gsheets, &database, &workflow, order_id, &to).await;
+    }
+
+    let resp = QuoteCreated {
+        code: 200,
+        quote: Some(created),
+        approval_url: Some(config.link(&token)),
+        token: Some(token),
+    };
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/quote/{token}",
+    params(
+        ("token" = String, Path, description = "token of the approval link")
+    ),
+    responses(
+        (status = 200, description = "the quote as the customer sees it, no login needed", body = QuoteViewResponse),
+        (status = 404, description = "no such quote", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("quote not found"))))),
+    )
+)]
+pub(crate) async fn quote_api(
+    Extension(database): Extension<Database>,
+    Path(token): Path<String>,
+) -> impl IntoResponse {
+    match query_quote_view(&database, &token).await {
+        Ok(Some(view)) => {
+            let resp = QuoteViewResponse {
+                code: 200,
+                quote: Some(view),
+            };
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Ok(None) => json_response(404, String::from("quote not found")),
+        Err(e) => json_response(500, format!("{e}")),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/quote/{token}",
+    params(
+        ("token" = String, Path, description = "token of the approval link")
+    ),
+    request_body = QuoteAnswer,
+    responses(
+        (status = 200, description = "answer recorded with the time and IP, no login needed; accepting moves the order to 更新 at the quoted cost, rejecting to 退件", body = QuoteViewResponse),
+        (status = 404, description = "no such quote", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("quote not found"))))),
+        (status = 409, description = "answered already", body = ApiResponse, example = json!(ApiResponse::new(409, Some(String::from("the quote is accepted already"))))),
+        (status = 410, description = "expired, or superseded by a revised quote", body = ApiResponse, example = json!(ApiResponse::new(410, Some(String::from("the quote has expired"))))),
+    )
+)]
+pub(crate) async fn quote_answer_api(
+    Extension(database): Extension<Database>,
+    gsheets: Option<Extension<SharedDcareGoogleSheet>>,
+    client: ClientInfo,
+    audit: Audit,
+    Path(token): Path<String>,
+    Json(answered): Json<QuoteAnswer>,
+) -> impl IntoResponse {
+    match answer(
+        &database,
+        gsheets.map(|Extension(gsheets)| gsheets),
+        &audit,
+        &token,
+        answered.decision,
+        &client,
+    )
+    .await
+    {
+        Ok(Answer::Done(sn)) => {
+            info!("customer {} quote of order/{sn}", answered.decision.state());
+            quote_api(Extension(database), Path(token))
+                .await
+                .into_response()
+        }
+        Ok(Answer::Unknown) => json_response(404, String::from("quote not found")),
+        Ok(Answer::Refused(refusal @ Refusal::Decided(_))) => {
+            json_response(409, format!("{refusal}"))
+        }
+        Ok(Answer::Refused(refusal)) => json_response(410, format!("{refusal}")),
+        Err(e) => json_response(500, format!("{e}")),
+    }
+}
+
+/// the approval page of the quote of `token` with `status`
+async fn quote_page(
+    database: &Database,
+    templates: &Templates,
+    token: &str,
+    status: StatusCode,
+) -> Response {
+    let view = match query_quote_view(database, token).await {
+        Ok(view) => view,
+        Err(e) => {
+            error!("quote page - {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let status = match view {
+        Some(_) => status,
+        None => StatusCode::NOT_FOUND,
+    };
+
+    let mut context = Context::new();
+    if let Some(ref view) = view {
+        context.insert("expires", &local_time(view.expires_at));
+        context.insert("decided", &view.decided_at.map(local_time));
+    }
+    context.insert("quote", &view);
+    match templates.render("quote.html", &context) {
+        Ok(page) => (status, Html(page)).into_response(),
+        Err(e) => {
+            error!("quote page - {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// the page the approval link opens, no login needed
+pub(crate) async fn quote_page_request(
+    Extension(database): Extension<Database>,
+    Extension(templates): Extension<Templates>,
+    Path(token): Path<String>,
+) -> impl IntoResponse {
+    quote_page(&database, &templates, &token, StatusCode::OK).await
+}
+
+/// the form of the approval page, the page again with the answer
+pub(crate) async fn quote_page_answer(
+    Extension(database): Extension<Database>,
+    Extension(templates): Extension<Templates>,
+    gsheets: Option<Extension<SharedDcareGoogleSheet>>,
+    client: ClientInfo,
+    audit: Audit,
+    Path(token): Path<String>,
+    Form(answered): Form<QuoteAnswer>,
+) -> impl IntoResponse {
+    let status = match answer(
+        &database,
+        gsheets.map(|Extension(gsheets)| gsheets),
+        &audit,
+        &token,
+        answered.decision,
+        &client,
+    )
+    .await
+    {
+        Ok(Answer::Done(sn)) => {
+            info!("customer {} quote of order/{sn}", answered.decision.state());
+            StatusCode::OK
+        }
+        Ok(Answer::Unknown) => StatusCode::NOT_FOUND,
+        Ok(Answer::Refused(Refusal::Decided(_))) => StatusCode::CONFLICT,
+        Ok(Answer::Refused(_)) => StatusCode::GONE,
+        Err(e) => {
+            error!("quote answer - {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    quote_page(&database, &templates, &token, status).await
+}
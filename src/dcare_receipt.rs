@@ -0,0 +1,462 @@
+use axum::{
+    extract::{Extension, Path, Query},
+    http::{header, HeaderValue, StatusCode},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::appearance::Catalog;
+use crate::audit::{Audit, AuditEvent};
+use crate::authentication::{AuthState, CurrentUser};
+use crate::dcare_order::{query_order, query_order_scope};
+use crate::department::department_shorten_query;
+use crate::errors::NotLoggedIn;
+use crate::policy::{self, OrderAction};
+use crate::rbac::{self, DepartmentRead, Require, TemplateManage};
+use crate::receipt::{self, Format, Kind, PdfFont, Slip, Store};
+use crate::{ApiResponse, Database, Templates};
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct PrintQuery {
+    /// `html` (default) or `pdf`
+    format: Option<Format>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema, sqlx::FromRow)]
+pub struct DepartmentTemplate {
+    #[schema(example = "receipt.html")]
+    name: String,
+    /// Tera template
+    body: String,
+    update_at: DateTime<Utc>,
+    /// account of the last editor
+    updated_by: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct DepartmentTemplatesResponse {
+    code: u16,
+    templates: Option<Vec<DepartmentTemplate>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct DepartmentTemplateBody {
+    #[schema(example = "<h1>{{ store.store_name }} {{ title }}</h1>{{ qr_svg | safe }}")]
+    body: String,
+}
+
+const SELECT: &str = r#"
+    SELECT
+        t.name,
+        t.body,
+        t.update_at,
+        u.account AS updated_by
+    FROM department_templates t
+        LEFT JOIN users u ON u.id = t.updated_by
+"#;
+
+fn json_response(code: u16, message: String) -> Response {
+    let resp = ApiResponse::new(code, Some(message));
+    if code >= 500 {
+        error!("{:?}", &resp);
+    }
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
+/// id of department `shorten` if within the user's reach, the response otherwise
+async fn department_id(
+    database: &Database,
+    current: &CurrentUser,
+    shorten: &str,
+) -> Result<i32, Response> {
+    match department_shorten_query(database, shorten).await {
+        Ok(id) if policy::sees_department(current, Some(id)) => Ok(id),
+        Ok(_) => Err(rbac::forbidden(format!(
+            "permission deny, department/{shorten}"
+        ))),
+        Err(e) => Err(json_response(404, format!("{e}"))),
+    }
+}
+
+async fn query_store(database: &Database, department_id: Option<i32>) -> Result<Store> {
+    const QUERY: &str = r#"
+        SELECT shorten, store_name, address, telephone
+        FROM departments
+        WHERE id = $1;"#;
+
+    let id = match department_id {
+        Some(id) => id,
+        None => return Ok(Store::default()),
+    };
+    sqlx::query_as::<_, Store>(QUERY)
+        .bind(id)
+        .fetch_optional(database)
+        .await
+        .map(Option::unwrap_or_default)
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+async fn query_templates(
+    database: &Database,
+    department_id: i32,
+) -> Result<Vec<DepartmentTemplate>> {
+    let query = format!("{SELECT} WHERE t.department_id = $1 ORDER BY t.name;");
+    sqlx::query_as::<_, DepartmentTemplate>(&query)
+        .bind(department_id)
+        .fetch_all(database)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+async fn query_template(
+    database: &Database,
+    department_id: i32,
+    name: &str,
+) -> Result<Option<DepartmentTemplate>> {
+    let query = format!("{SELECT} WHERE t.department_id = $1 AND t.name = $2;");
+    sqlx::query_as::<_, DepartmentTemplate>(&query)
+        .bind(department_id)
+        .bind(name)
+        .fetch_optional(database)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+/// `kind` of order `sn` as HTML or PDF
+async fn print(
+    kind: Kind,
+    mut auth_state: AuthState,
+    database: Database,
+    templates: Templates,
+    font: PdfFont,
+    sn: String,
+    format: Format,
+) -> Response {
+    let current = match auth_state.get_user().await {
+        Some(current) => current,
+        None => return json_response(400, format!("{}", &NotLoggedIn)),
+    };
+    let scope = match query_order_scope(&database, &sn).await {
+        Some((_, scope)) if policy::order_allowed(current, OrderAction::Read, scope) => scope,
+        Some(_) => return rbac::forbidden(format!("permission deny, order/{sn}")),
+        None => return json_response(404, format!("order/{sn} not found")),
+    };
+    let order = match query_order(&database, &sn).await {
+        Some(order) => order,
+        None => return json_response(404, format!("order/{sn} not found")),
+    };
+    let store = match query_store(&database, scope.department_id).await {
+        Ok(store) => store,
+        Err(e) => return json_response(500, format!("{e}")),
+    };
+    let catalog = match Catalog::load(&database).await {
+        Ok(catalog) => catalog,
+        Err(e) => return json_response(500, format!("{e}")),
+    };
+
+    let name = kind.template(format);
+    let custom = match scope.department_id {
+        Some(id) => match query_template(&database, id, &name).await {
+            Ok(custom) => custom.map(|t| t.body),
+            Err(e) => return json_response(500, format!("{e}")),
+        },
+        None => None,
+    };
+
+    let slip = Slip {
+        kind,
+        sn: &sn,
+        issue_at: order.issue_at(),
+        order: &order,
+        store: &store,
+        appearance: catalog.checklist(order.appearance()),
+        amounts: order.amounts(),
+    };
+    let rendered = match slip
+        .context(Utc::now())
+        .and_then(|context| receipt::render(&templates, &name, custom.as_deref(), &context))
+    {
+        Ok(rendered) => rendered,
+        Err(e) => return json_response(500, format!("{e}")),
+    };
+
+    match format {
+        /* department templates are anybody's HTML, no scripts, no same origin */
+        Format::Html => {
+            let mut response = Html(rendered).into_response();
+            response.headers_mut().insert(
+                header::CONTENT_SECURITY_POLICY,
+                HeaderValue::from_static("sandbox"),
+            );
+            response
+        }
+        Format::Pdf => {
+            let title = format!("{} {sn}", kind.title());
+            let bytes = match receipt::pdf(&title, &rendered, &sn, &font) {
+                Ok(bytes) => bytes,
+                Err(e) => return json_response(500, format!("{e}")),
+            };
+
+            let mut response = (StatusCode::OK, bytes).into_response();
+            let headers = response.headers_mut();
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/pdf"),
+            );
+            let disposition = format!("inline; filename=\"{sn}-{}.pdf\"", kind.name());
+            if let Ok(value) = HeaderValue::from_str(&disposition) {
+                headers.insert(header::CONTENT_DISPOSITION, value);
+            }
+            response
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/order/{sn}/receipt",
+    params(
+        ("sn" = String, Path, description = "order serial-number"),
+        PrintQuery,
+    ),
+    responses(
+        (status = 200, description = "intake receipt to sign at drop-off, `text/html` or `application/pdf`; the department's own template if it has one"),
+        (status = 403, description = "permission deny, order.read within reach required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 404, description = "order not found", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn order_receipt_request(
+    Extension(auth_state): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    Extension(templates): Extension<Templates>,
+    Extension(font): Extension<PdfFont>,
+    Path(sn): Path<String>,
+    Query(query): Query<PrintQuery>,
+) -> impl IntoResponse {
+    let format = query.format.unwrap_or_default();
+    print(
+        Kind::Receipt,
+        auth_state,
+        database,
+        templates,
+        font,
+        sn,
+        format,
+    )
+    .await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/order/{sn}/invoice",
+    params(
+        ("sn" = String, Path, description = "order serial-number"),
+        PrintQuery,
+    ),
+    responses(
+        (status = 200, description = "repair invoice with cost, prepaid_free, confirmed_paid and the amount due, `text/html` or `application/pdf`; the department's own template if it has one"),
+        (status = 403, description = "permission deny, order.read within reach required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 404, description = "order not found", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn order_invoice_request(
+    Extension(auth_state): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    Extension(templates): Extension<Templates>,
+    Extension(font): Extension<PdfFont>,
+    Path(sn): Path<String>,
+    Query(query): Query<PrintQuery>,
+) -> impl IntoResponse {
+    let format = query.format.unwrap_or_default();
+    print(
+        Kind::Invoice,
+        auth_state,
+        database,
+        templates,
+        font,
+        sn,
+        format,
+    )
+    .await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/department/{shorten}/templates",
+    params(
+        ("shorten" = String, Path, description = "department shorten name")
+    ),
+    responses(
+        (status = 200, description = "the department's own templates, the built-in ones are used for the others", body = DepartmentTemplatesResponse),
+        (status = 403, description = "permission deny, department.read within reach required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 404, description = "department not found", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn department_templates_request(
+    current: Require<DepartmentRead>,
+    Extension(database): Extension<Database>,
+    Path(shorten): Path<String>,
+) -> impl IntoResponse {
+    let department_id = match department_id(&database, &current, &shorten).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+
+    match query_templates(&database, department_id).await {
+        Ok(templates) => {
+            let resp = DepartmentTemplatesResponse {
+                code: 200,
+                templates: Some(templates),
+            };
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => json_response(500, format!("{e}")),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/department/{shorten}/templates/{name}",
+    params(
+        ("shorten" = String, Path, description = "department shorten name"),
+        ("name" = String, Path, description = "receipt.html, receipt.txt, invoice.html or invoice.txt; the `.txt` ones are the text of the PDFs"),
+    ),
+    request_body = DepartmentTemplateBody,
+    responses(
+        (status = 200, description = "the department prints with this template from now on", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 400, description = "unknown template name or the template doesn't parse", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, template.manage required, and the department within reach", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 404, description = "department not found", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn department_template_update(
+    current: Require<TemplateManage>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+    Path((shorten, name)): Path<(String, String)>,
+    Json(template): Json<DepartmentTemplateBody>,
+) -> impl IntoResponse {
+    if !receipt::is_template(&name) {
+        return json_response(400, format!("unknown template {name}"));
+    }
+    if let Some(e) = receipt::template_error(&name, &template.body) {
+        return json_response(400, format!("{name} - {e}"));
+    }
+    let department_id = match department_id(&database, &current, &shorten).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let before = match query_template(&database, department_id, &name).await {
+        Ok(before) => before,
+        Err(e) => return json_response(500, format!("{e}")),
+    };
+
+    const QUERY: &str = r#"
+        INSERT INTO department_templates (department_id, name, body, updated_by)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (department_id, name) DO UPDATE
+        SET body = EXCLUDED.body, update_at = NOW(), updated_by = EXCLUDED.updated_by;"#;
+    if let Err(e) = sqlx::query(QUERY)
+        .bind(department_id)
+        .bind(&name)
+        .bind(&template.body)
+        .bind(current.id)
+        .execute(&database)
+        .await
+    {
+        return json_response(500, format!("DB error - {e}"));
+    }
+
+    let mut event = AuditEvent::new("department.template_update", "department", &shorten)
+        .by(&*current)
+        .after(&template);
+    if let Some(before) = &before {
+        event = event.before(before);
+    }
+    let _ = audit.record(&database, event).await;
+
+    json_response(200, String::from("success"))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/department/{shorten}/templates/{name}",
+    params(
+        ("shorten" = String, Path, description = "department shorten name"),
+        ("name" = String, Path, description = "template name"),
+    ),
+    responses(
+        (status = 200, description = "the department prints with the built-in template again", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 403, description = "permission deny, template.manage required, and the department within reach", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 404, description = "department or its own template not found", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn department_template_delete(
+    current: Require<TemplateManage>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+    Path((shorten, name)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let department_id = match department_id(&database, &current, &shorten).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let before = match query_template(&database, department_id, &name).await {
+        Ok(Some(before)) => before,
+        Ok(None) => {
+            return json_response(
+                404,
+                format!("department/{shorten}/templates/{name} not found"),
+            )
+        }
+        Err(e) => return json_response(500, format!("{e}")),
+    };
+
+    const QUERY: &str = "DELETE FROM department_templates WHERE department_id = $1 AND name = $2;";
+    if let Err(e) = sqlx::query(QUERY)
+        .bind(department_id)
+        .bind(&name)
+        .execute(&database)
+        .await
+    {
+        return json_response(500, format!("DB error - {e}"));
+    }
+
+    let event = AuditEvent::new("department.template_delete", "department", &shorten)
+        .by(&*current)
+        .before(&before);
+    let _ = audit.record(&database, event).await;
+
+    json_response(200, String::from("success"))
+}
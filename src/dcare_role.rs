@@ -0,0 +1,670 @@
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, Postgres, Transaction};
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::audit::{Audit, AuditEvent};
+use crate::authentication::AuthState;
+use crate::dcare_user::query_user_id;
+use crate::errors::NotLoggedIn;
+use crate::rbac::{self, Require, RoleManage, UserRead};
+use crate::{ApiResponse, Database};
+
+/// seeded by the schema, can't be deleted
+const BUILTIN_ROLES: &[&str] = &[
+    "admin",
+    "gm",
+    "maintainer",
+    "comissioner",
+    "jshall",
+    "other",
+];
+/// holds every permission, always
+const ADMIN_ROLE: &str = "admin";
+
+#[derive(Debug, Serialize, Deserialize, ToSchema, sqlx::FromRow)]
+pub struct RoleInfo {
+    #[schema(example = "maintainer")]
+    name: String,
+    description: Option<String>,
+    #[schema(example = json!(["order.read", "order.update"]))]
+    permissions: Vec<String>,
+    /// users holding the role
+    users: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RolesResponse {
+    code: u16,
+    roles: Option<Vec<RoleInfo>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema, sqlx::FromRow)]
+pub struct PermissionInfo {
+    #[schema(example = "order.update")]
+    name: String,
+    description: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PermissionsResponse {
+    code: u16,
+    permissions: Option<Vec<PermissionInfo>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RoleNew {
+    /// lower case letters, digits, `-` or `_`
+    #[schema(example = "auditor")]
+    name: String,
+    description: Option<String>,
+    #[schema(example = json!(["user.read", "order.read"]))]
+    permissions: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RoleUpdate {
+    description: Option<String>,
+    /// replaces all permissions of the role
+    permissions: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct UserRolesUpdate {
+    /// replaces all roles of the user
+    #[schema(example = json!(["maintainer"]))]
+    roles: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct UserRolesResponse {
+    code: u16,
+    account: String,
+    roles: Vec<String>,
+    /// effective, over all roles
+    permissions: Vec<String>,
+}
+
+fn valid_role_name(name: &str) -> bool {
+    (1..=32).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| matches!(c, 'a'..='z' | '0'..='9' | '-' | '_'))
+}
+
+async fn query_roles<'e, E>(executor: E) -> Result<Vec<RoleInfo>>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    const QUERY: &str = r#"
+        SELECT
+            r.name,
+            r.description,
+            COALESCE(array_agg(p.name ORDER BY p.name) FILTER (WHERE p.name IS NOT NULL), '{}')
+                AS permissions,
+            (SELECT COUNT(*) FROM user_roles WHERE role_id = r.id) AS users
+        FROM roles r
+        LEFT JOIN role_permissions rp ON rp.role_id = r.id
+        LEFT JOIN permissions p ON p.id = rp.permission_id
+        GROUP BY r.id
+        ORDER BY r.id;
+    "#;
+
+    sqlx::query_as::<_, RoleInfo>(QUERY)
+        .fetch_all(executor)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+/// of the audit log, `None` if not found
+async fn query_role<'e, E>(executor: E, name: &str) -> Option<RoleInfo>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    query_roles(executor)
+        .await
+        .ok()?
+        .into_iter()
+        .find(|r| r.name == name)
+}
+
+async fn query_permissions(database: &Database) -> Result<Vec<PermissionInfo>> {
+    const QUERY: &str = "SELECT name, description FROM permissions ORDER BY name;";
+
+    sqlx::query_as::<_, PermissionInfo>(QUERY)
+        .fetch_all(database)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+/// names in `permissions` not known
+async fn unknown_permissions(database: &Database, permissions: &[String]) -> Result<Vec<String>> {
+    const QUERY: &str = r#"
+        SELECT name FROM UNNEST($1::text[]) AS name
+        WHERE name NOT IN (SELECT name FROM permissions);
+    "#;
+
+    sqlx::query_as(QUERY)
+        .bind(permissions)
+        .fetch_all(database)
+        .await
+        .map(|rows: Vec<(String,)>| rows.into_iter().map(|(name,)| name).collect())
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+/// insert or update in `tx`, `permissions` replace the current ones, `Ok(false)` if the
+/// role exists already (insert) or is not found (update)
+async fn role_save(
+    tx: &mut Transaction<'_, Postgres>,
+    name: &str,
+    description: Option<&str>,
+    permissions: Option<&[String]>,
+    create: bool,
+) -> Result<bool> {
+    const INSERT_QUERY: &str = r#"
+        INSERT INTO roles (name, description) VALUES ($1, $2)
+        ON CONFLICT (name) DO NOTHING RETURNING id;
+    "#;
+    const UPDATE_QUERY: &str = r#"
+        UPDATE roles SET description = COALESCE($2, description)
+        WHERE name = $1 RETURNING id;
+    "#;
+    const DELETE_QUERY: &str = "DELETE FROM role_permissions WHERE role_id = $1;";
+    const GRANT_QUERY: &str = r#"
+        INSERT INTO role_permissions (role_id, permission_id)
+        SELECT $1, id FROM permissions WHERE name = ANY($2);
+    "#;
+
+    let role_id: Option<(i32,)> = sqlx::query_as(if create { INSERT_QUERY } else { UPDATE_QUERY })
+        .bind(name)
+        .bind(description)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+    let role_id = match role_id {
+        Some((role_id,)) => role_id,
+        None => return Ok(false),
+    };
+
+    if let Some(permissions) = permissions {
+        sqlx::query(DELETE_QUERY)
+            .bind(role_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| anyhow!("DB error - {e}"))?;
+        sqlx::query(GRANT_QUERY)
+            .bind(role_id)
+            .bind(permissions)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| anyhow!("DB error - {e}"))?;
+    }
+
+    Ok(true)
+}
+
+/// `Ok(None)` no such role, `Ok(Some(n))` users still holding it, nothing deleted then
+async fn role_delete<'e, E>(executor: E, name: &str) -> Result<Option<i64>>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    const QUERY: &str = r#"
+        WITH target AS (
+            SELECT id, (SELECT COUNT(*) FROM user_roles WHERE role_id = roles.id) AS users
+            FROM roles WHERE name = $1
+        ), deleted AS (
+            DELETE FROM roles WHERE id IN (SELECT id FROM target WHERE users = 0)
+        )
+        SELECT users FROM target;
+    "#;
+
+    sqlx::query_as(QUERY)
+        .bind(name)
+        .fetch_optional(executor)
+        .await
+        .map(|row: Option<(i64,)>| row.map(|(users,)| users))
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+async fn user_roles_response<'e, E>(executor: E, account: &str) -> Result<Option<UserRolesResponse>>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    const QUERY: &str = r#"
+        SELECT account, user_role_names(id), user_permission_names(id)
+        FROM users WHERE account = $1 AND deleted_at IS NULL;
+    "#;
+
+    sqlx::query_as(QUERY)
+        .bind(account)
+        .fetch_optional(executor)
+        .await
+        .map(|row: Option<(String, Vec<String>, Vec<String>)>| {
+            row.map(|(account, roles, permissions)| UserRolesResponse {
+                code: 200,
+                account,
+                roles,
+                permissions,
+            })
+        })
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+fn error_response(e: anyhow::Error) -> axum::response::Response {
+    let resp = ApiResponse::new(500, Some(format!("{e}")));
+    error!("{:?}", &resp);
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
+/// 400 for unknown permission names
+async fn check_permissions(
+    database: &Database,
+    permissions: &[String],
+) -> Result<(), axum::response::Response> {
+    match unknown_permissions(database, permissions).await {
+        Ok(unknown) if unknown.is_empty() => Ok(()),
+        Ok(unknown) => {
+            let resp = ApiResponse::new(
+                400,
+                Some(format!("unknown permission {}", unknown.join(", "))),
+            );
+            Err((StatusCode::OK, Json(resp)).into_response())
+        }
+        Err(e) => Err(error_response(e)),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/roles",
+    responses(
+        (status = 200, description = "roles and their permissions", body = RolesResponse),
+        (status = 403, description = "permission deny, user.read required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn roles_api(
+    _: Require<UserRead>,
+    Extension(database): Extension<Database>,
+) -> impl IntoResponse {
+    match query_roles(&database).await {
+        Ok(roles) => {
+            let resp = RolesResponse {
+                code: 200,
+                roles: Some(roles),
+            };
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => error_response(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/roles",
+    request_body = RoleNew,
+    responses(
+        (status = 200, description = "role created", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 400, description = "invalid name, unknown permission, ", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, role.manage required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 409, description = "role exists", body = ApiResponse, example = json!(ApiResponse::new(409, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn role_create_api(
+    current: Require<RoleManage>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+    Json(role): Json<RoleNew>,
+) -> impl IntoResponse {
+    if !valid_role_name(&role.name) {
+        let resp = ApiResponse::new(400, Some(format!("invalid role name {}", role.name)));
+        return (StatusCode::OK, Json(resp)).into_response();
+    }
+    if let Err(resp) = check_permissions(&database, &role.permissions).await {
+        return resp;
+    }
+
+    let mut tx = match database.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return error_response(anyhow!("DB error - {e}")),
+    };
+    let resp = match role_save(
+        &mut tx,
+        &role.name,
+        role.description.as_deref(),
+        Some(&role.permissions),
+        true,
+    )
+    .await
+    {
+        Ok(true) => {
+            let event = AuditEvent::new("role.create", "role", &role.name)
+                .by(&*current)
+                .after(&role);
+            if let Err(e) = audit.commit(tx, event).await {
+                return error_response(e);
+            }
+            info!("{} created role/{}", current.account, role.name);
+            ApiResponse::new(200, Some(String::from("success")))
+        }
+        Ok(false) => ApiResponse::new(409, Some(format!("role/{} exists", role.name))),
+        Err(e) => return error_response(e),
+    };
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/roles/{name}",
+    params(
+        ("name" = String, Path, description = "role name")
+    ),
+    request_body = RoleUpdate,
+    responses(
+        (status = 200, description = "role updated", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 400, description = "unknown permission", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+        (status = 404, description = "role not found", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, role.manage required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 409, description = "permissions of admin are fixed", body = ApiResponse, example = json!(ApiResponse::new(409, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn role_update_api(
+    current: Require<RoleManage>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+    Path(name): Path<String>,
+    Json(role): Json<RoleUpdate>,
+) -> impl IntoResponse {
+    if name == ADMIN_ROLE && role.permissions.is_some() {
+        let resp = ApiResponse::new(409, Some(String::from("admin holds every permission")));
+        return (StatusCode::OK, Json(resp)).into_response();
+    }
+    if let Some(permissions) = &role.permissions {
+        if let Err(resp) = check_permissions(&database, permissions).await {
+            return resp;
+        }
+    }
+
+    let mut tx = match database.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return error_response(anyhow!("DB error - {e}")),
+    };
+    let before = query_role(&mut tx, &name).await;
+    let resp = match role_save(
+        &mut tx,
+        &name,
+        role.description.as_deref(),
+        role.permissions.as_deref(),
+        false,
+    )
+    .await
+    {
+        Ok(true) => {
+            let event = AuditEvent::new("role.update", "role", &name)
+                .by(&*current)
+                .before(&before)
+                .after(&query_role(&mut tx, &name).await);
+            if let Err(e) = audit.commit(tx, event).await {
+                return error_response(e);
+            }
+            info!("{} updated role/{name}", current.account);
+            ApiResponse::new(200, Some(String::from("success")))
+        }
+        Ok(false) => ApiResponse::new(404, Some(format!("role/{name} not found"))),
+        Err(e) => return error_response(e),
+    };
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/roles/{name}",
+    params(
+        ("name" = String, Path, description = "role name")
+    ),
+    responses(
+        (status = 200, description = "role deleted", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 404, description = "role not found", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, role.manage required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 409, description = "built-in role, or still assigned to users", body = ApiResponse, example = json!(ApiResponse::new(409, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn role_delete_api(
+    current: Require<RoleManage>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    if BUILTIN_ROLES.contains(&name.as_str()) {
+        let resp = ApiResponse::new(409, Some(format!("role/{name} is built-in")));
+        return (StatusCode::OK, Json(resp)).into_response();
+    }
+
+    let mut tx = match database.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return error_response(anyhow!("DB error - {e}")),
+    };
+    let before = query_role(&mut tx, &name).await;
+    let resp = match role_delete(&mut tx, &name).await {
+        Ok(Some(0)) => {
+            let event = AuditEvent::new("role.delete", "role", &name)
+                .by(&*current)
+                .before(&before);
+            if let Err(e) = audit.commit(tx, event).await {
+                return error_response(e);
+            }
+            info!("{} deleted role/{name}", current.account);
+            ApiResponse::new(200, Some(String::from("success")))
+        }
+        Ok(Some(users)) => ApiResponse::new(
+            409,
+            Some(format!("role/{name} still assigned to {users} user(s)")),
+        ),
+        Ok(None) => ApiResponse::new(404, Some(format!("role/{name} not found"))),
+        Err(e) => return error_response(e),
+    };
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/permissions",
+    responses(
+        (status = 200, description = "every permission a role can grant", body = PermissionsResponse),
+        (status = 403, description = "permission deny, user.read required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn permissions_api(
+    _: Require<UserRead>,
+    Extension(database): Extension<Database>,
+) -> impl IntoResponse {
+    match query_permissions(&database).await {
+        Ok(permissions) => {
+            let resp = PermissionsResponse {
+                code: 200,
+                permissions: Some(permissions),
+            };
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => error_response(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/me/permissions",
+    responses(
+        (status = 200, description = "roles and effective permissions of myself", body = UserRolesResponse),
+        (status = 400, description = "not login", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn my_permissions_api(
+    Extension(mut current_user): Extension<AuthState>,
+) -> impl IntoResponse {
+    match current_user.get_user().await {
+        Some(user) => {
+            let resp = UserRolesResponse {
+                code: 200,
+                account: user.account.clone(),
+                roles: user.roles.clone(),
+                permissions: user.permissions.clone(),
+            };
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        None => {
+            let resp = ApiResponse::new(400, Some(format!("{}", &NotLoggedIn)));
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/user/{account}/roles",
+    params(
+        ("account" = String, Path, description = "user account")
+    ),
+    responses(
+        (status = 200, description = "roles and effective permissions of the user", body = UserRolesResponse),
+        (status = 404, description = "user not found", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, user.read required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn user_roles_api(
+    _: Require<UserRead>,
+    Extension(database): Extension<Database>,
+    Path(account): Path<String>,
+) -> impl IntoResponse {
+    match user_roles_response(&database, &account).await {
+        Ok(Some(resp)) => (StatusCode::OK, Json(resp)).into_response(),
+        Ok(None) => {
+            let resp = ApiResponse::new(404, Some(format!("user/{account} not found")));
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => error_response(e),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/user/{account}/roles",
+    params(
+        ("account" = String, Path, description = "user account")
+    ),
+    request_body = UserRolesUpdate,
+    responses(
+        (status = 200, description = "roles of the user replaced", body = UserRolesResponse),
+        (status = 400, description = "no or unknown role", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+        (status = 404, description = "user not found", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, role.manage required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn user_roles_update_api(
+    current: Require<RoleManage>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+    Path(account): Path<String>,
+    Json(update): Json<UserRolesUpdate>,
+) -> impl IntoResponse {
+    if update.roles.is_empty() {
+        let resp = ApiResponse::new(400, Some(String::from("at least one role")));
+        return (StatusCode::OK, Json(resp)).into_response();
+    }
+
+    let user_id = match query_user_id(&database, &account).await {
+        Some(id) => id,
+        None => {
+            let resp = ApiResponse::new(404, Some(format!("user/{account} not found")));
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+    };
+
+    let before = user_roles_response(&database, &account)
+        .await
+        .ok()
+        .flatten();
+    /* recorded with the roles, or neither */
+    let mut tx = match database.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return error_response(anyhow!("DB error - {e}")),
+    };
+    match rbac::user_roles_replace(&mut tx, user_id, &update.roles).await {
+        Ok(true) => info!(
+            "{} set roles of {account} to {:?}",
+            current.account, update.roles
+        ),
+        Ok(false) => {
+            let resp = ApiResponse::new(400, Some(String::from("unknown role")));
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+        Err(e) => return error_response(e),
+    }
+
+    let resp = match user_roles_response(&mut tx, &account).await {
+        Ok(Some(resp)) => resp,
+        Ok(None) => {
+            let resp = ApiResponse::new(404, Some(format!("user/{account} not found")));
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+        Err(e) => return error_response(e),
+    };
+    let event = AuditEvent::new("user.roles", "user", &account)
+        .by(&*current)
+        .before(&before)
+        .after(&resp);
+    if let Err(e) = audit.record(&mut tx, event).await {
+        return error_response(e);
+    }
+    if let Err(e) = tx.commit().await {
+        return error_response(anyhow!("DB error - {e}"));
+    }
+
+    (StatusCode::OK, Json(resp)).into_response()
+}
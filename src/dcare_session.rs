@@ -0,0 +1,365 @@
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::error;
+use utoipa::ToSchema;
+
+use crate::audit::{Audit, AuditEvent};
+use crate::authentication::{AuthState, SessionToken};
+use crate::dcare_user::query_user_id;
+use crate::errors::NotLoggedIn;
+use crate::rbac::{self, Permission, UserSecurity};
+use crate::{ApiResponse, Database};
+
+#[derive(Debug, Serialize, Deserialize, ToSchema, sqlx::FromRow)]
+pub struct SessionInfo {
+    id: i32,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    created_at: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    /// the session this request is made with
+    current: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct SessionsResponse {
+    code: u16,
+    sessions: Option<Vec<SessionInfo>>,
+}
+
+async fn query_sessions(
+    database: &Database,
+    user_id: i32,
+    current: Option<SessionToken>,
+) -> Result<Vec<SessionInfo>> {
+    const QUERY: &str = r#"
+        SELECT
+            id,
+            user_agent,
+            ip,
+            created_at,
+            last_seen_at,
+            expires_at,
+            COALESCE(session_token = $2, false) AS current
+        FROM sessions
+        WHERE user_id = $1 AND expires_at > NOW()
+        ORDER BY last_seen_at DESC;
+    "#;
+
+    sqlx::query_as::<_, SessionInfo>(QUERY)
+        .bind(user_id)
+        .bind(current.map(|t| t.into_database_value()))
+        .fetch_all(database)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+async fn revoke_session(database: &Database, user_id: i32, id: i32) -> Result<bool> {
+    const QUERY: &str = "DELETE FROM sessions WHERE user_id = $1 AND id = $2;";
+
+    sqlx::query(QUERY)
+        .bind(user_id)
+        .bind(id)
+        .execute(database)
+        .await
+        .map(|r| r.rows_affected() > 0)
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+/// revoke every session of the user, except `keep` if given
+pub(crate) async fn revoke_sessions(
+    database: &Database,
+    user_id: i32,
+    keep: Option<SessionToken>,
+) -> Result<u64> {
+    const QUERY: &str = r#"
+        DELETE FROM sessions
+        WHERE user_id = $1 AND ($2::bytea IS NULL OR session_token <> $2);
+    "#;
+
+    sqlx::query(QUERY)
+        .bind(user_id)
+        .bind(keep.map(|t| t.into_database_value()))
+        .execute(database)
+        .await
+        .map(|r| r.rows_affected())
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/me/sessions",
+    responses(
+        (status = 200, description = "list my active sessions/devices", body = SessionsResponse),
+        (status = 400, description = "not login, ", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn my_sessions_api(
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+) -> impl IntoResponse {
+    let user_id = if let Some(myself) = current_user.get_user().await {
+        myself.id
+    } else {
+        let resp = ApiResponse::new(400, Some(format!("{}", &NotLoggedIn)));
+        return (StatusCode::OK, Json(resp)).into_response();
+    };
+
+    let sessions = query_sessions(&database, user_id, current_user.session_token()).await;
+    sessions_response(sessions)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/me/sessions/{id}",
+    params(
+        ("id" = i32, Path, description = "session id to revoke")
+    ),
+    responses(
+        (status = 200, description = "revoke success", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 400, description = "not login, ", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+        (status = 404, description = "session not found, ", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn my_session_revoke_api(
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+    Path(id): Path<i32>,
+) -> impl IntoResponse {
+    let user_id = if let Some(myself) = current_user.get_user().await {
+        myself.id
+    } else {
+        let resp = ApiResponse::new(400, Some(format!("{}", &NotLoggedIn)));
+        return (StatusCode::OK, Json(resp)).into_response();
+    };
+
+    let result = revoke_session(&database, user_id, id).await;
+    if let Ok(true) = result {
+        let event =
+            AuditEvent::new("session.revoke", "session", id).by(current_user.get_user().await);
+        let _ = audit.record(&database, event).await;
+    }
+    revoke_response(result, id)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/me/sessions",
+    responses(
+        (status = 200, description = "revoke all sessions but the current one", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 400, description = "not login, ", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn my_sessions_revoke_others_api(
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+) -> impl IntoResponse {
+    let (user_id, account) = if let Some(myself) = current_user.get_user().await {
+        (myself.id, myself.account.clone())
+    } else {
+        let resp = ApiResponse::new(400, Some(format!("{}", &NotLoggedIn)));
+        return (StatusCode::OK, Json(resp)).into_response();
+    };
+
+    let revoked = revoke_sessions(&database, user_id, current_user.session_token()).await;
+    if let Ok(n) = revoked {
+        let event = AuditEvent::new("session.revoke_all", "user", &account)
+            .by(current_user.get_user().await)
+            .after(&json!({ "revoked": n }));
+        let _ = audit.record(&database, event).await;
+    }
+    revoke_all_response(revoked)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/user/{account}/sessions",
+    params(
+        ("account" = String, Path, description = "user account")
+    ),
+    responses(
+        (status = 200, description = "list active sessions of user", body = SessionsResponse),
+        (status = 404, description = "user not found, ", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+        (status = 405, description = "permission deny", body = ApiResponse, example = json!(ApiResponse::new(405, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn user_sessions_api(
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    Path(account): Path<String>,
+) -> impl IntoResponse {
+    let user_id = match admin_target(&mut current_user, &database, &account).await {
+        Ok(id) => id,
+        Err(resp) => return (StatusCode::OK, Json(resp)).into_response(),
+    };
+
+    let sessions = query_sessions(&database, user_id, current_user.session_token()).await;
+    sessions_response(sessions)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/user/{account}/sessions/{id}",
+    params(
+        ("account" = String, Path, description = "user account"),
+        ("id" = i32, Path, description = "session id to revoke"),
+    ),
+    responses(
+        (status = 200, description = "revoke success", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 404, description = "user/session not found, ", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+        (status = 405, description = "permission deny", body = ApiResponse, example = json!(ApiResponse::new(405, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn user_session_revoke_api(
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+    Path((account, id)): Path<(String, i32)>,
+) -> impl IntoResponse {
+    let user_id = match admin_target(&mut current_user, &database, &account).await {
+        Ok(id) => id,
+        Err(resp) => return (StatusCode::OK, Json(resp)).into_response(),
+    };
+
+    let result = revoke_session(&database, user_id, id).await;
+    if let Ok(true) = result {
+        let event = AuditEvent::new("session.revoke", "session", id)
+            .by(current_user.get_user().await)
+            .after(&json!({ "account": account }));
+        let _ = audit.record(&database, event).await;
+    }
+    revoke_response(result, id)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/user/{account}/sessions",
+    params(
+        ("account" = String, Path, description = "user account")
+    ),
+    responses(
+        (status = 200, description = "revoke all sessions of user, except the caller's own", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 404, description = "user not found, ", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+        (status = 405, description = "permission deny", body = ApiResponse, example = json!(ApiResponse::new(405, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn user_sessions_revoke_api(
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+    Path(account): Path<String>,
+) -> impl IntoResponse {
+    let user_id = match admin_target(&mut current_user, &database, &account).await {
+        Ok(id) => id,
+        Err(resp) => return (StatusCode::OK, Json(resp)).into_response(),
+    };
+
+    /* keeps the admin's own session when the target is the admin */
+    let revoked = revoke_sessions(&database, user_id, current_user.session_token()).await;
+    if let Ok(n) = revoked {
+        let event = AuditEvent::new("session.revoke_all", "user", &account)
+            .by(current_user.get_user().await)
+            .after(&json!({ "revoked": n }));
+        let _ = audit.record(&database, event).await;
+    }
+    revoke_all_response(revoked)
+}
+
+/// `user.security` holders only, resolve the target account to its user-id
+async fn admin_target(
+    current_user: &mut AuthState,
+    database: &Database,
+    account: &str,
+) -> Result<i32, ApiResponse> {
+    if !rbac::allowed(current_user.get_user().await, UserSecurity::NAME) {
+        return Err(ApiResponse::new(405, Some(String::from("permission deny"))));
+    }
+
+    query_user_id(database, account)
+        .await
+        .ok_or_else(|| ApiResponse::new(404, Some(format!("user/{account} not found"))))
+}
+
+fn sessions_response(sessions: Result<Vec<SessionInfo>>) -> axum::response::Response {
+    match sessions {
+        Ok(sessions) => {
+            let resp = SessionsResponse {
+                code: 200,
+                sessions: Some(sessions),
+            };
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => {
+            let resp = ApiResponse::new(500, Some(format!("{e}")));
+            error!("{:?}", &resp);
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+    }
+}
+
+fn revoke_response(revoked: Result<bool>, id: i32) -> axum::response::Response {
+    let resp = match revoked {
+        Ok(true) => ApiResponse::new(200, Some(String::from("success"))),
+        Ok(false) => ApiResponse::new(404, Some(format!("session/{id} not found"))),
+        Err(e) => {
+            let resp = ApiResponse::new(500, Some(format!("{e}")));
+            error!("{:?}", &resp);
+            resp
+        }
+    };
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
+fn revoke_all_response(revoked: Result<u64>) -> axum::response::Response {
+    let resp = match revoked {
+        Ok(num) => ApiResponse::new(200, Some(format!("{num} session(s) revoked"))),
+        Err(e) => {
+            let resp = ApiResponse::new(500, Some(format!("{e}")));
+            error!("{:?}", &resp);
+            resp
+        }
+    };
+    (StatusCode::OK, Json(resp)).into_response()
+}
@@ -24,18 +24,31 @@ use bit_vec::BitVec;
 use chrono::{DateTime, NaiveDate, Utc};
 use serde::{/*serde_if_integer128, */ Deserialize, Serialize};
 use serde_json::json;
+use sqlx::{Executor, Postgres};
 use tracing::{debug, error, info};
 use utoipa::{IntoParams, ToSchema};
 
+use crate::audit::{Audit, AuditEvent};
 use crate::authentication::{
     /*auth, SessionToken,*/
-    delete_user2, login, password_hashed, signup2, AuthState, CurrentUser,
+    delete_user2, login, password_hashed, signup2, AuthConfig, AuthState, ClientInfo, CurrentUser,
+    LoginSession, MfaChallenge, SessionToken,
 };
-use crate::errors::NotLoggedIn;
+use crate::errors::{LoginError, NotLoggedIn, SignupError, SignupErrorResponse};
+use crate::etag::{self, IfMatch};
+use crate::login_throttle;
+use crate::password_policy;
+use crate::policy;
+use crate::query_filter::{list, ListFilter};
+use crate::rbac::{
+    self, Permission, Require, RoleManage, TrashManage, UserCreate, UserDelete, UserRead,
+    UserSecurity, UserUpdate,
+};
+use crate::trash::{self, Trash};
 //use crate::errors::{LoginError, NoUser, SignupError};
-use crate::dcare_order::query_order_by_user_id;
 use crate::department::{department_shorten_query, shared_store_departments_init};
-use crate::{ApiResponse, Database, Random, SharedState, COOKIE_MAX_AGE, USER_COOKIE_NAME};
+use crate::utils::session_cookie;
+use crate::{ApiResponse, Database, Random, SharedState, USER_COOKIE_NAME};
 
 /*#[derive(Clone)]
 pub struct SharedUserMap {
@@ -93,142 +106,36 @@ pub struct UserListQuery {
 }
 
 impl UserListQuery {
-    pub fn parse(mine: Option<Query<Self>>) -> (i32, i32, String) {
-        if let Some(ref q) = mine {
-            let offset = q.offset.map_or(0, |o| o);
-            let entries = q.entries.map_or(100, |e| e);
-
-            let where_is = if let Some(ref p) = q.phone {
-                format!("WHERE u.customer_phone = '{p}'")
-            } else {
-                "".to_string()
-            };
-            let where_is = if let Some(ref d) = q.department {
-                let sql_d =
-                    format!("u.department_id = (SELECT id FROM departments WHERE shorten = '{d}')");
-                if where_is.is_empty() {
-                    format!("WHERE {sql_d}")
-                } else {
-                    format!("{where_is} AND {sql_d}")
-                }
-            } else {
-                where_is
-            };
-            /* TODO permission which serde to? */
-            let where_is = if let Some(ref p) = q.permission {
-                let sql_u = format!("u.permission & {:?}", p);
-                if where_is.is_empty() {
-                    format!("WHERE {sql_u}")
-                } else {
-                    format!("{where_is} AND {sql_u}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref u) = q.username {
-                let sql_u = format!("u.username = '{u}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql_u}")
-                } else {
-                    format!("{where_is} AND {sql_u}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref u) = q.worker_id {
-                let sql_u = format!("u.work_id = '{u}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql_u}")
-                } else {
-                    format!("{where_is} AND {sql_u}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref t) = q.title {
-                let sql = format!("u.title_id = (SELECT id FROM titles WHERE name = '{t}')");
-                if where_is.is_empty() {
-                    format!("WHERE {sql}")
-                } else {
-                    format!("{where_is} AND {sql}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref e) = q.email {
-                let sql_u = format!("u.email = '{e}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql_u}")
-                } else {
-                    format!("{where_is} AND {sql_u}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref s) = q.create_start {
-                let sql = format!("u.create_at >= '{s}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql}")
-                } else {
-                    format!("{where_is} AND {sql}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref s) = q.create_end {
-                let sql = format!("u.create_at < '{s}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql}")
-                } else {
-                    format!("{where_is} AND {sql}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref s) = q.login_start {
-                let sql = format!("u.login_at >= '{s}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql}")
-                } else {
-                    format!("{where_is} AND {sql}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref s) = q.login_end {
-                let sql = format!("u.login_at < '{s}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql}")
-                } else {
-                    format!("{where_is} AND {sql}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref s) = q.update_start {
-                let sql = format!("u.update_at >= '{s}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql}")
-                } else {
-                    format!("{where_is} AND {sql}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref s) = q.update_end {
-                let sql = format!("u.update_at < '{s}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql}")
-                } else {
-                    format!("{where_is} AND {sql}")
-                }
-            } else {
-                where_is
-            };
-
-            (offset, entries, where_is)
+    /// add the filters to `filter`, `(offset, entries)` of the page
+    pub fn parse(mine: Option<Query<Self>>, filter: &mut ListFilter) -> (i32, i32) {
+        if let Some(Query(q)) = mine {
+            if let Some(permission) = q.permission {
+                /* shares any bit */
+                filter.with(|b| {
+                    b.push("(u.permission & ")
+                        .push_bind(permission)
+                        .push("::bit(8)) <> B'00000000'");
+                });
+            }
+            filter
+                .eq("u.phone", q.phone)
+                .any_of_lookup(
+                    "u.department_id",
+                    "departments",
+                    "shorten",
+                    list(&q.department),
+                )
+                .eq("u.username", q.username)
+                .eq("u.worker_id", q.worker_id)
+                .any_of_lookup("u.title_id", "titles", "name", list(&q.title))
+                .eq("u.email", q.email)
+                .range("u.create_at", q.create_start, q.create_end)
+                .range("u.login_at", q.login_start, q.login_end)
+                .range("u.update_at", q.update_start, q.update_end);
+
+            (q.offset.unwrap_or(0), q.entries.unwrap_or(100))
         } else {
-            (0, 100, "".to_string())
+            (0, 100)
         }
     }
 }
@@ -274,6 +181,7 @@ pub struct UserRawInfo {
     create_at: DateTime<Utc>,
     login_at: Option<DateTime<Utc>>,
     update_at: Option<DateTime<Utc>>,
+    version: i32,
 }
 
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow, ToSchema)]
@@ -307,7 +215,7 @@ pub(crate) async fn query_user(account: &str, database: &Database) -> Option<Use
         FROM users u
             LEFT JOIN titles t ON t.id = u.title_id
             LEFT JOIN departments d ON d.id = u.department_id
-        WHERE u.account = $1;
+        WHERE u.account = $1 AND u.deleted_at IS NULL;
     "#;
 
     if let Ok(user) = sqlx::query_as::<_, UserInfo>(QUERY)
@@ -322,7 +230,7 @@ pub(crate) async fn query_user(account: &str, database: &Database) -> Option<Use
 }
 
 pub(crate) async fn query_user_id(database: &Database, account: &str) -> Option<i32> {
-    const QUERY: &str = "SELECT id FROM users WHERE account = $1;";
+    const QUERY: &str = "SELECT id FROM users WHERE account = $1 AND deleted_at IS NULL;";
 
     match sqlx::query_as(QUERY)
         .bind(account)
@@ -358,21 +266,44 @@ pub struct ResponseUser {
         ("account" = String, Path, description = "user account")
     ),
     responses(
-        (status = 200, description = "get detail user information", body = ResponseUser)
-    )
+        (status = 200, description = "get detail user information, `ETag` is its version", body = ResponseUser),
+        (status = 403, description = "permission deny, user.read required for other users, of a department the user sees", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
 )]
 pub(crate) async fn user_api(
     Path(account): Path<String>,
-    //Extension(_auth_state): Extension<AuthState>,
+    Extension(mut auth_state): Extension<AuthState>,
     Extension(database): Extension<Database>,
 ) -> impl IntoResponse {
-    /* TODO, limit with auth_state's pemission */
-    if let Some(user) = query_user(&account, &database).await {
+    let orig = query_raw_user(&database, &account).await;
+    match auth_state.get_user().await {
+        Some(current) if current.account == account => {}
+        Some(current) if current.has_permission(UserRead::NAME) => {
+            let department_id = orig.as_ref().and_then(|u| u.department_id);
+            if !policy::sees_department(current, department_id) {
+                return rbac::forbidden(format!("permission deny, user/{account}"));
+            }
+        }
+        Some(_) => {
+            return rbac::forbidden(format!("permission deny, {} required", UserRead::NAME));
+        }
+        None => {
+            let resp = ApiResponse::new(400, Some(format!("{}", &NotLoggedIn)));
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+    }
+
+    if let (Some(user), Some(orig)) = (query_user(&account, &database).await, orig) {
         let resp = json!({
             "code": 200,
             "user": &user
         });
-        (StatusCode::OK, Json(resp)).into_response()
+        etag::with_etag(orig.version, (StatusCode::OK, Json(resp)))
     } else {
         let resp = json!({
             "code": 401,
@@ -406,20 +337,28 @@ pub struct UserNew {
             code: 200,
             message: Some(String::from("success")),
         })),
-        (status = 400, description = "user exist, ", body = ApiResponse, example = json!(ApiResponse {
-            code: 400,
-            message: Some(String::from("..."))
-        })),
+        (status = 400, description = "user exist (`ApiResponse`), or account/password refused by the policy, see `SignupError`", body = SignupErrorResponse, example = json!(SignupErrorResponse::from(SignupError::PasswordTooShort))),
+        (status = 403, description = "permission deny, user.create required, role.manage too for any `permission` bit", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 409, description = "a deleted user holds the account, restore or purge it first", body = ApiResponse, example = json!(ApiResponse::new(409, Some(String::from("user/alice is in the trash, restore or purge it first"))))),
         (status = 500, description = "server DB error, ", body = ApiResponse, example = json!(ApiResponse {
             code: 500,
             message: Some(String::from("..."))
         })),
     ),
+    security(
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
 )]
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn post_signup_api(
+    current: Require<UserCreate>,
     Extension(database): Extension<Database>,
     State(state): State<SharedState>,
     Extension(random): Extension<Random>,
+    Extension(auth_config): Extension<AuthConfig>,
+    client: ClientInfo,
+    audit: Audit,
     Json(user): Json<UserNew>,
 ) -> impl IntoResponse {
     let mut resp = ApiResponse {
@@ -427,11 +366,19 @@ pub(crate) async fn post_signup_api(
         message: Some(String::from("success")),
     };
 
+    /* the bits become roles, granting any is managing roles */
+    if user.permission.any() && !current.has_permission(RoleManage::NAME) {
+        return rbac::forbidden(format!("permission deny, {} required", RoleManage::NAME));
+    }
+
     if query_user(&user.account, &database).await.is_some() {
         resp.code = 400;
         resp.message = Some("user exist".to_string());
         return (StatusCode::OK, Json(resp)).into_response();
     }
+    if let Some(taken) = trash::taken(&database, Trash::User, &user.account).await {
+        return taken;
+    }
 
     let title_id = match user.title {
         Some(title) => match title_id_or_insert(&database, &title).await {
@@ -459,9 +406,14 @@ pub(crate) async fn post_signup_api(
         None => None,
     };
 
+    let event = AuditEvent::new("user.create", "user", &user.account).by(&*current);
     match signup2(
         &database,
+        &audit,
+        event,
         random,
+        &auth_config,
+        &client,
         &user.account,
         &user.password,
         &user.permission,
@@ -476,11 +428,11 @@ pub(crate) async fn post_signup_api(
     {
         Ok(_session_token) => {
             let _ = shared_store_users_set(state, &user.account, &user.username).await;
+
             (StatusCode::OK, Json(resp)).into_response()
         }
         Err(error) => {
-            resp.message = Some(format!("{error}"));
-            resp.code = 500;
+            let resp = SignupErrorResponse::from(error);
             error!("{:?}", &resp);
             (StatusCode::OK, Json(resp)).into_response()
         }
@@ -500,6 +452,8 @@ pub struct ResponseUserLogin {
     session_value: Option<String>,
     message: Option<String>,
     permission: Option<BitVec>,
+    /// second factor needed (code 202), `totp` or `enroll`
+    mfa: Option<String>,
 }
 
 impl ResponseUserLogin {
@@ -516,10 +470,38 @@ impl ResponseUserLogin {
             session_value: sval,
             message: msg,
             permission,
+            mfa: None,
         }
     }
 }
 
+/// logged in, session cookie and permission, as `post_login_api` answers
+pub(crate) fn login_success_response(
+    session_token: SessionToken,
+    permission: &BitVec,
+    max_age: i64,
+) -> Response<String> {
+    let token = session_token.into_cookie_value();
+    /*let resp = ResponseUserLogin::new(200, Some(USER_COOKIE_NAME.to_string()), Some(token.clone()), None, Some(permission));
+    (StatusCode::OK, Json(resp)).into_response()*/
+    let resp = json!({
+        "code": 200,
+        "session_key": USER_COOKIE_NAME,
+        "session_value": &token,
+        "permission": permission,
+    });
+
+    let cookie = session_cookie(&token, max_age);
+
+    Response::builder()
+        .status(http::StatusCode::OK)
+        .header("Location", "/")
+        .header("content-type", "application/json")
+        .header("Set-Cookie", cookie)
+        .body(resp.to_string())
+        .unwrap()
+}
+
 #[utoipa::path(
     post,
     path = "/api/v1/login",
@@ -527,45 +509,98 @@ impl ResponseUserLogin {
     responses(
         (status = 200, description = "login success, return cookie session key/value", body = ResponseUserLogin,
              example = json!(ResponseUserLogin::new(200, Some(String::from("cookie key")), Some(String::from("cookie value")), Some(String::from("...")), None))),
-        (status = 404, description = "user not found, ", body = ResponseUserLogin,
+        (status = 202, description = "password ok, second factor needed, `mfa` is `totp` (POST /api/v1/login/mfa) or `enroll` (role requires 2FA, enroll first); the returned session is pending until then", body = ResponseUserLogin,
+             example = json!({"code": 202, "session_key": "user_token", "session_value": "...", "mfa": "totp"})),
+        (status = 404, description = "invalid account or password, ", body = ResponseUserLogin,
             example = json!(ResponseUserLogin::new(404, None, None, Some(String::from("...")), None))),
+        (status = 429, description = "too many failed logins of the account or client IP, see `Retry-After`", body = ApiResponse,
+            example = json!(ApiResponse::new(429, Some(String::from("..."))))),
     )
 )]
 pub(crate) async fn post_login_api(
     Extension(database): Extension<Database>,
     State(state): State<SharedState>,
     Extension(random): Extension<Random>,
+    Extension(auth_config): Extension<AuthConfig>,
+    client: ClientInfo,
+    audit: Audit,
     Json(user): Json<UserLogin>,
 ) -> impl IntoResponse {
     let _ = shared_store_users_init(&database, state.clone()).await;
     let _ = shared_store_departments_init(&database, state.clone()).await;
     info!("[debug] dump state = {:?}", state);
 
-    match login(&database, random, &user.account, &user.password).await {
-        Ok((session_token, permission)) => {
+    let user_id = query_user_id(&database, &user.account).await;
+    let login_event =
+        |action| AuditEvent::new(action, "user", &user.account).by_account(user_id, &user.account);
+
+    match login(
+        &database,
+        random,
+        &auth_config,
+        &client,
+        &user.account,
+        &user.password,
+    )
+    .await
+    {
+        Ok(LoginSession {
+            session_token,
+            permission,
+            mfa: None,
+        }) => {
             let _ = update_login_at(&database, &user.account).await;
+            let _ = audit.record(&database, login_event("session.login")).await;
+            login_success_response(session_token, &permission, auth_config.cookie_max_age())
+        }
+        Ok(LoginSession {
+            session_token,
+            mfa: Some(challenge),
+            ..
+        }) => {
+            /* pending session, good for the second step only */
+            let event = login_event("session.login").after(&json!({"mfa": "pending"}));
+            let _ = audit.record(&database, event).await;
 
             let token = session_token.into_cookie_value();
-            /*let resp = ResponseUserLogin::new(200, Some(USER_COOKIE_NAME.to_string()), Some(token.clone()), None, Some(permission));
-            (StatusCode::OK, Json(resp)).into_response()*/
             let resp = json!({
-                "code": 200,
+                "code": 202,
                 "session_key": USER_COOKIE_NAME,
                 "session_value": &token,
-                "permission": permission,
+                "mfa": match challenge {
+                    MfaChallenge::Totp => "totp",
+                    MfaChallenge::Enroll => "enroll",
+                },
             });
 
-            let cookie = format!("{USER_COOKIE_NAME}={token}; Max-Age={COOKIE_MAX_AGE}");
+            let cookie = session_cookie(&token, auth_config.mfa_pending.num_seconds());
 
             Response::builder()
                 .status(http::StatusCode::OK)
-                .header("Location", "/")
                 .header("content-type", "application/json")
                 .header("Set-Cookie", cookie)
                 .body(resp.to_string())
                 .unwrap()
         }
+        Err(LoginError::TooManyAttempts(secs)) => {
+            let event = login_event("session.login_failed")
+                .after(&json!({"reason": "too many attempts", "retry_after": secs}));
+            let _ = audit.record(&database, event).await;
+
+            let resp =
+                ApiResponse::new(429, Some(format!("{}", LoginError::TooManyAttempts(secs))));
+            Response::builder()
+                .status(http::StatusCode::TOO_MANY_REQUESTS)
+                .header("content-type", "application/json")
+                .header("Retry-After", secs.to_string())
+                .body(json!(resp).to_string())
+                .unwrap()
+        }
         Err(error) => {
+            let event =
+                login_event("session.login_failed").after(&json!({"reason": error.to_string()}));
+            let _ = audit.record(&database, event).await;
+
             /*let resp = ResponseUserLogin::new(404, None, None, Some(format!("{}", error)), None);
             (StatusCode::NOT_FOUND, Json(resp)).into_response()*/
             Response::builder()
@@ -578,6 +613,50 @@ pub(crate) async fn post_login_api(
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/user/{account}/unlock",
+    params(
+        ("account" = String, Path, description = "user to unlock")
+    ),
+    responses(
+        (status = 200, description = "failed logins of the account cleared", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 404, description = "user not found, ", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, user.security required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn user_unlock_api(
+    current: Require<UserSecurity>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+    Path(account): Path<String>,
+) -> impl IntoResponse {
+    if query_user_id(&database, &account).await.is_none() {
+        let resp = ApiResponse::new(404, Some(format!("user/{account} not found")));
+        return (StatusCode::OK, Json(resp)).into_response();
+    }
+
+    let resp = match login_throttle::unlock(&database, &account).await {
+        Ok(_) => {
+            info!("{account} unlocked");
+            let event = AuditEvent::new("user.unlock", "user", &account).by(&*current);
+            let _ = audit.record(&database, event).await;
+            ApiResponse::new(200, Some(String::from("success")))
+        }
+        Err(e) => {
+            let resp = ApiResponse::new(500, Some(format!("{e}")));
+            error!("{:?}", &resp);
+            resp
+        }
+    };
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
 #[utoipa::path(
     delete,
     path = "/api/v1/user/{account}",
@@ -585,7 +664,7 @@ pub(crate) async fn post_login_api(
         ("account" = String, Path, description = "user to delete")
     ),
     responses(
-        (status = 200, description = "delete success", body = ApiResponse, example = json!(ApiResponse {
+        (status = 200, description = "deleted and logged out everywhere, restorable with trash.manage, orders keep the user", body = ApiResponse, example = json!(ApiResponse {
             code: 200,
             message: Some(String::from("success")),
         })),
@@ -593,21 +672,26 @@ pub(crate) async fn post_login_api(
             code: 404,
             message: Some(String::from("..."))
         })),
-        (status = 405, description = "permission deny", body = ApiResponse, example = json!(ApiResponse {
-            code: 405,
+        (status = 403, description = "permission deny", body = ApiResponse, example = json!(ApiResponse {
+            code: 403,
             message: Some(String::from("..."))
         })),
+        (status = 409, description = "updated by somebody else meanwhile, `current` is the user now", body = ApiResponse, example = json!(ApiResponse::new(409, Some(String::from("..."))))),
+        (status = 412, description = "`If-Match` isn't the current version, `current` is the user now", body = ApiResponse, example = json!(ApiResponse::new(412, Some(String::from("..."))))),
     ),
     security(
         //(), // <-- make optional authentication
-        ("logined cookie/session-id" = [])
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
     ),
 )]
 pub(crate) async fn post_delete_api(
     Extension(database): Extension<Database>,
     State(state): State<SharedState>,
     Extension(mut current_user): Extension<AuthState>,
+    audit: Audit,
     Path(account): Path<String>,
+    if_match: IfMatch,
 ) -> impl IntoResponse {
     let mut resp = ApiResponse {
         code: 200,
@@ -623,26 +707,45 @@ pub(crate) async fn post_delete_api(
         }
     };
 
-    if let Some(order) = query_order_by_user_id(&database, orig.id).await {
-        resp.update(400, Some(format!("reject due to order/{order} related")));
-        error!("{:?}", &resp);
-        return (StatusCode::OK, Json(resp)).into_response();
-    }
-
-    let allow = permission_check(current_user.get_user().await, &orig);
+    let allow = permission_check(
+        &database,
+        current_user.get_user().await,
+        &orig,
+        UserDelete::NAME,
+    )
+    .await;
 
     if !allow {
-        resp.code = 405;
-        resp.message = Some(String::from("permission deny"));
-        return (StatusCode::OK, Json(resp)).into_response();
+        return rbac::forbidden(String::from("permission deny"));
+    }
+    if !if_match.matches(orig.version) {
+        return etag::precondition_failed(query_user(&account, &database).await, orig.version);
     }
 
-    match delete_user2(&database, &account).await {
-        Ok(_) => {
+    let mut tx = match database.begin().await {
+        Ok(tx) => tx,
+        Err(e) => {
+            resp.code = 500;
+            resp.message = Some(format!("DB error - {e}"));
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+    };
+    let by = current_user.get_user().await.map(|u| u.id);
+    match delete_user2(&mut tx, &account, orig.version, by).await {
+        Ok(true) => {
+            let event = AuditEvent::new("user.delete", "user", &account)
+                .by(current_user.get_user().await)
+                .before(&orig);
+            if let Err(e) = audit.commit(tx, event).await {
+                resp.code = 500;
+                resp.message = Some(format!("{e}"));
+                return (StatusCode::OK, Json(resp)).into_response();
+            }
             let _ = shared_store_users_del(state, &account).await;
 
             (StatusCode::OK, Json(resp)).into_response()
         }
+        Ok(false) => user_conflict(&database, &account).await,
         Err(e) => {
             resp.code = 500;
             resp.message = Some(format!("{e}"));
@@ -651,6 +754,67 @@ pub(crate) async fn post_delete_api(
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/user/{account}/restore",
+    params(
+        ("account" = String, Path, description = "deleted user to restore")
+    ),
+    responses(
+        (status = 200, description = "restore success, `ETag` is the new version; sessions and reset tokens dropped by the delete stay gone", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("restore success"))))),
+        (status = 404, description = "no deleted user of the account", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, trash.manage required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn user_restore_api(
+    current: Require<TrashManage>,
+    Extension(database): Extension<Database>,
+    State(state): State<SharedState>,
+    audit: Audit,
+    Path(account): Path<String>,
+) -> impl IntoResponse {
+    let response = trash::restore(&database, &audit, &current, Trash::User, &account).await;
+    if let Some(UserRawInfo {
+        username: Some(username),
+        ..
+    }) = query_raw_user(&database, &account).await
+    {
+        let _ = shared_store_users_set(state, &account, &username).await;
+    }
+    response
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/user/{account}/purge",
+    params(
+        ("account" = String, Path, description = "deleted user to remove for good")
+    ),
+    responses(
+        (status = 200, description = "purge success, orders and histories of the user keep no reference to it", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("purge success"))))),
+        (status = 404, description = "no deleted user of the account, delete it first", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, trash.manage required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn user_purge_api(
+    current: Require<TrashManage>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+    Path(account): Path<String>,
+) -> impl IntoResponse {
+    trash::purge(&database, &audit, &current, Trash::User, &account).await
+}
+
 #[utoipa::path(
     get,
     path = "/api/v1/me",
@@ -667,7 +831,8 @@ pub(crate) async fn post_delete_api(
     ),
     security(
         //(), // <-- make optional authentication
-        ("logined cookie/session-id" = [])
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
     ),
 )]
 pub(crate) async fn me_api(
@@ -707,17 +872,21 @@ pub struct ResponseUsers {
         UserListQuery
     ),
     responses(
-        (status = 200, description = "get user list", body = ResponseUsers)
-    )
+        (status = 200, description = "get user list, of the departments the user sees", body = ResponseUsers),
+        (status = 403, description = "permission deny, user.read required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
 )]
 pub(crate) async fn users_api(
+    current: Require<UserRead>,
     Extension(database): Extension<Database>,
     query: Option<Query<UserListQuery>>,
 ) -> impl IntoResponse {
-    let (offset, entries, where_dep) = UserListQuery::parse(query);
-
-    let sselect = format!(
-        r#"
+    const SELECT: &str = r#"
         SELECT
             u.account,
             u.permission,
@@ -731,11 +900,17 @@ pub(crate) async fn users_api(
         FROM users u
             LEFT JOIN titles t ON t.id = u.title_id
             LEFT JOIN departments d ON d.id = u.department_id
-        {where_dep} LIMIT {entries} OFFSET {offset};
-    "#
-    );
+    "#;
 
-    if let Ok(users) = sqlx::query_as::<_, UserInfo>(&sselect)
+    let mut filter = ListFilter::new(SELECT);
+    let (offset, entries) = UserListQuery::parse(query, &mut filter);
+    let reach = policy::department_filter(&current, "u.department_id");
+    filter.trusted(&format!("{reach} OR u.id = {}", current.id));
+    filter.trusted("u.deleted_at IS NULL");
+    let mut query = filter.finish("", entries, offset);
+
+    if let Ok(users) = query
+        .build_query_as::<UserInfo>()
         .fetch_all(&database)
         .await
     {
@@ -753,11 +928,6 @@ pub(crate) async fn users_api(
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
-struct LogoutSqlRes {
-    id: i32,
-}
-
 #[utoipa::path(
     get,
     path = "/api/v1/logout",
@@ -766,12 +936,14 @@ struct LogoutSqlRes {
     ),
     security(
         //(), // <-- make optional authentication
-        ("logined cookie/session-id" = [])
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
     ),
 )]
 pub(crate) async fn logout_response_api(
     Extension(mut current_user): Extension<AuthState>,
     Extension(database): Extension<Database>,
+    audit: Audit,
 ) -> impl IntoResponse {
     let resp = json!({
         "code": 200,
@@ -779,17 +951,18 @@ pub(crate) async fn logout_response_api(
         "session_value": "_",
     });
 
-    if let Some(myself) = current_user.get_user().await {
-        let account = &myself.account;
-        const QUERY: &str = r#"
-        DELETE from sessions
-            WHERE user_id = ( SELECT id FROM users WHERE account = $1 )
-        RETURNING id;
-        "#;
+    /* only this device, other sessions stay (see /api/v1/me/sessions) */
+    if let Some(session_token) = current_user.session_token() {
+        const QUERY: &str = "DELETE FROM sessions WHERE session_token = $1;";
 
-        _ = sqlx::query_as::<_, LogoutSqlRes>(QUERY)
-            .bind(account)
-            .fetch_all(&database)
+        if let Some(user) = current_user.get_user().await {
+            let event = AuditEvent::new("session.logout", "user", &user.account).by(user);
+            let _ = audit.record(&database, event).await;
+        }
+
+        _ = sqlx::query(QUERY)
+            .bind(&session_token.into_database_value())
+            .execute(&database)
             .await
     }
 
@@ -797,7 +970,7 @@ pub(crate) async fn logout_response_api(
         .status(http::StatusCode::OK)
         .header("Location", "/")
         .header("content-type", "application/json")
-        .header("Set-Cookie", format!("{}=_; Max-Age=0", USER_COOKIE_NAME,))
+        .header("Set-Cookie", session_cookie("_", 0))
         .body(resp.to_string())
         .unwrap()
 }
@@ -819,6 +992,7 @@ pub struct UpdateMe {
             code: 200,
             message: Some(String::from("success")),
         })),
+        (status = 400, description = "password refused by the policy, see `SignupError`", body = SignupErrorResponse, example = json!(SignupErrorResponse::from(SignupError::PasswordReused))),
         (status = 405, description = "permission deny, ", body = ApiResponse, example = json!(ApiResponse {
             code: 405,
             message: Some(String::from("..."))
@@ -830,13 +1004,16 @@ pub struct UpdateMe {
     ),
     security(
         //(), // <-- make optional authentication
-        ("logined cookie/session-id" = [])
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
     ),
 )]
 pub(crate) async fn update_myself_api(
     Extension(mut current_user): Extension<AuthState>,
     Extension(database): Extension<Database>,
+    Extension(auth_config): Extension<AuthConfig>,
     State(state): State<SharedState>,
+    audit: Audit,
     Json(user): Json<UpdateMe>,
 ) -> impl IntoResponse {
     let mut resp = ApiResponse {
@@ -850,86 +1027,116 @@ pub(crate) async fn update_myself_api(
         resp.message = Some("permission deny".to_string());
         return (StatusCode::OK, Json(resp)).into_response();
     };
+    let event = AuditEvent::new("user.update", "user", &account)
+        .by(current_user.get_user().await)
+        .before(&query_raw_user(&database, &account).await);
+
+    if let Some(pwd) = &user.password {
+        let checked = match query_raw_user(&database, &account).await {
+            Some(orig) => {
+                password_change_check(
+                    &database,
+                    &auth_config,
+                    &orig,
+                    pwd,
+                    user.phone.as_deref(),
+                    user.email.as_deref(),
+                )
+                .await
+            }
+            None => Err(SignupError::InternalError),
+        };
+        if let Err(error) = checked {
+            let resp = SignupErrorResponse::from(error);
+            error!("{:?}", &resp);
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+    }
+
+    /* all fields or none, recorded with them */
+    let mut tx = match database.begin().await {
+        Ok(tx) => tx,
+        Err(e) => {
+            resp.update(500, Some(format!("{e}")));
+            error!("{:?}", &resp);
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+    };
 
     match user.password {
         None => info!("passowrd no change"),
         Some(pwd) => {
-            if let Ok(hashed_password) = password_hashed(&pwd) {
-                let fetch_one: Result<(i32,), _> = sqlx::query_as(
-                    "UPDATE users SET password = $1 WHERE account = $2 RETURNING id;",
-                )
-                .bind(&hashed_password)
-                .bind(&account)
-                .fetch_one(&database)
-                .await;
-                match fetch_one {
-                    Ok((id,)) => debug!("update passowrd ok {id}"),
-                    Err(err) => {
-                        error!("update password fail {err}");
-                        resp.code = 500;
-                        resp.message = Some(format!("update password fail {err}"));
-                    }
+            let hashed_password = match password_hashed(&auth_config, &pwd) {
+                Ok(hashed_password) => hashed_password,
+                Err(_) => {
+                    resp.message = Some("password hashed fail".to_string());
+                    resp.code = 500;
+                    error!("{:?}", &resp);
+                    return (StatusCode::OK, Json(resp)).into_response();
                 }
-            } else {
-                resp.message = Some("password hashed fail".to_string());
+            };
+            let fetch_one: Result<(i32,), _> =
+                sqlx::query_as("UPDATE users SET password = $1 WHERE account = $2 RETURNING id;")
+                    .bind(&hashed_password)
+                    .bind(&account)
+                    .fetch_one(&mut tx)
+                    .await;
+            let pushed = match fetch_one {
+                Ok((id,)) => {
+                    debug!("update passowrd ok {id}");
+                    password_policy::history_push(&mut tx, &auth_config, id, &hashed_password).await
+                }
+                Err(err) => Err(anyhow!("{err}")),
+            };
+            if let Err(err) = pushed {
                 resp.code = 500;
+                resp.message = Some(format!("update password fail {err}"));
                 error!("{:?}", &resp);
+                return (StatusCode::OK, Json(resp)).into_response();
             }
         }
     }
 
-    if let Some(username) = user.username {
-        let return_one: Result<(i32,), _> =
-            sqlx::query_as("UPDATE users SET username = $1 WHERE account = $2 RETURNING id;")
-                .bind(&username)
-                .bind(&account)
-                .fetch_one(&database)
-                .await;
+    if user.username.is_some() || user.phone.is_some() || user.email.is_some() {
+        const UPDATE_QUERY: &str = r#"
+            UPDATE users SET
+                username = COALESCE($1, username),
+                phone = COALESCE($2, phone),
+                email = COALESCE($3, email),
+                version = version + 1
+            WHERE account = $4 RETURNING id;
+        "#;
+        let return_one: Result<(i32,), _> = sqlx::query_as(UPDATE_QUERY)
+            .bind(&user.username)
+            .bind(&user.phone)
+            .bind(&user.email)
+            .bind(&account)
+            .fetch_one(&mut tx)
+            .await;
         match return_one {
-            Ok((id,)) => {
-                let _ = shared_store_users_set(state, &account, &username).await;
-                info!("update username ok {id}")
-            }
+            Ok((id,)) => info!("update profile ok {id}"),
             Err(err) => {
-                resp.message = Some(format!("update username fail {err}"));
+                resp.message = Some(format!("update profile fail {err}"));
                 resp.code = 500;
                 error!("{:?}", &resp);
+                return (StatusCode::OK, Json(resp)).into_response();
             }
         }
     }
 
-    if let Some(phone) = user.phone {
-        let return_one: Result<(i32,), _> =
-            sqlx::query_as("UPDATE users SET phone = $1 WHERE account = $2 RETURNING id;")
-                .bind(&phone)
-                .bind(&account)
-                .fetch_one(&database)
-                .await;
-        match return_one {
-            Ok((id,)) => info!("update phone ok {id}"),
-            Err(err) => {
-                resp.message = Some(format!("update phone fail {err}"));
-                resp.code = 500;
-                error!("{:?}", &resp);
-            }
-        }
+    let event = event.after(&query_raw_user(&mut tx, &account).await);
+    let recorded = match audit.record(&mut tx, event).await {
+        Ok(()) => tx.commit().await.map_err(|e| anyhow!("DB error - {e}")),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = recorded {
+        resp.update(500, Some(format!("{e}")));
+        error!("{:?}", &resp);
+        return (StatusCode::OK, Json(resp)).into_response();
     }
 
-    if let Some(email) = user.email {
-        let return_one: Result<(i32,), _> =
-            sqlx::query_as("UPDATE users SET email = $1 WHERE account = $2 RETURNING id;")
-                .bind(&email)
-                .bind(&account)
-                .fetch_one(&database)
-                .await;
-        match return_one {
-            Ok((id,)) => info!("update email ok {id}"),
-            Err(err) => {
-                resp.message = Some(format!("update email fail {err}"));
-                resp.code = 500;
-                error!("{:?}", &resp);
-            }
-        }
+    if let Some(username) = user.username {
+        let _ = shared_store_users_set(state, &account, &username).await;
     }
 
     (StatusCode::OK, Json(resp)).into_response()
@@ -948,47 +1155,6 @@ pub struct UpdateUser {
     email: Option<String>,
 }
 
-#[derive(Debug)]
-enum PermissionRole {
-    Admin(String),
-    Gm(String),
-    Maintainer(String),
-    Comissioner(String),
-    Jshall(String),
-    Other(String),
-}
-
-impl From<&BitVec> for PermissionRole {
-    fn from(p: &BitVec) -> Self {
-        if let Some(r) = p.get(0) {
-            if r {
-                return Self::Admin("admin".to_string());
-            }
-        }
-        if let Some(r) = p.get(1) {
-            if r {
-                return Self::Gm("GM".to_string());
-            }
-        }
-        if let Some(r) = p.get(2) {
-            if r {
-                return Self::Maintainer("Maintainer".to_string());
-            }
-        }
-        if let Some(r) = p.get(3) {
-            if r {
-                return Self::Comissioner("Comissioner".to_string());
-            }
-        }
-        if let Some(r) = p.get(4) {
-            if r {
-                return Self::Jshall("JSHall".to_string());
-            }
-        }
-        Self::Other("other".to_string())
-    }
-}
-
 #[utoipa::path(
     put,
     path = "/api/v1/user/{account}",
@@ -997,7 +1163,7 @@ impl From<&BitVec> for PermissionRole {
     ),
     request_body = UpdateUser,
     responses(
-        (status = 200, description = "delete success", body = ApiResponse, example = json!(ApiResponse {
+        (status = 200, description = "update success, `ETag` is the new version", body = ApiResponse, example = json!(ApiResponse {
             code: 200,
             message: Some(String::from("success")),
         })),
@@ -1005,10 +1171,13 @@ impl From<&BitVec> for PermissionRole {
             code: 404,
             message: Some(String::from("..."))
         })),
-        (status = 405, description = "permission deny, ", body = ApiResponse, example = json!(ApiResponse {
-            code: 405,
+        (status = 400, description = "password refused by the policy, see `SignupError`", body = SignupErrorResponse, example = json!(SignupErrorResponse::from(SignupError::PasswordReused))),
+        (status = 403, description = "permission deny, ", body = ApiResponse, example = json!(ApiResponse {
+            code: 403,
             message: Some(String::from("..."))
         })),
+        (status = 409, description = "updated by somebody else meanwhile, `current` is the user now", body = ApiResponse, example = json!(ApiResponse::new(409, Some(String::from("..."))))),
+        (status = 412, description = "`If-Match` isn't the current version, `current` is the user now", body = ApiResponse, example = json!(ApiResponse::new(412, Some(String::from("..."))))),
         (status = 500, description = "server error, ", body = ApiResponse, example = json!(ApiResponse {
             code: 500,
             message: Some(String::from("..."))
@@ -1016,13 +1185,17 @@ impl From<&BitVec> for PermissionRole {
     ),
     security(
         //(), // <-- make optional authentication
-        ("logined cookie/session-id" = [])
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
     ),
 )]
 pub(crate) async fn update_user_api(
     Extension(mut current_user): Extension<AuthState>,
     Path(account): Path<String>,
     Extension(database): Extension<Database>,
+    Extension(auth_config): Extension<AuthConfig>,
+    audit: Audit,
+    if_match: IfMatch,
     Json(user): Json<UpdateUser>,
 ) -> impl IntoResponse {
     let mut resp = ApiResponse {
@@ -1039,18 +1212,50 @@ pub(crate) async fn update_user_api(
         }
     };
 
-    let allow = permission_check(current_user.get_user().await, &orig);
+    let allow = permission_check(
+        &database,
+        current_user.get_user().await,
+        &orig,
+        UserUpdate::NAME,
+    )
+    .await;
 
     if !allow {
-        resp.code = 405;
-        resp.message = Some(String::from("permission deny"));
-        return (StatusCode::OK, Json(resp)).into_response();
+        return rbac::forbidden(String::from("permission deny"));
+    }
+    if !if_match.matches(orig.version) {
+        return etag::precondition_failed(query_user(&account, &database).await, orig.version);
     }
 
+    /* the bits are the built-in roles, changing them is a role assignment */
+    if user.permission.is_some() && !rbac::allowed(current_user.get_user().await, RoleManage::NAME)
+    {
+        return rbac::forbidden(format!("permission deny, {} required", RoleManage::NAME));
+    }
+    let event = AuditEvent::new("user.update", "user", &account)
+        .by(current_user.get_user().await)
+        .before(&orig);
+
+    let password_changed = user.password.is_some();
     let password = match user.password {
-        None => orig.password,
+        None => orig.password.clone(),
         Some(pwd) => {
-            if let Ok(hashed_password) = password_hashed(&pwd) {
+            if let Err(error) = password_change_check(
+                &database,
+                &auth_config,
+                &orig,
+                &pwd,
+                user.phone.as_deref(),
+                user.email.as_deref(),
+            )
+            .await
+            {
+                let resp = SignupErrorResponse::from(error);
+                error!("{:?}", &resp);
+                return (StatusCode::OK, Json(resp)).into_response();
+            }
+
+            if let Ok(hashed_password) = password_hashed(&auth_config, &pwd) {
                 hashed_password
             } else {
                 resp.message = Some("password hashed wrong".to_string());
@@ -1061,6 +1266,7 @@ pub(crate) async fn update_user_api(
         }
     };
 
+    let roles = user.permission.as_ref().map(rbac::roles_from_bits);
     let permission = user.permission.map_or(orig.permission, |p| p);
 
     let username = user.username.or(orig.username);
@@ -1110,11 +1316,21 @@ pub(crate) async fn update_user_api(
             department_id = $6,
             phone = $7,
             email = $8,
-            update_at = $9
-        WHERE id = $10 RETURNING id;
+            update_at = $9,
+            version = version + 1
+        WHERE id = $10 AND version = $11 RETURNING id;
     "#;
-    let fetch_one: Result<(i32,), _> = sqlx::query_as(UPDATE_QUERY)
-        .bind(password)
+    /* the roles bump the version too, both or neither */
+    let mut tx = match database.begin().await {
+        Ok(tx) => tx,
+        Err(e) => {
+            resp.update(500, Some(format!("{e}")));
+            error!("{:?}", &resp);
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+    };
+    let fetch_one: Result<Option<(i32,)>, _> = sqlx::query_as(UPDATE_QUERY)
+        .bind(&password)
         .bind(permission)
         .bind(username)
         .bind(worker_id)
@@ -1124,70 +1340,123 @@ pub(crate) async fn update_user_api(
         .bind(email)
         .bind(Utc::now())
         .bind(orig.id)
-        .fetch_one(&database)
+        .bind(orig.version)
+        .fetch_optional(&mut tx)
         .await;
 
-    match fetch_one {
-        Ok((id,)) => {
-            resp.update(200, Some(format!("user update success - history{id}")));
-        }
+    let id = match fetch_one {
+        Ok(None) => return user_conflict(&database, &account).await,
+        Ok(Some((id,))) => id,
         Err(e) => {
             resp.update(500, Some(format!("{e}")));
             error!("{:?}", &resp);
+            return (StatusCode::OK, Json(resp)).into_response();
         }
+    };
+
+    let done = async {
+        if let Some(roles) = roles {
+            if !rbac::user_roles_replace(&mut tx, id, &roles).await? {
+                return Err(anyhow!("unknown role in {roles:?}"));
+            }
+        }
+        if password_changed {
+            password_policy::history_push(&mut tx, &auth_config, id, &password).await?;
+        }
+        let after = query_raw_user(&mut tx, &account).await;
+        audit.record(&mut tx, event.after(&after)).await?;
+        tx.commit().await.map_err(|e| anyhow!("DB error - {e}"))?;
+        Ok(after)
     }
-    (StatusCode::OK, Json(resp)).into_response()
+    .await;
+    let after = match done {
+        Ok(after) => after,
+        Err(e) => {
+            resp.update(500, Some(format!("user/{id} - {e}")));
+            error!("{:?}", &resp);
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+    };
+    resp.update(200, Some(format!("user update success - history{id}")));
+
+    /* the committed version, the roles bumped it too */
+    let version = after.map_or(orig.version + 1, |u| u.version);
+    etag::with_etag(version, (StatusCode::OK, Json(resp)))
 }
 
-fn permission_check(current: Option<&CurrentUser>, target: &UserRawInfo) -> bool {
-    if let Some(current) = current {
-        let current_role = PermissionRole::from(&current.permission);
-        let target_role = PermissionRole::from(&target.permission);
+/// 409 with the user as it is now, or as deleted
+async fn user_conflict(database: &Database, account: &str) -> axum::response::Response {
+    let current = query_raw_user(database, account).await.map(|u| u.version);
+    etag::conflict(query_user(account, database).await, current)
+}
 
-        info!(
-            "TODO, user-{}/{:?} is {:?} try to change {:?}",
-            current.account, current.permission, current_role, target_role
-        );
+/// policy and reuse check of a new password of `orig`, `phone`/`email` about to be set count too
+async fn password_change_check(
+    database: &Database,
+    config: &AuthConfig,
+    orig: &UserRawInfo,
+    password: &str,
+    phone: Option<&str>,
+    email: Option<&str>,
+) -> Result<(), SignupError> {
+    let personal = [
+        Some(orig.account.as_str()),
+        Some(orig.phone.as_str()),
+        orig.email.as_deref(),
+        phone,
+        email,
+    ];
+    let personal: Vec<&str> = personal.into_iter().flatten().collect();
+
+    password_policy::check_new(database, config, Some(orig.id), password, &personal).await
+}
 
-        if target.account == current.account {
-            debug!("{} modify myself is OK", current.account);
-            return true;
+/// self is always fine, otherwise `permission` is needed and the target must hold fewer
+/// permissions than the current user, unless the current user manages roles.
+async fn permission_check(
+    database: &Database,
+    current: Option<&CurrentUser>,
+    target: &UserRawInfo,
+    permission: &str,
+) -> bool {
+    let current = match current {
+        Some(current) => current,
+        None => {
+            error!("TODO, not login");
+            return false;
         }
+    };
 
-        match (current_role, target_role) {
-            (PermissionRole::Admin(role), _) => {
-                info!("{role} change anything");
-                true
-            }
-            (PermissionRole::Gm(role), PermissionRole::Admin(_)) => {
-                error!("{role} can't change admin");
-                false
-            }
-            (PermissionRole::Gm(role), PermissionRole::Gm(_)) => {
-                if target.account != current.account {
-                    error!("{role} can't change other GM");
-                    false
-                } else {
-                    info!("{role} change herself");
-                    true
-                }
-            }
-            (PermissionRole::Gm(role), _) => {
-                info!("{role} change other");
-                true
-            }
-            (_, _) => {
-                error!("staff can't change each other");
-                false
+    if target.account == current.account {
+        debug!("{} modify myself is OK", current.account);
+        return true;
+    }
+    if !current.has_permission(permission) {
+        error!("{} lacks {permission}", current.account);
+        return false;
+    }
+    if current.has_permission(RoleManage::NAME) {
+        info!("{} change anything", current.account);
+        return true;
+    }
+
+    match rbac::user_permissions(database, target.id).await {
+        Ok(target_permissions) => {
+            let below = target_permissions.len() < current.permissions.len()
+                && target_permissions.iter().all(|p| current.has_permission(p));
+            if !below {
+                error!("{} can't change {}", current.account, target.account);
             }
+            below
+        }
+        Err(e) => {
+            error!("permissions of {} - {e}", target.account);
+            false
         }
-    } else {
-        error!("TODO, not login");
-        false
     }
 }
 
-async fn update_login_at(database: &Database, account: &str) -> Result<()> {
+pub(crate) async fn update_login_at(database: &Database, account: &str) -> Result<()> {
     let fetch_one: Result<(i32,), _> =
         sqlx::query_as("UPDATE users SET login_at = $1 WHERE account = $2 RETURNING id;")
             .bind(Utc::now())
@@ -1211,12 +1480,15 @@ pub struct TestUser {
     permission: BitVec,
 }
 
-pub(crate) async fn query_raw_user(database: &Database, account: &str) -> Option<UserRawInfo> {
-    const QUERY: &str = "SELECT * FROM users WHERE account = $1;";
+pub(crate) async fn query_raw_user<'e, E>(executor: E, account: &str) -> Option<UserRawInfo>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    const QUERY: &str = "SELECT * FROM users WHERE account = $1 AND deleted_at IS NULL;";
 
     if let Ok(user) = sqlx::query_as::<_, UserRawInfo>(QUERY)
         .bind(account)
-        .fetch_optional(database)
+        .fetch_optional(executor)
         .await
     {
         user
@@ -1226,7 +1498,7 @@ pub(crate) async fn query_raw_user(database: &Database, account: &str) -> Option
 }
 
 pub(crate) async fn query_user_by_department_id(database: &Database, did: i32) -> Option<String> {
-    const QUERY: &str = "SELECT * FROM users WHERE department_id = $1;";
+    const QUERY: &str = "SELECT * FROM users WHERE department_id = $1 AND deleted_at IS NULL;";
 
     if let Ok(user) = sqlx::query_as::<_, UserRawInfo>(QUERY)
         .bind(did)
@@ -1244,7 +1516,7 @@ async fn shared_store_users_init(database: &Database, state: SharedState) -> Res
         return Ok(());
     }
 
-    const QUERY: &str = "SELECT * FROM users;";
+    const QUERY: &str = "SELECT * FROM users WHERE deleted_at IS NULL;";
 
     if let Ok(users) = sqlx::query_as::<_, UserRawInfo>(QUERY)
         .fetch_all(database)
@@ -2,7 +2,7 @@ use axum::{
     extract::State,
     extract::{Extension, Path, Query},
     http::StatusCode,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     //response::{Html, Redirect},
     Json,
 };
@@ -11,7 +11,8 @@ use anyhow::{anyhow, Result};
 use bit_vec::BitVec;
 use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
-//use serde_json::json;
+use serde_json::json;
+use sqlx::{Executor, Postgres, Transaction};
 use tracing::{
     error,
     //debug,
@@ -24,11 +25,17 @@ use utoipa::{IntoParams, ToSchema};
 //postgres::PgRow,
 //};
 
+use crate::audit::{Audit, AuditEvent};
 use crate::authentication::AuthState;
 use crate::dcare_order::query_order_by_department_id;
 use crate::dcare_user::query_user_by_department_id;
 
-use crate::errors::NotLoggedIn;
+use crate::etag::{self, IfMatch};
+use crate::order_sn;
+use crate::policy;
+use crate::query_filter::{list, ListFilter};
+use crate::rbac::{self, DepartmentCreate, DepartmentDelete, DepartmentRead, Require, TrashManage};
+use crate::trash::{self, Trash};
 use crate::{ApiResponse, Database, Pagination, SharedState};
 
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
@@ -52,110 +59,29 @@ pub struct DepartmentListQuery {
 }
 
 impl DepartmentListQuery {
-    pub fn parse(mine: Option<Query<Self>>) -> (i32, i32, String) {
-        if let Some(ref q) = mine {
-            let offset = q.offset.map_or(0, |o| o);
-            let entries = q.entries.map_or(100, |e| e);
-
-            let where_is = if let Some(ref p) = q.telephone {
-                format!("WHERE d.telephone = '{p}'")
-            } else {
-                "".to_string()
-            };
-            let where_is = if let Some(ref s) = q.shorten {
-                let sql_d = format!("d.shorten = '{s}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql_d}")
-                } else {
-                    format!("{where_is} AND {sql_d}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref s) = q.store_name {
-                let sql_d = format!("d.store_name = '{s}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql_d}")
-                } else {
-                    format!("{where_is} AND {sql_d}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref s) = q.owner {
-                let sql_d = format!("d.owner = '{s}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql_d}")
-                } else {
-                    format!("{where_is} AND {sql_d}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref s) = q.address {
-                let sql_d = format!("d.address = '{s}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql_d}")
-                } else {
-                    format!("{where_is} AND {sql_d}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref s) = q.type_mask {
-                let sql_d = format!("d.type_mask & {:?}", s);
-                if where_is.is_empty() {
-                    format!("WHERE {sql_d}")
-                } else {
-                    format!("{where_is} AND {sql_d}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref s) = q.create_start {
-                let sql = format!("d.create_at >= '{s}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql}")
-                } else {
-                    format!("{where_is} AND {sql}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref s) = q.create_end {
-                let sql = format!("d.create_at < '{s}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql}")
-                } else {
-                    format!("{where_is} AND {sql}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref s) = q.update_start {
-                let sql = format!("d.update_at >= '{s}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql}")
-                } else {
-                    format!("{where_is} AND {sql}")
-                }
-            } else {
-                where_is
-            };
-            let where_is = if let Some(ref s) = q.update_end {
-                let sql = format!("d.update_at < '{s}'");
-                if where_is.is_empty() {
-                    format!("WHERE {sql}")
-                } else {
-                    format!("{where_is} AND {sql}")
-                }
-            } else {
-                where_is
-            };
-
-            (offset, entries, where_is)
+    /// add the filters to `filter`, `(offset, entries)` of the page
+    pub fn parse(mine: Option<Query<Self>>, filter: &mut ListFilter) -> (i32, i32) {
+        if let Some(Query(q)) = mine {
+            if let Some(type_mask) = q.type_mask {
+                /* shares any bit */
+                filter.with(|b| {
+                    b.push("(d.type_mask & ")
+                        .push_bind(type_mask)
+                        .push("::bit(8)) <> B'00000000'");
+                });
+            }
+            filter
+                .eq("d.telephone", q.telephone)
+                .any_of("d.shorten", list(&q.shorten))
+                .eq("d.store_name", q.store_name)
+                .eq("d.owner", q.owner)
+                .eq("d.address", q.address)
+                .range("d.create_at", q.create_start, q.create_end)
+                .range("d.update_at", q.update_start, q.update_end);
+
+            (q.offset.unwrap_or(0), q.entries.unwrap_or(100))
         } else {
-            (0, 100, "".to_string())
+            (0, 100)
         }
     }
 }
@@ -210,6 +136,7 @@ pub struct DepartmentRawInfo {
     telephone: Option<String>,
     address: Option<String>,
     type_mask: BitVec,
+    version: i32,
 }
 
 #[derive(Debug, Serialize, Deserialize, ToSchema, sqlx::FromRow)]
@@ -329,11 +256,17 @@ pub struct DepartmentsResponse {
         ("shorten" = String, Path, description = "department shorten name")
     ),
     responses(
-        (status = 200, description = "get detail department information", body = DepartmentResponse)
-    )
+        (status = 200, description = "get detail department information, `ETag` is its version", body = DepartmentResponse),
+        (status = 403, description = "permission deny, department out of reach", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
 )]
 pub(crate) async fn department_request(
-    Extension(_auth_state): Extension<AuthState>,
+    current: Require<DepartmentRead>,
     Extension(database): Extension<Database>,
     Path(shorten): Path<String>,
 ) -> impl IntoResponse {
@@ -342,11 +275,19 @@ pub(crate) async fn department_request(
         department: None,
     };
 
+    let orig = match query_raw_department(&database, &shorten).await {
+        Some(orig) => orig,
+        None => return (StatusCode::OK, Json(resp)).into_response(),
+    };
+    if !policy::sees_department(&current, Some(orig.id)) {
+        return rbac::forbidden(format!("permission deny, department/{shorten}"));
+    }
+
     if let Some(o) = query_department(&database, &shorten).await {
         resp.code = 200;
         resp.department = Some(o);
     }
-    (StatusCode::OK, Json(resp)).into_response()
+    etag::with_etag(orig.version, (StatusCode::OK, Json(resp)))
 }
 
 #[utoipa::path(
@@ -357,32 +298,30 @@ pub(crate) async fn department_request(
     ),
     request_body = DepartmentUpdate,
     responses(
-        (status = 200, description = "update success", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 200, description = "update success, `ETag` is the new version", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
         (status = 404, description = "department not found, ", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
-        (status = 405, description = "permission deny, ", body = ApiResponse, example = json!(ApiResponse::new(405, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, department.update required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 409, description = "updated by somebody else meanwhile, `current` is the department now; or the shorten is held by a deleted department or collides with another one in order numbers", body = ApiResponse, example = json!(ApiResponse::new(409, Some(String::from("..."))))),
+        (status = 412, description = "`If-Match` isn't the current version, `current` is the department now", body = ApiResponse, example = json!(ApiResponse::new(412, Some(String::from("..."))))),
         (status = 500, description = "server error, ", body = ApiResponse, example = json!(ApiResponse::new(500, Some(String::from("..."))))),
     ),
     security(
         //(), // <-- make optional authentication
-        ("logined cookie/session-id" = [])
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
     ),
 )]
 pub(crate) async fn department_update(
-    Extension(mut current_user): Extension<AuthState>,
+    current: Require<rbac::DepartmentUpdate>,
     Path(shorten): Path<String>,
     Extension(database): Extension<Database>,
     State(state): State<SharedState>,
+    audit: Audit,
+    if_match: IfMatch,
     Json(department): Json<DepartmentUpdate>,
 ) -> impl IntoResponse {
     let mut resp = ApiResponse::new(400, None);
 
-    let _issuer = if let Some(user) = current_user.get_user().await {
-        user
-    } else {
-        resp.update(400, Some(format!("{}", &NotLoggedIn)));
-        return (StatusCode::OK, Json(resp)).into_response();
-    };
-
     let orig = match query_raw_department(&database, &shorten).await {
         Some(orig) => orig,
         None => {
@@ -391,6 +330,21 @@ pub(crate) async fn department_update(
             return (StatusCode::OK, Json(resp)).into_response();
         }
     };
+    if !if_match.matches(orig.version) {
+        let current = query_department(&database, &shorten).await;
+        return etag::precondition_failed(current, orig.version);
+    }
+    if let Some(shorten) = department.shorten.as_ref().filter(|s| **s != orig.shorten) {
+        if let Some(taken) = trash::taken(&database, Trash::Department, shorten).await {
+            return taken;
+        }
+        if let Some(refused) = shorten_refused(&database, shorten, Some(orig.id)).await {
+            return refused;
+        }
+    }
+    let event = AuditEvent::new("department.update", "department", &orig.shorten)
+        .by(&*current)
+        .before(&orig);
 
     //let shorten = department.shorten.or(Some(orig.shorten));
     //let store_name = department.store_name.or(orig.store_name);
@@ -432,9 +386,20 @@ pub(crate) async fn department_update(
             owner = $4,
             telephone = $5,
             type_mask = $6,
-            shorten = $7
-        WHERE id = $8 RETURNING id;"#;
-    let fetch_one: Result<(i32,), _> = sqlx::query_as(UPDATE_QUERY)
+            shorten = $7,
+            version = version + 1
+        WHERE id = $8 AND version = $9 RETURNING id;"#;
+
+    /* the department, its parents and the audit event commit together */
+    let mut tx = match database.begin().await {
+        Ok(tx) => tx,
+        Err(e) => {
+            resp.update(500, Some(format!("DB error - {e}")));
+            error!("{:?}", &resp);
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+    };
+    let fetch_one: Result<Option<(i32,)>, _> = sqlx::query_as(UPDATE_QUERY)
         .bind(Utc::now())
         .bind(store_name)
         .bind(address)
@@ -443,22 +408,32 @@ pub(crate) async fn department_update(
         .bind(type_mask)
         .bind(&shorten)
         .bind(orig.id)
-        .fetch_one(&database)
+        .bind(orig.version)
+        .fetch_optional(&mut tx)
         .await;
 
     match fetch_one {
-        Ok((id,)) => {
-            let org_done = match department.parents {
-                Some(ref parents) => {
-                    department_org_update_parents(&database, orig.id, parents).await
+        Ok(None) => return department_conflict(&database, orig.id).await,
+        Ok(Some((id,))) => {
+            if let Some(ref parents) = department.parents {
+                if let Err(e) = department_org_update_parents(&mut tx, orig.id, parents).await {
+                    resp.update(
+                        400,
+                        Some(format!("department organization update fail - {e}")),
+                    );
+                    error!("{:?}", &resp);
+                    return (StatusCode::OK, Json(resp)).into_response();
                 }
-                None => Ok(()),
-            };
-            if org_done.is_ok() {
-                resp.update(200, Some(format!("department{id} update success")));
-            } else {
-                resp.update(400, Some("department organization update fail".to_string()));
             }
+
+            let after = query_raw_department(&mut tx, shorten.as_deref().unwrap_or_default()).await;
+            if let Err(e) = audit.commit(tx, event.after(&after)).await {
+                resp.update(500, Some(format!("{e}")));
+                return (StatusCode::OK, Json(resp)).into_response();
+            }
+            resp.update(200, Some(format!("department{id} update success")));
+
+            return etag::with_etag(orig.version + 1, (StatusCode::OK, Json(resp)));
         }
         Err(e) => {
             resp.update(500, Some(format!("{e}")));
@@ -469,6 +444,44 @@ pub(crate) async fn department_update(
     (StatusCode::OK, Json(resp)).into_response()
 }
 
+/// 409 if order numbers of `shorten` could be another department's (`BM` and `BM0` are
+/// both `BM0...`), `except` is the department itself
+async fn shorten_refused(
+    database: &Database,
+    shorten: &str,
+    except: Option<i32>,
+) -> Option<Response> {
+    let resp = match order_sn::shorten_collision(database, shorten, except).await {
+        Ok(None) => return None,
+        Ok(Some(other)) => ApiResponse::new(
+            409,
+            Some(format!(
+                "shorten {shorten} collides with {other} in order numbers"
+            )),
+        ),
+        Err(e) => ApiResponse::new(500, Some(format!("{e}"))),
+    };
+    error!("{:?}", &resp);
+    Some((StatusCode::OK, Json(resp)).into_response())
+}
+
+/// 409 with the department as it is now, or as deleted
+async fn department_conflict(database: &Database, id: i32) -> Response {
+    const QUERY: &str = "SELECT shorten, version FROM departments WHERE id = $1;";
+
+    let now: Option<(String, i32)> = sqlx::query_as(QUERY)
+        .bind(id)
+        .fetch_optional(database)
+        .await
+        .unwrap_or_default();
+    match now {
+        Some((shorten, version)) => {
+            etag::conflict(query_department(database, &shorten).await, Some(version))
+        }
+        None => etag::conflict(Option::<DepartmentInfo>::None, None),
+    }
+}
+
 #[utoipa::path(
     delete,
     path = "/api/v1/department/{shorten}",
@@ -477,34 +490,38 @@ pub(crate) async fn department_update(
         DepartmentOrgPair,
     ),
     responses(
-        (status = 200, description = "delete success", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
+        (status = 200, description = "delete success, without a pair the department goes to the trash, trash.manage restores it", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
         (status = 404, description = "department not found, ", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
-        (status = 405, description = "permission deny", body = ApiResponse, example = json!(ApiResponse::new(405, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, department.delete required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 409, description = "updated by somebody else meanwhile, `current` is the department now", body = ApiResponse, example = json!(ApiResponse::new(409, Some(String::from("..."))))),
+        (status = 412, description = "`If-Match` isn't the current version, `current` is the department now", body = ApiResponse, example = json!(ApiResponse::new(412, Some(String::from("..."))))),
     ),
     security(
         //(), // <-- make optional authentication
-        ("logined cookie/session-id" = [])
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
     ),
 )]
 pub(crate) async fn department_delete(
-    Extension(mut current_user): Extension<AuthState>,
+    current: Require<DepartmentDelete>,
     Extension(database): Extension<Database>,
     State(state): State<SharedState>,
+    audit: Audit,
     Path(shorten): Path<String>,
     pair: Query<DepartmentOrgPair>,
+    if_match: IfMatch,
 ) -> impl IntoResponse {
     let mut resp = ApiResponse::new(400, None);
 
-    let _issuer = if let Some(user) = current_user.get_user().await {
-        user
-    } else {
-        resp.update(400, Some(format!("{}", &NotLoggedIn)));
-        return (StatusCode::OK, Json(resp)).into_response();
-    };
-
-    match query_raw_department(&database, &shorten).await {
+    let orig = match query_raw_department(&database, &shorten).await {
         Some(orig) => {
             if pair.parent.is_none() && pair.child.is_none() {
+                /* If-Match is about the department, not one pair of the organization */
+                if !if_match.matches(orig.version) {
+                    let current = query_department(&database, &shorten).await;
+                    return etag::precondition_failed(current, orig.version);
+                }
+
                 /* check related before deleted it */
                 if let Some(user) = query_user_by_department_id(&database, orig.id).await {
                     resp.update(400, Some(format!("reject due to user/{user} related")));
@@ -517,13 +534,14 @@ pub(crate) async fn department_delete(
                     return (StatusCode::OK, Json(resp)).into_response();
                 }
             }
+            orig
         }
         None => {
             resp.update(404, Some(format!("department{shorten} not found")));
             error!("{:?}", &resp);
             return (StatusCode::OK, Json(resp)).into_response();
         }
-    }
+    };
 
     /* manual delete organization....
      * if query_childs(&database, orig.id).await.is_some() {
@@ -531,7 +549,17 @@ pub(crate) async fn department_delete(
         error!("{:?}", &resp);
         return (StatusCode::OK, Json(resp)).into_response();
     }*/
-    match org_delete(&database, &shorten, pair).await {
+    /* the organization pairs, the department and the audit event commit together */
+    let mut tx = match database.begin().await {
+        Ok(tx) => tx,
+        Err(e) => {
+            resp.update(500, Some(format!("DB error - {e}")));
+            error!("{:?}", &resp);
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+    };
+    let pair_removed = json!({ "parent": pair.parent, "child": pair.child });
+    match org_delete(&mut tx, &shorten, pair).await {
         Err(e) => {
             resp.update(400, Some(format!("delete organization pair fail - {e}")));
             error!("{:?}", &resp);
@@ -539,27 +567,102 @@ pub(crate) async fn department_delete(
         }
         Ok(all) => {
             if !all {
+                let event = AuditEvent::new("department.org_delete", "department", &shorten)
+                    .by(&*current)
+                    .before(&pair_removed);
+                if let Err(e) = audit.commit(tx, event).await {
+                    resp.update(500, Some(format!("{e}")));
+                    return (StatusCode::OK, Json(resp)).into_response();
+                }
                 resp.update(200, Some("delete organization pair success".to_string()));
-                error!("{:?}", &resp);
                 return (StatusCode::OK, Json(resp)).into_response();
             }
         }
     }
 
-    const QUERY: &str = r#"
-        DELETE from departments WHERE shorten = $1
-        RETURNING id;"#;
+    let by = Some(current.id);
+    match trash::delete(&mut tx, Trash::Department, &shorten, orig.version, by).await {
+        Ok(true) => {
+            let event = AuditEvent::new("department.delete", "department", &shorten)
+                .by(&*current)
+                .before(&orig);
+            if let Err(e) = audit.commit(tx, event).await {
+                resp.update(500, Some(format!("{e}")));
+                return (StatusCode::OK, Json(resp)).into_response();
+            }
+            let _ = shared_store_departments_del(state.clone(), &shorten).await;
+            resp.update(200, Some("delete success".to_string()));
+        }
+        Ok(false) => return department_conflict(&database, orig.id).await,
+        Err(e) => {
+            resp.update(500, Some(format!("delete fail - {e}")));
+            error!("{:?}", &resp);
+        }
+    }
+    (StatusCode::OK, Json(resp)).into_response()
+}
 
-    if sqlx::query_as::<_, DepartmentDeleteRes>(QUERY)
-        .bind(&shorten)
-        .fetch_all(&database)
-        .await
-        .is_ok()
+#[utoipa::path(
+    post,
+    path = "/api/v1/department/{shorten}/restore",
+    params(
+        ("shorten" = String, Path, description = "deleted department shorten to restore")
+    ),
+    responses(
+        (status = 200, description = "restore success, `ETag` is the new version; organization pairs were kept", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("restore success"))))),
+        (status = 404, description = "no deleted department of the shorten", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, trash.manage required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn department_restore(
+    current: Require<TrashManage>,
+    Extension(database): Extension<Database>,
+    State(state): State<SharedState>,
+    audit: Audit,
+    Path(shorten): Path<String>,
+) -> impl IntoResponse {
+    let response = trash::restore(&database, &audit, &current, Trash::Department, &shorten).await;
+    if let Some(DepartmentRawInfo {
+        id,
+        store_name: Some(store),
+        ..
+    }) = query_raw_department(&database, &shorten).await
     {
-        let _ = shared_store_departments_del(state.clone(), &shorten).await;
-        resp.update(200, Some("delete success".to_string()));
+        let _ = shared_store_departments_set(state, &shorten, &store, Some(id)).await;
     }
-    (StatusCode::OK, Json(resp)).into_response()
+    response
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/department/{shorten}/purge",
+    params(
+        ("shorten" = String, Path, description = "deleted department shorten to remove for good")
+    ),
+    responses(
+        (status = 200, description = "purge success, its organization pairs go with it", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("purge success"))))),
+        (status = 404, description = "no deleted department of the shorten, delete it first", body = ApiResponse, example = json!(ApiResponse::new(404, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, trash.manage required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 409, description = "orders or users, deleted ones too, still refer to the department", body = ApiResponse, example = json!(ApiResponse::new(409, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
+)]
+pub(crate) async fn department_purge(
+    current: Require<TrashManage>,
+    Extension(database): Extension<Database>,
+    audit: Audit,
+    Path(shorten): Path<String>,
+) -> impl IntoResponse {
+    trash::purge(&database, &audit, &current, Trash::Department, &shorten).await
 }
 
 #[utoipa::path(
@@ -569,10 +672,17 @@ pub(crate) async fn department_delete(
         DepartmentListQuery
     ),
     responses(
-        (status = 200, description = "get department list", body = DepartmentsResponse)
-    )
+        (status = 200, description = "get department list, own department and descendants", body = DepartmentsResponse),
+        (status = 403, description = "permission deny, department.read required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+    ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
 )]
 pub(crate) async fn department_list_request(
+    current: Require<DepartmentRead>,
     Extension(database): Extension<Database>,
     query: Option<Query<DepartmentListQuery>>,
 ) -> impl IntoResponse {
@@ -581,10 +691,7 @@ pub(crate) async fn department_list_request(
         departments: None,
     };
 
-    let (offset, entries, where_dep) = DepartmentListQuery::parse(query);
-
-    let sselect = format!(
-        r#"
+    const SELECT: &str = r#"
         SELECT
             d.id,
             create_at,
@@ -596,11 +703,16 @@ pub(crate) async fn department_list_request(
             type_mask,
             address
         FROM departments d
-        {where_dep} LIMIT {entries} OFFSET {offset};
-    "#
-    );
+    "#;
 
-    if let Ok(mut departments) = sqlx::query_as::<_, DepartmentInfoPartial>(&sselect)
+    let mut filter = ListFilter::new(SELECT);
+    let (offset, entries) = DepartmentListQuery::parse(query, &mut filter);
+    filter.trusted(&policy::department_filter(&current, "d.id"));
+    filter.trusted("d.deleted_at IS NULL");
+    let mut query = filter.finish("", entries, offset);
+
+    if let Ok(mut departments) = query
+        .build_query_as::<DepartmentInfoPartial>()
         .fetch_all(&database)
         .await
     {
@@ -626,23 +738,31 @@ pub(crate) async fn department_list_request(
     responses(
         (status = 200, description = "add department success", body = ApiResponse, example = json!(ApiResponse::new(200, Some(String::from("success"))))),
         (status = 400, description = "department exist, ", body = ApiResponse, example = json!(ApiResponse::new(400, Some(String::from("..."))))),
+        (status = 403, description = "permission deny, department.create required", body = ApiResponse, example = json!(ApiResponse::new(403, Some(String::from("..."))))),
+        (status = 409, description = "a deleted department holds the shorten (restore or purge it first), or it collides with another one in order numbers (`BM`, `BM0`)", body = ApiResponse, example = json!(ApiResponse::new(409, Some(String::from("shorten BM0 collides with BM in order numbers"))))),
         (status = 500, description = "server DB error, ", body = ApiResponse, example = json!(ApiResponse::new(500, Some(String::from("..."))))),
     ),
+    security(
+        //(), // <-- make optional authentication
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
+    ),
 )]
 pub(crate) async fn department_create(
+    current: Require<DepartmentCreate>,
     Extension(database): Extension<Database>,
     State(state): State<SharedState>,
-    Extension(mut current_user): Extension<AuthState>,
+    audit: Audit,
     Json(department): Json<DepartmentNew>,
 ) -> impl IntoResponse {
     let mut resp = ApiResponse::new(200, Some(String::from("success")));
 
-    let _issuer = if let Some(user) = current_user.get_user().await {
-        user
-    } else {
-        resp.update(400, Some(format!("{}", &NotLoggedIn)));
-        return (StatusCode::OK, Json(resp)).into_response();
-    };
+    if let Some(taken) = trash::taken(&database, Trash::Department, &department.shorten).await {
+        return taken;
+    }
+    if let Some(refused) = shorten_refused(&database, &department.shorten, None).await {
+        return refused;
+    }
 
     const INSERT_QUERY: &str = r#"
         INSERT INTO departments (
@@ -655,6 +775,16 @@ pub(crate) async fn department_create(
         ) VALUES (
             $1, $2, $3, $4, $5, $6
         ) RETURNING id;"#;
+
+    /* the department, its parents and the audit event commit together */
+    let mut tx = match database.begin().await {
+        Ok(tx) => tx,
+        Err(e) => {
+            resp.update(500, Some(format!("DB error - {e}")));
+            error!("{:?}", &resp);
+            return (StatusCode::OK, Json(resp)).into_response();
+        }
+    };
     let fetch_one: Result<(i32,), _> = sqlx::query_as(INSERT_QUERY)
         .bind(&department.shorten)
         .bind(&department.store_name)
@@ -662,24 +792,33 @@ pub(crate) async fn department_create(
         .bind(department.telephone)
         .bind(department.type_mask)
         .bind(department.address)
-        .fetch_one(&database)
+        .fetch_one(&mut tx)
         .await;
 
     match fetch_one {
         Ok((id,)) => {
-            let org_done = match department.parents {
-                Some(ref parents) => department_org_update_parents(&database, id, parents).await,
-                None => Ok(()),
-            };
-            if org_done.is_ok() {
-                if let Some(store) = department.store_name {
-                    shared_store_departments_set(state, &department.shorten, &store, Some(id))
-                        .await;
+            if let Some(ref parents) = department.parents {
+                if let Err(e) = department_org_update_parents(&mut tx, id, parents).await {
+                    resp.update(
+                        400,
+                        Some(format!("department organization update fail - {e}")),
+                    );
+                    error!("{:?}", &resp);
+                    return (StatusCode::OK, Json(resp)).into_response();
                 }
-                resp.update(200, Some(format!("department{id} create success")));
-            } else {
-                resp.update(400, Some("department organization update fail".to_string()));
             }
+            let event = AuditEvent::new("department.create", "department", &department.shorten)
+                .by(&*current)
+                .after(&query_raw_department(&mut tx, &department.shorten).await);
+            if let Err(e) = audit.commit(tx, event).await {
+                resp.update(500, Some(format!("{e}")));
+                return (StatusCode::OK, Json(resp)).into_response();
+            }
+
+            if let Some(store) = department.store_name {
+                shared_store_departments_set(state, &department.shorten, &store, Some(id)).await;
+            }
+            resp.update(200, Some(format!("department{id} create success")));
         }
         Err(e) => {
             resp.update(500, Some(format!("{e}")));
@@ -709,13 +848,15 @@ pub struct DepartmentOrgPair {
     ),
     security(
         //(), // <-- make optional authentication
-        ("logined cookie/session-id" = [])
+        ("logined cookie/session-id" = []),
+        ("bearer token/api-key" = [])
     ),
 )]
 #[allow(dead_code)]
 pub(crate) async fn department_org_delete(
-    Extension(_current_user): Extension<AuthState>,
+    Extension(mut current_user): Extension<AuthState>,
     Extension(database): Extension<Database>,
+    audit: Audit,
     Path(shorten): Path<String>,
     pair: Query<DepartmentOrgPair>,
 ) -> impl IntoResponse {
@@ -771,6 +912,11 @@ pub(crate) async fn department_org_delete(
         }
     }
 
+    let event = AuditEvent::new("department.org_delete", "department", &shorten)
+        .by(current_user.get_user().await)
+        .before(&json!({ "parent": pair.parent, "child": pair.child }));
+    let _ = audit.record(&database, event).await;
+
     (StatusCode::OK, Json(resp)).into_response()
 }
 
@@ -859,12 +1005,15 @@ async fn query_department(database: &Database, shorten: &str) -> Option<Departme
 }
 
 #[allow(dead_code)]
-async fn query_raw_department(database: &Database, shorten: &str) -> Option<DepartmentRawInfo> {
-    const QUERY: &str = "SELECT * FROM departments WHERE shorten = $1;";
+async fn query_raw_department<'e, E>(executor: E, shorten: &str) -> Option<DepartmentRawInfo>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    const QUERY: &str = "SELECT * FROM departments WHERE shorten = $1 AND deleted_at IS NULL;";
 
     match sqlx::query_as::<_, DepartmentRawInfo>(QUERY)
         .bind(shorten)
-        .fetch_optional(database)
+        .fetch_optional(executor)
         .await
     {
         Ok(res) => res,
@@ -941,7 +1090,7 @@ pub(crate) async fn department_shorten_or_insert(
 }*/
 
 pub(crate) async fn department_shorten_query(database: &Database, shorten: &str) -> Result<i32> {
-    const QUERY: &str = "SELECT id FROM departments WHERE shorten = $1;";
+    const QUERY: &str = "SELECT id FROM departments WHERE shorten = $1 AND deleted_at IS NULL;";
     let department: Option<(i32,)> = sqlx::query_as(QUERY)
         .bind(shorten)
         .fetch_optional(database)
@@ -962,7 +1111,7 @@ async fn query_parent_shorten(database: &Database, id: i32) -> Option<Vec<Depart
             d.shorten
         FROM department_orgs o
         LEFT JOIN departments d ON d.id = o.parent_id
-        WHERE o.child_id = $1;
+        WHERE o.child_id = $1 AND d.deleted_at IS NULL;
     "#;
 
     let row = sqlx::query_as::<_, DepartmentOrg>(QUERY)
@@ -1009,7 +1158,7 @@ async fn query_childs(database: &Database, pid: i32) -> Option<Vec<DepartmentOrg
     const QUERY: &str = r#"
         SELECT d.shorten FROM department_orgs o
         LEFT JOIN departments d ON d.id = o.child_id
-        WHERE o.parent_id = $1;
+        WHERE o.parent_id = $1 AND d.deleted_at IS NULL;
     "#;
 
     let row = sqlx::query_as::<_, DepartmentOrg>(QUERY)
@@ -1024,7 +1173,7 @@ async fn query_childs(database: &Database, pid: i32) -> Option<Vec<DepartmentOrg
 
 #[allow(dead_code)]
 async fn department_org_update_parents(
-    database: &Database,
+    tx: &mut Transaction<'_, Postgres>,
     id: i32,
     parents: &[String],
 ) -> Result<()> {
@@ -1043,7 +1192,7 @@ async fn department_org_update_parents(
         let found: Result<Option<(i32,)>, _> = sqlx::query_as(QUERY)
             .bind(p)
             .bind(id)
-            .fetch_optional(database)
+            .fetch_optional(&mut *tx)
             .await;
 
         if let Ok(Some(_)) = found {
@@ -1059,7 +1208,7 @@ async fn department_org_update_parents(
             let fetch_one: Result<(i32,), _> = sqlx::query_as(INSERT_QUERY)
                 .bind(p)
                 .bind(id)
-                .fetch_one(database)
+                .fetch_one(&mut *tx)
                 .await;
 
             match fetch_one {
@@ -1139,7 +1288,7 @@ async fn department_org_renew_parents(
 }
 
 async fn org_delete(
-    database: &Database,
+    tx: &mut Transaction<'_, Postgres>,
     shorten: &String,
     pair: Query<DepartmentOrgPair>,
 ) -> Result<bool> {
@@ -1157,7 +1306,7 @@ async fn org_delete(
         if let Err(e) = sqlx::query_as::<_, DepartmentDeleteRes>(QUERY)
             .bind(shorten)
             .bind(parent)
-            .fetch_all(database)
+            .fetch_all(&mut *tx)
             .await
         {
             return Err(anyhow!({ e }));
@@ -1177,7 +1326,7 @@ async fn org_delete(
         if let Err(e) = sqlx::query_as::<_, DepartmentDeleteRes>(QUERY)
             .bind(child)
             .bind(shorten)
-            .fetch_all(database)
+            .fetch_all(&mut *tx)
             .await
         {
             return Err(anyhow!({ e }));
@@ -1197,7 +1346,7 @@ async fn org_delete(
 
         if let Err(e) = sqlx::query_as::<_, DepartmentDeleteRes>(QUERY)
             .bind(shorten)
-            .fetch_all(database)
+            .fetch_all(&mut *tx)
             .await
         {
             return Err(anyhow!({ e }));
@@ -1212,7 +1361,7 @@ pub async fn shared_store_departments_init(database: &Database, state: SharedSta
         return Ok(());
     }
 
-    const QUERY: &str = "SELECT * FROM departments;";
+    const QUERY: &str = "SELECT * FROM departments WHERE deleted_at IS NULL;";
 
     if let Ok(departments) = sqlx::query_as::<_, DepartmentRawInfo>(QUERY)
         .fetch_all(database)
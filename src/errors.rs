@@ -1,5 +1,8 @@
 use std::{error::Error, fmt::Display};
 
+use serde::Serialize;
+use utoipa::ToSchema;
+
 #[derive(Debug)]
 #[allow(dead_code)]
 pub(crate) enum MultipartError {
@@ -31,17 +34,40 @@ impl Display for NotLoggedIn {
 
 impl Error for NotLoggedIn {}
 
-#[derive(Debug)]
+/// Why a signup or password change was refused, `error` of `SignupErrorResponse`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
 #[allow(dead_code)]
-pub(crate) enum SignupError {
+pub enum SignupError {
     UsernameExists,
     InvalidUsername,
     PasswordsDoNotMatch,
     MissingDetails,
     InvalidPassword,
+    /// shorter than `PASSWORD_MIN_LENGTH`
+    PasswordTooShort,
+    /// fewer character classes than `PASSWORD_MIN_CLASSES`
+    PasswordTooSimple,
+    /// on the common password list
+    PasswordTooCommon,
+    /// same as the account, phone or email
+    PasswordIsPersonal,
+    /// one of the last `PASSWORD_HISTORY` passwords
+    PasswordReused,
     InternalError,
 }
 
+impl SignupError {
+    /// `code` of the response body
+    pub fn code(&self) -> u16 {
+        match self {
+            SignupError::UsernameExists => 409,
+            SignupError::InternalError => 500,
+            _ => 400,
+        }
+    }
+}
+
 impl Display for SignupError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -50,6 +76,15 @@ impl Display for SignupError {
             SignupError::PasswordsDoNotMatch => f.write_str("Passwords do not match"),
             SignupError::MissingDetails => f.write_str("Missing Details"),
             SignupError::InvalidPassword => f.write_str("Invalid Password"),
+            SignupError::PasswordTooShort => f.write_str("Password is too short"),
+            SignupError::PasswordTooSimple => {
+                f.write_str("Password needs more kinds of characters")
+            }
+            SignupError::PasswordTooCommon => f.write_str("Password is too common"),
+            SignupError::PasswordIsPersonal => {
+                f.write_str("Password can't be the account, phone or email")
+            }
+            SignupError::PasswordReused => f.write_str("Password was used recently"),
             SignupError::InternalError => f.write_str("Internal Error"),
         }
     }
@@ -57,26 +92,71 @@ impl Display for SignupError {
 
 impl Error for SignupError {}
 
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SignupErrorResponse {
+    code: u16,
+    error: SignupError,
+    message: String,
+}
+
+impl From<SignupError> for SignupErrorResponse {
+    fn from(error: SignupError) -> Self {
+        Self {
+            code: error.code(),
+            error,
+            message: error.to_string(),
+        }
+    }
+}
+
 #[derive(Debug)]
 #[allow(dead_code)]
 pub(crate) enum LoginError {
     MissingDetails,
-    UserDoesNotExist,
-    WrongPassword,
+    /// unknown account or wrong password, never told apart
+    InvalidCredentials,
+    /// account or client IP blocked, retry after seconds
+    TooManyAttempts(i64),
+    InternalError,
 }
 
 impl Display for LoginError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            LoginError::UserDoesNotExist => f.write_str("User does not exist"),
             LoginError::MissingDetails => f.write_str("Missing details"),
-            LoginError::WrongPassword => f.write_str("Wrong password"),
+            LoginError::InvalidCredentials => f.write_str("Invalid account or password"),
+            LoginError::TooManyAttempts(secs) => f.write_fmt(format_args!(
+                "Too many failed logins, retry after {secs} seconds"
+            )),
+            LoginError::InternalError => f.write_str("Internal error"),
         }
     }
 }
 
 impl Error for LoginError {}
 
+#[derive(Debug)]
+pub(crate) enum PasswordHashError {
+    /// stored hash is not a readable PHC string
+    Corrupt,
+    Unsupported(String),
+    Hashing(String),
+}
+
+impl Display for PasswordHashError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PasswordHashError::Corrupt => f.write_str("Corrupt password hash"),
+            PasswordHashError::Unsupported(algorithm) => {
+                f.write_fmt(format_args!("Unsupported password hash {algorithm}"))
+            }
+            PasswordHashError::Hashing(e) => f.write_fmt(format_args!("Password hashing - {e}")),
+        }
+    }
+}
+
+impl Error for PasswordHashError {}
+
 #[derive(Debug)]
 pub(crate) struct NoUser(pub String);
 
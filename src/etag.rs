@@ -0,0 +1,138 @@
+//! Optimistic concurrency of orders, users and departments. Their updates bump `version`,
+//! which GET shows as a strong `ETag`. An `If-Match` of PUT/DELETE naming another version is
+//! refused with 412, an update racing another one with 409, both with the current state in
+//! the body so the client can merge and retry.
+
+use std::convert::Infallible;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{
+        header::{ETAG, IF_MATCH},
+        HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use http::request::Parts;
+use serde::Serialize;
+use serde_json::json;
+use tracing::error;
+
+/// `If-Match` of the request, no header matches anything
+#[derive(Debug, Default)]
+pub(crate) struct IfMatch(Option<Vec<String>>);
+
+impl IfMatch {
+    fn parse(header: &str) -> Self {
+        Self(Some(
+            header
+                .split(',')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(String::from)
+                .collect(),
+        ))
+    }
+
+    /// strong comparison, a weak `W/"1"` never matches
+    pub fn matches(&self, version: i32) -> bool {
+        match self.0 {
+            None => true,
+            Some(ref tags) => {
+                let current = etag(version);
+                tags.iter().any(|tag| tag == "*" || *tag == current)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for IfMatch {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        /* unreadable header values are a tag nothing matches */
+        Ok(match parts.headers.get(IF_MATCH) {
+            Some(value) => Self::parse(value.to_str().unwrap_or("\"\"")),
+            None => Self::default(),
+        })
+    }
+}
+
+pub(crate) fn etag(version: i32) -> String {
+    format!("\"{version}\"")
+}
+
+/// `response` with the `ETag` of `version`
+pub(crate) fn with_etag(version: i32, response: impl IntoResponse) -> Response {
+    let mut response = response.into_response();
+    if let Ok(value) = HeaderValue::from_str(&etag(version)) {
+        response.headers_mut().insert(ETAG, value);
+    }
+    response
+}
+
+/// `If-Match` names another version than `version` of `current`
+pub(crate) fn precondition_failed(current: impl Serialize, version: i32) -> Response {
+    refused(StatusCode::PRECONDITION_FAILED, current, version)
+}
+
+/// changed by somebody else since it was read, `current` is the state now
+pub(crate) fn conflict(current: impl Serialize, version: Option<i32>) -> Response {
+    match version {
+        Some(version) => refused(StatusCode::CONFLICT, current, version),
+        None => {
+            let resp = json!({
+                "code": 409,
+                "message": "deleted meanwhile",
+                "current": current,
+            });
+            error!("{resp}");
+            (StatusCode::CONFLICT, Json(resp)).into_response()
+        }
+    }
+}
+
+fn refused(status: StatusCode, current: impl Serialize, version: i32) -> Response {
+    let resp = json!({
+        "code": status.as_u16(),
+        "message": format!("version {} is current, merge and retry", etag(version)),
+        "current": current,
+    });
+    error!("{resp}");
+    with_etag(version, (status, Json(resp)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn if_match_compares_strong_tags() {
+        assert!(IfMatch::default().matches(3));
+        assert!(IfMatch::parse("\"3\"").matches(3));
+        assert!(IfMatch::parse("\"1\", \"3\"").matches(3));
+        assert!(IfMatch::parse("*").matches(7));
+        assert!(!IfMatch::parse("\"2\"").matches(3));
+        assert!(!IfMatch::parse("W/\"3\"").matches(3));
+        assert!(!IfMatch::parse("3").matches(3));
+        assert!(!IfMatch::parse("").matches(3));
+    }
+
+    #[test]
+    fn refusals_carry_the_current_version() {
+        let response = precondition_failed(json!({"sn": "BM1"}), 4);
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(response.headers()[ETAG], "\"4\"");
+
+        let response = conflict(json!({"sn": "BM1"}), Some(5));
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(response.headers()[ETAG], "\"5\"");
+
+        let response = conflict(Option::<i32>::None, None);
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert!(response.headers().get(ETAG).is_none());
+    }
+}
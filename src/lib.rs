@@ -1,9 +1,40 @@
+mod appearance;
+mod attachment;
+mod audit;
 mod authentication;
+pub mod blob;
+mod dcare_api_key;
+mod dcare_appearance;
+mod dcare_attachment;
+mod dcare_audit;
+mod dcare_mfa;
 mod dcare_order;
+mod dcare_password;
+mod dcare_payment;
+mod dcare_quote;
+mod dcare_receipt;
+mod dcare_role;
+mod dcare_session;
 mod dcare_user;
 mod department;
 mod errors;
+mod etag;
 mod gsheets;
+mod login_throttle;
+pub mod mailer;
+mod mfa;
+mod order_changes;
+mod order_sn;
+mod order_status;
+mod password;
+mod password_policy;
+mod payment;
+mod policy;
+mod query_filter;
+mod quote;
+mod rbac;
+mod receipt;
+mod trash;
 mod utils;
 
 use std::{
@@ -12,11 +43,11 @@ use std::{
 };
 
 use axum::{
-    extract::{Extension, Query},
+    extract::{DefaultBodyLimit, Extension, Query},
     //extract::Multipart,
     middleware,
     response::{Html, IntoResponse, Redirect},
-    routing::{any, get, post},
+    routing::{any, delete, get, post, put},
     //Json,
     Router,
 };
@@ -32,7 +63,7 @@ use shuttle_service::{error::CustomError, ShuttleAxum};
 use sqlx::Executor;
 use tera::{Context, Tera};
 use utoipa::{
-    openapi::security::{ApiKey, ApiKeyValue, SecurityScheme},
+    openapi::security::{ApiKey, ApiKeyValue, Http, HttpAuthScheme, SecurityScheme},
     IntoParams, Modify, OpenApi, ToSchema,
 };
 use utoipa_swagger_ui::SwaggerUi;
@@ -43,11 +74,44 @@ use utils::*;
 use authentication::{
     //delete_user, login, signup,
     auth,
+    AuthConfig,
     AuthState,
 };
+use blob::SharedBlobStore;
+use dcare_api_key::{
+    my_api_key_create_api, my_api_key_delete_api, my_api_key_update_api, my_api_keys_api,
+};
+use dcare_appearance::{appearance_api, appearance_create_api, appearance_update_api};
+use dcare_attachment::{
+    order_attachment_delete, order_attachment_request, order_attachment_thumbnail,
+    order_attachment_upload, order_attachments_request,
+};
+use dcare_audit::{audit_export_api, audit_list_api};
+use dcare_mfa::{
+    login_mfa_api, mfa_confirm_api, mfa_disable_api, mfa_enroll_api, user_mfa_reset_api,
+};
 use dcare_order::{
     order_create, order_delete, order_history_list_request, order_history_request,
-    order_list_request, order_request, order_update,
+    order_list_request, order_purge, order_request, order_restore, order_sn_validate,
+    order_transitions_request, order_update,
+};
+use dcare_password::{password_forgot_api, password_reset_api};
+use dcare_payment::{order_payment_create, order_payment_void, order_payments_request};
+use dcare_quote::{
+    order_quote_create, order_quotes_request, quote_answer_api, quote_api, quote_page_answer,
+    quote_page_request,
+};
+use dcare_receipt::{
+    department_template_delete, department_template_update, department_templates_request,
+    order_invoice_request, order_receipt_request,
+};
+use dcare_role::{
+    my_permissions_api, permissions_api, role_create_api, role_delete_api, role_update_api,
+    roles_api, user_roles_api, user_roles_update_api,
+};
+use dcare_session::{
+    my_session_revoke_api, my_sessions_api, my_sessions_revoke_others_api, user_session_revoke_api,
+    user_sessions_api, user_sessions_revoke_api,
 };
 use dcare_user::{
     logout_response_api,
@@ -58,17 +122,25 @@ use dcare_user::{
     update_myself_api,
     update_user_api,
     user_api,
+    user_purge_api,
+    user_restore_api,
+    user_unlock_api,
     users_api,
     //SharedUserMap,
 };
 use department::{
-    department_create, department_delete, department_list_request, department_request,
+    department_create, department_delete, department_list_request, department_purge,
+    department_request, department_restore,
     department_update,
     /*department_org_delete, department_org_list_request, department_org_request,*/
 };
 use gsheets::SharedDcareGoogleSheet;
+use mailer::SharedMailer;
+use order_sn::SnFormat;
+use quote::QuoteConfig;
+use receipt::PdfFont;
 
-type Templates = Arc<Tera>;
+pub(crate) type Templates = Arc<Tera>;
 type Database = sqlx::PgPool;
 type Random = Arc<Mutex<ChaCha8Rng>>;
 
@@ -81,7 +153,11 @@ pub struct AppState {
 }
 
 const USER_COOKIE_NAME: &str = "user_token";
-const COOKIE_MAX_AGE: &str = "9999999";
+
+/// secret first, then the environment variable of the same name
+pub(crate) fn secret(secret_store: &SecretStore, key: &str) -> Option<String> {
+    secret_store.get(key).or_else(|| std::env::var(key).ok())
+}
 
 #[derive(Deserialize, IntoParams)]
 pub struct Pagination {
@@ -122,23 +198,42 @@ async fn server(
         .map_err(CustomError::new)?;
 
     let key = secret_store.get("SERVICE_ACCOUNT_JSON");
-    let doc_id = secret_store.get("GOOGLE_DOCUMENT_ID")
-        .or_else(|| std::env::var("GOOGLE_DOCUMENT_ID")
-                 .ok());
-    let tab_name = secret_store.get("GOOGLE_DOC_TAB_NAME")
-        .or_else(|| std::env::var("GOOGLE_DOC_TAB_NAME")
-                 .ok());
-
-    let gsheet = SharedDcareGoogleSheet::new(
-        key, doc_id, tab_name
-    )
-    .await
-    .ok();
-
-    Ok(sync_wrapper::SyncWrapper::new(get_router(pool, gsheet)))
+    let doc_id = secret(&secret_store, "GOOGLE_DOCUMENT_ID");
+    let tab_name = secret(&secret_store, "GOOGLE_DOC_TAB_NAME");
+    let auth_config = AuthConfig::from_secrets(&secret_store);
+    let sn_format = SnFormat::from_secrets(&secret_store);
+    let mailer = mailer::from_secrets(&secret_store).map_err(CustomError::new)?;
+    let blobs = blob::from_secrets(&secret_store).map_err(CustomError::new)?;
+    let pdf_font = PdfFont::from_secrets(&secret_store);
+    let quote_config = QuoteConfig::from_secrets(&secret_store);
+
+    let gsheet = SharedDcareGoogleSheet::new(key, doc_id, tab_name)
+        .await
+        .ok();
+
+    Ok(sync_wrapper::SyncWrapper::new(get_router(
+        pool,
+        gsheet,
+        auth_config,
+        sn_format,
+        mailer,
+        blobs,
+        pdf_font,
+        quote_config,
+    )))
 }
 
-pub fn get_router(database: Database, gsheet: Option<SharedDcareGoogleSheet>) -> Router {
+#[allow(clippy::too_many_arguments)]
+pub fn get_router(
+    database: Database,
+    gsheet: Option<SharedDcareGoogleSheet>,
+    auth_config: AuthConfig,
+    sn_format: SnFormat,
+    mailer: SharedMailer,
+    blobs: SharedBlobStore,
+    pdf_font: PdfFont,
+    quote_config: QuoteConfig,
+) -> Router {
     let mut tera = Tera::default();
     tera.add_raw_templates(vec![
         ("base.html", include_str!("../templates/base.html")),
@@ -147,10 +242,13 @@ pub fn get_router(database: Database, gsheet: Option<SharedDcareGoogleSheet>) ->
         ("login", include_str!("../templates/login.html")),
         ("users", include_str!("../templates/users.html")),
         ("user", include_str!("../templates/user.html")),
+        ("quote.html", include_str!("../templates/quote.html")),
     ])
     .unwrap();
+    tera.add_raw_templates(receipt::TEMPLATES.to_vec()).unwrap();
 
     let middleware_database = database.clone();
+    let middleware_auth_config = auth_config.clone();
     let random = ChaCha8Rng::seed_from_u64(OsRng.next_u64());
     //let shared_usermap = SharedUserMap::new();
     let shared_state = SharedState::default();
@@ -161,25 +259,87 @@ pub fn get_router(database: Database, gsheet: Option<SharedDcareGoogleSheet>) ->
             dcare_user::post_signup_api,
             dcare_user::post_login_api,
             dcare_user::post_delete_api,
+            dcare_user::user_restore_api,
+            dcare_user::user_purge_api,
             dcare_user::me_api,
             dcare_user::update_myself_api,
             dcare_user::user_api,
             dcare_user::update_user_api,
             dcare_user::users_api,
+            dcare_user::user_unlock_api,
+
+            dcare_mfa::login_mfa_api,
+            dcare_mfa::mfa_enroll_api,
+            dcare_mfa::mfa_confirm_api,
+            dcare_mfa::mfa_disable_api,
+            dcare_mfa::user_mfa_reset_api,
+
+            dcare_password::password_forgot_api,
+            dcare_password::password_reset_api,
+
+            dcare_role::roles_api,
+            dcare_role::role_create_api,
+            dcare_role::role_update_api,
+            dcare_role::role_delete_api,
+            dcare_role::permissions_api,
+            dcare_role::my_permissions_api,
+            dcare_role::user_roles_api,
+            dcare_role::user_roles_update_api,
+
+            dcare_api_key::my_api_keys_api,
+            dcare_api_key::my_api_key_create_api,
+            dcare_api_key::my_api_key_update_api,
+            dcare_api_key::my_api_key_delete_api,
+
+            dcare_session::my_sessions_api,
+            dcare_session::my_session_revoke_api,
+            dcare_session::my_sessions_revoke_others_api,
+            dcare_session::user_sessions_api,
+            dcare_session::user_session_revoke_api,
+            dcare_session::user_sessions_revoke_api,
+
+            dcare_audit::audit_list_api,
+            dcare_audit::audit_export_api,
 
             dcare_order::order_request,
+            dcare_order::order_transitions_request,
+            dcare_order::order_sn_validate,
             dcare_order::order_list_request,
             dcare_order::order_delete,
+            dcare_order::order_restore,
+            dcare_order::order_purge,
             dcare_order::order_update,
             dcare_order::order_create,
             dcare_order::order_history_request,
             dcare_order::order_history_list_request,
+            dcare_attachment::order_attachment_upload,
+            dcare_attachment::order_attachments_request,
+            dcare_attachment::order_attachment_request,
+            dcare_attachment::order_attachment_thumbnail,
+            dcare_attachment::order_attachment_delete,
+            dcare_receipt::order_receipt_request,
+            dcare_receipt::order_invoice_request,
+            dcare_payment::order_payments_request,
+            dcare_payment::order_payment_create,
+            dcare_payment::order_payment_void,
+            dcare_quote::order_quotes_request,
+            dcare_quote::order_quote_create,
+            dcare_quote::quote_api,
+            dcare_quote::quote_answer_api,
+            dcare_appearance::appearance_api,
+            dcare_appearance::appearance_create_api,
+            dcare_appearance::appearance_update_api,
 
             department::department_request,
             department::department_list_request,
             department::department_delete,
+            department::department_restore,
+            department::department_purge,
             department::department_update,
             department::department_create,
+            dcare_receipt::department_templates_request,
+            dcare_receipt::department_template_update,
+            dcare_receipt::department_template_delete,
 
             /*department::department_org_request,
             department::department_org_list_request,
@@ -191,15 +351,48 @@ pub fn get_router(database: Database, gsheet: Option<SharedDcareGoogleSheet>) ->
                 dcare_user::UserInfo, dcare_user::ResponseUser, dcare_user::ResponseUsers,
                 dcare_user::UpdateMe, dcare_user::UpdateUser,
                 ApiResponse,
+                errors::SignupError, errors::SignupErrorResponse,
+
+                dcare_mfa::MfaCode, dcare_mfa::MfaEnrollResponse, dcare_mfa::MfaConfirmResponse,
+
+                dcare_password::PasswordForgot, dcare_password::PasswordReset,
+
+                dcare_role::RoleInfo, dcare_role::RolesResponse,
+                dcare_role::PermissionInfo, dcare_role::PermissionsResponse,
+                dcare_role::RoleNew, dcare_role::RoleUpdate,
+                dcare_role::UserRolesUpdate, dcare_role::UserRolesResponse,
+
+                dcare_api_key::ApiKeyInfo, dcare_api_key::ApiKeysResponse,
+                dcare_api_key::ApiKeyCreated, dcare_api_key::ApiKeyNew, dcare_api_key::ApiKeyUpdate,
+
+                dcare_session::SessionInfo, dcare_session::SessionsResponse,
+
+                dcare_audit::AuditEventInfo, dcare_audit::AuditEventsResponse,
 
                 dcare_order::OrdersResponse, dcare_order::OrderResponse,
                 dcare_order::OrderInfo, dcare_order::OrderSummary,
                 dcare_order::OrderNew, dcare_order::OrderUpdate,
                 dcare_order::OrderApiResponse,
+                dcare_order::OrderTransition, dcare_order::OrderTransitionsResponse,
+                dcare_order::OrderSnValidateResponse,
+                dcare_order::OrderHistory, dcare_order::OrderHistoriesResponse,
+                dcare_attachment::AttachmentInfo, dcare_attachment::AttachmentsResponse,
+                receipt::Format,
+                dcare_payment::PaymentInfo, dcare_payment::PaymentsResponse,
+                dcare_payment::PaymentNew, dcare_payment::PaymentVoid,
+                payment::Direction, payment::Method,
+                dcare_quote::QuoteInfo, dcare_quote::QuoteItemInfo, dcare_quote::QuotesResponse,
+                dcare_quote::QuoteNew, dcare_quote::QuoteItemNew, dcare_quote::QuoteCreated,
+                dcare_quote::QuoteView, dcare_quote::QuoteViewResponse, dcare_quote::QuoteAnswer,
+                quote::ItemKind, quote::Decision,
+                appearance::AppearanceFlag, dcare_appearance::AppearanceResponse,
+                dcare_appearance::AppearanceFlagNew, dcare_appearance::AppearanceFlagUpdate,
 
                 department::DepartmentsResponse, department::DepartmentResponse,
                 department::DepartmentInfo, department::DepartmentSummary,
                 department::DepartmentNew, department::DepartmentUpdate,
+                dcare_receipt::DepartmentTemplate, dcare_receipt::DepartmentTemplatesResponse,
+                dcare_receipt::DepartmentTemplateBody,
 
                 /*department::DepartmentOrgsResponse, department::DepartmentOrgResponse,
                 department::DepartmentOrgData,*/
@@ -220,7 +413,12 @@ pub fn get_router(database: Database, gsheet: Option<SharedDcareGoogleSheet>) ->
                 components.add_security_scheme(
                     "logined cookie/session-id",
                     SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new(USER_COOKIE_NAME))),
-                )
+                );
+                /* session-id or API key(dck_...) as Authorization: Bearer */
+                components.add_security_scheme(
+                    "bearer token/api-key",
+                    SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+                );
             }
         }
     }
@@ -230,26 +428,134 @@ pub fn get_router(database: Database, gsheet: Option<SharedDcareGoogleSheet>) ->
         .route("/", get(index))
         .route("/styles.css", any(styles))
         .route("/api/v1/login", post(post_login_api))
+        .route("/api/v1/login/mfa", post(login_mfa_api))
         .route("/api/v1/logout", get(logout_response_api))
+        .route("/api/v1/password/forgot", post(password_forgot_api))
+        .route("/api/v1/password/reset", post(password_reset_api))
+        .route(
+            "/api/v1/quote/:token",
+            get(quote_api).post(quote_answer_api),
+        )
+        .route(
+            "/quote/:token",
+            get(quote_page_request).post(quote_page_answer),
+        )
         .route("/api/v1/me", get(me_api).put(update_myself_api))
+        .route(
+            "/api/v1/me/sessions",
+            get(my_sessions_api).delete(my_sessions_revoke_others_api),
+        )
+        .route("/api/v1/me/sessions/:id", delete(my_session_revoke_api))
+        .route("/api/v1/me/permissions", get(my_permissions_api))
+        .route("/api/v1/me/mfa", delete(mfa_disable_api))
+        .route("/api/v1/me/mfa/enroll", post(mfa_enroll_api))
+        .route("/api/v1/me/mfa/confirm", post(mfa_confirm_api))
+        .route(
+            "/api/v1/me/api-keys",
+            get(my_api_keys_api).post(my_api_key_create_api),
+        )
+        .route(
+            "/api/v1/me/api-keys/:id",
+            put(my_api_key_update_api).delete(my_api_key_delete_api),
+        )
         .route(
             "/api/v1/user/:account",
             get(user_api).put(update_user_api).delete(post_delete_api),
         )
+        .route(
+            "/api/v1/user/:account/sessions",
+            get(user_sessions_api).delete(user_sessions_revoke_api),
+        )
+        .route(
+            "/api/v1/user/:account/sessions/:id",
+            delete(user_session_revoke_api),
+        )
+        .route("/api/v1/user/:account/mfa", delete(user_mfa_reset_api))
+        .route("/api/v1/user/:account/unlock", post(user_unlock_api))
+        .route("/api/v1/user/:account/restore", post(user_restore_api))
+        .route("/api/v1/user/:account/purge", delete(user_purge_api))
+        .route(
+            "/api/v1/user/:account/roles",
+            get(user_roles_api).put(user_roles_update_api),
+        )
         .route("/api/v1/user", get(users_api).post(post_signup_api))
+        .route("/api/v1/roles", get(roles_api).post(role_create_api))
+        .route(
+            "/api/v1/roles/:name",
+            put(role_update_api).delete(role_delete_api),
+        )
+        .route("/api/v1/permissions", get(permissions_api))
+        .route("/api/v1/audit", get(audit_list_api))
+        .route("/api/v1/audit/export", get(audit_export_api))
         .route("/api/v1/order/history/:sn", get(order_history_request))
         .route("/api/v1/order/history", get(order_history_list_request))
+        .route(
+            "/api/v1/order/:sn/transitions",
+            get(order_transitions_request),
+        )
+        .route("/api/v1/order/sn/validate/:sn", get(order_sn_validate))
+        .route("/api/v1/order/:sn/restore", post(order_restore))
+        .route(
+            "/api/v1/order/:sn/attachments",
+            get(order_attachments_request)
+                .post(order_attachment_upload)
+                .layer(DefaultBodyLimit::max(attachment::MAX_REQUEST_BYTES)),
+        )
+        .route(
+            "/api/v1/order/:sn/attachments/:id",
+            get(order_attachment_request).delete(order_attachment_delete),
+        )
+        .route(
+            "/api/v1/order/:sn/attachments/:id/thumbnail",
+            get(order_attachment_thumbnail),
+        )
+        .route("/api/v1/order/:sn/receipt", get(order_receipt_request))
+        .route("/api/v1/order/:sn/invoice", get(order_invoice_request))
+        .route(
+            "/api/v1/order/:sn/payments",
+            get(order_payments_request).post(order_payment_create),
+        )
+        .route(
+            "/api/v1/order/:sn/payments/:id/void",
+            post(order_payment_void),
+        )
+        .route(
+            "/api/v1/order/:sn/quotes",
+            get(order_quotes_request).post(order_quote_create),
+        )
+        .route("/api/v1/order/:sn/purge", delete(order_purge))
         .route(
             "/api/v1/order/:sn",
             get(order_request).put(order_update).delete(order_delete),
         )
         .route("/api/v1/order", get(order_list_request).post(order_create))
+        .route(
+            "/api/v1/appearance",
+            get(appearance_api).post(appearance_create_api),
+        )
+        .route("/api/v1/appearance/:name", put(appearance_update_api))
         .route(
             "/api/v1/department/:shorten",
             get(department_request)
                 .put(department_update)
                 .delete(department_delete),
         )
+        .route(
+            "/api/v1/department/:shorten/restore",
+            post(department_restore),
+        )
+        .route(
+            "/api/v1/department/:shorten/purge",
+            delete(department_purge),
+        )
+        .route(
+            "/api/v1/department/:shorten/templates",
+            get(department_templates_request),
+        )
+        .route(
+            "/api/v1/department/:shorten/templates/:name",
+            put(department_template_update).delete(department_template_delete),
+        )
         .route(
             "/api/v1/department",
             get(department_list_request).post(department_create),
@@ -260,9 +566,21 @@ pub fn get_router(database: Database, gsheet: Option<SharedDcareGoogleSheet>) ->
         )
         .route("/api/v1/department/org", get(department_org_list_request))*/
         .layer(middleware::from_fn(move |req, next| {
-            auth(req, next, middleware_database.clone())
+            auth(
+                req,
+                next,
+                middleware_database.clone(),
+                middleware_auth_config.clone(),
+            )
         }))
+        .layer(middleware::from_fn(audit::request_id))
         .layer(Extension(Arc::new(tera)))
+        .layer(Extension(auth_config))
+        .layer(Extension(sn_format))
+        .layer(Extension(mailer))
+        .layer(Extension(blobs))
+        .layer(Extension(pdf_font))
+        .layer(Extension(quote_config))
         .layer(Extension(database))
         //.layer(Extension(Arc::new(shared_usermap)))
         //.layer(Extension(Arc::clone(&shared_state)))
@@ -0,0 +1,222 @@
+//! Failed login tracking per account and per client IP, kept in `login_throttles`. Wrong
+//! second factors count per account on their own, over every pending session, so logging in
+//! again with the password doesn't buy fresh guesses.
+
+use anyhow::{anyhow, Result};
+use chrono::Duration;
+
+use crate::{authentication::AuthConfig, Database};
+
+const SCOPE_ACCOUNT: &str = "account";
+const SCOPE_IP: &str = "ip";
+const SCOPE_MFA: &str = "mfa";
+
+/// first back-off step, doubled by each further failure
+const BACKOFF_BASE_SECS: i64 = 1;
+const BACKOFF_MAX_SECS: i64 = 15 * 60;
+
+/// Limits for one scope.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ThrottlePolicy {
+    /// failures before back-off starts
+    pub free_failures: i32,
+    /// failures that lock for `lock`
+    pub lock_failures: i32,
+    pub lock: Duration,
+}
+
+impl ThrottlePolicy {
+    /// how long to refuse logins after `failures` consecutive failures
+    pub fn blocked_for(&self, failures: i32) -> Option<Duration> {
+        if failures >= self.lock_failures {
+            Some(self.lock)
+        } else if failures > self.free_failures {
+            let exp = (failures - self.free_failures - 1).min(20) as u32;
+            let secs = (BACKOFF_BASE_SECS << exp).min(BACKOFF_MAX_SECS);
+            Some(Duration::seconds(secs))
+        } else {
+            None
+        }
+    }
+}
+
+/// `Some(seconds)` to wait if the account, its second factor or the IP is blocked right now
+pub(crate) async fn retry_after(
+    database: &Database,
+    account: &str,
+    ip: Option<&str>,
+) -> Result<Option<i64>> {
+    const QUERY: &str = r#"
+        SELECT CEIL(EXTRACT(EPOCH FROM MAX(locked_until) - NOW()))::bigint
+        FROM login_throttles
+        WHERE ((scope = ANY($1) AND key = $2) OR (scope = $3 AND key = $4))
+            AND locked_until > NOW();
+    "#;
+
+    let (secs,): (Option<i64>,) = sqlx::query_as(QUERY)
+        .bind(&[SCOPE_ACCOUNT, SCOPE_MFA][..])
+        .bind(account)
+        .bind(SCOPE_IP)
+        .bind(ip)
+        .fetch_one(database)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+
+    Ok(secs.map(|s| s.max(1)))
+}
+
+/// count a failure on the account and the IP, block them as their policy says
+pub(crate) async fn record_failure(
+    database: &Database,
+    config: &AuthConfig,
+    account: &str,
+    ip: Option<&str>,
+) -> Result<()> {
+    let _ = purge_stale(database, config).await;
+
+    record_scope_failure(
+        database,
+        config,
+        SCOPE_ACCOUNT,
+        account,
+        &config.throttle_account,
+    )
+    .await?;
+    if let Some(ip) = ip {
+        record_scope_failure(database, config, SCOPE_IP, ip, &config.throttle_ip).await?;
+    }
+    Ok(())
+}
+
+/// count a wrong second factor on the account and the IP, under the account's policy
+pub(crate) async fn record_mfa_failure(
+    database: &Database,
+    config: &AuthConfig,
+    account: &str,
+    ip: Option<&str>,
+) -> Result<()> {
+    record_scope_failure(
+        database,
+        config,
+        SCOPE_MFA,
+        account,
+        &config.throttle_account,
+    )
+    .await?;
+    if let Some(ip) = ip {
+        record_scope_failure(database, config, SCOPE_IP, ip, &config.throttle_ip).await?;
+    }
+    Ok(())
+}
+
+/// logged in, with the second factor if one is owed: the account starts over, the IP keeps
+/// its count
+pub(crate) async fn record_success(database: &Database, account: &str) -> Result<()> {
+    unlock(database, account).await.map(|_| ())
+}
+
+/// admin unlock, `false` if the account had no failures
+pub(crate) async fn unlock(database: &Database, account: &str) -> Result<bool> {
+    const QUERY: &str = "DELETE FROM login_throttles WHERE scope = ANY($1) AND key = $2;";
+
+    sqlx::query(QUERY)
+        .bind(&[SCOPE_ACCOUNT, SCOPE_MFA][..])
+        .bind(account)
+        .execute(database)
+        .await
+        .map(|r| r.rows_affected() > 0)
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+async fn record_scope_failure(
+    database: &Database,
+    config: &AuthConfig,
+    scope: &str,
+    key: &str,
+    policy: &ThrottlePolicy,
+) -> Result<()> {
+    /* failures older than the window don't add up */
+    const FAILURE_QUERY: &str = r#"
+        INSERT INTO login_throttles (scope, key, failures, last_failure_at)
+        VALUES ($1, $2, 1, NOW())
+        ON CONFLICT (scope, key) DO UPDATE SET
+            failures = CASE
+                WHEN login_throttles.last_failure_at < NOW() - make_interval(secs => $3) THEN 1
+                ELSE login_throttles.failures + 1
+            END,
+            last_failure_at = NOW()
+        RETURNING failures;
+    "#;
+    const LOCK_QUERY: &str = r#"
+        UPDATE login_throttles SET locked_until = NOW() + make_interval(secs => $3)
+        WHERE scope = $1 AND key = $2;
+    "#;
+
+    let (failures,): (i32,) = sqlx::query_as(FAILURE_QUERY)
+        .bind(scope)
+        .bind(key)
+        .bind(config.throttle_window.num_seconds() as f64)
+        .fetch_one(database)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+
+    if let Some(blocked) = policy.blocked_for(failures) {
+        sqlx::query(LOCK_QUERY)
+            .bind(scope)
+            .bind(key)
+            .bind(blocked.num_seconds() as f64)
+            .execute(database)
+            .await
+            .map_err(|e| anyhow!("DB error - {e}"))?;
+    }
+    Ok(())
+}
+
+async fn purge_stale(database: &Database, config: &AuthConfig) -> Result<u64> {
+    const QUERY: &str = r#"
+        DELETE FROM login_throttles
+        WHERE last_failure_at < NOW() - make_interval(secs => $1)
+            AND (locked_until IS NULL OR locked_until < NOW());
+    "#;
+
+    sqlx::query(QUERY)
+        .bind(config.throttle_window.num_seconds() as f64)
+        .execute(database)
+        .await
+        .map(|r| r.rows_affected())
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_then_lock() {
+        let policy = ThrottlePolicy {
+            free_failures: 3,
+            lock_failures: 10,
+            lock: Duration::minutes(30),
+        };
+
+        assert_eq!(policy.blocked_for(3), None);
+        assert_eq!(policy.blocked_for(4), Some(Duration::seconds(1)));
+        assert_eq!(policy.blocked_for(5), Some(Duration::seconds(2)));
+        assert_eq!(policy.blocked_for(9), Some(Duration::seconds(32)));
+        assert_eq!(policy.blocked_for(10), Some(Duration::minutes(30)));
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let policy = ThrottlePolicy {
+            free_failures: 0,
+            lock_failures: i32::MAX,
+            lock: Duration::minutes(30),
+        };
+
+        assert_eq!(
+            policy.blocked_for(1000),
+            Some(Duration::seconds(BACKOFF_MAX_SECS))
+        );
+    }
+}
@@ -0,0 +1,159 @@
+//! Outgoing mail, `SmtpMailer` for production, `FileMailer`/`MemoryMailer` for
+//! local run and tests.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use axum::async_trait;
+use chrono::Utc;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use shuttle_secrets::SecretStore;
+use tracing::warn;
+
+use crate::secret;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, mail: Mail) -> Result<()>;
+}
+
+pub type SharedMailer = Arc<dyn Mailer>;
+
+/// `SMTP_HOST` set, send by SMTP, otherwise drop mails as files into `MAIL_DIR`.
+pub fn from_secrets(secret_store: &SecretStore) -> Result<SharedMailer> {
+    let from =
+        secret(secret_store, "MAIL_FROM").unwrap_or_else(|| "dcare <noreply@localhost>".into());
+
+    if let Some(host) = secret(secret_store, "SMTP_HOST") {
+        let credentials = secret(secret_store, "SMTP_USERNAME")
+            .zip(secret(secret_store, "SMTP_PASSWORD"))
+            .map(|(username, password)| Credentials::new(username, password));
+        let port = secret(secret_store, "SMTP_PORT").and_then(|p| p.parse::<u16>().ok());
+
+        Ok(Arc::new(SmtpMailer::new(&host, port, credentials, &from)?))
+    } else {
+        let dir = secret(secret_store, "MAIL_DIR")
+            .map_or_else(|| std::env::temp_dir().join("dcare-mails"), PathBuf::from);
+        warn!("SMTP_HOST not set, mails go to {}", dir.display());
+
+        Ok(Arc::new(FileMailer::new(dir)))
+    }
+}
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    /// STARTTLS on `port` (default 587)
+    pub fn new(
+        host: &str,
+        port: Option<u16>,
+        credentials: Option<Credentials>,
+        from: &str,
+    ) -> Result<Self> {
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+            .map_err(|e| anyhow!("SMTP relay {host} - {e}"))?;
+        if let Some(port) = port {
+            builder = builder.port(port);
+        }
+        if let Some(credentials) = credentials {
+            builder = builder.credentials(credentials);
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from: from
+                .parse()
+                .map_err(|e| anyhow!("MAIL_FROM {from} - {e}"))?,
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, mail: Mail) -> Result<()> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(mail
+                .to
+                .parse()
+                .map_err(|e| anyhow!("mail to {} - {e}", mail.to))?)
+            .subject(mail.subject)
+            .body(mail.body)
+            .map_err(|e| anyhow!("mail build - {e}"))?;
+
+        self.transport
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|e| anyhow!("SMTP send - {e}"))
+    }
+}
+
+/// Write each mail as a text file, for local development.
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, mail: Mail) -> Result<()> {
+        std::fs::create_dir_all(&self.dir).map_err(|e| anyhow!("mail dir - {e}"))?;
+
+        let file = self.dir.join(format!(
+            "{}-{}.txt",
+            Utc::now().format("%Y%m%d%H%M%S%.f"),
+            mail.to.replace(
+                |c: char| !c.is_ascii_alphanumeric() && c != '@' && c != '.',
+                "_"
+            )
+        ));
+        let content = format!(
+            "To: {}\nSubject: {}\n\n{}\n",
+            mail.to, mail.subject, mail.body
+        );
+
+        std::fs::write(&file, content).map_err(|e| anyhow!("mail write {} - {e}", file.display()))
+    }
+}
+
+/// Keep mails in memory, for tests.
+#[derive(Default)]
+pub struct MemoryMailer {
+    sent: Mutex<Vec<Mail>>,
+}
+
+impl MemoryMailer {
+    pub fn sent(&self) -> Vec<Mail> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, mail: Mail) -> Result<()> {
+        self.sent.lock().unwrap().push(mail);
+        Ok(())
+    }
+}
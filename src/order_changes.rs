@@ -0,0 +1,96 @@
+//! Field-level history of orders. Every update stores the `OrderRawInfo` fields it changed
+//! as `{"field": {"old": .., "new": ..}}` in `order_histories.changes`. Putting the old
+//! values of the later changes back into the order gives the order as it was at any time,
+//! as far as the changes go: histories before the upgrade have none.
+
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+/// bookkeeping of the row, not part of the order
+const IGNORED: [&str; 2] = ["id", "version"];
+
+/// the fields changed from `old` to `new`, both serializing to an object
+pub(crate) fn diff(old: &impl Serialize, new: &impl Serialize) -> Value {
+    let old = fields(old);
+    let changes: Map<String, Value> = fields(new)
+        .into_iter()
+        .filter_map(|(field, new)| {
+            let old = old.get(&field).cloned().unwrap_or(Value::Null);
+            (old != new).then(|| (field, json!({ "old": old, "new": new })))
+        })
+        .collect();
+
+    Value::Object(changes)
+}
+
+/// `current` before the `later` changes, given newest first
+pub(crate) fn as_of<'a>(
+    current: &impl Serialize,
+    later: impl IntoIterator<Item = &'a Value>,
+) -> Value {
+    let mut state = fields(current);
+    for changes in later {
+        revert(&mut state, changes);
+    }
+    Value::Object(state)
+}
+
+/// put the old values of `changes` back
+fn revert(state: &mut Map<String, Value>, changes: &Value) {
+    if let Value::Object(changes) = changes {
+        for (field, change) in changes {
+            if let Some(old) = change.get("old") {
+                state.insert(field.clone(), old.clone());
+            }
+        }
+    }
+}
+
+fn fields(value: &impl Serialize) -> Map<String, Value> {
+    match serde_json::to_value(value) {
+        Ok(Value::Object(mut fields)) => {
+            fields.retain(|field, _| !IGNORED.contains(&field.as_str()));
+            fields
+        }
+        _ => Map::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_has_changed_fields_only() {
+        let old = json!({"id": 7, "version": 3, "customer_phone": "0912345678", "fault_id1": 2, "remark": null});
+        let new = json!({"id": 7, "version": 4, "customer_phone": "0987654321", "fault_id1": 2, "remark": "刮傷"});
+
+        assert_eq!(
+            diff(&old, &new),
+            json!({
+                "customer_phone": {"old": "0912345678", "new": "0987654321"},
+                "remark": {"old": null, "new": "刮傷"},
+            })
+        );
+        assert_eq!(diff(&old, &old), json!({}));
+    }
+
+    #[test]
+    fn as_of_walks_the_changes_back() {
+        let first = json!({"id": 7, "version": 1, "customer_phone": "0912345678", "cost": null});
+        let second = json!({"id": 7, "version": 2, "customer_phone": "0987654321", "cost": null});
+        let now = json!({"id": 7, "version": 3, "customer_phone": "0987654321", "cost": 1500});
+        let changes = [diff(&second, &now), diff(&first, &second)];
+
+        assert_eq!(
+            as_of(&now, &changes[..1]),
+            json!({"customer_phone": "0987654321", "cost": null})
+        );
+        assert_eq!(
+            as_of(&now, &changes),
+            json!({"customer_phone": "0912345678", "cost": null})
+        );
+        /* histories of before the upgrade change nothing */
+        assert_eq!(as_of(&now, &[json!({})]), as_of(&now, []));
+    }
+}
@@ -0,0 +1,212 @@
+//! Serial numbers of new orders, `{dept}{yy}{mm}{dd}{seq}` plus a check digit by default.
+//! `seq` counts per department and day in `order_sn_counters`, taken in the transaction
+//! inserting the order, so concurrent creates never get the same number.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use shuttle_secrets::SecretStore;
+use sqlx::{Executor, Postgres};
+use tracing::error;
+
+use crate::{secret, Database};
+
+const DEFAULT_TEMPLATE: &str = "{dept}{yy}{mm}{dd}{seq}";
+
+/// Template of the serial number, the check digit is always appended.
+///
+/// `{dept}` department shorten (padded to 3 with `0`, see `shorten_collision`), `{yyyy}` or `{yy}` year, `{mm}`,
+/// `{dd}`, `{hh}` and `{seq}` the counter of the day (4 digits at least). Other text is
+/// kept as is. Department, year, month, day and seq are required, the counter starts
+/// over every day.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnFormat {
+    template: String,
+}
+
+impl Default for SnFormat {
+    fn default() -> Self {
+        Self {
+            template: DEFAULT_TEMPLATE.to_string(),
+        }
+    }
+}
+
+impl SnFormat {
+    pub fn from_secrets(secret_store: &SecretStore) -> Self {
+        match secret(secret_store, "ORDER_SN_FORMAT") {
+            Some(template) => Self::new(&template).unwrap_or_else(|| {
+                error!("ORDER_SN_FORMAT {template} isn't unique per day, using {DEFAULT_TEMPLATE}");
+                Self::default()
+            }),
+            None => Self::default(),
+        }
+    }
+
+    /// `None` if numbers of the template could repeat
+    pub fn new(template: &str) -> Option<Self> {
+        let unique = ["{dept}", "{mm}", "{dd}", "{seq}"]
+            .iter()
+            .all(|field| template.contains(field))
+            && (template.contains("{yy}") || template.contains("{yyyy}"));
+
+        unique.then(|| Self {
+            template: template.to_string(),
+        })
+    }
+
+    pub fn render(&self, shorten: &str, at: DateTime<Utc>, seq: i32) -> String {
+        let body = self
+            .template
+            .replace("{dept}", &padded(shorten))
+            .replace("{yyyy}", &format!("{:04}", at.year()))
+            .replace("{yy}", &format!("{:02}", at.year() % 100))
+            .replace("{mm}", &format!("{:02}", at.month()))
+            .replace("{dd}", &format!("{:02}", at.day()))
+            .replace("{hh}", &format!("{:02}", at.hour()))
+            .replace("{seq}", &format!("{seq:04}"));
+
+        let check = check_digit(&body);
+        format!("{body}{check}")
+    }
+}
+
+/// `{dept}` of the shorten
+fn padded(shorten: &str) -> String {
+    format!("{shorten:0<3}")
+}
+
+/// Another department whose shorten is the same once padded (`BM` and `BM0`), their order
+/// numbers would collide. `except` is the department being renamed.
+pub(crate) async fn shorten_collision(
+    database: &Database,
+    shorten: &str,
+    except: Option<i32>,
+) -> Result<Option<String>> {
+    /* deleted departments still own their order numbers */
+    const QUERY: &str = r#"
+        SELECT shorten FROM departments
+        WHERE id IS DISTINCT FROM $2
+            AND CASE WHEN length(shorten) < 3 THEN rpad(shorten, 3, '0') ELSE shorten END = $1
+        LIMIT 1;
+    "#;
+
+    sqlx::query_as(QUERY)
+        .bind(padded(shorten))
+        .bind(except)
+        .fetch_optional(database)
+        .await
+        .map(|row| row.map(|(shorten,)| shorten))
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+/// next serial number of the department, run it in the transaction inserting the order
+pub(crate) async fn next_sn<'e, E>(
+    executor: E,
+    format: &SnFormat,
+    department_id: i32,
+    shorten: &str,
+    at: DateTime<Utc>,
+) -> Result<String>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    /* the row stays locked until the transaction ends, creates of the department queue up */
+    const QUERY: &str = r#"
+        INSERT INTO order_sn_counters (department_id, day, last) VALUES ($1, $2, 1)
+        ON CONFLICT (department_id, day) DO UPDATE SET last = order_sn_counters.last + 1
+        RETURNING last;
+    "#;
+
+    let (seq,): (i32,) = sqlx::query_as(QUERY)
+        .bind(department_id)
+        .bind(at.date_naive())
+        .fetch_one(executor)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+
+    Ok(format.render(shorten, at, seq))
+}
+
+/// Damm check digit, letters count as two digits (`A` = 10 ... `Z` = 35) like in an IBAN,
+/// anything else is skipped. Catches every single typo and swap of neighbouring digits.
+pub(crate) fn check_digit(body: &str) -> char {
+    const TABLE: [[u8; 10]; 10] = [
+        [0, 3, 1, 7, 5, 9, 8, 6, 4, 2],
+        [7, 0, 9, 2, 1, 5, 4, 8, 6, 3],
+        [4, 2, 0, 6, 8, 7, 1, 3, 5, 9],
+        [1, 7, 5, 0, 9, 8, 3, 4, 2, 6],
+        [6, 1, 2, 3, 0, 4, 5, 9, 7, 8],
+        [3, 6, 7, 4, 2, 0, 9, 5, 8, 1],
+        [5, 8, 6, 9, 7, 2, 0, 1, 3, 4],
+        [8, 9, 4, 5, 3, 6, 2, 0, 1, 7],
+        [9, 4, 3, 8, 6, 1, 7, 2, 0, 5],
+        [2, 5, 8, 1, 4, 3, 6, 7, 9, 0],
+    ];
+
+    let mut interim = 0;
+    for value in body.chars().filter_map(|c| c.to_digit(36)) {
+        if value >= 10 {
+            interim = TABLE[interim][(value / 10) as usize] as usize;
+        }
+        interim = TABLE[interim][(value % 10) as usize] as usize;
+    }
+    char::from(b'0' + interim as u8)
+}
+
+/// the last character is the check digit of the rest
+pub(crate) fn is_valid(sn: &str) -> bool {
+    let mut body = sn.to_string();
+    match body.pop() {
+        Some(check) if !body.is_empty() => check_digit(&body) == check,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn damm_check_digit() {
+        assert_eq!(check_digit("572"), '4');
+        assert!(is_valid("5724"));
+        assert!(!is_valid("5274"));
+        assert!(!is_valid("5734"));
+        assert!(!is_valid("4"));
+        assert!(!is_valid(""));
+        /* letters as their two digits */
+        assert_eq!(check_digit("BM"), check_digit("1122"));
+        assert_eq!(check_digit("bm"), check_digit("BM"));
+    }
+
+    #[test]
+    fn renders_the_template() {
+        let at = Utc.with_ymd_and_hms(2031, 2, 3, 4, 5, 6).unwrap();
+
+        let sn = SnFormat::default().render("BM", at, 12);
+        assert_eq!(&sn[..13], "BM03102030012");
+        assert!(is_valid(&sn));
+
+        let custom = SnFormat::new("{dept}-{yyyy}{mm}{dd}{hh}-{seq}").unwrap();
+        let sn = custom.render("ADM", at, 12345);
+        assert_eq!(&sn[..sn.len() - 1], "ADM-2031020304-12345");
+        assert!(is_valid(&sn));
+    }
+
+    #[test]
+    fn short_shortens_are_padded() {
+        assert_eq!(padded("BM"), "BM0");
+        assert_eq!(padded("BM0"), "BM0");
+        assert_eq!(padded("ADMN"), "ADMN");
+    }
+
+    #[test]
+    fn template_must_stay_unique() {
+        assert!(SnFormat::new("{dept}{yy}{mm}{dd}{seq}").is_some());
+        assert!(SnFormat::new("{dept}{mm}{dd}{seq}").is_none());
+        assert!(SnFormat::new("{yy}{mm}{dd}{seq}").is_none());
+        assert!(SnFormat::new("{dept}{yy}{mm}{seq}").is_none());
+    }
+}
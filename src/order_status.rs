@@ -0,0 +1,372 @@
+//! Workflow of the order `status`: the seeded statuses, which have a `code`, and the
+//! `status_transitions` between them. A transition may need a permission of its own,
+//! `order.lock` (maintainers) to lock and `order.reopen` (GM) to reopen a closed order.
+//! Orders without a status, or with one of the uncoded leftovers, move on like new ones.
+
+use std::{error::Error, fmt::Display};
+
+use anyhow::{anyhow, Result};
+
+use crate::{authentication::CurrentUser, Database};
+
+/// code of the closing status, orders are settled before, see `payment`
+pub(crate) const COMPLETED: &str = "completed";
+/// codes of the quote round, see `quote`: issuing one moves the order to `QUOTED`, the
+/// customer's answer on to `APPROVED` or back to `RETURNED`
+pub(crate) const QUOTED: &str = "quoted";
+pub(crate) const APPROVED: &str = "updated";
+pub(crate) const RETURNED: &str = "returned";
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub(crate) struct Status {
+    pub id: i32,
+    pub code: String,
+    /// display name, `收件`, `報價`, ...
+    pub flow: String,
+    pub initial: bool,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub(crate) struct Transition {
+    pub from_id: i32,
+    pub to_id: i32,
+    /// needed on top of updating the order
+    pub permission: Option<String>,
+}
+
+#[derive(Debug)]
+pub(crate) enum TransitionError {
+    /// not a status of the workflow, with the ones that are
+    Unknown(String, Vec<String>),
+    /// orders are created with an initial status only
+    NotInitial(String, Vec<String>),
+    /// no such transition, with the ones the user may take
+    NotAllowed {
+        from: String,
+        to: String,
+        next: Vec<String>,
+    },
+    /// the transition needs `permission`
+    PermissionDeny { to: String, permission: String },
+}
+
+impl Display for TransitionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransitionError::Unknown(status, valid) => f.write_fmt(format_args!(
+                "unknown status {status}, one of {}",
+                valid.join(", ")
+            )),
+            TransitionError::NotInitial(status, valid) => f.write_fmt(format_args!(
+                "orders can't be created as {status}, one of {}",
+                valid.join(", ")
+            )),
+            TransitionError::NotAllowed { from, to, next } if next.is_empty() => {
+                f.write_fmt(format_args!("status {from} -> {to} not allowed, no next"))
+            }
+            TransitionError::NotAllowed { from, to, next } => f.write_fmt(format_args!(
+                "status {from} -> {to} not allowed, next: {}",
+                next.join(", ")
+            )),
+            TransitionError::PermissionDeny { to, permission } => f.write_fmt(format_args!(
+                "permission deny, {permission} required for status {to}"
+            )),
+        }
+    }
+}
+
+impl Error for TransitionError {}
+
+/// statuses and transitions, loaded per request as they are few
+pub(crate) struct Workflow {
+    /// by `sort_order`
+    statuses: Vec<Status>,
+    transitions: Vec<Transition>,
+}
+
+impl Workflow {
+    pub async fn load(database: &Database) -> Result<Self> {
+        const STATUS_QUERY: &str = r#"
+            SELECT id, code, flow, initial FROM status
+            WHERE code IS NOT NULL
+            ORDER BY sort_order, id;
+        "#;
+        const TRANSITION_QUERY: &str = "SELECT from_id, to_id, permission FROM status_transitions;";
+
+        let statuses = sqlx::query_as(STATUS_QUERY)
+            .fetch_all(database)
+            .await
+            .map_err(|e| anyhow!("DB error - {e}"))?;
+        let transitions = sqlx::query_as(TRANSITION_QUERY)
+            .fetch_all(database)
+            .await
+            .map_err(|e| anyhow!("DB error - {e}"))?;
+
+        Ok(Self {
+            statuses,
+            transitions,
+        })
+    }
+
+    /// status of the workflow by code or display name
+    pub fn find(&self, name: &str) -> Result<&Status, TransitionError> {
+        self.statuses
+            .iter()
+            .find(|s| s.code == name || s.flow == name)
+            .ok_or_else(|| TransitionError::Unknown(name.to_string(), names(self.statuses.iter())))
+    }
+
+    pub fn status(&self, id: i32) -> Option<&Status> {
+        self.statuses.iter().find(|s| s.id == id)
+    }
+
+    /// `id` is the status orders are closed with, `完成`
+    pub fn closes(&self, id: i32) -> bool {
+        matches!(self.status(id), Some(s) if s.code == COMPLETED)
+    }
+
+    /// status of a new order
+    pub fn initial(&self, name: &str) -> Result<&Status, TransitionError> {
+        let status = self.find(name)?;
+        if status.initial {
+            Ok(status)
+        } else {
+            let initial = self.statuses.iter().filter(|s| s.initial);
+            Err(TransitionError::NotInitial(
+                name.to_string(),
+                names(initial),
+            ))
+        }
+    }
+
+    /// the status orders in `from` move on from, uncoded ones and none count as initial
+    fn current(&self, from: Option<i32>) -> Option<&Status> {
+        from.and_then(|id| self.status(id))
+            .or_else(|| self.statuses.iter().find(|s| s.initial))
+    }
+
+    /// statuses `user` may move an order in `from` to
+    pub fn next(&self, from: Option<i32>, user: &CurrentUser) -> Vec<&Status> {
+        let current = match self.current(from) {
+            Some(current) => current,
+            None => return vec![],
+        };
+
+        self.statuses
+            .iter()
+            .filter(|to| {
+                self.transitions.iter().any(|t| {
+                    t.from_id == current.id
+                        && t.to_id == to.id
+                        && t.permission
+                            .as_deref()
+                            .map_or(true, |p| user.has_permission(p))
+                })
+            })
+            .collect()
+    }
+
+    /// id of status `to` if `user` may move an order in `from` there, staying is always fine
+    pub fn check(
+        &self,
+        from: Option<i32>,
+        to: &str,
+        user: &CurrentUser,
+    ) -> Result<i32, TransitionError> {
+        let target = self.find(to)?;
+        if Some(target.id) == from {
+            return Ok(target.id);
+        }
+
+        let current = self
+            .current(from)
+            .ok_or_else(|| TransitionError::NotAllowed {
+                from: String::from("none"),
+                to: target.flow.clone(),
+                next: vec![],
+            })?;
+        if current.id == target.id {
+            return Ok(target.id);
+        }
+
+        let transition = self
+            .transitions
+            .iter()
+            .find(|t| t.from_id == current.id && t.to_id == target.id)
+            .ok_or_else(|| TransitionError::NotAllowed {
+                from: current.flow.clone(),
+                to: target.flow.clone(),
+                next: names(self.next(from, user).into_iter()),
+            })?;
+
+        match transition.permission {
+            Some(ref permission) if !user.has_permission(permission) => {
+                Err(TransitionError::PermissionDeny {
+                    to: target.flow.clone(),
+                    permission: permission.clone(),
+                })
+            }
+            _ => Ok(target.id),
+        }
+    }
+
+    /// status `code` if orders in `from` move on there, by the rules alone: the customer
+    /// answering a quote holds no permission
+    pub fn follow(&self, from: Option<i32>, code: &str) -> Option<&Status> {
+        let current = self.current(from)?;
+        let target = self.statuses.iter().find(|s| s.code == code)?;
+        self.transitions
+            .iter()
+            .any(|t| t.from_id == current.id && t.to_id == target.id)
+            .then_some(target)
+    }
+}
+
+fn names<'a>(statuses: impl Iterator<Item = &'a Status>) -> Vec<String> {
+    statuses.map(|s| s.flow.clone()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use bit_vec::BitVec;
+
+    use super::*;
+
+    fn user(permissions: &[&str]) -> CurrentUser {
+        CurrentUser {
+            id: 1,
+            account: String::from("user1"),
+            department_reach: None,
+            permission: BitVec::from_elem(8, false),
+            roles: vec![],
+            permissions: permissions.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    /// the seeded workflow of the migration
+    fn workflow() -> Workflow {
+        let seed = [
+            (1, "received", "收件"),
+            (2, "quoted", "報價"),
+            (3, "updated", "更新"),
+            (4, "locked", "鎖定"),
+            (5, "returned", "退件"),
+            (6, "completed", "完成"),
+        ];
+        let rules = [
+            (1, 2, None),
+            (1, 5, None),
+            (2, 3, None),
+            (2, 4, Some("order.lock")),
+            (2, 5, None),
+            (3, 2, None),
+            (3, 4, Some("order.lock")),
+            (3, 5, None),
+            (4, 6, None),
+            (4, 5, None),
+            (6, 3, Some("order.reopen")),
+            (5, 1, Some("order.reopen")),
+        ];
+
+        Workflow {
+            statuses: seed
+                .iter()
+                .map(|&(id, code, flow)| Status {
+                    id,
+                    code: code.to_string(),
+                    flow: flow.to_string(),
+                    initial: id == 1,
+                })
+                .collect(),
+            transitions: rules
+                .iter()
+                .map(|&(from_id, to_id, permission)| Transition {
+                    from_id,
+                    to_id,
+                    permission: permission.map(String::from),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn follows_the_workflow() {
+        let workflow = workflow();
+        let clerk = user(&["order.update"]);
+
+        assert_eq!(workflow.check(Some(1), "報價", &clerk).unwrap(), 2);
+        assert_eq!(workflow.check(Some(2), "updated", &clerk).unwrap(), 3);
+        assert_eq!(workflow.check(Some(3), "更新", &clerk).unwrap(), 3);
+        assert_eq!(
+            workflow
+                .check(Some(1), "完成", &clerk)
+                .unwrap_err()
+                .to_string(),
+            "status 收件 -> 完成 not allowed, next: 報價, 退件"
+        );
+        assert!(matches!(
+            workflow.check(Some(1), "收建", &clerk),
+            Err(TransitionError::Unknown(_, _))
+        ));
+    }
+
+    #[test]
+    fn completed_closes() {
+        let workflow = workflow();
+        assert!(workflow.closes(6));
+        assert!(!workflow.closes(5) && !workflow.closes(7));
+    }
+
+    #[test]
+    fn lock_and_reopen_need_their_permission() {
+        let workflow = workflow();
+        let clerk = user(&["order.update"]);
+        let maintainer = user(&["order.update_assigned", "order.lock"]);
+        let gm = user(&["order.update", "order.reopen"]);
+
+        assert!(matches!(
+            workflow.check(Some(3), "鎖定", &clerk),
+            Err(TransitionError::PermissionDeny { .. })
+        ));
+        assert_eq!(workflow.check(Some(3), "鎖定", &maintainer).unwrap(), 4);
+        assert!(workflow.check(Some(6), "更新", &maintainer).is_err());
+        assert_eq!(workflow.check(Some(6), "更新", &gm).unwrap(), 3);
+
+        let codes = |user| -> Vec<String> {
+            workflow
+                .next(Some(3), user)
+                .iter()
+                .map(|s| s.code.clone())
+                .collect()
+        };
+        assert_eq!(codes(&clerk), vec!["quoted", "returned"]);
+        assert_eq!(codes(&maintainer), vec!["quoted", "locked", "returned"]);
+        assert!(workflow.next(Some(6), &clerk).is_empty());
+    }
+
+    #[test]
+    fn quote_answers_follow_the_rules_only() {
+        let workflow = workflow();
+
+        assert_eq!(workflow.follow(Some(2), APPROVED).unwrap().id, 3);
+        assert_eq!(workflow.follow(Some(2), RETURNED).unwrap().id, 5);
+        /* moved on meanwhile, or no such transition */
+        assert!(workflow.follow(Some(4), APPROVED).is_none());
+        assert!(workflow.follow(Some(2), QUOTED).is_none());
+    }
+
+    #[test]
+    fn new_and_legacy_orders_start_received() {
+        let workflow = workflow();
+        let clerk = user(&["order.update"]);
+
+        assert_eq!(workflow.initial("收件").unwrap().id, 1);
+        assert_eq!(
+            workflow.initial("完成").unwrap_err().to_string(),
+            "orders can't be created as 完成, one of 收件"
+        );
+        assert_eq!(workflow.check(None, "報價", &clerk).unwrap(), 2);
+        assert_eq!(workflow.check(Some(99), "received", &clerk).unwrap(), 1);
+        assert!(workflow.check(Some(99), "鎖定", &clerk).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+//! Password hashing policy, new hashes are Argon2id, existing PBKDF2 ones still verify
+//! and get upgraded on the next login.
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use pbkdf2::Pbkdf2;
+use rand_core::OsRng;
+use shuttle_secrets::SecretStore;
+
+use crate::{errors::PasswordHashError, secret};
+
+/// Argon2id cost, OWASP minimum by default (19 MiB, 2 passes, 1 lane).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HashPolicy {
+    /// memory in KiB
+    pub(crate) m_cost: u32,
+    pub(crate) t_cost: u32,
+    pub(crate) p_cost: u32,
+}
+
+impl Default for HashPolicy {
+    fn default() -> Self {
+        Self {
+            m_cost: 19 * 1024,
+            t_cost: 2,
+            p_cost: 1,
+        }
+    }
+}
+
+impl HashPolicy {
+    pub fn from_secrets(secret_store: &SecretStore) -> Self {
+        let default = Self::default();
+        let cost = |key: &str, fallback: u32| {
+            secret(secret_store, key)
+                .and_then(|s| s.parse::<u32>().ok())
+                .filter(|s| *s > 0)
+                .unwrap_or(fallback)
+        };
+
+        Self {
+            m_cost: cost("ARGON2_M_COST", default.m_cost),
+            t_cost: cost("ARGON2_T_COST", default.t_cost),
+            p_cost: cost("ARGON2_P_COST", default.p_cost),
+        }
+    }
+
+    fn argon2(&self) -> Result<Argon2<'static>, PasswordHashError> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, None)
+            .map_err(|e| PasswordHashError::Hashing(e.to_string()))?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+
+    /// PHC string (`$argon2id$...`)
+    pub fn hash(&self, password: &str) -> Result<String, PasswordHashError> {
+        let salt = SaltString::generate(&mut OsRng);
+
+        self.argon2()?
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| PasswordHashError::Hashing(e.to_string()))
+    }
+
+    /// `Ok(false)` for a wrong password, `Err` if the stored hash can't be used at all
+    pub fn verify(&self, password: &str, phc: &str) -> Result<bool, PasswordHashError> {
+        let hash = PasswordHash::new(phc).map_err(|_| PasswordHashError::Corrupt)?;
+
+        let verified = match hash.algorithm.as_str() {
+            "argon2id" | "argon2i" | "argon2d" => {
+                Argon2::default().verify_password(password.as_bytes(), &hash)
+            }
+            "pbkdf2-sha256" | "pbkdf2-sha512" | "pbkdf2" => {
+                Pbkdf2.verify_password(password.as_bytes(), &hash)
+            }
+            other => return Err(PasswordHashError::Unsupported(other.to_string())),
+        };
+
+        match verified {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(_) => Err(PasswordHashError::Corrupt),
+        }
+    }
+
+    /// stored hash is another algorithm or weaker/other params than the policy
+    pub fn outdated(&self, phc: &str) -> bool {
+        let hash = match PasswordHash::new(phc) {
+            Ok(hash) => hash,
+            Err(_) => return true,
+        };
+        if hash.algorithm.as_str() != "argon2id" || hash.version != Some(Version::V0x13.into()) {
+            return true;
+        }
+
+        match Params::try_from(&hash) {
+            Ok(params) => {
+                params.m_cost() != self.m_cost
+                    || params.t_cost() != self.t_cost
+                    || params.p_cost() != self.p_cost
+            }
+            Err(_) => true,
+        }
+    }
+
+    /// hash to store instead of `phc` after `password` verified against it, `None` if it's
+    /// up to the policy already
+    pub fn upgrade(&self, password: &str, phc: &str) -> Result<Option<String>, PasswordHashError> {
+        if self.outdated(phc) {
+            self.hash(password).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// cheap params, tests only
+    fn policy() -> HashPolicy {
+        HashPolicy {
+            m_cost: 1024,
+            t_cost: 1,
+            p_cost: 1,
+        }
+    }
+
+    #[test]
+    fn argon2id_round_trip() {
+        let hash = policy().hash("hunter42").unwrap();
+
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(policy().verify("hunter42", &hash).unwrap());
+        assert!(!policy().verify("hunter43", &hash).unwrap());
+        assert!(!policy().outdated(&hash));
+    }
+
+    #[test]
+    fn other_params_are_outdated() {
+        let hash = policy().hash("hunter42").unwrap();
+        let stronger = HashPolicy {
+            t_cost: 3,
+            ..policy()
+        };
+
+        assert!(stronger.verify("hunter42", &hash).unwrap());
+        assert!(stronger.outdated(&hash));
+    }
+
+    #[test]
+    fn legacy_pbkdf2_verifies_and_is_outdated() {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Pbkdf2
+            .hash_password(b"hunter42", &salt)
+            .unwrap()
+            .to_string();
+
+        assert!(policy().verify("hunter42", &hash).unwrap());
+        assert!(!policy().verify("hunter43", &hash).unwrap());
+        assert!(policy().outdated(&hash));
+    }
+
+    #[test]
+    fn login_upgrades_legacy_hashes_once() {
+        let salt = SaltString::generate(&mut OsRng);
+        let legacy = Pbkdf2
+            .hash_password(b"hunter42", &salt)
+            .unwrap()
+            .to_string();
+
+        let upgraded = policy().upgrade("hunter42", &legacy).unwrap().unwrap();
+        assert!(upgraded.starts_with("$argon2id$"));
+        assert!(policy().verify("hunter42", &upgraded).unwrap());
+        assert_eq!(policy().upgrade("hunter42", &upgraded).unwrap(), None);
+    }
+
+    #[test]
+    fn corrupt_hash_is_an_error() {
+        assert!(matches!(
+            policy().verify("hunter42", "not a PHC string"),
+            Err(PasswordHashError::Corrupt)
+        ));
+        assert!(matches!(
+            policy().verify(
+                "hunter42",
+                "$scrypt$ln=1,r=8,p=1$c2FsdHNhbHQ$aGFzaGhhc2hoYXNoaGFzaA"
+            ),
+            Err(PasswordHashError::Unsupported(_))
+        ));
+    }
+}
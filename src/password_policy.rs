@@ -0,0 +1,277 @@
+//! Password rules checked wherever a password is set, plus the reuse history in
+//! `password_histories`.
+
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use shuttle_secrets::SecretStore;
+use sqlx::{Executor, Postgres};
+use tracing::error;
+
+use crate::{authentication::AuthConfig, errors::SignupError, secret, Database};
+
+lazy_static! {
+    /// bundled list, lower case, one per line
+    static ref COMMON_PASSWORDS: HashSet<&'static str> = include_str!("common-passwords.txt")
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect();
+}
+
+/// Strength rules, 10+ characters of at least 3 classes and the last 5 not reused by default.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PasswordPolicy {
+    pub(crate) min_length: usize,
+    /// of lower case, upper case, digit and other
+    pub(crate) min_classes: usize,
+    /// previous passwords refused, 0 turns reuse checks off
+    pub(crate) history: i64,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 10,
+            min_classes: 3,
+            history: 5,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn from_secrets(secret_store: &SecretStore) -> Self {
+        let default = Self::default();
+        let number = |key: &str, fallback: usize| {
+            secret(secret_store, key)
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(fallback)
+        };
+
+        Self {
+            min_length: number("PASSWORD_MIN_LENGTH", default.min_length).max(1),
+            min_classes: number("PASSWORD_MIN_CLASSES", default.min_classes).min(4),
+            history: number("PASSWORD_HISTORY", default.history as usize) as i64,
+        }
+    }
+
+    /// rules not needing the DB, `personal` are account, phone, email, ... of the user
+    pub fn check(&self, password: &str, personal: &[&str]) -> Result<(), SignupError> {
+        if password.chars().count() < self.min_length {
+            return Err(SignupError::PasswordTooShort);
+        }
+        if char_classes(password) < self.min_classes {
+            return Err(SignupError::PasswordTooSimple);
+        }
+        if is_common(password) {
+            return Err(SignupError::PasswordTooCommon);
+        }
+        if is_personal(password, personal) {
+            return Err(SignupError::PasswordIsPersonal);
+        }
+        Ok(())
+    }
+}
+
+fn char_classes(password: &str) -> usize {
+    let mut classes = [false; 4];
+    for c in password.chars() {
+        let class = if c.is_lowercase() {
+            0
+        } else if c.is_uppercase() {
+            1
+        } else if c.is_numeric() {
+            2
+        } else {
+            3
+        };
+        classes[class] = true;
+    }
+    classes.iter().filter(|c| **c).count()
+}
+
+/// listed as is, or once trailing digits/symbols are cut (`Password123!`)
+fn is_common(password: &str) -> bool {
+    let lower = password.to_lowercase();
+    let stem = lower.trim_end_matches(|c: char| !c.is_alphabetic());
+
+    COMMON_PASSWORDS.contains(lower.as_str())
+        || (!stem.is_empty() && COMMON_PASSWORDS.contains(stem))
+}
+
+fn is_personal(password: &str, personal: &[&str]) -> bool {
+    let lower = password.trim().to_lowercase();
+
+    personal
+        .iter()
+        .map(|p| p.trim().to_lowercase())
+        .filter(|p| !p.is_empty())
+        .any(|p| {
+            lower == p
+                || p.split_once('@')
+                    .map_or(false, |(local, _)| !local.is_empty() && lower == local)
+        })
+}
+
+/// policy and reuse check of a new password, `user_id` is `None` at signup
+pub(crate) async fn check_new(
+    database: &Database,
+    config: &AuthConfig,
+    user_id: Option<i32>,
+    password: &str,
+    personal: &[&str],
+) -> Result<(), SignupError> {
+    config.password_policy.check(password, personal)?;
+
+    match user_id {
+        Some(user_id) => match reused(database, config, user_id, password).await {
+            Ok(false) => Ok(()),
+            Ok(true) => Err(SignupError::PasswordReused),
+            Err(e) => {
+                error!("password history of user/{user_id} - {e}");
+                Err(SignupError::InternalError)
+            }
+        },
+        None => Ok(()),
+    }
+}
+
+/// same as the current password or one of the last `history`
+async fn reused(
+    database: &Database,
+    config: &AuthConfig,
+    user_id: i32,
+    password: &str,
+) -> Result<bool> {
+    const QUERY: &str = r#"
+        SELECT password FROM users WHERE id = $1
+        UNION ALL
+        (SELECT password FROM password_histories
+            WHERE user_id = $1 ORDER BY id DESC LIMIT $2);
+    "#;
+
+    if config.password_policy.history == 0 {
+        return Ok(false);
+    }
+
+    let hashes: Vec<(String,)> = sqlx::query_as(QUERY)
+        .bind(user_id)
+        .bind(config.password_policy.history)
+        .fetch_all(database)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+
+    /* an unreadable old hash can't match, don't block the change for it */
+    Ok(hashes
+        .iter()
+        .any(|(hash,)| config.password_hash.verify(password, hash).unwrap_or(false)))
+}
+
+/// remember the new hash, keep only the last `history` of the user
+pub(crate) async fn history_push<'e, E>(
+    executor: E,
+    config: &AuthConfig,
+    user_id: i32,
+    hashed_password: &str,
+) -> Result<()>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    /* the DELETE doesn't see the new row, keep history - 1 older ones */
+    const QUERY: &str = r#"
+        WITH pushed AS (
+            INSERT INTO password_histories (user_id, password) VALUES ($1, $2)
+        )
+        DELETE FROM password_histories
+        WHERE user_id = $1 AND id NOT IN (
+            SELECT id FROM password_histories
+            WHERE user_id = $1 ORDER BY id DESC LIMIT $3
+        );
+    "#;
+
+    if config.password_policy.history == 0 {
+        return Ok(());
+    }
+
+    executor
+        .execute(
+            sqlx::query(QUERY)
+                .bind(user_id)
+                .bind(hashed_password)
+                .bind(config.password_policy.history - 1),
+        )
+        .await
+        .map(|_| ())
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_and_classes() {
+        let policy = PasswordPolicy::default();
+
+        assert!(matches!(
+            policy.check("Ab1!", &[]),
+            Err(SignupError::PasswordTooShort)
+        ));
+        assert!(matches!(
+            policy.check("abcdefghijk1", &[]),
+            Err(SignupError::PasswordTooSimple)
+        ));
+        assert!(policy.check("Correct-horse9", &[]).is_ok());
+        assert!(policy.check("中文密碼Abc12345", &[]).is_ok());
+    }
+
+    #[test]
+    fn common_passwords() {
+        let policy = PasswordPolicy {
+            min_length: 6,
+            min_classes: 1,
+            history: 0,
+        };
+
+        assert!(matches!(
+            policy.check("password", &[]),
+            Err(SignupError::PasswordTooCommon)
+        ));
+        assert!(matches!(
+            policy.check("Password123!", &[]),
+            Err(SignupError::PasswordTooCommon)
+        ));
+        assert!(matches!(
+            policy.check("QWERTYUIOP", &[]),
+            Err(SignupError::PasswordTooCommon)
+        ));
+        assert!(policy.check("1234567!", &[]).is_ok());
+    }
+
+    #[test]
+    fn personal_details() {
+        let policy = PasswordPolicy {
+            min_length: 6,
+            min_classes: 1,
+            history: 0,
+        };
+        let personal = ["Clerk_2023x", "0900123456", "wang.ming@dcare.tw"];
+
+        for password in [
+            "clerk_2023X",
+            "0900123456",
+            "Wang.Ming@dcare.tw",
+            "wang.ming",
+        ] {
+            assert!(
+                matches!(
+                    policy.check(password, &personal),
+                    Err(SignupError::PasswordIsPersonal)
+                ),
+                "{password}"
+            );
+        }
+        assert!(policy.check("Clerk_2023x!", &personal).is_ok());
+    }
+}
@@ -0,0 +1,240 @@
+//! Ledger of the money taken on an order, `order_payments`. Entries are only appended: a
+//! refund is an entry of its own, a wrong entry is voided by a reversing one that points at
+//! it. `orders.confirmed_paid` is the net of the ledger, kept along in the same transaction,
+//! and the balance due is the cost less that.
+
+use std::{error::Error, fmt::Display};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Postgres, Transaction};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// money taken from the customer
+    Charge,
+    /// money given back
+    Refund,
+}
+
+impl Direction {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Charge => "charge",
+            Direction::Refund => "refund",
+        }
+    }
+
+    pub(crate) fn parse(direction: &str) -> Option<Self> {
+        match direction {
+            "charge" => Some(Direction::Charge),
+            "refund" => Some(Direction::Refund),
+            _ => None,
+        }
+    }
+
+    /// direction of the entry voiding one of `self`
+    pub(crate) fn reverse(self) -> Self {
+        match self {
+            Direction::Charge => Direction::Refund,
+            Direction::Refund => Direction::Charge,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Method {
+    Cash,
+    Card,
+    Transfer,
+    LinePay,
+}
+
+impl Method {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Method::Cash => "cash",
+            Method::Card => "card",
+            Method::Transfer => "transfer",
+            Method::LinePay => "line_pay",
+        }
+    }
+}
+
+/// what the customer still owes, negative when a refund is owed
+pub(crate) fn balance_due(cost: Option<i32>, paid: Option<i32>) -> i32 {
+    cost.unwrap_or_default() - paid.unwrap_or_default()
+}
+
+/// a row to append, `method` as stored
+pub(crate) struct Entry<'a> {
+    pub order_id: i32,
+    pub direction: Direction,
+    pub method: &'a str,
+    pub amount: i32,
+    pub receiver_id: Option<i32>,
+    pub remark: Option<&'a str>,
+    /// the entry this one voids
+    pub voids_id: Option<i32>,
+}
+
+#[derive(Debug)]
+pub(crate) enum LedgerError {
+    /// more refunded than paid
+    Overdrawn {
+        paid: i64,
+        amount: i32,
+    },
+    /// the entry has a void already
+    Voided(i32),
+    Database(anyhow::Error),
+}
+
+impl Display for LedgerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LedgerError::Overdrawn { paid, amount } => {
+                f.write_fmt(format_args!("{amount} can't be given back, {paid} paid"))
+            }
+            LedgerError::Voided(id) => f.write_fmt(format_args!("payments/{id} voided already")),
+            LedgerError::Database(e) => f.write_fmt(format_args!("{e}")),
+        }
+    }
+}
+
+impl Error for LedgerError {}
+
+/// net paid once `amount` in `direction` is added to `paid`
+fn settle(paid: i64, direction: Direction, amount: i32) -> Result<i64, LedgerError> {
+    match direction {
+        Direction::Charge => Ok(paid + i64::from(amount)),
+        Direction::Refund if i64::from(amount) > paid => {
+            Err(LedgerError::Overdrawn { paid, amount })
+        }
+        Direction::Refund => Ok(paid - i64::from(amount)),
+    }
+}
+
+/// the row of `entry` only, the department is the receiver's or else the order's;
+/// an entry voided already isn't voided again
+pub(crate) async fn insert(
+    tx: &mut Transaction<'_, Postgres>,
+    entry: &Entry<'_>,
+) -> Result<i32, LedgerError> {
+    const QUERY: &str = r#"
+        INSERT INTO order_payments (
+            order_id, direction, method, amount, receiver_id, department_id, remark, voids_id
+        )
+        SELECT o.id, $2, $3, $4, $5, COALESCE(u.department_id, o.department_id), $6, $7
+        FROM orders o
+            LEFT JOIN users u ON u.id = $5
+        WHERE o.id = $1
+        ON CONFLICT (voids_id) DO NOTHING
+        RETURNING id;"#;
+
+    let inserted = sqlx::query_as::<_, (i32,)>(QUERY)
+        .bind(entry.order_id)
+        .bind(entry.direction.as_str())
+        .bind(entry.method)
+        .bind(entry.amount)
+        .bind(entry.receiver_id)
+        .bind(entry.remark)
+        .bind(entry.voids_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| LedgerError::Database(anyhow!("DB error - {e}")))?;
+    match (inserted, entry.voids_id) {
+        (Some((id,)), _) => Ok(id),
+        /* voided meanwhile */
+        (None, Some(voided)) => Err(LedgerError::Voided(voided)),
+        (None, None) => Err(LedgerError::Database(anyhow!(
+            "order {} not found",
+            entry.order_id
+        ))),
+    }
+}
+
+/// append `entry` to the ledger of its order, which stays locked until `tx` commits, and
+/// bring `confirmed_paid` along with a history of the change; the id and the new net paid
+pub(crate) async fn append(
+    tx: &mut Transaction<'_, Postgres>,
+    entry: &Entry<'_>,
+) -> Result<(i32, i32), LedgerError> {
+    const LOCK_QUERY: &str = r#"
+        SELECT o.confirmed_paid, (
+            SELECT COALESCE(SUM(CASE p.direction WHEN 'charge' THEN p.amount ELSE -p.amount END), 0)
+            FROM order_payments p
+            WHERE p.order_id = o.id
+        )
+        FROM orders o
+        WHERE o.id = $1
+        FOR UPDATE;"#;
+    let (cached, paid) = sqlx::query_as::<_, (Option<i32>, i64)>(LOCK_QUERY)
+        .bind(entry.order_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| LedgerError::Database(anyhow!("DB error - {e}")))?;
+
+    let paid = settle(paid, entry.direction, entry.amount)?;
+    let paid = i32::try_from(paid)
+        .map_err(|_| LedgerError::Database(anyhow!("paid {paid} out of range")))?;
+    let id = insert(tx, entry).await?;
+
+    const UPDATE_QUERY: &str = r#"
+        WITH order_updated AS (
+            UPDATE orders SET
+                confirmed_paid = $2,
+                version = version + 1,
+                update_at = NOW()
+            WHERE id = $1
+            RETURNING *
+        )
+        INSERT INTO order_histories (
+            order_id, issuer_id, status_id, life_cycle, remark, cost, changes
+        )
+        SELECT id, $3, status_id, life_cycle, remark, cost, $4
+        FROM order_updated;"#;
+    sqlx::query(UPDATE_QUERY)
+        .bind(entry.order_id)
+        .bind(paid)
+        .bind(entry.receiver_id)
+        .bind(json!({ "confirmed_paid": { "old": cached, "new": paid } }))
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| LedgerError::Database(anyhow!("DB error - {e}")))?;
+
+    Ok((id, paid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refunds_never_exceed_the_paid_amount() {
+        assert_eq!(settle(0, Direction::Charge, 500).unwrap(), 500);
+        assert_eq!(settle(500, Direction::Refund, 500).unwrap(), 0);
+        assert_eq!(
+            settle(300, Direction::Refund, 500).unwrap_err().to_string(),
+            "500 can't be given back, 300 paid"
+        );
+    }
+
+    #[test]
+    fn balance_due_is_the_cost_less_the_paid() {
+        assert_eq!(balance_due(Some(3000), Some(500)), 2500);
+        assert_eq!(balance_due(Some(1000), Some(1200)), -200);
+        assert_eq!(balance_due(None, None), 0);
+    }
+
+    #[test]
+    fn voids_reverse_the_direction() {
+        let voided = Direction::parse("refund").unwrap();
+        assert_eq!(voided.reverse(), Direction::Charge);
+        assert_eq!(Direction::parse("line_pay"), None);
+    }
+}
@@ -0,0 +1,206 @@
+//! Which orders, users and departments a user may reach, on top of the role permissions.
+//! Everybody sees their own department and its descendants in `department_orgs`, the ADM
+//! department and admins see everything. Holders of `order.all` (GM) reach every order,
+//! and `order.update_assigned` lets maintainers update the orders assigned to them.
+
+use crate::{
+    authentication::CurrentUser,
+    rbac::{
+        OrderAll, OrderCreate, OrderDelete, OrderRead, OrderUpdate, OrderUpdateAssigned, Permission,
+    },
+};
+
+/// What is done to an order, create is checked with `order_create_allowed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OrderAction {
+    Read,
+    Update,
+    Delete,
+}
+
+/// The fields of an order the policy looks at.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct OrderScope {
+    pub department_id: Option<i32>,
+    pub maintainer_id: Option<i32>,
+}
+
+/// `department_id` is the user's own department or one of its descendants
+pub(crate) fn sees_department(user: &CurrentUser, department_id: Option<i32>) -> bool {
+    match (&user.department_reach, department_id) {
+        (None, _) => true,
+        (Some(reach), Some(id)) => reach.contains(&id),
+        (Some(_), None) => false,
+    }
+}
+
+/// SQL condition on a department id `column` of the departments the user sees
+pub(crate) fn department_filter(user: &CurrentUser, column: &str) -> String {
+    match user.department_reach {
+        None => "TRUE".to_string(),
+        Some(ref reach) => {
+            let ids: Vec<String> = reach.iter().map(i32::to_string).collect();
+            format!("{column} = ANY('{{{}}}')", ids.join(","))
+        }
+    }
+}
+
+pub(crate) fn order_allowed(user: &CurrentUser, action: OrderAction, order: OrderScope) -> bool {
+    let reach = user.has_permission(OrderAll::NAME) || sees_department(user, order.department_id);
+    let assigned = order.maintainer_id == Some(user.id);
+
+    match action {
+        OrderAction::Read => user.has_permission(OrderRead::NAME) && (reach || assigned),
+        OrderAction::Update => {
+            (user.has_permission(OrderUpdate::NAME) && reach)
+                || (user.has_permission(OrderUpdateAssigned::NAME) && assigned)
+        }
+        OrderAction::Delete => user.has_permission(OrderDelete::NAME) && reach,
+    }
+}
+
+pub(crate) fn order_create_allowed(user: &CurrentUser, department_id: Option<i32>) -> bool {
+    user.has_permission(OrderCreate::NAME)
+        && (user.has_permission(OrderAll::NAME) || sees_department(user, department_id))
+}
+
+/// SQL condition on `orders o` of the orders the user may read, `None` for none at all
+pub(crate) fn order_read_filter(user: &CurrentUser) -> Option<String> {
+    if !user.has_permission(OrderRead::NAME) {
+        return None;
+    }
+    if user.has_permission(OrderAll::NAME) || user.department_reach.is_none() {
+        return Some("TRUE".to_string());
+    }
+
+    Some(format!(
+        "({} OR o.maintainer_id = {})",
+        department_filter(user, "o.department_id"),
+        user.id
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use bit_vec::BitVec;
+
+    use super::*;
+
+    /// `reach` is the own department and descendants, `None` for everything
+    fn user(id: i32, reach: Option<&[i32]>, permissions: &[&str]) -> CurrentUser {
+        CurrentUser {
+            id,
+            account: format!("user{id}"),
+            department_reach: reach.map(<[i32]>::to_vec),
+            permission: BitVec::from_elem(8, false),
+            roles: vec![],
+            permissions: permissions.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn clerk_stays_in_own_department() {
+        let clerk = user(7, Some(&[3]), &["order.read", "order.create"]);
+        let own = OrderScope {
+            department_id: Some(3),
+            maintainer_id: None,
+        };
+        let other = OrderScope {
+            department_id: Some(4),
+            maintainer_id: None,
+        };
+
+        assert!(order_allowed(&clerk, OrderAction::Read, own));
+        assert!(!order_allowed(&clerk, OrderAction::Read, other));
+        assert!(!order_allowed(&clerk, OrderAction::Update, own));
+        assert!(!order_allowed(&clerk, OrderAction::Delete, own));
+        assert!(order_create_allowed(&clerk, Some(3)));
+        assert!(!order_create_allowed(&clerk, Some(4)));
+        assert!(!order_create_allowed(
+            &user(8, Some(&[]), &["order.create"]),
+            None
+        ));
+    }
+
+    #[test]
+    fn maintainer_updates_assigned_only() {
+        let maintainer = user(9, Some(&[5]), &["order.read", "order.update_assigned"]);
+        let assigned = OrderScope {
+            department_id: Some(3),
+            maintainer_id: Some(9),
+        };
+        let own_department = OrderScope {
+            department_id: Some(5),
+            maintainer_id: Some(10),
+        };
+
+        assert!(order_allowed(&maintainer, OrderAction::Read, assigned));
+        assert!(order_allowed(&maintainer, OrderAction::Update, assigned));
+        assert!(!order_allowed(
+            &maintainer,
+            OrderAction::Update,
+            own_department
+        ));
+        assert!(!order_allowed(&maintainer, OrderAction::Delete, assigned));
+    }
+
+    #[test]
+    fn order_all_reaches_everything() {
+        let gm = user(
+            1,
+            Some(&[]),
+            &[
+                "order.all",
+                "order.read",
+                "order.create",
+                "order.update",
+                "order.delete",
+            ],
+        );
+        let any = OrderScope {
+            department_id: Some(42),
+            maintainer_id: None,
+        };
+
+        assert!(order_allowed(&gm, OrderAction::Update, any));
+        assert!(order_allowed(&gm, OrderAction::Delete, any));
+        assert!(order_create_allowed(&gm, Some(42)));
+        assert_eq!(order_read_filter(&gm).as_deref(), Some("TRUE"));
+        assert_eq!(
+            order_read_filter(&user(7, Some(&[3]), &["order.read"])).as_deref(),
+            Some("(o.department_id = ANY('{3}') OR o.maintainer_id = 7)")
+        );
+        assert_eq!(order_read_filter(&user(7, Some(&[3]), &[])), None);
+    }
+
+    #[test]
+    fn descendants_and_admins() {
+        let manager = user(4, Some(&[2, 6, 7]), &["order.read", "order.update"]);
+        let child = OrderScope {
+            department_id: Some(7),
+            maintainer_id: None,
+        };
+        let sibling = OrderScope {
+            department_id: Some(3),
+            maintainer_id: None,
+        };
+
+        assert!(order_allowed(&manager, OrderAction::Update, child));
+        assert!(!order_allowed(&manager, OrderAction::Read, sibling));
+        assert!(!sees_department(&manager, None));
+        assert_eq!(
+            department_filter(&manager, "u.department_id"),
+            "u.department_id = ANY('{2,6,7}')"
+        );
+        assert_eq!(
+            department_filter(&user(5, Some(&[]), &[]), "d.id"),
+            "d.id = ANY('{}')"
+        );
+
+        let admin = user(1, None, &["order.read"]);
+        assert!(order_allowed(&admin, OrderAction::Read, sibling));
+        assert!(sees_department(&admin, None));
+        assert_eq!(department_filter(&admin, "d.id"), "TRUE");
+        assert_eq!(order_read_filter(&admin).as_deref(), Some("TRUE"));
+    }
+}
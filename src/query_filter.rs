@@ -0,0 +1,339 @@
+//! `WHERE` clauses of the list endpoints. Values from the query string are always bound
+//! as parameters, only column names and SQL written in the code end up in the text.
+
+use sqlx::{Encode, Postgres, QueryBuilder, Type};
+
+/// `SELECT ... FROM ...` of a list plus the conditions added so far, all `AND`ed.
+pub(crate) struct ListFilter<'a> {
+    builder: QueryBuilder<'a, Postgres>,
+    conditions: usize,
+    rank: Option<Rank<'a>>,
+}
+
+/// `ORDER BY` part pushed by `finish`, for rankings with bound values
+type Rank<'a> = Box<dyn FnOnce(&mut QueryBuilder<'a, Postgres>) + Send + 'a>;
+
+impl<'a> ListFilter<'a> {
+    pub fn new(select: &str) -> Self {
+        Self {
+            builder: QueryBuilder::new(select),
+            conditions: 0,
+            rank: None,
+        }
+    }
+
+    /// start the next condition, `WHERE` for the first one
+    fn condition(&mut self) -> &mut QueryBuilder<'a, Postgres> {
+        let keyword = if self.conditions == 0 {
+            " WHERE "
+        } else {
+            " AND "
+        };
+        self.conditions += 1;
+        self.builder.push(keyword)
+    }
+
+    /// `column = value`
+    pub fn eq<T>(&mut self, column: &str, value: Option<T>) -> &mut Self
+    where
+        T: 'a + Encode<'a, Postgres> + Send + Type<Postgres>,
+    {
+        if let Some(value) = value {
+            self.condition().push(column).push(" = ").push_bind(value);
+        }
+        self
+    }
+
+    /// `column = ANY(values)`, an empty list is no filter
+    pub fn any_of<T>(&mut self, column: &str, values: Option<Vec<T>>) -> &mut Self
+    where
+        T: 'a + Send,
+        Vec<T>: Encode<'a, Postgres> + Type<Postgres>,
+    {
+        if let Some(values) = values.filter(|v| !v.is_empty()) {
+            self.condition()
+                .push(column)
+                .push(" = ANY(")
+                .push_bind(values)
+                .push(")");
+        }
+        self
+    }
+
+    /// `column IN (SELECT id FROM <table> WHERE <key> = ANY(values))`, for filters by
+    /// name (department shorten, user account, ...) of a foreign key
+    pub fn any_of_lookup(
+        &mut self,
+        column: &str,
+        table: &str,
+        key: &str,
+        values: Option<Vec<String>>,
+    ) -> &mut Self {
+        if let Some(values) = values.filter(|v| !v.is_empty()) {
+            self.condition()
+                .push(format!(
+                    "{column} IN (SELECT id FROM {table} WHERE {key} = ANY("
+                ))
+                .push_bind(values)
+                .push("))");
+        }
+        self
+    }
+
+    /// `column ILIKE 'value%'`, `%`, `_` and `\` in the value match literally
+    pub fn prefix(&mut self, column: &str, value: Option<&str>) -> &mut Self {
+        if let Some(value) = value.filter(|v| !v.is_empty()) {
+            self.condition()
+                .push(column)
+                .push(" ILIKE ")
+                .push_bind(format!("{}%", like_escape(value)));
+        }
+        self
+    }
+
+    /// `start <= column < end`, either end open
+    pub fn range<T>(&mut self, column: &str, start: Option<T>, end: Option<T>) -> &mut Self
+    where
+        T: 'a + Encode<'a, Postgres> + Send + Type<Postgres>,
+    {
+        if let Some(start) = start {
+            self.condition().push(column).push(" >= ").push_bind(start);
+        }
+        if let Some(end) = end {
+            self.condition().push(column).push(" < ").push_bind(end);
+        }
+        self
+    }
+
+    /// condition built by the server, e.g. the reach of `policy`, never request input
+    pub fn trusted(&mut self, condition: &str) -> &mut Self {
+        self.condition().push("(").push(condition).push(")");
+        self
+    }
+
+    /// any other condition, `build` pushes the SQL and binds the values itself
+    pub fn with(&mut self, build: impl FnOnce(&mut QueryBuilder<'a, Postgres>)) -> &mut Self {
+        build(self.condition());
+        self
+    }
+
+    /// order by `build` before the `order_by` of `finish`, e.g. the relevance of a search
+    pub fn rank_by(
+        &mut self,
+        build: impl FnOnce(&mut QueryBuilder<'a, Postgres>) + Send + 'a,
+    ) -> &mut Self {
+        self.rank = Some(Box::new(build));
+        self
+    }
+
+    /// `ORDER BY` (SQL of the code) and the page
+    pub fn finish(
+        mut self,
+        order_by: &str,
+        entries: i32,
+        offset: i32,
+    ) -> QueryBuilder<'a, Postgres> {
+        if let Some(rank) = self.rank.take() {
+            self.builder.push(" ORDER BY ");
+            rank(&mut self.builder);
+            if !order_by.is_empty() {
+                self.builder.push(", ").push(order_by);
+            }
+        } else if !order_by.is_empty() {
+            self.builder.push(" ORDER BY ").push(order_by);
+        }
+        self.builder
+            .push(" LIMIT ")
+            .push_bind(i64::from(entries.max(0)))
+            .push(" OFFSET ")
+            .push_bind(i64::from(offset.max(0)));
+        self.builder
+    }
+}
+
+/// comma separated query-string list, `BM,NN` or a single `BM`
+pub(crate) fn list(value: &Option<String>) -> Option<Vec<String>> {
+    value.as_ref().map(|v| {
+        v.split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(String::from)
+            .collect()
+    })
+}
+
+/// `LIKE` wildcards of `value` as plain characters, `\` is the default escape
+pub(crate) fn like_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::Query;
+    use serde::de::DeserializeOwned;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        dcare_audit::AuditListQuery,
+        dcare_order::{OrderHistoryListQuery, OrderListQuery},
+        dcare_user::UserListQuery,
+        department::DepartmentListQuery,
+    };
+
+    const HOSTILE: [&str; 6] = [
+        "' OR '1'='1",
+        "x'; DROP TABLE users; --",
+        "\") UNION SELECT password FROM users --",
+        "$$; SELECT 1; $$",
+        "%_\\",
+        "BM','NN",
+    ];
+
+    /// every field of `fields` set to `value`
+    fn query<T: DeserializeOwned>(fields: &[&str], value: &str) -> Option<Query<T>> {
+        let object: serde_json::Map<String, Value> = fields
+            .iter()
+            .map(|f| (f.to_string(), json!(value)))
+            .collect();
+        Some(Query(
+            serde_json::from_value(Value::Object(object)).unwrap(),
+        ))
+    }
+
+    /// the SQL text doesn't depend on the values, those are all bound
+    fn assert_bound<F>(fields: &[&str], build: F)
+    where
+        F: Fn(&[&str], &str) -> String,
+    {
+        let benign = build(fields, "benign");
+        for hostile in HOSTILE {
+            let sql = build(fields, hostile);
+            assert_eq!(sql, benign, "{hostile}");
+            assert!(!sql.contains(hostile), "{hostile}");
+        }
+
+        /* and every field is a filter of its own */
+        let unfiltered = build(&[], "").matches('$').count();
+        for field in fields {
+            let sql = build(&[field], "benign");
+            assert!(sql.matches('$').count() > unfiltered, "{field}: {sql}");
+        }
+    }
+
+    #[test]
+    fn order_list_filters() {
+        let fields = [
+            "q",
+            "phone",
+            "customer",
+            "department",
+            "contact",
+            "servicer",
+            "maintainer",
+            "status",
+            "life_cycle",
+        ];
+        assert_bound(&fields, |fields, value| {
+            let mut filter = ListFilter::new("SELECT o.sn FROM orders o");
+            let (offset, entries) = OrderListQuery::parse(query(fields, value), &mut filter);
+            filter.finish("o.issue_at", entries, offset).into_sql()
+        });
+    }
+
+    #[test]
+    fn order_history_list_filters() {
+        let fields = ["issuer", "department"];
+        assert_bound(&fields, |fields, value| {
+            let mut filter = ListFilter::new("SELECT h.id FROM order_histories h");
+            let (offset, entries) = OrderHistoryListQuery::parse(query(fields, value), &mut filter);
+            filter.finish("h.change_at", entries, offset).into_sql()
+        });
+    }
+
+    #[test]
+    fn user_list_filters() {
+        let fields = [
+            "username",
+            "worker_id",
+            "title",
+            "department",
+            "phone",
+            "email",
+        ];
+        assert_bound(&fields, |fields, value| {
+            let mut filter = ListFilter::new("SELECT u.account FROM users u");
+            let (offset, entries) = UserListQuery::parse(query(fields, value), &mut filter);
+            filter.finish("", entries, offset).into_sql()
+        });
+    }
+
+    #[test]
+    fn department_list_filters() {
+        let fields = ["shorten", "store_name", "owner", "telephone", "address"];
+        assert_bound(&fields, |fields, value| {
+            let mut filter = ListFilter::new("SELECT d.id FROM departments d");
+            let (offset, entries) = DepartmentListQuery::parse(query(fields, value), &mut filter);
+            filter.finish("", entries, offset).into_sql()
+        });
+    }
+
+    #[test]
+    fn audit_list_filters() {
+        let fields = [
+            "actor",
+            "action",
+            "entity_type",
+            "entity_id",
+            "ip",
+            "request_id",
+        ];
+        assert_bound(&fields, |fields, value| {
+            let mut filter = ListFilter::new("SELECT a.id FROM audit_events a");
+            let (offset, entries) = AuditListQuery::parse(query(fields, value), &mut filter);
+            filter.finish("a.at DESC", entries, offset).into_sql()
+        });
+    }
+
+    #[test]
+    fn builder_shapes() {
+        let mut filter = ListFilter::new("SELECT 1 FROM orders o");
+        filter
+            .eq("o.sn", Some("x"))
+            .eq::<&str>("o.remark", None)
+            .any_of("o.life_cycle", list(&Some("a, b,,".to_string())))
+            .any_of::<String>("o.service", Some(vec![]))
+            .any_of_lookup(
+                "o.department_id",
+                "departments",
+                "shorten",
+                Some(vec!["BM".into()]),
+            )
+            .prefix("o.customer_phone", Some("0912"))
+            .range("o.cost", Some(1), None::<i32>)
+            .trusted("o.department_id = 3");
+
+        assert_eq!(
+            filter.finish("o.issue_at", -5, 20).into_sql(),
+            "SELECT 1 FROM orders o WHERE o.sn = $1 AND o.life_cycle = ANY($2) \
+             AND o.department_id IN (SELECT id FROM departments WHERE shorten = ANY($3)) \
+             AND o.customer_phone ILIKE $4 AND o.cost >= $5 AND (o.department_id = 3) \
+             ORDER BY o.issue_at LIMIT $6 OFFSET $7"
+        );
+    }
+
+    #[test]
+    fn like_wildcards_are_literal() {
+        assert_eq!(like_escape("50%_off\\"), "50\\%\\_off\\\\");
+        assert_eq!(like_escape("0912"), "0912");
+        assert_eq!(list(&Some(" ,".to_string())), Some(vec![]));
+        assert_eq!(list(&None), None);
+    }
+}
@@ -0,0 +1,189 @@
+//! Quotes of an order, `quotes` and their `quote_items`. Items are faults and accessories of
+//! the catalogs, at their catalog price unless given, and labour. A revised quote is the next
+//! version and supersedes the pending one. The customer answers through an unguessable link,
+//! only the sha256 of its token is kept: accepting moves the order on to `更新` with the total
+//! as its cost, rejecting moves it to `退件`.
+
+use std::{error::Error, fmt::Display};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use shuttle_secrets::SecretStore;
+use utoipa::ToSchema;
+
+use crate::order_status::{APPROVED, RETURNED};
+use crate::secret;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemKind {
+    /// repair of a fault in `faults`
+    Fault,
+    /// part from `accessories`
+    Accessory,
+    Labour,
+}
+
+impl ItemKind {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ItemKind::Fault => "fault",
+            ItemKind::Accessory => "accessory",
+            ItemKind::Labour => "labour",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Accept,
+    Reject,
+}
+
+impl Decision {
+    /// state of the quote once answered
+    pub(crate) fn state(&self) -> &'static str {
+        match self {
+            Decision::Accept => "accepted",
+            Decision::Reject => "rejected",
+        }
+    }
+
+    /// status code the order moves on to
+    pub(crate) fn status(&self) -> &'static str {
+        match self {
+            Decision::Accept => APPROVED,
+            Decision::Reject => RETURNED,
+        }
+    }
+}
+
+/// why a quote can't be answered
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Refusal {
+    Expired,
+    /// a later version replaced it
+    Superseded,
+    /// answered already, with the state
+    Decided(String),
+}
+
+impl Display for Refusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Refusal::Expired => f.write_str("the quote has expired"),
+            Refusal::Superseded => f.write_str("the quote has been revised"),
+            Refusal::Decided(state) => f.write_fmt(format_args!("the quote is {state} already")),
+        }
+    }
+}
+
+impl Error for Refusal {}
+
+/// a quote in `state` expiring at `expires_at` may be answered `now`
+pub(crate) fn answerable(
+    state: &str,
+    expires_at: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Result<(), Refusal> {
+    match state {
+        "pending" if expires_at <= now => Err(Refusal::Expired),
+        "pending" => Ok(()),
+        "superseded" => Err(Refusal::Superseded),
+        state => Err(Refusal::Decided(state.to_string())),
+    }
+}
+
+/// sum of the items as quantity and unit price, `None` if it overflows
+pub(crate) fn total(items: impl IntoIterator<Item = (i32, i32)>) -> Option<i32> {
+    items
+        .into_iter()
+        .try_fold(0i32, |sum, (quantity, unit_price)| {
+            quantity
+                .checked_mul(unit_price)
+                .and_then(|amount| sum.checked_add(amount))
+        })
+}
+
+/// Quote settings, read from `SecretStore` (or environment) at startup.
+#[derive(Clone, Debug)]
+pub struct QuoteConfig {
+    /// approval links expire after this
+    pub(crate) lifetime: Duration,
+    /// customer page, the link is `<approval_url>/<token>`; the built-in `/quote/<token>`
+    /// page if not set
+    pub(crate) approval_url: Option<String>,
+}
+
+impl Default for QuoteConfig {
+    fn default() -> Self {
+        Self {
+            lifetime: Duration::days(14),
+            approval_url: None,
+        }
+    }
+}
+
+impl QuoteConfig {
+    pub fn from_secrets(secret_store: &SecretStore) -> Self {
+        let default = Self::default();
+        let lifetime = secret(secret_store, "QUOTE_LIFETIME_SECS")
+            .and_then(|s| s.parse::<i64>().ok())
+            .filter(|s| *s > 0)
+            .map(Duration::seconds)
+            .unwrap_or(default.lifetime);
+
+        Self {
+            lifetime,
+            approval_url: secret(secret_store, "QUOTE_APPROVAL_URL"),
+        }
+    }
+
+    /// the link sent to the customer
+    pub(crate) fn link(&self, token: &str) -> String {
+        match &self.approval_url {
+            Some(url) => format!("{}/{token}", url.trim_end_matches('/')),
+            None => format!("/quote/{token}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn total_of_the_items_or_none_on_overflow() {
+        assert_eq!(total([(1, 1500), (2, 300), (1, 0)]), Some(2100));
+        assert_eq!(total([]), Some(0));
+        assert_eq!(total([(2, i32::MAX)]), None);
+        assert_eq!(total([(1, i32::MAX), (1, 1)]), None);
+    }
+
+    #[test]
+    fn only_pending_quotes_in_time_are_answered() {
+        let now = Utc::now();
+        let later = now + Duration::hours(1);
+
+        assert_eq!(answerable("pending", later, now), Ok(()));
+        assert_eq!(answerable("pending", now, now), Err(Refusal::Expired));
+        assert_eq!(
+            answerable("superseded", later, now),
+            Err(Refusal::Superseded)
+        );
+        assert_eq!(
+            answerable("accepted", later, now).unwrap_err().to_string(),
+            "the quote is accepted already"
+        );
+    }
+
+    #[test]
+    fn links_go_to_the_approval_page() {
+        let mut config = QuoteConfig::default();
+        assert_eq!(config.link("abc"), "/quote/abc");
+
+        config.approval_url = Some(String::from("https://dcare.example/quote/"));
+        assert_eq!(config.link("abc"), "https://dcare.example/quote/abc");
+    }
+}
@@ -0,0 +1,236 @@
+//! Named permissions (`order.update`, ...) granted through roles, and the `Require` guard
+//! handlers use to declare the permission they need.
+
+use std::{marker::PhantomData, ops::Deref};
+
+use anyhow::{anyhow, Result};
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use bit_vec::BitVec;
+use http::request::Parts;
+use sqlx::{Postgres, Transaction};
+use tracing::error;
+
+use crate::{
+    authentication::{AuthState, CurrentUser},
+    ApiResponse, Database,
+};
+
+/// built-in roles of the old `users.permission` bits, by bit position
+const BIT_ROLES: [&str; 5] = ["admin", "gm", "maintainer", "comissioner", "jshall"];
+/// role of users with no bit set
+const NO_BIT_ROLE: &str = "other";
+const PERMISSION_BITS: usize = 8;
+
+/// A permission a handler can require, see `Require`.
+pub(crate) trait Permission {
+    const NAME: &'static str;
+}
+
+macro_rules! permissions {
+    ($($ty:ident => $name:literal),* $(,)?) => {
+        $(
+            #[allow(dead_code)]
+            pub(crate) struct $ty;
+
+            impl Permission for $ty {
+                const NAME: &'static str = $name;
+            }
+        )*
+    };
+}
+
+permissions! {
+    UserRead => "user.read",
+    UserCreate => "user.create",
+    UserUpdate => "user.update",
+    UserDelete => "user.delete",
+    UserSecurity => "user.security",
+    RoleManage => "role.manage",
+    OrderRead => "order.read",
+    OrderCreate => "order.create",
+    OrderUpdate => "order.update",
+    OrderDelete => "order.delete",
+    OrderAll => "order.all",
+    OrderUpdateAssigned => "order.update_assigned",
+    OrderCloseUnpaid => "order.close_unpaid",
+    DepartmentRead => "department.read",
+    DepartmentCreate => "department.create",
+    DepartmentUpdate => "department.update",
+    DepartmentDelete => "department.delete",
+    AuditRead => "audit.read",
+    TrashManage => "trash.manage",
+    CatalogManage => "catalog.manage",
+    TemplateManage => "template.manage",
+}
+
+/// Logged-in user holding permission `P`, otherwise the request is answered
+/// with `not login` (400) or `permission deny` (403 status).
+pub(crate) struct Require<P: Permission>(CurrentUser, PhantomData<P>);
+
+impl<P: Permission> Deref for Require<P> {
+    type Target = CurrentUser;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[async_trait]
+impl<S, P> FromRequestParts<S> for Require<P>
+where
+    S: Send + Sync,
+    P: Permission + Send,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let mut auth_state = parts
+            .extensions
+            .get::<AuthState>()
+            .cloned()
+            .ok_or_else(|| deny(400, String::from("not login")))?;
+
+        match auth_state.get_user().await {
+            Some(user) if user.has_permission(P::NAME) => Ok(Self(user.clone(), PhantomData)),
+            Some(user) => {
+                error!("{} lacks {}", user.account, P::NAME);
+                Err(forbidden(format!("permission deny, {} required", P::NAME)))
+            }
+            None => Err(deny(400, String::from("not login"))),
+        }
+    }
+}
+
+fn deny(code: u16, message: String) -> Response {
+    let resp = ApiResponse::new(code, Some(message));
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
+/// denial with a real 403 status, not only in the body `code`
+pub(crate) fn forbidden(message: String) -> Response {
+    let resp = ApiResponse::new(403, Some(message));
+    error!("{:?}", &resp);
+    (StatusCode::FORBIDDEN, Json(resp)).into_response()
+}
+
+/// for handlers that decide on the permission at runtime
+pub(crate) fn allowed(current: Option<&CurrentUser>, permission: &str) -> bool {
+    current.map_or(false, |c| c.has_permission(permission))
+}
+
+/// roles of the old permission bits, every set bit counts
+pub(crate) fn roles_from_bits(bits: &BitVec) -> Vec<String> {
+    let roles: Vec<String> = BIT_ROLES
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| bits.get(*bit).unwrap_or(false))
+        .map(|(_, role)| role.to_string())
+        .collect();
+
+    if roles.is_empty() {
+        vec![NO_BIT_ROLE.to_string()]
+    } else {
+        roles
+    }
+}
+
+/// permission bits still reported to older clients, roles without a bit are left out
+pub(crate) fn bits_from_roles(roles: &[String]) -> BitVec {
+    let mut bits = BitVec::from_elem(PERMISSION_BITS, false);
+    for (bit, role) in BIT_ROLES.iter().enumerate() {
+        if roles.iter().any(|r| r == role) {
+            bits.set(bit, true);
+        }
+    }
+    bits
+}
+
+/// effective permissions of a user
+pub(crate) async fn user_permissions(database: &Database, user_id: i32) -> Result<Vec<String>> {
+    const QUERY: &str = "SELECT user_permission_names($1);";
+
+    sqlx::query_as(QUERY)
+        .bind(user_id)
+        .fetch_one(database)
+        .await
+        .map(|(permissions,)| permissions)
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+/// replace the roles of a user, keep `users.permission` in step, `Ok(false)` for an
+/// unknown role name, roll the transaction back then
+pub(crate) async fn user_roles_replace(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i32,
+    roles: &[String],
+) -> Result<bool> {
+    const DELETE_QUERY: &str = "DELETE FROM user_roles WHERE user_id = $1;";
+    const INSERT_QUERY: &str = r#"
+        INSERT INTO user_roles (user_id, role_id)
+        SELECT $1, id FROM roles WHERE name = ANY($2);
+    "#;
+    const BITS_QUERY: &str =
+        "UPDATE users SET permission = $2, version = version + 1 WHERE id = $1;";
+
+    sqlx::query(DELETE_QUERY)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+    let inserted = sqlx::query(INSERT_QUERY)
+        .bind(user_id)
+        .bind(roles)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?
+        .rows_affected();
+
+    let mut distinct = roles.to_vec();
+    distinct.sort();
+    distinct.dedup();
+    if inserted != distinct.len() as u64 {
+        return Ok(false);
+    }
+
+    sqlx::query(BITS_QUERY)
+        .bind(user_id)
+        .bind(bits_from_roles(roles))
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| anyhow!("DB error - {e}"))?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_set_bit_is_a_role() {
+        let mut bits = BitVec::from_elem(8, false);
+        assert_eq!(roles_from_bits(&bits), vec!["other"]);
+
+        bits.set(1, true);
+        bits.set(4, true);
+        bits.set(7, true);
+        assert_eq!(roles_from_bits(&bits), vec!["gm", "jshall"]);
+    }
+
+    #[test]
+    fn bits_round_trip() {
+        let roles = vec![String::from("admin"), String::from("maintainer")];
+        let bits = bits_from_roles(&roles);
+
+        assert_eq!(bits.len(), 8);
+        assert_eq!(bits.to_bytes(), vec![0b1010_0000]);
+        assert_eq!(roles_from_bits(&bits), roles);
+        assert!(!bits_from_roles(&[String::from("auditor")]).any());
+    }
+}
@@ -0,0 +1,506 @@
+//! Printed slips of an order: the intake receipt the customer signs at drop-off and the
+//! invoice handed over at pick-up. Both are Tera templates, rendered to HTML as they are or
+//! to text laid out on A4 pages of a PDF, with a QR code of the sn in the top-right corner.
+//! A department may replace any of the templates with its own, rendered in a Tera of its
+//! own without `get_env`, and its pages are served sandboxed.
+
+use std::{collections::HashMap, fmt::Write as _, io::Cursor, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, Utc};
+use printpdf::{path::PaintMode, BuiltinFont, Mm, PdfDocument, Rect};
+use qrcode::{Color, QrCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use shuttle_secrets::SecretStore;
+use tera::{Context, Tera};
+use tracing::{error, warn};
+use utoipa::ToSchema;
+
+use crate::appearance::Check;
+use crate::secret;
+
+/// built-in templates, `.html` for the page and `.txt` for the text of the PDF
+pub(crate) const TEMPLATES: [(&str, &str); 4] = [
+    ("receipt.html", include_str!("../templates/receipt.html")),
+    ("receipt.txt", include_str!("../templates/receipt.txt")),
+    ("invoice.html", include_str!("../templates/invoice.html")),
+    ("invoice.txt", include_str!("../templates/invoice.txt")),
+];
+
+const PAGE_WIDTH: f32 = 210.0;
+const PAGE_HEIGHT: f32 = 297.0;
+const MARGIN: f32 = 15.0;
+/// edge of the QR code in the PDF
+const QR_SIZE: f32 = 30.0;
+/// modules of blank border around the QR code in the HTML
+const QR_QUIET: usize = 4;
+const TEXT_PT: f32 = 10.0;
+/// a text line starting with `# `
+const HEADING_PT: f32 = 16.0;
+/// width of a half-width character at `TEXT_PT`, CJK ones take two
+const COLUMN_MM: f32 = 1.8;
+const PT_MM: f32 = 25.4 / 72.0;
+const LINE_SPACING: f32 = 1.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Receipt,
+    Invoice,
+}
+
+impl Kind {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Receipt => "receipt",
+            Self::Invoice => "invoice",
+        }
+    }
+
+    pub(crate) fn title(self) -> &'static str {
+        match self {
+            Self::Receipt => "收件單",
+            Self::Invoice => "請款單",
+        }
+    }
+
+    pub(crate) fn template(self, format: Format) -> String {
+        format!("{}.{}", self.name(), format.template_extension())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Html,
+    Pdf,
+}
+
+impl Format {
+    fn template_extension(self) -> &'static str {
+        match self {
+            Self::Html => "html",
+            Self::Pdf => "txt",
+        }
+    }
+}
+
+/// one of the built-in template names, the ones a department can replace
+pub(crate) fn is_template(name: &str) -> bool {
+    TEMPLATES.iter().any(|(known, _)| *known == name)
+}
+
+/// the department printed in the header
+#[derive(Debug, Default, Serialize, sqlx::FromRow)]
+pub(crate) struct Store {
+    pub shorten: String,
+    pub store_name: Option<String>,
+    pub address: Option<String>,
+    pub telephone: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct Amounts {
+    pub cost: i32,
+    /// taken at intake, counted in `confirmed_paid`
+    pub prepaid_free: i32,
+    /// net of the payment ledger
+    pub confirmed_paid: i32,
+    /// still to pay, never below zero
+    pub due: i32,
+    /// paid beyond the cost, owed back
+    pub refund: i32,
+}
+
+impl Amounts {
+    pub(crate) fn new(cost: Option<i32>, prepaid_free: Option<i32>, paid: Option<i32>) -> Self {
+        let (cost, prepaid_free, confirmed_paid) = (
+            cost.unwrap_or_default(),
+            prepaid_free.unwrap_or_default(),
+            paid.unwrap_or_default(),
+        );
+        Self {
+            cost,
+            prepaid_free,
+            confirmed_paid,
+            due: (cost - confirmed_paid).max(0),
+            refund: (confirmed_paid - cost).max(0),
+        }
+    }
+}
+
+/// what the templates see
+pub(crate) struct Slip<'a, T: Serialize> {
+    pub kind: Kind,
+    pub sn: &'a str,
+    pub issue_at: DateTime<Utc>,
+    pub order: &'a T,
+    pub store: &'a Store,
+    pub appearance: Vec<Check>,
+    pub amounts: Amounts,
+}
+
+impl<T: Serialize> Slip<'_, T> {
+    pub(crate) fn context(&self, now: DateTime<Utc>) -> Result<Context> {
+        let mut context = Context::new();
+        context.insert("kind", self.kind.name());
+        context.insert("title", self.kind.title());
+        context.insert("sn", self.sn);
+        context.insert("issued", &local_time(self.issue_at));
+        context.insert("printed", &local_time(now));
+        context.insert("order", self.order);
+        context.insert("store", self.store);
+        context.insert("appearance", &self.appearance);
+        context.insert("amounts", &self.amounts);
+        context.insert("qr_svg", &qr_svg(self.sn)?);
+        Ok(context)
+    }
+}
+
+pub(crate) fn local_time(at: DateTime<Utc>) -> String {
+    DateTime::<Local>::from(at)
+        .format("%Y/%m/%d %H:%M")
+        .to_string()
+}
+
+/// `name` with the department's own template if it has one and that renders, the built-in
+/// one otherwise
+pub(crate) fn render(
+    templates: &Tera,
+    name: &str,
+    custom: Option<&str>,
+    context: &Context,
+) -> Result<String> {
+    if let Some(body) = custom {
+        match render_custom(name, body, context) {
+            Ok(rendered) => return Ok(rendered),
+            Err(e) => error!(
+                "department template {name} - {}, built-in one used",
+                describe(&e)
+            ),
+        }
+    }
+
+    templates
+        .render(name, context)
+        .map_err(|e| anyhow!("template {name} - {}", describe(&e)))
+}
+
+/// `.html` autoescaped like the built-in ones, `get_env` stubbed out, it would read the
+/// secrets of the environment
+fn render_custom(name: &str, body: &str, context: &Context) -> tera::Result<String> {
+    let mut tera = Tera::default();
+    tera.register_function("get_env", |_: &HashMap<String, Value>| {
+        Err(tera::Error::msg(
+            "get_env isn't available to department templates",
+        ))
+    });
+    tera.add_raw_template(name, body)?;
+    tera.render(name, context)
+}
+
+/// `None` if `body` parses as a template, the reason otherwise
+pub(crate) fn template_error(name: &str, body: &str) -> Option<String> {
+    Tera::default()
+        .add_raw_template(name, body)
+        .err()
+        .map(|e| describe(&e))
+}
+
+/// tera puts the actual cause in the source chain
+fn describe(e: &tera::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = std::error::Error::source(e);
+    while let Some(cause) = source {
+        let _ = write!(message, ": {cause}");
+        source = cause.source();
+    }
+    message
+}
+
+/// font of the PDFs, a TrueType/OpenType file with CJK glyphs
+#[derive(Clone, Default)]
+pub struct PdfFont(Option<Arc<Vec<u8>>>);
+
+impl PdfFont {
+    pub fn from_secrets(secret_store: &SecretStore) -> Self {
+        match secret(secret_store, "PDF_FONT") {
+            Some(path) => match std::fs::read(&path) {
+                Ok(bytes) => Self::new(bytes),
+                Err(e) => {
+                    error!("PDF_FONT {path} - {e}, PDFs fall back to Helvetica");
+                    Self::default()
+                }
+            },
+            None => {
+                warn!("no PDF_FONT, PDFs are in Helvetica and can't show CJK");
+                Self::default()
+            }
+        }
+    }
+
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self(Some(Arc::new(bytes)))
+    }
+}
+
+/// `text` laid out on A4 pages, lines starting with `# ` as headings
+pub(crate) fn pdf(title: &str, text: &str, sn: &str, font: &PdfFont) -> Result<Vec<u8>> {
+    let (doc, page, layer) = PdfDocument::new(title, Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "text");
+    let font_ref = match &font.0 {
+        Some(bytes) => doc.add_external_font(Cursor::new(bytes.as_slice())),
+        None => doc.add_builtin_font(BuiltinFont::Helvetica),
+    }
+    .map_err(|e| anyhow!("PDF font - {e}"))?;
+
+    let top = PAGE_HEIGHT - MARGIN;
+    let mut layer = doc.get_page(page).get_layer(layer);
+
+    let (width, dark) = qr_modules(sn)?;
+    let module = QR_SIZE / width as f32;
+    let left = PAGE_WIDTH - MARGIN - QR_SIZE;
+    for (i, _) in dark.iter().enumerate().filter(|(_, dark)| **dark) {
+        let x = left + (i % width) as f32 * module;
+        let y = top - (i / width + 1) as f32 * module;
+        layer.add_rect(
+            Rect::new(Mm(x), Mm(y), Mm(x + module), Mm(y + module)).with_mode(PaintMode::Fill),
+        );
+    }
+
+    let mut y = top;
+    for source in text.lines() {
+        let (size, line) = match source.strip_prefix("# ") {
+            Some(heading) => (HEADING_PT, heading),
+            None => (TEXT_PT, source),
+        };
+        /* beside the QR code the line is shorter */
+        let right = if y > top - QR_SIZE {
+            left - 5.0
+        } else {
+            PAGE_WIDTH - MARGIN
+        };
+        let columns = ((right - MARGIN) / (COLUMN_MM * size / TEXT_PT)) as usize;
+        let advance = size * PT_MM * LINE_SPACING;
+
+        for piece in wrap(line, columns) {
+            if y - advance < MARGIN {
+                let (page, next) = doc.add_page(Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "text");
+                layer = doc.get_page(page).get_layer(next);
+                y = top;
+            }
+            y -= advance;
+            if piece.is_empty() {
+                continue;
+            }
+            let piece = match font.0 {
+                Some(_) => piece,
+                None => latin1(&piece),
+            };
+            layer.use_text(piece, size, Mm(MARGIN), Mm(y), &font_ref);
+        }
+    }
+
+    doc.save_to_bytes().map_err(|e| anyhow!("PDF - {e}"))
+}
+
+/// the built-in fonts only have Latin-1
+fn latin1(text: &str) -> String {
+    text.chars()
+        .map(|c| if (c as u32) < 0x100 { c } else { '?' })
+        .collect()
+}
+
+fn char_columns(c: char) -> usize {
+    if (c as u32) < 0x1100 {
+        1
+    } else {
+        2
+    }
+}
+
+/// `line` cut into pieces of at most `columns`, a blank line stays one empty piece
+fn wrap(line: &str, columns: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut piece = String::new();
+    let mut used = 0;
+    for c in line.trim_end().chars() {
+        let width = char_columns(c);
+        if used + width > columns && !piece.is_empty() {
+            pieces.push(std::mem::take(&mut piece));
+            used = 0;
+        }
+        piece.push(c);
+        used += width;
+    }
+    pieces.push(piece);
+    pieces
+}
+
+/// width and dark modules, row by row
+fn qr_modules(sn: &str) -> Result<(usize, Vec<bool>)> {
+    let code = QrCode::new(sn.as_bytes()).map_err(|e| anyhow!("QR code of {sn} - {e}"))?;
+    let dark = code
+        .to_colors()
+        .into_iter()
+        .map(|color| color == Color::Dark)
+        .collect();
+    Ok((code.width(), dark))
+}
+
+/// inline SVG of the QR code of `sn`
+fn qr_svg(sn: &str) -> Result<String> {
+    let (width, dark) = qr_modules(sn)?;
+    let size = width + 2 * QR_QUIET;
+
+    let mut path = String::new();
+    for (i, _) in dark.iter().enumerate().filter(|(_, dark)| **dark) {
+        let _ = write!(
+            path,
+            "M{} {}h1v1h-1z",
+            i % width + QR_QUIET,
+            i / width + QR_QUIET
+        );
+    }
+
+    Ok(format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {size} {size}" shape-rendering="crispEdges"><rect width="{size}" height="{size}" fill="#fff"/><path d="{path}" fill="#000"/></svg>"##
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn templates() -> Tera {
+        let mut tera = Tera::default();
+        tera.add_raw_templates(TEMPLATES.to_vec()).unwrap();
+        tera
+    }
+
+    fn context(kind: Kind) -> Context {
+        let order = json!({
+            "sn": "ADM230101001",
+            "customer_name": "王小明",
+            "customer_phone": "0912345678",
+            "customer_address": null,
+            "brand": "Apple",
+            "model": "iPhone 12",
+            "accessory1": "充電線",
+            "accessory2": null,
+            "accessory_other": null,
+            "appearance_other": "背蓋刮傷",
+            "service": null,
+            "fault1": "無法開機",
+            "fault2": null,
+            "fault_other": null,
+            "remark": null,
+            "warranty_expired": null,
+        });
+        let store = Store {
+            shorten: "ADM".to_string(),
+            store_name: Some("總部".to_string()),
+            address: None,
+            telephone: Some("02-12345678".to_string()),
+        };
+        Slip {
+            kind,
+            sn: "ADM230101001",
+            issue_at: Utc::now(),
+            order: &order,
+            store: &store,
+            appearance: vec![
+                Check {
+                    name: "screen_cracked".to_string(),
+                    label: "螢幕破裂".to_string(),
+                    checked: false,
+                },
+                Check {
+                    name: "water_indicator_tripped".to_string(),
+                    label: "進水標籤變色".to_string(),
+                    checked: true,
+                },
+            ],
+            amounts: Amounts::new(Some(3000), Some(500), Some(500)),
+        }
+        .context(Utc::now())
+        .unwrap()
+    }
+
+    #[test]
+    fn built_in_templates_render() {
+        let tera = templates();
+        for kind in [Kind::Receipt, Kind::Invoice] {
+            for format in [Format::Html, Format::Pdf] {
+                let name = kind.template(format);
+                let rendered = render(&tera, &name, None, &context(kind)).unwrap();
+                assert!(rendered.contains("ADM230101001"), "{name}");
+                assert!(rendered.contains("王小明"), "{name}");
+                assert!(rendered.contains("無法開機"), "{name}");
+                assert!(rendered.contains("進水標籤變色"), "{name}");
+            }
+        }
+
+        let invoice = render(&tera, "invoice.txt", None, &context(Kind::Invoice)).unwrap();
+        assert!(invoice.contains("2500"));
+    }
+
+    #[test]
+    fn broken_department_template_falls_back() {
+        let tera = templates();
+        let context = context(Kind::Receipt);
+
+        let custom = render(&tera, "receipt.html", Some("<b>{{ sn }}</b>"), &context).unwrap();
+        assert_eq!(custom, "<b>ADM230101001</b>");
+
+        let fallback = render(&tera, "receipt.html", Some("{{ nothing.here }}"), &context).unwrap();
+        assert!(fallback.contains("<svg"));
+
+        assert!(template_error("receipt.html", "{{ sn }}").is_none());
+        assert!(template_error("receipt.html", "{% if sn %}").is_some());
+    }
+
+    #[test]
+    fn department_template_reads_no_environment() {
+        let tera = templates();
+        let context = context(Kind::Receipt);
+        std::env::set_var("RECEIPT_TEST_SECRET", "hunter42");
+
+        let rendered = render(
+            &tera,
+            "receipt.html",
+            Some(r#"{{ get_env(name="RECEIPT_TEST_SECRET") }}"#),
+            &context,
+        )
+        .unwrap();
+        assert!(!rendered.contains("hunter42"));
+        assert!(rendered.contains("<svg"));
+    }
+
+    #[test]
+    fn amounts_due_never_negative() {
+        /* the prepaid amount is a payment of the ledger already */
+        assert_eq!(Amounts::new(Some(1000), Some(200), Some(300)).due, 700);
+        let overpaid = Amounts::new(Some(1000), None, Some(1200));
+        assert_eq!((overpaid.due, overpaid.refund), (0, 200));
+        assert_eq!(Amounts::new(None, None, None).due, 0);
+    }
+
+    #[test]
+    fn wrap_counts_cjk_double() {
+        assert_eq!(wrap("abcdef", 4), ["abcd", "ef"]);
+        assert_eq!(wrap("螢幕破裂", 5), ["螢幕", "破裂"]);
+        assert_eq!(wrap("", 4), [""]);
+    }
+
+    #[test]
+    fn pdf_spans_pages() {
+        let text = "# 收件單\n".to_string() + &"line\n".repeat(60);
+        let bytes = pdf("receipt", &text, "ADM230101001", &PdfFont::default()).unwrap();
+        assert!(bytes.starts_with(b"%PDF"));
+
+        let document = printpdf::lopdf::Document::load_mem(&bytes).unwrap();
+        assert_eq!(document.get_pages().len(), 2);
+    }
+}
@@ -0,0 +1,275 @@
+//! Soft delete of orders, users and departments. Deleting sets `deleted_at`/`deleted_by`
+//! and bumps `version`, every query skips those rows from then on. Holders of
+//! `trash.manage` restore them, or purge them for good: the schema sets references to a
+//! purged user NULL and refuses to purge a department orders or users still point at.
+//! A deleted row keeps its `sn`/`account`/`shorten`, creating it again is refused with a
+//! 409 until it's restored or purged.
+
+use anyhow::{anyhow, Result};
+use axum::{http::StatusCode, response::IntoResponse, Json};
+use sqlx::{Executor, Postgres};
+use tracing::error;
+
+use crate::audit::{Audit, AuditEvent};
+use crate::authentication::CurrentUser;
+use crate::etag;
+use crate::{ApiResponse, Database};
+
+/// foreign_key_violation, a purge refused by a RESTRICT reference
+const FOREIGN_KEY_VIOLATION: &str = "23503";
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Trash {
+    Order,
+    User,
+    Department,
+}
+
+impl Trash {
+    /// table of the rows, and the column the API names them by
+    fn table(self) -> (&'static str, &'static str) {
+        match self {
+            Self::Order => ("orders", "sn"),
+            Self::User => ("users", "account"),
+            Self::Department => ("departments", "shorten"),
+        }
+    }
+
+    /// entity of the audit log
+    fn entity(self) -> &'static str {
+        match self {
+            Self::Order => "order",
+            Self::User => "user",
+            Self::Department => "department",
+        }
+    }
+
+    fn restore_action(self) -> &'static str {
+        match self {
+            Self::Order => "order.restore",
+            Self::User => "user.restore",
+            Self::Department => "department.restore",
+        }
+    }
+
+    fn purge_action(self) -> &'static str {
+        match self {
+            Self::Order => "order.purge",
+            Self::User => "user.purge",
+            Self::Department => "department.purge",
+        }
+    }
+
+    fn delete_query(self) -> String {
+        let (table, key) = self.table();
+        format!(
+            "UPDATE {table} SET deleted_at = NOW(), deleted_by = $3, version = version + 1 \
+             WHERE {key} = $1 AND version = $2 AND deleted_at IS NULL;"
+        )
+    }
+
+    fn restore_query(self) -> String {
+        let (table, key) = self.table();
+        format!(
+            "UPDATE {table} SET deleted_at = NULL, deleted_by = NULL, version = version + 1 \
+             WHERE {key} = $1 AND deleted_at IS NOT NULL RETURNING version;"
+        )
+    }
+
+    fn purge_query(self) -> String {
+        let (table, key) = self.table();
+        format!("DELETE FROM {table} WHERE {key} = $1 AND deleted_at IS NOT NULL;")
+    }
+
+    fn trashed_query(self) -> String {
+        let (table, key) = self.table();
+        format!(
+            "SELECT EXISTS (SELECT 1 FROM {table} WHERE {key} = $1 AND deleted_at IS NOT NULL);"
+        )
+    }
+}
+
+/// 409 if `key` is taken by a deleted row, creating it again needs a restore or purge first
+pub(crate) async fn taken(
+    database: &Database,
+    trash: Trash,
+    key: &str,
+) -> Option<axum::response::Response> {
+    let entity = trash.entity();
+    let trashed: Result<(bool,), _> = sqlx::query_as(&trash.trashed_query())
+        .bind(key)
+        .fetch_one(database)
+        .await;
+
+    let resp = match trashed {
+        Ok((false,)) => return None,
+        Ok((true,)) => ApiResponse::new(
+            409,
+            Some(format!(
+                "{entity}/{key} is in the trash, restore or purge it first"
+            )),
+        ),
+        Err(e) => ApiResponse::new(500, Some(format!("DB error - {e}"))),
+    };
+    error!("{:?}", &resp);
+    Some((StatusCode::OK, Json(resp)).into_response())
+}
+
+/// soft delete `key` at `version`, `Ok(false)` if it isn't at `version` anymore
+pub(crate) async fn delete<'e, E>(
+    executor: E,
+    trash: Trash,
+    key: &str,
+    version: i32,
+    by: Option<i32>,
+) -> Result<bool>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    sqlx::query(&trash.delete_query())
+        .bind(key)
+        .bind(version)
+        .bind(by)
+        .execute(executor)
+        .await
+        .map(|done| done.rows_affected() > 0)
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+/// `Ok(None)` if `key` isn't deleted, its new version otherwise
+async fn restore_row<'e, E>(executor: E, trash: Trash, key: &str) -> Result<Option<i32>>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    sqlx::query_as(&trash.restore_query())
+        .bind(key)
+        .fetch_optional(executor)
+        .await
+        .map(|row| row.map(|(version,)| version))
+        .map_err(|e| anyhow!("DB error - {e}"))
+}
+
+enum Purged {
+    Done,
+    NotDeleted,
+    /// still referenced, the reason of the database
+    Referenced(String),
+}
+
+async fn purge_row<'e, E>(executor: E, trash: Trash, key: &str) -> Result<Purged>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    match sqlx::query(&trash.purge_query())
+        .bind(key)
+        .execute(executor)
+        .await
+    {
+        Ok(done) if done.rows_affected() > 0 => Ok(Purged::Done),
+        Ok(_) => Ok(Purged::NotDeleted),
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some(FOREIGN_KEY_VIOLATION) => {
+            Ok(Purged::Referenced(e.message().to_string()))
+        }
+        Err(e) => Err(anyhow!("DB error - {e}")),
+    }
+}
+
+fn internal_error(message: String) -> axum::response::Response {
+    let resp = ApiResponse::new(500, Some(message));
+    error!("{:?}", &resp);
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
+/// **RESTORE** of the handlers, 200 with the `ETag` of the new version, 404 if not deleted
+pub(crate) async fn restore(
+    database: &Database,
+    audit: &Audit,
+    by: &CurrentUser,
+    trash: Trash,
+    key: &str,
+) -> axum::response::Response {
+    let entity = trash.entity();
+    let mut tx = match database.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return internal_error(format!("DB error - {e}")),
+    };
+    match restore_row(&mut tx, trash, key).await {
+        Ok(Some(version)) => {
+            let event = AuditEvent::new(trash.restore_action(), entity, key).by(by);
+            if let Err(e) = audit.commit(tx, event).await {
+                return internal_error(format!("{e}"));
+            }
+
+            let resp = ApiResponse::new(200, Some(String::from("restore success")));
+            etag::with_etag(version, (StatusCode::OK, Json(resp)))
+        }
+        Ok(None) => {
+            let resp = ApiResponse::new(404, Some(format!("deleted {entity}/{key} not found")));
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => internal_error(format!("{e}")),
+    }
+}
+
+/// **PURGE** of the handlers, only of deleted rows, 409 if still referenced
+pub(crate) async fn purge(
+    database: &Database,
+    audit: &Audit,
+    by: &CurrentUser,
+    trash: Trash,
+    key: &str,
+) -> axum::response::Response {
+    let entity = trash.entity();
+    let mut tx = match database.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return internal_error(format!("DB error - {e}")),
+    };
+    let resp = match purge_row(&mut tx, trash, key).await {
+        Ok(Purged::Done) => {
+            let event = AuditEvent::new(trash.purge_action(), entity, key).by(by);
+            if let Err(e) = audit.commit(tx, event).await {
+                return internal_error(format!("{e}"));
+            }
+
+            ApiResponse::new(200, Some(String::from("purge success")))
+        }
+        Ok(Purged::NotDeleted) => ApiResponse::new(
+            404,
+            Some(format!("deleted {entity}/{key} not found, delete it first")),
+        ),
+        Ok(Purged::Referenced(reason)) => ApiResponse::new(
+            409,
+            Some(format!("{entity}/{key} still referenced - {reason}")),
+        ),
+        Err(e) => {
+            let resp = ApiResponse::new(500, Some(format!("{e}")));
+            error!("{:?}", &resp);
+            resp
+        }
+    };
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_trash_is_restored_or_purged() {
+        for trash in [Trash::Order, Trash::User, Trash::Department] {
+            let (table, key) = trash.table();
+
+            let delete = trash.delete_query();
+            assert!(delete.starts_with(&format!("UPDATE {table} ")), "{delete}");
+            assert!(delete.contains(&format!(
+                "WHERE {key} = $1 AND version = $2 AND deleted_at IS NULL"
+            )));
+
+            assert!(trash.restore_query().contains("AND deleted_at IS NOT NULL"));
+            assert_eq!(
+                trash.purge_query(),
+                format!("DELETE FROM {table} WHERE {key} = $1 AND deleted_at IS NOT NULL;")
+            );
+        }
+    }
+}
@@ -3,24 +3,23 @@
 use http::Response;
 use http_body::Empty;
 
-use crate::{
-    authentication::SessionToken, /*, errors::MultipartError*/
-    COOKIE_MAX_AGE, USER_COOKIE_NAME,
-};
+use crate::{authentication::SessionToken /*, errors::MultipartError*/, USER_COOKIE_NAME};
+
+pub(crate) fn session_cookie(value: &str, max_age: i64) -> String {
+    format!("{USER_COOKIE_NAME}={value}; Max-Age={max_age}; Path=/; HttpOnly")
+}
 
 #[allow(dead_code)]
-pub(crate) fn login_response(session_token: SessionToken) -> impl axum::response::IntoResponse {
+pub(crate) fn login_response(
+    session_token: SessionToken,
+    max_age: i64,
+) -> impl axum::response::IntoResponse {
     http::Response::builder()
         .status(http::StatusCode::SEE_OTHER)
         .header("Location", "/")
         .header(
             "Set-Cookie",
-            format!(
-                "{}={}; Max-Age={}",
-                USER_COOKIE_NAME,
-                session_token.into_cookie_value(),
-                COOKIE_MAX_AGE
-            ),
+            session_cookie(&session_token.into_cookie_value(), max_age),
         )
         .body(http_body::Empty::new())
         .unwrap()
@@ -32,7 +31,7 @@ pub(crate) async fn logout_response() -> impl axum::response::IntoResponse {
     Response::builder()
         .status(http::StatusCode::SEE_OTHER)
         .header("Location", "/")
-        .header("Set-Cookie", format!("{}=_; Max-Age=0", USER_COOKIE_NAME,))
+        .header("Set-Cookie", session_cookie("_", 0))
         .body(Empty::new())
         .unwrap()
 }
@@ -0,0 +1,36 @@
+use dcare_rest_service::blob::{BlobStore, FsBlobStore};
+
+#[tokio::test]
+async fn fs_blob_store_round_trip() {
+    let dir = std::env::temp_dir().join(format!("dcare-blobs-{}", std::process::id()));
+    let store = FsBlobStore::new(&dir);
+
+    store
+        .put("orders/1/photo.jpg", "image/jpeg", b"jpeg".to_vec())
+        .await
+        .unwrap();
+    assert_eq!(
+        store.get("orders/1/photo.jpg").await.unwrap(),
+        Some(b"jpeg".to_vec())
+    );
+
+    store.delete("orders/1/photo.jpg").await.unwrap();
+    assert_eq!(store.get("orders/1/photo.jpg").await.unwrap(), None);
+    /* gone already */
+    store.delete("orders/1/photo.jpg").await.unwrap();
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn fs_blob_store_stays_in_its_directory() {
+    let store = FsBlobStore::new(std::env::temp_dir().join("dcare-blobs-escape"));
+
+    for key in ["../escape", "/etc/passwd", "orders/../../escape", ""] {
+        assert!(
+            store.put(key, "text/plain", Vec::new()).await.is_err(),
+            "{key}"
+        );
+        assert!(store.get(key).await.is_err(), "{key}");
+    }
+}
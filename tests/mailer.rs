@@ -0,0 +1,32 @@
+use dcare_rest_service::mailer::{FileMailer, Mail, Mailer, MemoryMailer};
+
+fn reset_mail() -> Mail {
+    Mail {
+        to: String::from("clerk01@example.com"),
+        subject: String::from("dcare password reset"),
+        body: String::from("reset token: 0123abcd"),
+    }
+}
+
+#[tokio::test]
+async fn memory_mailer_keeps_sent() {
+    let mailer = MemoryMailer::default();
+    mailer.send(reset_mail()).await.unwrap();
+
+    assert_eq!(mailer.sent(), vec![reset_mail()]);
+}
+
+#[tokio::test]
+async fn file_mailer_writes_mail() {
+    let dir = std::env::temp_dir().join(format!("dcare-mails-{}", std::process::id()));
+    let mailer = FileMailer::new(&dir);
+    mailer.send(reset_mail()).await.unwrap();
+
+    let files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+    assert_eq!(files.len(), 1);
+    let content = std::fs::read_to_string(files[0].as_ref().unwrap().path()).unwrap();
+    assert!(content.contains("To: clerk01@example.com"));
+    assert!(content.contains("reset token: 0123abcd"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}